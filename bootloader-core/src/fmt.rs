@@ -0,0 +1,40 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Logging macros forwarding to `defmt` when the `defmt` feature is enabled.
+
+macro_rules! trace {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "defmt")]
+        defmt::trace!($s $(, $x)*);
+        #[cfg(not(feature = "defmt"))]
+        let _ = ($(&$x),*);
+    }};
+}
+
+macro_rules! debug {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "defmt")]
+        defmt::debug!($s $(, $x)*);
+        #[cfg(not(feature = "defmt"))]
+        let _ = ($(&$x),*);
+    }};
+}
+
+macro_rules! info {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "defmt")]
+        defmt::info!($s $(, $x)*);
+        #[cfg(not(feature = "defmt"))]
+        let _ = ($(&$x),*);
+    }};
+}
+
+macro_rules! error {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "defmt")]
+        defmt::error!($s $(, $x)*);
+        #[cfg(not(feature = "defmt"))]
+        let _ = ($(&$x),*);
+    }};
+}
@@ -0,0 +1,597 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Hardware independent update state machine of the bootloader.
+//!
+//! [`BootCore`] handles the host-protocol requests of the bootloader: erasing and writing the
+//! application area, sealing the challenge secret and verifying the firmware before booting it.
+//! Flash is accessed through [`NorFlash`], the UICR through [`Uicr`] and the signature check
+//! through [`Verifier`], so the same logic runs in the bootloader and, with the [`mock`]
+//! implementations, on the host.
+
+#![cfg_attr(not(any(test, feature = "mock")), no_std)]
+
+#[macro_use]
+mod fmt;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+
+use consts::{UICR_SEALED_SECRET, UICR_SEALED_WIPED};
+use crc::{Crc, CRC_32_ISCSI};
+use embedded_storage::nor_flash::NorFlash;
+use heapless::String;
+use hmac::{Hmac, Mac};
+use host_protocol::{envelope::Envelope, frame};
+use host_protocol::{Bootloader, Capabilities, HostProtocolMessage, PostcardError, ProtocolInfo, SecretSaveResponse, State, TrustLevel};
+use postcard::from_bytes;
+use sha2::Sha256 as ShaChallenge;
+
+/// Largest unaligned head of the application area that can be preserved when erasing.
+/// `BASE_APP_ADDR` is 1 kB past a flash page boundary.
+pub const MAX_PRESERVED: usize = 0x400;
+
+/// UICR registers holding the challenge secret
+pub trait Uicr {
+    /// Value of the seal register
+    fn seal(&self) -> u32;
+
+    /// The challenge secret, only meaningful once sealed
+    fn secret(&self) -> &[u8];
+
+    /// Writes the secret and then the seal.
+    /// Returns false if the secret doesn't read back.
+    fn write_secret(&mut self, secret: [u32; 8], seal: u32) -> bool;
+}
+
+/// Result of a signature check
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VerificationResult {
+    Valid,
+    Invalid,
+}
+
+/// Signature check of the firmware in the application area
+pub trait Verifier {
+    /// Version from the cosign2 header, `None` if there is no header
+    fn firmware_version(&mut self) -> Option<String<20>>;
+
+    /// Checks the firmware twice, so that glitching it once is not enough.
+    /// Returns both results and the firmware hash, `None` if there is no header.
+    fn verify(&mut self, trust: TrustLevel) -> Option<(VerificationResult, VerificationResult, [u8; 32])>;
+}
+
+/// Flash area reserved for the signed application
+#[derive(Clone, Copy, Debug)]
+pub struct AppArea {
+    /// First address of the application, not necessarily page aligned
+    pub start: u32,
+    /// First address past the application, page aligned
+    pub end: u32,
+}
+
+/// Tracks the state of firmware updates
+#[derive(Debug, Default)]
+pub struct BootState {
+    /// Current offset into flash memory
+    pub offset: u32,
+    /// Current flash sector being written
+    pub actual_sector: u32,
+    /// Index of the last successfully written packet
+    pub actual_pkt_idx: u32,
+}
+
+impl BootState {
+    fn reset(&mut self) {
+        self.offset = 0;
+        self.actual_sector = 0;
+        self.actual_pkt_idx = 0;
+    }
+}
+
+/// What the transport has to do after [`BootCore::process`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// Send the first bytes of the response buffer
+    Respond(usize),
+    /// Send the first bytes of the response buffer, then protect the flash and jump to the application
+    Boot(usize),
+    /// The host asked for a system reset
+    Reset,
+}
+
+/// Bootloader request handler
+pub struct BootCore<F, U, V> {
+    flash: F,
+    uicr: U,
+    verifier: V,
+    area: AppArea,
+    version: &'static str,
+    boot_status: BootState,
+    firmware_version: String<20>,
+}
+
+impl<F: NorFlash, U: Uicr, V: Verifier> BootCore<F, U, V> {
+    /// `version` is the bootloader version reported to the host
+    pub fn new(flash: F, uicr: U, verifier: V, area: AppArea, version: &'static str) -> Self {
+        Self {
+            flash,
+            uicr,
+            verifier,
+            area,
+            version,
+            boot_status: BootState::default(),
+            firmware_version: String::new(),
+        }
+    }
+
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    pub fn uicr(&mut self) -> &mut U {
+        &mut self.uicr
+    }
+
+    pub fn verifier(&mut self) -> &mut V {
+        &mut self.verifier
+    }
+
+    pub fn boot_status(&self) -> &BootState {
+        &self.boot_status
+    }
+
+    /// Handles a raw request and writes the response into `resp`.
+    ///
+    /// Framed requests get a CRC-protected response and enveloped requests get their request ID
+    /// echoed back. Returns `None` if there is nothing to send.
+    pub fn process(&mut self, req: &[u8], resp: &mut [u8]) -> Option<Outcome> {
+        let framed = frame::is_framed_request(req);
+        let (request_id, req) = match frame::open_request(req) {
+            Ok(buf) => {
+                let (request_id, buf) = Envelope::split(buf);
+                (request_id, from_bytes(buf).map_err(|_| PostcardError::Deser))
+            }
+            Err(e) => (None, Err(e)),
+        };
+        let (resp_msg, boot) = match req {
+            Ok(HostProtocolMessage::Reset) => return Some(Outcome::Reset),
+            Ok(HostProtocolMessage::Bootloader(Bootloader::BootFirmware { trust })) => self.boot_firmware(trust),
+            Ok(req) => (self.handle(req)?, false),
+            Err(e) => (HostProtocolMessage::PostcardError(e), false),
+        };
+
+        let payload_end = resp.len().checked_sub(frame::CRC_SIZE)?;
+        let payload_buf = resp.get_mut(frame::LEN_SIZE..payload_end)?;
+        let Ok(payload_len) = Envelope::new(request_id, resp_msg).encode(payload_buf).map(|payload| payload.len()) else {
+            error!("Failed to serialize response");
+            return None;
+        };
+        let Some(resp_len) = frame::finish_response(resp, payload_len, framed) else {
+            error!("Failed to frame response");
+            return None;
+        };
+        Some(match boot {
+            true => Outcome::Boot(resp_len),
+            false => Outcome::Respond(resp_len),
+        })
+    }
+
+    /// Handles a decoded request.
+    ///
+    /// `Reset` and `BootFirmware` are only handled by [`process`](Self::process),
+    /// unknown bootloader requests are not answered.
+    pub fn handle<'a>(&'a mut self, req: HostProtocolMessage<'a>) -> Option<HostProtocolMessage<'a>> {
+        match req {
+            HostProtocolMessage::Bootloader(boot_msg) => self.handle_bootloader(boot_msg).map(HostProtocolMessage::Bootloader),
+            // Handle challenge-response authentication
+            HostProtocolMessage::ChallengeRequest { nonce } => Some(self.hmac_challenge_response(nonce)),
+            // Report bootloader state
+            HostProtocolMessage::GetState => Some(HostProtocolMessage::AckState(State::FirmwareUpgrade)),
+            // Report protocol version and capabilities
+            HostProtocolMessage::GetProtocolInfo => Some(HostProtocolMessage::AckProtocolInfo(ProtocolInfo::new(
+                Capabilities::BOOTLOADER | Capabilities::CHALLENGE | Capabilities::REQUEST_ID | Capabilities::FRAME_CRC,
+            ))),
+            _ => Some(HostProtocolMessage::InappropriateMessage(State::FirmwareUpgrade)),
+        }
+    }
+
+    fn handle_bootloader<'a>(&'a mut self, req: Bootloader<'a>) -> Option<Bootloader<'a>> {
+        match req {
+            // Handle firmware erase command
+            Bootloader::EraseFirmware => {
+                trace!("Erase firmware");
+                Some(self.erase_firmware())
+            }
+            // Handle firmware block write
+            Bootloader::WriteFirmwareBlock {
+                block_idx: idx,
+                block_data: data,
+            } => Some(self.write_firmware_block(idx, data)),
+            // Get firmware version from header
+            Bootloader::FirmwareVersion => match self.verifier.firmware_version() {
+                Some(version) => {
+                    self.firmware_version = version;
+                    Some(Bootloader::AckFirmwareVersion {
+                        version: &self.firmware_version,
+                    })
+                }
+                None => Some(Bootloader::NoCosignHeader),
+            },
+            // Get bootloader version
+            Bootloader::BootloaderVersion => Some(Bootloader::AckBootloaderVersion { version: self.version }),
+            // Set challenge secret
+            Bootloader::ChallengeSet { secret } => Some(Bootloader::AckChallengeSet {
+                result: self.challenge_set(secret),
+            }),
+            _ => None,
+        }
+    }
+
+    fn erase_firmware(&mut self) -> Bootloader<'static> {
+        let page = F::ERASE_SIZE as u32;
+        let start = self.area.start / page * page;
+        debug!("start: 0x{:08X}", start);
+        if start == self.area.start {
+            return if self.flash.erase(self.area.start, self.area.end).is_ok() {
+                self.boot_status.reset();
+                Bootloader::AckEraseFirmware
+            } else {
+                error!("erase error");
+                Bootloader::NackEraseFirmware
+            };
+        }
+
+        // Keep the end of the SoftDevice sharing the first page with the application
+        let mut saved = [0; MAX_PRESERVED];
+        let Some(saved) = saved.get_mut(..(self.area.start - start) as usize) else {
+            error!("preserved area too large");
+            return Bootloader::NackEraseFirmwareRead;
+        };
+        if self.flash.read(start, saved).is_err() {
+            error!("read error");
+            return Bootloader::NackEraseFirmwareRead;
+        }
+        if self.flash.erase(start, self.area.end).is_err() {
+            error!("erase error");
+            return Bootloader::NackEraseFirmware;
+        }
+        self.boot_status.reset();
+        if self.flash.write(start, saved).is_err() {
+            error!("write error");
+            return Bootloader::NackEraseFirmwareWrite;
+        }
+        Bootloader::AckEraseFirmware
+    }
+
+    fn write_firmware_block(&mut self, idx: usize, data: &[u8]) -> Bootloader<'static> {
+        // Calculate target flash address
+        let cursor = self.area.start + self.boot_status.offset;
+        // Validate write is within application area
+        if cursor.checked_add(data.len() as u32).is_none_or(|end| end > self.area.end) {
+            return Bootloader::FirmwareOutOfBounds { block_idx: idx };
+        }
+        if self.flash.write(cursor, data).is_err() {
+            return Bootloader::NackWithIdx { block_idx: idx };
+        }
+        let page = F::ERASE_SIZE as u32;
+        self.boot_status.offset += data.len() as u32;
+        // Update status and sector tracking
+        self.boot_status.actual_sector = self.area.start + (self.boot_status.offset / page) * page;
+        debug!("Updating flash page starting at addr: {:02X}", self.boot_status.actual_sector);
+        debug!("offset : {:02X}", self.boot_status.actual_sector + self.boot_status.offset % page);
+
+        // Calculate CRC of written data
+        let crc = Crc::<u32>::new(&CRC_32_ISCSI);
+        let crc_pkt = crc.checksum(data);
+
+        self.boot_status.actual_pkt_idx = idx as u32;
+
+        // Send success acknowledgement with CRC
+        Bootloader::AckWithIdxCrc {
+            block_idx: idx,
+            crc: crc_pkt,
+        }
+    }
+
+    fn challenge_set(&mut self, secret: [u32; 8]) -> SecretSaveResponse {
+        let seal = self.uicr.seal();
+        if seal == UICR_SEALED_SECRET || seal == UICR_SEALED_WIPED {
+            SecretSaveResponse::NotAllowed
+        } else if secret.iter().all(|word| *word == 0) || secret.iter().all(|word| *word == u32::MAX) {
+            SecretSaveResponse::Error
+        } else if self.uicr.write_secret(secret, UICR_SEALED_SECRET) {
+            info!("Challenge secret is saved");
+            SecretSaveResponse::Sealed
+        } else {
+            SecretSaveResponse::Error
+        }
+    }
+
+    /// Handles a request to boot into firmware, returns the response and whether to boot
+    fn boot_firmware(&mut self, trust: TrustLevel) -> (HostProtocolMessage<'static>, bool) {
+        match self.verifier.verify(trust) {
+            Some((VerificationResult::Valid, VerificationResult::Valid, hash)) => {
+                info!("fw is valid");
+                // Clear the authentication secret if we are possibly booting a dev firmware
+                if self.uicr.seal() == UICR_SEALED_SECRET && trust == TrustLevel::Developer {
+                    self.uicr.write_secret([0; 8], UICR_SEALED_WIPED);
+                }
+                (
+                    HostProtocolMessage::Bootloader(Bootloader::AckVerifyFirmware { result: true, hash }),
+                    true,
+                )
+            }
+            Some((_, _, hash)) => {
+                info!("fw is invalid");
+                (
+                    HostProtocolMessage::Bootloader(Bootloader::AckVerifyFirmware { result: false, hash }),
+                    false,
+                )
+            }
+            None => (HostProtocolMessage::Bootloader(Bootloader::NoCosignHeader), false),
+        }
+    }
+
+    /// Handles HMAC challenge-response authentication
+    fn hmac_challenge_response(&self, nonce: u64) -> HostProtocolMessage<'static> {
+        if self.uicr.seal() != UICR_SEALED_SECRET {
+            return HostProtocolMessage::ChallengeResult { result: [0xFF; 32] };
+        }
+        type HmacSha256 = Hmac<ShaChallenge>;
+        if let Ok(mut mac) = HmacSha256::new_from_slice(self.uicr.secret()) {
+            mac.update(&nonce.to_be_bytes());
+            let result = mac.finalize().into_bytes();
+            HostProtocolMessage::ChallengeResult { result: result.into() }
+        } else {
+            HostProtocolMessage::ChallengeResult { result: [0xFF; 32] }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockBootCore, APP_AREA, FLASH_PAGE};
+    use consts::{BASE_APP_ADDR, BASE_BOOTLOADER_ADDR, BOND_STORAGE_ADDR};
+    use host_protocol::MAX_MSG_SIZE;
+
+    const OLD_FIRMWARE: u8 = 0x5D;
+
+    /// Re-decodes a response from a leaked buffer so it outlives the borrow of the core
+    fn to_static(msg: &Bootloader) -> Bootloader<'static> {
+        let mut buf = [0u8; MAX_MSG_SIZE];
+        let encoded = postcard::to_slice(msg, &mut buf).unwrap();
+        from_bytes(encoded.to_vec().leak()).unwrap()
+    }
+
+    fn bootloader(core: &mut MockBootCore, req: Bootloader) -> Option<Bootloader<'static>> {
+        match core.handle(HostProtocolMessage::Bootloader(req)) {
+            Some(HostProtocolMessage::Bootloader(resp)) => Some(to_static(&resp)),
+            None => None,
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    fn boot(core: &mut MockBootCore, trust: TrustLevel) -> (Option<Outcome>, HostProtocolMessage<'static>) {
+        let mut req = [0u8; MAX_MSG_SIZE];
+        let req = postcard::to_slice(&HostProtocolMessage::Bootloader(Bootloader::BootFirmware { trust }), &mut req).unwrap();
+        let mut resp = [0u8; MAX_MSG_SIZE];
+        let outcome = core.process(req, &mut resp);
+        let (Some(Outcome::Boot(len)) | Some(Outcome::Respond(len))) = outcome else {
+            panic!("no response");
+        };
+        let msg = from_bytes::<HostProtocolMessage>(resp[frame::LEN_SIZE..len].to_vec().leak()).unwrap();
+        (outcome, msg)
+    }
+
+    #[test]
+    fn erase_preserves_unaligned_head() {
+        let mut core = MockBootCore::mock(OLD_FIRMWARE);
+        assert_ne!(BASE_APP_ADDR as usize % FLASH_PAGE, 0);
+        assert_eq!(bootloader(&mut core, Bootloader::EraseFirmware), Some(Bootloader::AckEraseFirmware));
+
+        let page_start = BASE_APP_ADDR / FLASH_PAGE as u32 * FLASH_PAGE as u32;
+        let flash = core.flash();
+        assert!(flash.slice(0, BASE_APP_ADDR as usize).iter().all(|b| *b == OLD_FIRMWARE));
+        assert!(flash
+            .slice(BASE_APP_ADDR, (BOND_STORAGE_ADDR - BASE_APP_ADDR) as usize)
+            .iter()
+            .all(|b| *b == 0xFF));
+        assert_eq!(flash.slice(page_start, 4), &[OLD_FIRMWARE; 4]);
+        // The bonds survive updates
+        assert!(flash
+            .slice(BOND_STORAGE_ADDR, (BASE_BOOTLOADER_ADDR - BOND_STORAGE_ADDR) as usize)
+            .iter()
+            .all(|b| *b == OLD_FIRMWARE));
+    }
+
+    #[test]
+    fn erase_aligned_area() {
+        let area = AppArea {
+            start: 0x1B000,
+            end: BASE_BOOTLOADER_ADDR,
+        };
+        let mut core = MockBootCore::mock_with_area(OLD_FIRMWARE, area);
+        assert_eq!(bootloader(&mut core, Bootloader::EraseFirmware), Some(Bootloader::AckEraseFirmware));
+        assert!(core.flash().slice(0, 0x1B000).iter().all(|b| *b == OLD_FIRMWARE));
+        assert!(core.flash().slice(0x1B000, 0xC000).iter().all(|b| *b == 0xFF));
+
+        core.flash().fail_erase = true;
+        assert_eq!(
+            bootloader(&mut core, Bootloader::EraseFirmware),
+            Some(Bootloader::NackEraseFirmware)
+        );
+    }
+
+    #[test]
+    fn erase_too_far_from_page_start() {
+        let area = AppArea {
+            start: 0x1B800,
+            end: BASE_BOOTLOADER_ADDR,
+        };
+        let mut core = MockBootCore::mock_with_area(OLD_FIRMWARE, area);
+        assert_eq!(
+            bootloader(&mut core, Bootloader::EraseFirmware),
+            Some(Bootloader::NackEraseFirmwareRead)
+        );
+        assert!(core.flash().mem.iter().all(|b| *b == OLD_FIRMWARE));
+    }
+
+    #[test]
+    fn erase_write_verify_boot() {
+        let mut core = MockBootCore::mock(OLD_FIRMWARE);
+        let image: Vec<u8> = (0..3 * 256 + 64).map(|i| (i * 7) as u8).collect();
+
+        assert_eq!(bootloader(&mut core, Bootloader::EraseFirmware), Some(Bootloader::AckEraseFirmware));
+        for (block_idx, block_data) in image.chunks(256).enumerate() {
+            assert_eq!(
+                bootloader(&mut core, Bootloader::WriteFirmwareBlock { block_idx, block_data }),
+                Some(Bootloader::AckWithIdxCrc {
+                    block_idx,
+                    crc: Crc::<u32>::new(&CRC_32_ISCSI).checksum(block_data),
+                })
+            );
+        }
+        assert_eq!(core.boot_status().offset, image.len() as u32);
+        assert_eq!(core.boot_status().actual_pkt_idx, 3);
+        assert_eq!(core.flash().slice(BASE_APP_ADDR, image.len()), image);
+
+        assert_eq!(bootloader(&mut core, Bootloader::FirmwareVersion), Some(Bootloader::NoCosignHeader));
+        core.verifier().version = Some("4.0.0");
+        core.verifier().trusted = true;
+        core.verifier().hash = [0x11; 32];
+        assert_eq!(
+            bootloader(&mut core, Bootloader::FirmwareVersion),
+            Some(Bootloader::AckFirmwareVersion { version: "4.0.0" })
+        );
+        assert_eq!(
+            boot(&mut core, TrustLevel::Full),
+            (
+                Some(Outcome::Boot(37)),
+                HostProtocolMessage::Bootloader(Bootloader::AckVerifyFirmware {
+                    result: true,
+                    hash: [0x11; 32]
+                })
+            )
+        );
+    }
+
+    #[test]
+    fn untrusted_firmware_does_not_boot() {
+        let mut core = MockBootCore::mock(OLD_FIRMWARE);
+        assert_eq!(
+            boot(&mut core, TrustLevel::Full).1,
+            HostProtocolMessage::Bootloader(Bootloader::NoCosignHeader)
+        );
+        core.verifier().version = Some("4.0.0");
+        let (outcome, resp) = boot(&mut core, TrustLevel::Full);
+        assert!(matches!(outcome, Some(Outcome::Respond(_))));
+        assert_eq!(
+            resp,
+            HostProtocolMessage::Bootloader(Bootloader::AckVerifyFirmware {
+                result: false,
+                hash: [0; 32]
+            })
+        );
+        assert!(matches!(boot(&mut core, TrustLevel::Developer).0, Some(Outcome::Boot(_))));
+    }
+
+    #[test]
+    fn write_out_of_bounds() {
+        let mut core = MockBootCore::mock(0xFF);
+        let block = [0u8; 256];
+        let blocks = (APP_AREA.end - APP_AREA.start) as usize / block.len();
+        for block_idx in 0..blocks {
+            let resp = bootloader(
+                &mut core,
+                Bootloader::WriteFirmwareBlock {
+                    block_idx,
+                    block_data: &block,
+                },
+            );
+            assert!(matches!(resp, Some(Bootloader::AckWithIdxCrc { .. })), "block {block_idx}");
+        }
+        assert_eq!(
+            bootloader(
+                &mut core,
+                Bootloader::WriteFirmwareBlock {
+                    block_idx: blocks,
+                    block_data: &block[..4]
+                }
+            ),
+            Some(Bootloader::FirmwareOutOfBounds { block_idx: blocks })
+        );
+        // misaligned writes are refused by the flash
+        let mut core = MockBootCore::mock(0xFF);
+        assert_eq!(
+            bootloader(
+                &mut core,
+                Bootloader::WriteFirmwareBlock {
+                    block_idx: 0,
+                    block_data: &block[..3]
+                }
+            ),
+            Some(Bootloader::NackWithIdx { block_idx: 0 })
+        );
+    }
+
+    #[test]
+    fn challenge_set_sealing_rules() {
+        let mut core = MockBootCore::mock(0xFF);
+        let set = |core: &mut MockBootCore, secret| match bootloader(core, Bootloader::ChallengeSet { secret }) {
+            Some(Bootloader::AckChallengeSet { result }) => result,
+            other => panic!("unexpected {other:?}"),
+        };
+        let challenge = |core: &mut MockBootCore| match core.handle(HostProtocolMessage::ChallengeRequest { nonce: 7 }) {
+            Some(HostProtocolMessage::ChallengeResult { result }) => result,
+            other => panic!("unexpected {other:?}"),
+        };
+
+        assert_eq!(challenge(&mut core), [0xFF; 32]);
+        assert_eq!(set(&mut core, [0; 8]), SecretSaveResponse::Error);
+        assert_eq!(set(&mut core, [u32::MAX; 8]), SecretSaveResponse::Error);
+        assert_eq!(set(&mut core, [0x01020304; 8]), SecretSaveResponse::Sealed);
+        assert_eq!(core.uicr().seal, UICR_SEALED_SECRET);
+        assert_eq!(set(&mut core, [0x05060708; 8]), SecretSaveResponse::NotAllowed);
+
+        let mut mac = Hmac::<ShaChallenge>::new_from_slice(&[4, 3, 2, 1].repeat(8)).unwrap();
+        mac.update(&7u64.to_be_bytes());
+        let expected: [u8; 32] = mac.finalize().into_bytes().into();
+        assert_eq!(challenge(&mut core), expected);
+
+        // booting a developer firmware wipes the secret for good
+        core.verifier().version = Some("4.0.0");
+        assert!(matches!(boot(&mut core, TrustLevel::Developer).0, Some(Outcome::Boot(_))));
+        assert_eq!(core.uicr().seal, UICR_SEALED_WIPED);
+        assert_eq!(core.uicr().secret, [0; 32]);
+        assert_eq!(challenge(&mut core), [0xFF; 32]);
+        assert_eq!(set(&mut core, [0x01020304; 8]), SecretSaveResponse::NotAllowed);
+    }
+
+    #[test]
+    fn process_requests() {
+        let mut core = MockBootCore::mock(0xFF);
+        let mut resp = [0u8; MAX_MSG_SIZE];
+        // GetState
+        assert_eq!(core.process(&[3], &mut resp), Some(Outcome::Respond(4)));
+        assert_eq!(&resp[..4], &[0, 2, 4, 2]);
+        // Reset
+        assert_eq!(core.process(&[2], &mut resp), Some(Outcome::Reset));
+        // Bootloader responses are not answered
+        assert_eq!(core.process(&[1, 1], &mut resp), None);
+
+        let mut req = [0u8; MAX_MSG_SIZE];
+        let request = Envelope::new(Some(9), HostProtocolMessage::Bootloader(Bootloader::BootloaderVersion));
+        let req = frame::encode_request(&request, &mut req).unwrap();
+        let Some(Outcome::Respond(len)) = core.process(req, &mut resp) else {
+            panic!("no response");
+        };
+        assert_eq!(
+            frame::decode_response(&resp[..len]).unwrap(),
+            Envelope::new(
+                Some(9),
+                HostProtocolMessage::Bootloader(Bootloader::AckBootloaderVersion { version: "3.0.3" })
+            )
+        );
+    }
+}
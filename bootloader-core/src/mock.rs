@@ -0,0 +1,170 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! In-memory implementations of the hardware traits, to run [`BootCore`](crate::BootCore) on the host.
+
+use crate::{AppArea, BootCore, Uicr, VerificationResult, Verifier};
+use consts::{BASE_APP_ADDR, BASE_BOOTLOADER_ADDR, BOND_STORAGE_ADDR};
+use embedded_storage::nor_flash::{check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash};
+use heapless::String;
+use host_protocol::TrustLevel;
+
+/// Flash page size of the nRF52805
+pub const FLASH_PAGE: usize = 4096;
+
+/// NOR flash in RAM with the write and erase granularity of the nRF52805 NVMC.
+///
+/// Writes can only clear bits, like on the real flash.
+pub struct RamFlash {
+    pub mem: Vec<u8>,
+    /// Erase operations fail while set
+    pub fail_erase: bool,
+}
+
+impl RamFlash {
+    /// Flash covering everything below the bootloader, filled with `fill`
+    pub fn new(fill: u8) -> Self {
+        Self::with_capacity(fill, BASE_BOOTLOADER_ADDR as usize)
+    }
+
+    /// Flash of `capacity` bytes filled with `fill`
+    pub fn with_capacity(fill: u8, capacity: usize) -> Self {
+        Self {
+            mem: vec![fill; capacity],
+            fail_erase: false,
+        }
+    }
+
+    pub fn slice(&self, from: u32, len: usize) -> &[u8] {
+        &self.mem[from as usize..from as usize + len]
+    }
+}
+
+impl ErrorType for RamFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for RamFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        bytes.copy_from_slice(self.slice(offset, bytes.len()));
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.mem.len()
+    }
+}
+
+impl NorFlash for RamFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = FLASH_PAGE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        if self.fail_erase {
+            return Err(NorFlashErrorKind::Other);
+        }
+        self.mem[from as usize..to as usize].fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        for (cell, byte) in self.mem[offset as usize..].iter_mut().zip(bytes) {
+            *cell &= byte;
+        }
+        Ok(())
+    }
+}
+
+/// UICR customer registers, erased by default
+pub struct MockUicr {
+    pub secret: [u8; 32],
+    pub seal: u32,
+}
+
+impl Default for MockUicr {
+    fn default() -> Self {
+        Self {
+            secret: [0xFF; 32],
+            seal: u32::MAX,
+        }
+    }
+}
+
+impl Uicr for MockUicr {
+    fn seal(&self) -> u32 {
+        self.seal
+    }
+
+    fn secret(&self) -> &[u8] {
+        &self.secret
+    }
+
+    fn write_secret(&mut self, secret: [u32; 8], seal: u32) -> bool {
+        // UICR is flash as well
+        for (chunk, word) in self.secret.chunks_exact_mut(4).zip(secret) {
+            for (cell, byte) in chunk.iter_mut().zip(word.to_le_bytes()) {
+                *cell &= byte;
+            }
+        }
+        if self
+            .secret
+            .chunks_exact(4)
+            .zip(secret)
+            .any(|(chunk, word)| chunk != word.to_le_bytes())
+        {
+            return false;
+        }
+        self.seal &= seal;
+        true
+    }
+}
+
+/// Firmware header as the cosign2 verification would see it
+#[derive(Default)]
+pub struct MockVerifier {
+    /// Version in the header, `None` if there is no header
+    pub version: Option<&'static str>,
+    /// Signed by enough known signers to be fully trusted
+    pub trusted: bool,
+    pub hash: [u8; 32],
+}
+
+impl Verifier for MockVerifier {
+    fn firmware_version(&mut self) -> Option<String<20>> {
+        self.version.map(|version| version.try_into().unwrap())
+    }
+
+    fn verify(&mut self, trust: TrustLevel) -> Option<(VerificationResult, VerificationResult, [u8; 32])> {
+        self.version?;
+        let result = match self.trusted || trust == TrustLevel::Developer {
+            true => VerificationResult::Valid,
+            false => VerificationResult::Invalid,
+        };
+        Some((result, result, self.hash))
+    }
+}
+
+/// State machine with all mocks
+pub type MockBootCore = BootCore<RamFlash, MockUicr, MockVerifier>;
+
+/// Application area of the real memory layout
+pub const APP_AREA: AppArea = AppArea {
+    start: BASE_APP_ADDR,
+    end: BOND_STORAGE_ADDR,
+};
+
+impl MockBootCore {
+    /// Bootloader over a flash filled with `fill`, for the real memory layout
+    pub fn mock(fill: u8) -> Self {
+        Self::mock_with_area(fill, APP_AREA)
+    }
+
+    pub fn mock_with_area(fill: u8, area: AppArea) -> Self {
+        BootCore::new(RamFlash::new(fill), MockUicr::default(), MockVerifier::default(), area, "3.0.3")
+    }
+}
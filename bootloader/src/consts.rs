@@ -1,10 +1,10 @@
 // SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use crate::{BASE_APP_ADDR, BASE_BOOTLOADER_ADDR};
+use crate::{BASE_APP_ADDR, BASE_BOOTLOADER_ADDR, BOND_STORAGE_ADDR};
 #[cfg(not(feature = "debug"))]
 use consts_global::SIGNATURE_HEADER_SIZE;
-pub use consts_global::{UICR_SEALED_SECRET as SEALED_SECRET, UICR_SEALED_WIPED as SEALED_WIPED, UICR_SEAL_INDEX as SEAL_IDX};
+pub use consts_global::UICR_SEAL_INDEX as SEAL_IDX;
 
 #[used]
 /// Start address of the bootloader in flash memory, stored in UICR
@@ -34,13 +34,7 @@ pub const INT_VECTOR_TABLE_BASE: u32 = 0x1000;
 
 /// Size of the application area in flash memory
 /// This constant defines the maximum size available for the application firmware.
-/// Starting from BASE_APP_ADDR up to BASE_BOOTLOADER_ADDR
+/// Starting from BASE_APP_ADDR up to BOND_STORAGE_ADDR
 /// consider that a header is needed for cosign2 signature so real fw app goes from
-/// BASE_APP_ADDR + SIGNATURE_HEADER_SIZE to BASE_BOOTLOADER_ADDR
-pub const APP_SIZE: u32 = BASE_BOOTLOADER_ADDR - BASE_APP_ADDR;
-
-/// Size of a flash memory page in bytes (4KB)
-/// This constant defines the size of a single flash memory page on the nRF52 microcontroller.
-/// Flash memory is organized into pages that can be erased and written independently.
-/// The page size is important for flash operations as they must be aligned to page boundaries.
-pub const FLASH_PAGE: u32 = 4096;
+/// BASE_APP_ADDR + SIGNATURE_HEADER_SIZE to BOND_STORAGE_ADDR
+pub const APP_SIZE: u32 = BOND_STORAGE_ADDR - BASE_APP_ADDR;
@@ -18,15 +18,15 @@ mod verify;
 
 use defmt_rtt as _;
 use embassy_nrf::{self as _};
-use host_protocol::{State, TrustLevel};
+use host_protocol::TrustLevel;
 use panic_probe as _;
 
-use consts::{FLASH_PAGE, SEALED_SECRET, SEALED_WIPED, SEAL_IDX};
-use consts_global::{BASE_APP_ADDR, BASE_BOOTLOADER_ADDR, SIGNATURE_HEADER_SIZE, UICR_SECRET_SIZE, UICR_SECRET_START};
+use bootloader_core::{AppArea, BootCore, Outcome, Uicr, VerificationResult, Verifier};
+use consts::SEAL_IDX;
+use consts_global::{BASE_APP_ADDR, BASE_BOOTLOADER_ADDR, BOND_STORAGE_ADDR, SIGNATURE_HEADER_SIZE, UICR_SECRET_SIZE, UICR_SECRET_START};
 use core::cell::RefCell;
-use cosign2::VerificationResult;
-use crc::{Crc, CRC_32_ISCSI};
-use defmt::{debug, error, info, trace};
+#[cfg(not(feature = "debug"))]
+use defmt::debug;
 use embassy_executor::Spawner;
 use embassy_nrf::{
     bind_interrupts,
@@ -45,17 +45,11 @@ use embassy_nrf::{
 };
 use embassy_sync::blocking_mutex::CriticalSectionMutex;
 use embassy_sync::blocking_mutex::Mutex;
-use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
-use hmac::{Hmac, Mac};
+use heapless::String;
 use host_protocol::MAX_MSG_SIZE;
-use host_protocol::{Bootloader, SecretSaveResponse};
-use host_protocol::{HostProtocolMessage, PostcardError};
 use jump_app::jump_to_app;
 #[allow(unused_imports)]
 use nrf_softdevice::Softdevice;
-use postcard::{from_bytes, to_slice};
-use serde::{Deserialize, Serialize};
-use sha2::Sha256 as ShaChallenge;
 use verify::{get_fw_image_slice, read_version_and_build_date, verify_fw_image, write_secret};
 
 // Global mutex for hardware RNG access
@@ -68,35 +62,39 @@ bind_interrupts!(struct Irqs {
     UARTE0_UART0 => uarte::InterruptHandler<UARTE0>;
 });
 
-/// Tracks the state of firmware updates
-#[derive(Debug, Serialize, Deserialize, Default)]
-pub struct BootState {
-    /// Current offset into flash memory
-    pub offset: u32,
-    /// Current flash sector being written
-    pub actual_sector: u32,
-    /// Index of the last successfully written packet
-    pub actual_pkt_idx: u32,
-}
-impl BootState {
-    fn reset(&mut self) {
-        self.offset = 0;
-        self.actual_sector = 0;
-        self.actual_pkt_idx = 0;
+/// Challenge secret and seal in the UICR customer registers
+struct NrfUicr;
+
+impl Uicr for NrfUicr {
+    fn seal(&self) -> u32 {
+        unsafe { &*nrf52805_pac::UICR::ptr() }.customer[SEAL_IDX].read().customer().bits()
+    }
+
+    fn secret(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(UICR_SECRET_START as *const u8, UICR_SECRET_SIZE as usize) }
+    }
+
+    fn write_secret(&mut self, secret: [u32; 8], seal: u32) -> bool {
+        unsafe { write_secret(secret, seal) }
     }
 }
 
-/// Sends a message over SPI using postcard encoding
-#[inline(never)]
-fn ack_msg_send(message: HostProtocolMessage, spi: &mut Spis<SPI0>) {
-    let mut buf = [0_u8; MAX_MSG_SIZE];
-    let Ok(resp) = to_slice(&message, &mut buf[2..]) else {
-        error!("Failed to serialize response");
-        return;
-    };
-    let resp_len = resp.len();
-    buf[..2].copy_from_slice(&u16::to_be_bytes(resp_len as u16));
-    let _ = spi.blocking_write_from_ram(&buf[..resp_len + 2]);
+/// cosign2 verification of the firmware in flash
+struct CosignVerifier;
+
+impl Verifier for CosignVerifier {
+    fn firmware_version(&mut self) -> Option<String<20>> {
+        let image = get_fw_image_slice(BASE_APP_ADDR, SIGNATURE_HEADER_SIZE);
+        read_version_and_build_date(image, false).map(|(version, _build_date)| version)
+    }
+
+    fn verify(&mut self, trust: TrustLevel) -> Option<(VerificationResult, VerificationResult, [u8; 32])> {
+        let result = |result| match result {
+            cosign2::VerificationResult::Valid => VerificationResult::Valid,
+            _ => VerificationResult::Invalid,
+        };
+        verify_fw_image(trust).map(|(first, second, hash)| (result(first), result(second), hash))
+    }
 }
 
 #[cfg(not(feature = "debug"))]
@@ -187,15 +185,13 @@ fn flash_protect_sd_application() {
     let bits_0 = unsafe { &*nrf52805_pac::BPROT::ptr() }.config0.read().bits();
     debug!("CONFIG0_BITS : {}", bits_0);
 
-    // Protect Nordic SD area and application area
+    // Protect Nordic SD area and application area, the bond storage pages (0x25000-0x27000) stay writable
     unsafe { &*nrf52805_pac::BPROT::ptr() }.config1.write(|w| {
         w.region32().enabled(); //0x20000-0x21000
         w.region33().enabled(); //0x21000-0x22000
         w.region34().enabled(); //0x22000-0x23000
         w.region35().enabled(); //0x23000-0x24000
         w.region36().enabled(); //0x24000-0x25000
-        w.region37().enabled(); //0x25000-0x26000
-        w.region38().enabled(); //0x26000-0x27000
         w
     });
     let bits_1 = unsafe { &*nrf52805_pac::BPROT::ptr() }.config1.read().bits();
@@ -227,15 +223,21 @@ async fn main(_spawner: Spawner) {
     let _irq_out_pin = Output::new(p.P0_20, Level::High, OutputDrive::Standard);
 
     // Initialize flash controller
-    let mut flash = Nvmc::new(p.NVMC);
+    let flash = Nvmc::new(p.NVMC);
 
-    // Check if secrets are sealed
-    let mut seal = unsafe { &*nrf52805_pac::UICR::ptr() }.customer[SEAL_IDX].read().customer().bits();
-
-    let mut boot_status: BootState = Default::default();
+    let mut boot = BootCore::new(
+        flash,
+        NrfUicr,
+        CosignVerifier,
+        AppArea {
+            start: BASE_APP_ADDR,
+            end: BOND_STORAGE_ADDR,
+        },
+        env!("CARGO_PKG_VERSION"),
+    );
 
     let mut raw_buf = [0u8; 512];
-    let mut firmware_version: heapless::String<20>;
+    let mut resp_buf = [0u8; MAX_MSG_SIZE];
 
     // Main command processing loop
     loop {
@@ -246,183 +248,28 @@ async fn main(_spawner: Spawner) {
                 continue;
             }
 
-            let buf = &mut raw_buf[..n];
-
-            if let Some(resp) = match from_bytes(buf) {
-                Ok(req) => match req {
-                    HostProtocolMessage::Bootloader(boot_msg) => match boot_msg {
-                        // Handle firmware erase command
-                        Bootloader::EraseFirmware => {
-                            trace!("Erase firmware");
-                            let start = BASE_APP_ADDR / FLASH_PAGE * FLASH_PAGE;
-                            debug!("start: 0x{:08X}", start);
-                            if start == BASE_APP_ADDR {
-                                if flash.erase(BASE_APP_ADDR, BASE_BOOTLOADER_ADDR).is_ok() {
-                                    boot_status.reset();
-                                    Some(HostProtocolMessage::Bootloader(Bootloader::AckEraseFirmware))
-                                } else {
-                                    error!("erase error");
-                                    Some(HostProtocolMessage::Bootloader(Bootloader::NackEraseFirmware))
-                                }
-                            } else {
-                                let mut saved = [0; (BASE_APP_ADDR % FLASH_PAGE) as usize];
-                                if flash.read(start, &mut saved).is_err() {
-                                    error!("read error");
-                                    Some(HostProtocolMessage::Bootloader(Bootloader::NackEraseFirmwareRead))
-                                } else {
-                                    if flash.erase(start, BASE_BOOTLOADER_ADDR).is_ok() {
-                                        boot_status.reset();
-                                        if flash.write(start, &saved).is_err() {
-                                            error!("write error");
-                                            Some(HostProtocolMessage::Bootloader(Bootloader::NackEraseFirmwareWrite))
-                                        } else {
-                                            Some(HostProtocolMessage::Bootloader(Bootloader::AckEraseFirmware))
-                                        }
-                                    } else {
-                                        error!("erase error");
-                                        Some(HostProtocolMessage::Bootloader(Bootloader::NackEraseFirmware))
-                                    }
-                                }
-                            }
-                        }
-                        // Handle firmware block write
-                        Bootloader::WriteFirmwareBlock {
-                            block_idx: idx,
-                            block_data: data,
-                        } => {
-                            // Calculate target flash address
-                            let cursor = BASE_APP_ADDR + boot_status.offset;
-                            Some(
-                                // Validate write is within application area
-                                HostProtocolMessage::Bootloader(if (BASE_APP_ADDR..=BASE_BOOTLOADER_ADDR).contains(&cursor) {
-                                    match flash.write(cursor, data) {
-                                        Ok(()) => {
-                                            boot_status.offset += data.len() as u32;
-                                            // Update status and sector tracking
-                                            boot_status.actual_sector = BASE_APP_ADDR + (boot_status.offset / FLASH_PAGE) * FLASH_PAGE;
-                                            debug!("Updating flash page starting at addr: {:02X}", boot_status.actual_sector);
-                                            debug!("offset : {:02X}", boot_status.actual_sector + boot_status.offset % FLASH_PAGE);
-
-                                            // Calculate CRC of written data
-                                            let crc = Crc::<u32>::new(&CRC_32_ISCSI);
-                                            let crc_pkt = crc.checksum(data);
-
-                                            boot_status.actual_pkt_idx = idx as u32;
-
-                                            // Send success acknowledgement with CRC
-                                            Bootloader::AckWithIdxCrc {
-                                                block_idx: idx,
-                                                crc: crc_pkt,
-                                            }
-                                        }
-                                        Err(_) => Bootloader::NackWithIdx { block_idx: idx },
-                                    }
-                                } else {
-                                    Bootloader::FirmwareOutOfBounds { block_idx: idx }
-                                }),
-                            )
-                        }
-                        // Get firmware version from header
-                        Bootloader::FirmwareVersion => {
-                            let image = get_fw_image_slice(BASE_APP_ADDR, SIGNATURE_HEADER_SIZE);
-                            if let Some((version, _build_date)) = read_version_and_build_date(image, false) {
-                                firmware_version = version;
-                                Some(HostProtocolMessage::Bootloader(Bootloader::AckFirmwareVersion {
-                                    version: &firmware_version,
-                                }))
-                            } else {
-                                Some(HostProtocolMessage::Bootloader(Bootloader::NoCosignHeader))
-                            }
-                        }
-                        // Get bootloader version
-                        Bootloader::BootloaderVersion => {
-                            let version = env!("CARGO_PKG_VERSION");
-                            Some(HostProtocolMessage::Bootloader(Bootloader::AckBootloaderVersion { version }))
-                        }
-                        // Set challenge secret
-                        Bootloader::ChallengeSet { secret } => {
-                            let result = if seal == SEALED_SECRET || seal == SEALED_WIPED {
-                                SecretSaveResponse::NotAllowed
-                            } else if secret.iter().all(|word| *word == 0) || secret.iter().all(|word| *word == u32::MAX) {
-                                SecretSaveResponse::Error
-                            } else {
-                                unsafe {
-                                    match write_secret(secret, SEALED_SECRET) {
-                                        true => {
-                                            info!("Challenge secret is saved");
-                                            seal = SEALED_SECRET;
-                                            SecretSaveResponse::Sealed
-                                        }
-                                        false => SecretSaveResponse::Error,
-                                    }
-                                }
-                            };
-                            Some(HostProtocolMessage::Bootloader(Bootloader::AckChallengeSet { result }))
-                        }
-                        // Handle request to boot into firmware
-                        Bootloader::BootFirmware { trust } => {
-                            match verify_fw_image(trust) {
-                                Some((VerificationResult::Valid, VerificationResult::Valid, hash)) => {
-                                    info!("fw is valid");
-                                    // Clear the authentication secret if we are possibly booting a dev firmware
-                                    if seal == SEALED_SECRET && trust == TrustLevel::Developer {
-                                        unsafe { write_secret([0; 8], SEALED_WIPED) };
-                                    }
-                                    let msg = HostProtocolMessage::Bootloader(Bootloader::AckVerifyFirmware { result: true, hash });
-                                    #[cfg(not(feature = "debug"))]
-                                    flash_protect_sd_application();
-                                    // immedate send response before jumping
-                                    ack_msg_send(msg, &mut spi);
-                                    // Clean up SPI resources before jumping
-                                    drop(spi);
-                                    // Jump to application code if firmware is valid
-                                    unsafe {
-                                        jump_to_app();
-                                    }
-                                }
-                                Some((_, _, hash)) => {
-                                    info!("fw is invalid");
-                                    Some(HostProtocolMessage::Bootloader(Bootloader::AckVerifyFirmware {
-                                        result: false,
-                                        hash,
-                                    }))
-                                }
-                                None => Some(HostProtocolMessage::Bootloader(Bootloader::NoCosignHeader)),
-                            }
-                        }
-                        _ => None,
-                    },
-                    // Handle reset command
-                    HostProtocolMessage::Reset => {
-                        drop(spi);
-                        cortex_m::peripheral::SCB::sys_reset();
-                    }
-                    // Handle challenge-response authentication
-                    HostProtocolMessage::ChallengeRequest { nonce } => {
-                        Some(if seal == SEALED_SECRET {
-                            type HmacSha256 = Hmac<ShaChallenge>;
-                            let secret_as_slice =
-                                unsafe { core::slice::from_raw_parts(UICR_SECRET_START as *const u8, UICR_SECRET_SIZE as usize) };
-                            // debug!("slice {:02X}", secret_as_slice);
-                            if let Ok(mut mac) = HmacSha256::new_from_slice(secret_as_slice) {
-                                mac.update(&nonce.to_be_bytes());
-                                let result = mac.finalize().into_bytes();
-                                // debug!("{=[u8;32]:#X}", result.into());
-                                HostProtocolMessage::ChallengeResult { result: result.into() }
-                            } else {
-                                HostProtocolMessage::ChallengeResult { result: [0xFF; 32] }
-                            }
-                        } else {
-                            HostProtocolMessage::ChallengeResult { result: [0xFF; 32] }
-                        })
+            match boot.process(&raw_buf[..n], &mut resp_buf) {
+                Some(Outcome::Respond(resp_len)) => {
+                    let _ = spi.blocking_write_from_ram(&resp_buf[..resp_len]);
+                }
+                Some(Outcome::Boot(resp_len)) => {
+                    #[cfg(not(feature = "debug"))]
+                    flash_protect_sd_application();
+                    // immedate send response before jumping
+                    let _ = spi.blocking_write_from_ram(&resp_buf[..resp_len]);
+                    // Clean up SPI resources before jumping
+                    drop(spi);
+                    // Jump to application code if firmware is valid
+                    unsafe {
+                        jump_to_app();
                     }
-                    // Report bootloader state
-                    HostProtocolMessage::GetState => Some(HostProtocolMessage::AckState(State::FirmwareUpgrade)),
-                    _ => Some(HostProtocolMessage::InappropriateMessage(State::FirmwareUpgrade)),
-                },
-                Err(_) => Some(HostProtocolMessage::PostcardError(PostcardError::Deser)),
-            } {
-                ack_msg_send(resp, &mut spi);
+                }
+                // Handle reset command
+                Some(Outcome::Reset) => {
+                    drop(spi);
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+                None => {}
             }
             embassy_time::Timer::after_millis(1).await;
         }
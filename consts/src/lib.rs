@@ -28,11 +28,15 @@ pub const DEFAULT_DEVICE_NAME: &str = "Passport Prime";
 /// to stay within the 31-byte advertising data size limit.
 pub const SHORT_NAME: &str = "Passport";
 
+/// Manufacturer name reported by the Device Information Service.
+pub const MANUFACTURER_NAME: &str = "Foundation Devices";
+
 /// UUID for the Nordic UART Service (NUS).
 pub const NUS_UUID: u128 = 0x6E400001_B5A3_F393_E0A9_E50E24DCCA9E;
 
-/// List of BLE service UUIDs supported by this device.
-/// Currently only includes the Nordic UART Service (NUS).
+/// List of BLE service UUIDs advertised by this device.
+/// Only includes the Nordic UART Service (NUS), which fills the legacy advertising data with the flags
+/// and the host AD structures. Centrals find the Device Information Service by service discovery.
 pub const SERVICES_LIST: [[u8; 16]; 1] = [NUS_UUID.to_le_bytes()];
 
 /// Starting address in UICR (User Information Configuration Registers) where the device secret is stored.
@@ -64,5 +68,11 @@ pub const BASE_BOOTLOADER_ADDR: u32 = 0x27000;
 /// after the SoftDevice and before the bootloader region
 pub const BASE_APP_ADDR: u32 = 0x1B400;
 
+/// Two flash pages reserved for the BLE bonds of the application, right below the bootloader
+/// The application area ends here, so the pages are neither part of the signed firmware image
+/// nor write protected by the bootloader. Bootloaders from before the pages were reserved still
+/// protect them, the application then doesn't bond.
+pub const BOND_STORAGE_ADDR: u32 = 0x25000;
+
 /// 256B are needed for cosign2 signature
 pub const SIGNATURE_HEADER_SIZE: u32 = 0x100;
@@ -0,0 +1,304 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Bonds persisted in two reserved flash pages.
+//!
+//! A page holds `MAGIC: u32 (LE) | sequence: u32 (LE) | len: u16 (LE) | postcard encoded bonds |
+//! crc32 (LE)`, padded with `0xFF` to whole flash words. The CRC covers everything before it, so an
+//! erased page or a write interrupted by a reset holds no copy. Each change is written to the page
+//! not holding the newest copy, which survives until the new one is complete.
+
+use crc::{Crc, CRC_32_ISCSI};
+use heapless::Vec;
+use host_protocol::{Bonds, BtAddress, MAX_BONDS};
+use serde::{Deserialize, Serialize};
+
+/// Identifies the page format, to be changed with the [`Bond`] layout
+const MAGIC: u32 = 0xB0AD_0002;
+
+/// Size of the magic, the sequence number and the payload length
+const HEADER_SIZE: usize = 10;
+
+const CRC_SIZE: usize = 4;
+
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+/// Size of the flash words the page is written with
+pub const WRITE_SIZE: usize = 4;
+
+/// Buffer size for [`BondList::encode`], enough for [`MAX_BONDS`] bonds
+pub const MAX_ENCODED_LEN: usize = 256;
+
+/// Buffer for [`BondList::encode`], word aligned as the flash only writes from aligned sources
+#[repr(align(4))]
+pub struct EncodeBuffer([u8; MAX_ENCODED_LEN]);
+
+impl EncodeBuffer {
+    pub const fn new() -> Self {
+        Self([0; MAX_ENCODED_LEN])
+    }
+}
+
+impl Default for EncodeBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keys of a bonded peer, as distributed during pairing
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Bond {
+    /// Identity address of the peer
+    pub peer: BtAddress,
+    /// Identity resolving key of the peer, to recognize its resolvable private addresses
+    pub irk: [u8; 16],
+    /// Long term key of the link
+    pub ltk: [u8; 16],
+    /// SoftDevice `ble_gap_enc_info_t` flags: LESC, authenticated and key length
+    pub ltk_flags: u8,
+    /// Master identification of the key, zero for LESC keys
+    pub ediv: u16,
+    pub rand: [u8; 8],
+}
+
+/// Where the next copy of the bonds goes, the page not holding the newest copy
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct NextWrite {
+    /// Index of the page to erase and write
+    pub page: usize,
+    /// Sequence number of the copy
+    pub sequence: u32,
+}
+
+impl NextWrite {
+    /// Moves on to the other page once the copy was written
+    pub fn advance(&mut self) {
+        self.page ^= 1;
+        self.sequence = self.sequence.wrapping_add(1);
+    }
+}
+
+/// Bonded peers, oldest first
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BondList {
+    bonds: Vec<Bond, MAX_BONDS>,
+}
+
+impl BondList {
+    pub const fn new() -> Self {
+        Self { bonds: Vec::new() }
+    }
+
+    /// Reads the newest copy of the bonds stored in the two pages, erased or corrupted pages hold
+    /// none. Also returns where to write the next copy.
+    pub fn load(pages: [&[u8]; 2]) -> (Self, NextWrite) {
+        let newest = match pages.map(Self::decode) {
+            [Some((seq0, list0)), Some((seq1, list1))] => match (seq1.wrapping_sub(seq0) as i32) > 0 {
+                true => Some((1, seq1, list1)),
+                false => Some((0, seq0, list0)),
+            },
+            [Some((seq, list)), None] => Some((0, seq, list)),
+            [None, Some((seq, list))] => Some((1, seq, list)),
+            [None, None] => None,
+        };
+        match newest {
+            Some((page, sequence, list)) => {
+                let mut next = NextWrite { page, sequence };
+                next.advance();
+                (list, next)
+            }
+            None => {
+                debug!("No valid bonds stored");
+                (Self::new(), NextWrite::default())
+            }
+        }
+    }
+
+    /// Reads the sequence number and the bonds of a page
+    fn decode(page: &[u8]) -> Option<(u32, Self)> {
+        let header = page.get(..HEADER_SIZE)?;
+        if u32::from_le_bytes(header[..4].try_into().ok()?) != MAGIC {
+            return None;
+        }
+        let sequence = u32::from_le_bytes(header[4..8].try_into().ok()?);
+        let crc_start = HEADER_SIZE + usize::from(u16::from_le_bytes(header[8..].try_into().ok()?));
+        let crc = page.get(crc_start..crc_start + CRC_SIZE)?;
+        if u32::from_le_bytes(crc.try_into().ok()?) != CRC.checksum(&page[..crc_start]) {
+            return None;
+        }
+        let bonds = postcard::from_bytes(&page[HEADER_SIZE..crc_start]).ok()?;
+        Some((sequence, Self { bonds }))
+    }
+
+    /// Writes the content of the page with the copy number `sequence` into `buf`, returns the part
+    /// to write to the erased page
+    pub fn encode<'a>(&self, sequence: u32, buf: &'a mut EncodeBuffer) -> &'a [u8] {
+        let buf = &mut buf.0;
+        // Always fits, see the `full_list_fits` test
+        let len = postcard::to_slice(&self.bonds, &mut buf[HEADER_SIZE..MAX_ENCODED_LEN - CRC_SIZE])
+            .map(|payload| payload.len())
+            .unwrap_or_default();
+        buf[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&sequence.to_le_bytes());
+        buf[8..HEADER_SIZE].copy_from_slice(&(len as u16).to_le_bytes());
+        let crc_start = HEADER_SIZE + len;
+        let crc = CRC.checksum(&buf[..crc_start]);
+        buf[crc_start..crc_start + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+        let end = (crc_start + CRC_SIZE).next_multiple_of(WRITE_SIZE);
+        buf[crc_start + CRC_SIZE..end].fill(0xFF);
+        &buf[..end]
+    }
+
+    /// Stores `bond`, replacing an older bond with the same peer or the oldest bond when full
+    pub fn insert(&mut self, bond: Bond) {
+        self.remove(&bond.peer);
+        if self.bonds.is_full() {
+            self.bonds.remove(0);
+        }
+        let _ = self.bonds.push(bond);
+    }
+
+    /// Forgets the bond with `peer`, `false` if there is none
+    pub fn remove(&mut self, peer: &BtAddress) -> bool {
+        match self.bonds.iter().position(|bond| bond.peer == *peer) {
+            Some(i) => {
+                self.bonds.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.bonds.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Bond> {
+        self.bonds.iter()
+    }
+
+    /// Identity addresses of the bonded peers, oldest first
+    pub fn peers(&self) -> Bonds {
+        self.bonds.iter().map(|bond| bond.peer).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use host_protocol::AddressType;
+
+    fn bond(i: u8) -> Bond {
+        Bond {
+            peer: BtAddress {
+                addr_type: AddressType::RandomStatic,
+                addr: [0xC0, 0, 0, 0, 0, i],
+            },
+            irk: [i; 16],
+            ltk: [!i; 16],
+            ltk_flags: 0x47,
+            ediv: u16::MAX,
+            rand: [0xFF; 8],
+        }
+    }
+
+    #[test]
+    fn full_list_fits() {
+        let mut list = BondList::new();
+        for i in 0..MAX_BONDS as u8 {
+            list.insert(bond(i));
+        }
+        let mut buf = EncodeBuffer::new();
+        let page = list.encode(7, &mut buf);
+        assert_eq!(page.len() % WRITE_SIZE, 0);
+        assert_eq!(page.as_ptr() as usize % WRITE_SIZE, 0);
+        assert_eq!(BondList::decode(page), Some((7, list)));
+    }
+
+    #[test]
+    fn corrupted_pages() {
+        assert_eq!(BondList::decode(&[0xFF; 4096]), None);
+        assert_eq!(BondList::decode(&[]), None);
+        let erased = BondList::load([&[0xFF; 4096], &[0xFF; 4096]]);
+        assert_eq!(erased, (BondList::new(), NextWrite { page: 0, sequence: 0 }));
+
+        let mut list = BondList::new();
+        list.insert(bond(1));
+        let mut buf = EncodeBuffer::new();
+        let mut page = list.encode(0, &mut buf).to_vec();
+        // Interrupted write
+        page[20..].fill(0xFF);
+        assert_eq!(BondList::decode(&page), None);
+        let mut page = list.encode(0, &mut buf).to_vec();
+        page[12] ^= 1;
+        assert_eq!(BondList::decode(&page), None);
+        let mut page = list.encode(0, &mut buf).to_vec();
+        page[8] = 0xFF;
+        assert_eq!(BondList::decode(&page), None);
+    }
+
+    #[test]
+    fn alternating_pages() {
+        let mut old = BondList::new();
+        old.insert(bond(1));
+        let mut new = old.clone();
+        new.insert(bond(2));
+        let mut buf = EncodeBuffer::new();
+        let old_page = old.encode(5, &mut buf).to_vec();
+        let new_page = new.encode(6, &mut buf).to_vec();
+        let erased = [0xFF; 256];
+
+        // The newest copy wins, the next one replaces the older page
+        let next = NextWrite { page: 0, sequence: 7 };
+        assert_eq!(BondList::load([&old_page, &new_page]), (new.clone(), next));
+        assert_eq!(
+            BondList::load([&new_page, &old_page]),
+            (new.clone(), NextWrite { page: 1, sequence: 7 })
+        );
+        // A reset while replacing the older page keeps the newest copy, the page is written again
+        let mut interrupted = new.encode(7, &mut buf).to_vec();
+        interrupted[30..].fill(0xFF);
+        assert_eq!(BondList::load([&interrupted, &new_page]), (new.clone(), next));
+        assert_eq!(BondList::load([&erased, &new_page]), (new.clone(), next));
+        let next = NextWrite { page: 1, sequence: 6 };
+        assert_eq!(BondList::load([&old_page, &erased]), (old, next));
+
+        // Sequence numbers wrap around
+        let wrapped = new.encode(0, &mut buf).to_vec();
+        let before = BondList::new().encode(u32::MAX, &mut buf).to_vec();
+        let next = NextWrite { page: 0, sequence: 1 };
+        assert_eq!(BondList::load([&before, &wrapped]), (new, next));
+
+        let mut next = NextWrite {
+            page: 1,
+            sequence: u32::MAX,
+        };
+        next.advance();
+        assert_eq!(next, NextWrite { page: 0, sequence: 0 });
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let mut list = BondList::new();
+        for i in 0..=MAX_BONDS as u8 {
+            list.insert(bond(i));
+        }
+        // The oldest bond made room
+        let peers: std::vec::Vec<_> = list.peers().iter().map(|peer| peer.addr[5]).collect();
+        assert_eq!(peers, [1, 2, 3, 4]);
+
+        // Pairing again replaces the keys and makes the bond the newest
+        let mut renewed = bond(2);
+        renewed.ltk = [0x42; 16];
+        list.insert(renewed.clone());
+        let peers: std::vec::Vec<_> = list.peers().iter().map(|peer| peer.addr[5]).collect();
+        assert_eq!(peers, [1, 3, 4, 2]);
+        assert_eq!(list.iter().last(), Some(&renewed));
+
+        assert!(list.remove(&bond(3).peer));
+        assert!(!list.remove(&bond(3).peer));
+        assert_eq!(list.peers().len(), 3);
+        list.clear();
+        assert!(list.peers().is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Key reports of the HID-over-GATT keyboard, typing text on a US keyboard layout.
+//!
+//! The report map describes a boot keyboard, so the same 8 byte reports are sent in the boot and
+//! in the report protocol mode: `modifiers | reserved | 6 key codes`.
+
+/// Report descriptor of a boot keyboard, with a 5 LEDs output report
+#[rustfmt::skip]
+pub const REPORT_MAP: [u8; 63] = [
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xA1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xE0, //   Usage Minimum (Left Control)
+    0x29, 0xE7, //   Usage Maximum (Right GUI)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute): modifiers
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant): reserved byte
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (Num Lock)
+    0x29, 0x05, //   Usage Maximum (Kana)
+    0x91, 0x02, //   Output (Data, Variable, Absolute): LEDs
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Constant): LED padding
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x65, //   Logical Maximum (101)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0x65, //   Usage Maximum (101)
+    0x81, 0x00, //   Input (Data, Array): key codes
+    0xC0,       // End Collection
+];
+
+pub const KEY_REPORT_LEN: usize = 8;
+
+pub type KeyReport = [u8; KEY_REPORT_LEN];
+
+/// All keys released, sent after every key so that repeated characters are typed again
+pub const RELEASE: KeyReport = [0; KEY_REPORT_LEN];
+
+const LEFT_SHIFT: u8 = 1 << 1;
+
+/// Report pressing the key typing `c` on a US keyboard layout, `None` if there is no such key
+pub fn key_report(c: char) -> Option<KeyReport> {
+    const SHIFTED_DIGITS: &str = "!@#$%^&*(";
+    let (modifiers, key) = match c {
+        'a'..='z' => (0, 0x04 + (c as u8 - b'a')),
+        'A'..='Z' => (LEFT_SHIFT, 0x04 + (c as u8 - b'A')),
+        '1'..='9' => (0, 0x1E + (c as u8 - b'1')),
+        '0' => (0, 0x27),
+        ')' => (LEFT_SHIFT, 0x27),
+        '\n' => (0, 0x28),
+        '\t' => (0, 0x2B),
+        ' ' => (0, 0x2C),
+        '-' => (0, 0x2D),
+        '_' => (LEFT_SHIFT, 0x2D),
+        '=' => (0, 0x2E),
+        '+' => (LEFT_SHIFT, 0x2E),
+        '[' => (0, 0x2F),
+        '{' => (LEFT_SHIFT, 0x2F),
+        ']' => (0, 0x30),
+        '}' => (LEFT_SHIFT, 0x30),
+        '\\' => (0, 0x31),
+        '|' => (LEFT_SHIFT, 0x31),
+        ';' => (0, 0x33),
+        ':' => (LEFT_SHIFT, 0x33),
+        '\'' => (0, 0x34),
+        '"' => (LEFT_SHIFT, 0x34),
+        '`' => (0, 0x35),
+        '~' => (LEFT_SHIFT, 0x35),
+        ',' => (0, 0x36),
+        '<' => (LEFT_SHIFT, 0x36),
+        '.' => (0, 0x37),
+        '>' => (LEFT_SHIFT, 0x37),
+        '/' => (0, 0x38),
+        '?' => (LEFT_SHIFT, 0x38),
+        _ => (LEFT_SHIFT, 0x1E + SHIFTED_DIGITS.find(c)? as u8),
+    };
+    Some([modifiers, 0, key, 0, 0, 0, 0, 0])
+}
+
+/// Checks that every character of `text` has a key
+pub fn is_typeable(text: &str) -> bool {
+    text.chars().all(|c| key_report(c).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn us_layout() {
+        assert_eq!(key_report('a'), Some([0, 0, 0x04, 0, 0, 0, 0, 0]));
+        assert_eq!(key_report('Z'), Some([LEFT_SHIFT, 0, 0x1D, 0, 0, 0, 0, 0]));
+        assert_eq!(key_report('1'), Some([0, 0, 0x1E, 0, 0, 0, 0, 0]));
+        assert_eq!(key_report('!'), Some([LEFT_SHIFT, 0, 0x1E, 0, 0, 0, 0, 0]));
+        assert_eq!(key_report('('), Some([LEFT_SHIFT, 0, 0x26, 0, 0, 0, 0, 0]));
+        assert_eq!(key_report(')'), Some([LEFT_SHIFT, 0, 0x27, 0, 0, 0, 0, 0]));
+        assert_eq!(key_report('?'), Some([LEFT_SHIFT, 0, 0x38, 0, 0, 0, 0, 0]));
+        assert_eq!(key_report('é'), None);
+
+        // Every printable ASCII character can be typed
+        assert!((' '..='~').all(|c| key_report(c).is_some()));
+        assert!(is_typeable("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq\n"));
+        assert!(!is_typeable("5€"));
+        assert!(!is_typeable("\r"));
+    }
+}
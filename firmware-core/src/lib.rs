@@ -0,0 +1,1682 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Hardware independent host-protocol handling of the BLE firmware.
+//!
+//! [`Comms`] decodes requests from the MPU and answers them. The SoftDevice, the IRQ line
+//! and the UICR are reached through the [`BleLink`], [`IrqLine`] and [`Secret`] traits,
+//! so the same logic runs in the firmware and, with the [`mock`] implementations, on the host.
+
+#![cfg_attr(not(any(test, feature = "mock")), no_std)]
+
+#[macro_use]
+mod fmt;
+pub mod bonds;
+#[cfg(feature = "hid")]
+pub mod hid;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod sar_rx;
+
+use consts::APP_MTU;
+use hmac::{Hmac, Mac};
+use host_protocol::envelope::{Envelope, RequestId};
+use host_protocol::frame;
+use host_protocol::sar;
+#[cfg(feature = "hid")]
+use host_protocol::KeyboardText;
+use host_protocol::{
+    batch_response_len, is_random_static, iter_packets, AcceptList, AdvChan, AdvData, AdvMode, AdvParams, Bluetooth, BluetoothStatus,
+    Bonds, BtAddress, Capabilities, ConnParams, ConnectionStatus, CustomService, CustomValue, DeviceInformation, DeviceName, Event,
+    EventBatch, HostProtocolMessage, Message, PacketBatch, Phy, PhyStatus, PostcardError, Privacy, ProtocolInfo, SendDataResponse, State,
+    Stats, TxPower,
+};
+use postcard::from_bytes;
+use sha2::Sha256 as ShaChallenge;
+
+/// BLE side of the firmware
+#[allow(async_fn_in_trait)]
+pub trait BleLink {
+    /// Starts or stops the BLE task
+    fn set_enabled(&self, enabled: bool);
+
+    /// Disconnects the current connection, if any
+    async fn disconnect(&self);
+
+    /// Signal strength of the current connection, `None` if not connected
+    async fn rssi(&self) -> Option<i8>;
+
+    /// Notifies `data` to the connected central
+    async fn send(&self, data: &[u8]) -> SendDataResponse;
+
+    /// Takes the oldest packet received from the central
+    fn receive(&self) -> Option<Message>;
+
+    /// Number of received packets waiting to be taken
+    fn received_count(&self) -> usize;
+
+    /// Returns and clears the flag set when a received packet was dropped
+    fn take_overflow(&self) -> bool;
+
+    /// Reassembles the packets received from the central as [`sar`] segments instead of queueing
+    /// them, drops the message being reassembled
+    fn set_sar_rx(&self, enabled: bool);
+
+    /// Copies the reassembled message into `buf`, `None` if no message is complete
+    fn receive_sar(&self, buf: &mut [u8]) -> Option<usize>;
+
+    /// Takes the oldest queued link event
+    fn next_event(&self) -> Option<Event>;
+
+    /// Returns and clears the flag set when an event was dropped
+    fn take_events_overflow(&self) -> bool;
+
+    /// Restricts advertising to the channels not in `disabled`
+    fn set_adv_channels(&self, disabled: AdvChan);
+
+    /// Restarts advertising with new timing
+    async fn set_adv_params(&self, params: AdvParams);
+
+    /// Restarts advertising with new host-defined AD structures
+    async fn set_adv_data(&self, data: AdvData);
+
+    /// Sets the advertising mode used from the next time advertising starts
+    async fn set_adv_mode(&self, mode: AdvMode);
+
+    /// Sets the filter accept list and policy used from the next time advertising starts
+    async fn set_accept_list(&self, list: AcceptList);
+
+    async fn accept_list(&self) -> AcceptList;
+
+    fn set_tx_power(&self, power: TxPower);
+
+    async fn set_device_name(&self, name: DeviceName);
+
+    /// Updates the Device Information Service strings supplied by the host
+    fn set_device_information(&self, info: DeviceInformation);
+
+    /// Updates the Battery Service level, and notifies the connected central if it changed
+    async fn set_battery_level(&self, level: u8);
+
+    /// Starts typing `text` on the HID keyboard and reports `TextTyped` once done. `false` if not
+    /// connected or a text is still being typed.
+    #[cfg(feature = "hid")]
+    async fn type_text(&self, text: KeyboardText) -> bool;
+
+    /// Adds the host-defined service to the GATT table, `false` if it didn't fit in the attribute
+    /// table or an earlier attempt was made, even a failed one
+    async fn register_custom_service(&self, service: CustomService) -> bool;
+
+    /// Value last written by the central to a custom characteristic, `None` for an unknown one
+    async fn custom_value(&self, index: u8) -> Option<CustomValue>;
+
+    /// Answers the pending read of a custom characteristic, `false` if there is none or the value
+    /// is too long for the characteristic, which leaves the read pending
+    async fn reply_custom_read(&self, value: CustomValue) -> bool;
+
+    /// Notifies the connected central of a new value of a custom characteristic, `false` if it is
+    /// unknown, not notifiable or too short for the value, if the central didn't subscribe or if
+    /// the notification queue is full
+    async fn notify_custom(&self, index: u8, value: CustomValue) -> bool;
+
+    /// Asks the central for new connection parameters, `false` if not connected or the request failed
+    async fn set_conn_params(&self, params: ConnParams) -> bool;
+
+    /// Parameters of the current connection, `None` if not connected
+    async fn conn_params(&self) -> Option<ConnParams>;
+
+    /// Sets the PHYs requested after connecting, also requested on the current connection. PHY
+    /// updates started by the central are answered by the SoftDevice, which accepts any PHY.
+    /// `false` if the request failed.
+    async fn set_preferred_phy(&self, phys: Phy) -> bool;
+
+    /// PHYs of the current connection, `None` if not connected
+    async fn phy(&self) -> Option<PhyStatus>;
+
+    /// Answers the pending numeric comparison, `false` if there is none
+    async fn confirm_passkey(&self, confirm: bool) -> bool;
+
+    /// Bonds can be stored in flash. Bootloaders from before the bond storage was reserved write
+    /// protect it, bonding is then refused and [`Capabilities::BONDING`] isn't reported.
+    fn bond_storage_available(&self) -> bool;
+
+    /// Identity addresses of the bonded peers, oldest first
+    async fn bonds(&self) -> Bonds;
+
+    /// Forgets the bond with `peer`, also in flash. `false` if there is no such bond.
+    async fn delete_bond(&self, peer: BtAddress) -> bool;
+
+    /// Forgets all bonds, also in flash
+    async fn clear_bonds(&self);
+
+    /// Sets the address privacy used from the next time advertising starts
+    async fn set_privacy(&self, privacy: Privacy);
+
+    /// Sets the random static identity address used from the next time advertising starts,
+    /// `None` for the factory address
+    async fn set_identity_address(&self, addr: Option<[u8; 6]>);
+
+    /// Address in the advertising packets, `None` while not advertising
+    async fn address_on_air(&self) -> Option<BtAddress>;
+
+    /// Counters of the received packets, the connections and the advertising restarts, cleared
+    /// after reading with `reset`
+    fn stats(&self, reset: bool) -> Stats;
+}
+
+/// Active low interrupt line to the MPU, pulled low when BLE data is received or an event is queued
+#[allow(async_fn_in_trait)]
+pub trait IrqLine {
+    /// Releases the line once all received data was read
+    async fn set_high(&self);
+}
+
+/// Secret used for the HMAC challenge
+pub trait Secret {
+    /// The provisioned secret, `None` if the UICR isn't sealed
+    fn sealed_secret(&self) -> Option<&[u8]>;
+}
+
+/// Identification reported to the host
+pub struct DeviceInfo {
+    pub address: [u8; 6],
+    pub device_id: [u8; 8],
+    pub version: &'static str,
+}
+
+/// What the transport has to do after [`Comms::process`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// Send the first bytes of the response buffer
+    Respond(usize),
+    /// The host asked for a system reset
+    Reset,
+}
+
+/// Host-protocol request handler
+pub struct Comms<L, I, S> {
+    link: L,
+    irq: I,
+    secret: S,
+    info: DeviceInfo,
+    // This is redundant with the BLE task state, only used to report
+    // the current state over the host-protocol.
+    enabled: bool,
+    /// Set by the first `Enable`, centrals may have cached the GATT table from then on
+    started: bool,
+    /// Packet taken from the link that didn't fit in the last batch
+    pending: Option<Message>,
+    /// Counters of the notifications and of the request errors, the link counts the rest
+    stats: Stats,
+}
+
+impl<L: BleLink, I: IrqLine, S: Secret> Comms<L, I, S> {
+    pub fn new(link: L, irq: I, secret: S, info: DeviceInfo) -> Self {
+        Self {
+            link,
+            irq,
+            secret,
+            info,
+            enabled: false,
+            started: false,
+            pending: None,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn link(&self) -> &L {
+        &self.link
+    }
+
+    pub fn irq(&self) -> &I {
+        &self.irq
+    }
+
+    /// Counts a request that couldn't be read from the bus
+    pub fn count_read_error(&mut self) {
+        self.stats.spi_errors += 1;
+    }
+
+    /// Sends a notification, counted in the stats
+    async fn send(&mut self, data: &[u8]) -> SendDataResponse {
+        let resp = self.link.send(data).await;
+        match resp {
+            SendDataResponse::Sent => {
+                self.stats.tx_packets += 1;
+                self.stats.tx_bytes += data.len() as u64;
+            }
+            SendDataResponse::BufferFull => self.stats.tx_buffer_full += 1,
+        }
+        resp
+    }
+
+    /// Handles a raw request and writes the response into `resp`.
+    ///
+    /// Framed requests get a CRC-protected response and enveloped requests get their request ID
+    /// echoed back. Returns `None` if the response doesn't fit in `resp`, which should hold
+    /// `MAX_BATCH_MSG_SIZE` bytes to answer `GetReceivedDataBatch` and `GetReceivedSarData` requests.
+    /// The packets of a batch and reassembled messages are taken straight into `resp`.
+    pub async fn process(&mut self, req: &[u8], resp: &mut [u8]) -> Option<Outcome> {
+        let framed = frame::is_framed_request(req);
+        let (request_id, resp_msg) = match frame::open_request(req) {
+            Ok(req) => {
+                // Echo back the request ID of enveloped requests
+                let (request_id, req) = Envelope::split(req);
+                let resp_msg = match from_bytes(req) {
+                    Ok(HostProtocolMessage::Reset) => {
+                        trace!("Reset");
+                        return Some(Outcome::Reset);
+                    }
+                    Ok(HostProtocolMessage::Bluetooth(Bluetooth::GetReceivedDataBatch { max_len })) => {
+                        trace!("GetReceivedDataBatch");
+                        return self.respond_batch(request_id, max_len, framed, resp).await;
+                    }
+                    Ok(HostProtocolMessage::Bluetooth(Bluetooth::GetReceivedSarData)) => {
+                        trace!("GetReceivedSarData");
+                        return self.respond_sar(request_id, framed, resp).await;
+                    }
+                    Ok(req) => self.handle(req).await,
+                    Err(_) => {
+                        self.stats.deser_errors += 1;
+                        HostProtocolMessage::PostcardError(PostcardError::Deser)
+                    }
+                };
+                (request_id, resp_msg)
+            }
+            Err(e) => {
+                error!("Invalid request frame");
+                self.stats.deser_errors += 1;
+                (None, HostProtocolMessage::PostcardError(e))
+            }
+        };
+        encode_response(resp, request_id, resp_msg, framed)
+    }
+
+    /// Handles a decoded request, except `Reset` which is up to the caller.
+    /// `GetReceivedDataBatch` only reports the number of queued packets here and
+    /// `GetReceivedSarData` is inappropriate, [`Comms::process`] answers them with the data.
+    pub async fn handle<'a>(&'a mut self, req: HostProtocolMessage<'a>) -> HostProtocolMessage<'a> {
+        match req {
+            HostProtocolMessage::Bluetooth(bluetooth_msg) => {
+                trace!("Received HostProtocolMessage::Bluetooth");
+                self.handle_bluetooth(bluetooth_msg).await
+            }
+            HostProtocolMessage::ChallengeRequest { nonce } => {
+                trace!("ChallengeRequest");
+                self.hmac_challenge_response(nonce)
+            }
+            HostProtocolMessage::GetState => {
+                trace!("GetState");
+                HostProtocolMessage::AckState(self.state())
+            }
+            HostProtocolMessage::GetProtocolInfo => {
+                trace!("GetProtocolInfo");
+                HostProtocolMessage::AckProtocolInfo(ProtocolInfo::new(
+                    Capabilities::BLUETOOTH
+                        | Capabilities::CHALLENGE
+                        | Capabilities::REQUEST_ID
+                        | Capabilities::FRAME_CRC
+                        | Capabilities::EVENTS
+                        | Capabilities::RECEIVE_BATCH
+                        | Capabilities::SEND_BATCH
+                        | Capabilities::SAR
+                        | Capabilities::CONN_PARAMS
+                        | Capabilities::PHY
+                        | Capabilities::ADV_PARAMS
+                        | Capabilities::ADV_DATA
+                        | Capabilities::ADV_MODE
+                        | Capabilities::ACCEPT_LIST
+                        | Capabilities::PRIVACY
+                        | Capabilities::DEVICE_INFORMATION
+                        | Capabilities::BATTERY
+                        | Capabilities::CUSTOM_GATT
+                        | Capabilities::STATS
+                        | match self.link.bond_storage_available() {
+                            true => Capabilities::BONDING,
+                            false => Capabilities::empty(),
+                        }
+                        | match cfg!(feature = "hid") {
+                            true => Capabilities::HID,
+                            false => Capabilities::empty(),
+                        },
+                ))
+            }
+            _ => {
+                trace!("Other");
+                HostProtocolMessage::InappropriateMessage(self.state())
+            }
+        }
+    }
+
+    async fn handle_bluetooth<'a>(&'a mut self, req: Bluetooth<'a>) -> HostProtocolMessage<'a> {
+        match req {
+            Bluetooth::DisableChannels(chan) => {
+                trace!("DisableChannels");
+                if chan == AdvChan::all() {
+                    HostProtocolMessage::Bluetooth(Bluetooth::NackDisableChannels)
+                } else {
+                    self.link.set_adv_channels(chan);
+                    HostProtocolMessage::Bluetooth(Bluetooth::AckDisableChannels)
+                }
+            }
+            Bluetooth::Enable => {
+                trace!("Enabled");
+                self.link.set_enabled(true);
+                self.enabled = true;
+                self.started = true;
+                HostProtocolMessage::Bluetooth(Bluetooth::AckEnable)
+            }
+            Bluetooth::Disable => {
+                trace!("Disabled");
+                // clean disconnect if connected
+                self.link.disconnect().await;
+                self.link.set_enabled(false);
+                self.enabled = false;
+                HostProtocolMessage::Bluetooth(Bluetooth::AckDisable)
+            }
+            Bluetooth::GetStatus => {
+                trace!("GetStatus");
+                let rssi = self.link.rssi().await;
+                let queue_overflow = self.link.take_overflow();
+                let connection = match rssi {
+                    Some(rssi) => ConnectionStatus::Connected { rssi },
+                    None if self.enabled => ConnectionStatus::WaitingForConnection,
+                    None => ConnectionStatus::Disabled,
+                };
+                HostProtocolMessage::Bluetooth(Bluetooth::Status(BluetoothStatus {
+                    connection,
+                    queue_overflow,
+                }))
+            }
+            Bluetooth::GetFirmwareVersion => {
+                trace!("GetFirmwareVersion");
+                HostProtocolMessage::Bluetooth(Bluetooth::AckFirmwareVersion {
+                    version: self.info.version,
+                })
+            }
+            Bluetooth::GetReceivedData => HostProtocolMessage::Bluetooth(match self.pending.take().or_else(|| self.link.receive()) {
+                Some(data) => {
+                    trace!("GetReceivedData Some");
+                    Bluetooth::ReceivedData(data)
+                }
+                None => {
+                    trace!("GetReceivedData None");
+                    self.irq.set_high().await;
+                    Bluetooth::NoReceivedData
+                }
+            }),
+            Bluetooth::SendData(data) => {
+                trace!("SendData Some");
+                HostProtocolMessage::Bluetooth(Bluetooth::SendDataResponse(self.send(&data).await))
+            }
+            Bluetooth::GetBtAddress => HostProtocolMessage::Bluetooth(Bluetooth::AckBtAddress {
+                bt_address: self.info.address,
+            }),
+            Bluetooth::SetTxPower { power } => {
+                trace!("SetTxPower");
+                self.link.set_tx_power(power);
+                HostProtocolMessage::Bluetooth(Bluetooth::AckTxPower)
+            }
+            Bluetooth::GetDeviceId => HostProtocolMessage::Bluetooth(Bluetooth::AckDeviceId {
+                device_id: self.info.device_id,
+            }),
+            Bluetooth::Disconnect => {
+                trace!("Disconnect");
+                self.link.disconnect().await;
+                HostProtocolMessage::Bluetooth(Bluetooth::AckDisconnect)
+            }
+            Bluetooth::SetDeviceName { name } => {
+                trace!("SetDeviceName");
+                self.link.set_device_name(name).await;
+                HostProtocolMessage::Bluetooth(Bluetooth::AckSetDeviceName)
+            }
+            Bluetooth::SetDeviceInformation(info) => {
+                trace!("SetDeviceInformation");
+                self.link.set_device_information(info);
+                HostProtocolMessage::Bluetooth(Bluetooth::AckSetDeviceInformation)
+            }
+            Bluetooth::SetBatteryLevel(level) => {
+                trace!("SetBatteryLevel");
+                if level <= 100 {
+                    self.link.set_battery_level(level).await;
+                    HostProtocolMessage::Bluetooth(Bluetooth::AckSetBatteryLevel)
+                } else {
+                    HostProtocolMessage::Bluetooth(Bluetooth::NackSetBatteryLevel)
+                }
+            }
+            #[cfg(feature = "hid")]
+            Bluetooth::TypeText(text) => {
+                trace!("TypeText");
+                HostProtocolMessage::Bluetooth(match hid::is_typeable(&text) && self.link.type_text(text).await {
+                    true => Bluetooth::AckTypeText,
+                    false => Bluetooth::NackTypeText,
+                })
+            }
+            #[cfg(not(feature = "hid"))]
+            Bluetooth::TypeText(_) => HostProtocolMessage::Bluetooth(Bluetooth::NackTypeText),
+            Bluetooth::RegisterCustomService(service) => {
+                trace!("RegisterCustomService");
+                HostProtocolMessage::Bluetooth(
+                    match !self.started && service.is_valid() && self.link.register_custom_service(service).await {
+                        true => Bluetooth::AckRegisterCustomService,
+                        false => Bluetooth::NackRegisterCustomService,
+                    },
+                )
+            }
+            Bluetooth::GetCustomValue(index) => {
+                trace!("GetCustomValue");
+                HostProtocolMessage::Bluetooth(Bluetooth::CustomValue(self.link.custom_value(index).await))
+            }
+            Bluetooth::ReplyCustomRead(value) => {
+                trace!("ReplyCustomRead");
+                HostProtocolMessage::Bluetooth(match self.link.reply_custom_read(value).await {
+                    true => Bluetooth::AckReplyCustomRead,
+                    false => Bluetooth::NackReplyCustomRead,
+                })
+            }
+            Bluetooth::NotifyCustom { index, value } => {
+                trace!("NotifyCustom");
+                HostProtocolMessage::Bluetooth(match self.link.notify_custom(index, value).await {
+                    true => Bluetooth::AckNotifyCustom,
+                    false => Bluetooth::NackNotifyCustom,
+                })
+            }
+            Bluetooth::GetStats { reset } => {
+                trace!("GetStats");
+                let link = self.link.stats(reset);
+                let stats = Stats {
+                    rx_packets: link.rx_packets,
+                    rx_bytes: link.rx_bytes,
+                    rx_dropped: link.rx_dropped,
+                    connections: link.connections,
+                    disconnections: link.disconnections,
+                    adv_restarts: link.adv_restarts,
+                    ..self.stats
+                };
+                if reset {
+                    self.stats = Stats::default();
+                }
+                HostProtocolMessage::Bluetooth(Bluetooth::Stats(stats))
+            }
+            Bluetooth::Echo(msg) => HostProtocolMessage::Bluetooth(Bluetooth::EchoResponse(msg)),
+            Bluetooth::GetEvents => {
+                trace!("GetEvents");
+                HostProtocolMessage::Bluetooth(Bluetooth::Events(self.drain_events().await))
+            }
+            Bluetooth::GetReceivedDataBatch { .. } => {
+                trace!("GetReceivedDataBatch");
+                HostProtocolMessage::Bluetooth(Bluetooth::ReceivedDataBatch(PacketBatch {
+                    packets: &[],
+                    remaining: self.remaining(),
+                }))
+            }
+            Bluetooth::SendDataBatch { packets } => {
+                trace!("SendDataBatch");
+                let mut sent = 0;
+                for packet in iter_packets(packets) {
+                    if packet.len() > APP_MTU || self.send(packet).await != SendDataResponse::Sent {
+                        break;
+                    }
+                    sent += 1;
+                }
+                HostProtocolMessage::Bluetooth(Bluetooth::SendDataBatchResponse { sent })
+            }
+            Bluetooth::SendSarData { total_len, offset, data } => {
+                trace!("SendSarData");
+                let mut sent = 0;
+                let mut segment = [0u8; APP_MTU];
+                while let Some((len, payload_len)) =
+                    sar::write_segment(total_len.into(), usize::from(offset) + sent, &data[sent..], &mut segment)
+                {
+                    if self.send(&segment[..len]).await != SendDataResponse::Sent {
+                        break;
+                    }
+                    sent += payload_len;
+                }
+                HostProtocolMessage::Bluetooth(Bluetooth::SendSarDataResponse { sent: sent as u16 })
+            }
+            Bluetooth::SetConnParams(params) => {
+                trace!("SetConnParams");
+                HostProtocolMessage::Bluetooth(match params.is_valid() && self.link.set_conn_params(params).await {
+                    true => Bluetooth::AckSetConnParams,
+                    false => Bluetooth::NackSetConnParams,
+                })
+            }
+            Bluetooth::GetConnParams => {
+                trace!("GetConnParams");
+                HostProtocolMessage::Bluetooth(Bluetooth::ConnParams(self.link.conn_params().await))
+            }
+            Bluetooth::SetAdvParams(params) => {
+                trace!("SetAdvParams");
+                if params.is_valid() {
+                    self.link.set_adv_params(params).await;
+                    HostProtocolMessage::Bluetooth(Bluetooth::AckSetAdvParams)
+                } else {
+                    HostProtocolMessage::Bluetooth(Bluetooth::NackSetAdvParams)
+                }
+            }
+            Bluetooth::SetAdvData(data) => {
+                trace!("SetAdvData");
+                if data.fits() {
+                    self.link.set_adv_data(data).await;
+                    HostProtocolMessage::Bluetooth(Bluetooth::AckSetAdvData)
+                } else {
+                    HostProtocolMessage::Bluetooth(Bluetooth::NackSetAdvData)
+                }
+            }
+            Bluetooth::SetAdvMode(mode) => {
+                trace!("SetAdvMode");
+                self.link.set_adv_mode(mode).await;
+                HostProtocolMessage::Bluetooth(Bluetooth::AckSetAdvMode)
+            }
+            Bluetooth::SetAcceptList(list) => {
+                trace!("SetAcceptList");
+                if list.is_valid() {
+                    self.link.set_accept_list(list).await;
+                    HostProtocolMessage::Bluetooth(Bluetooth::AckSetAcceptList)
+                } else {
+                    HostProtocolMessage::Bluetooth(Bluetooth::NackSetAcceptList)
+                }
+            }
+            Bluetooth::GetAcceptList => {
+                trace!("GetAcceptList");
+                HostProtocolMessage::Bluetooth(Bluetooth::AcceptList(self.link.accept_list().await))
+            }
+            Bluetooth::ConfirmPasskey(confirm) => {
+                trace!("ConfirmPasskey");
+                HostProtocolMessage::Bluetooth(match self.link.confirm_passkey(confirm).await {
+                    true => Bluetooth::AckConfirmPasskey,
+                    false => Bluetooth::NackConfirmPasskey,
+                })
+            }
+            Bluetooth::ListBonds => {
+                trace!("ListBonds");
+                HostProtocolMessage::Bluetooth(Bluetooth::Bonds(self.link.bonds().await))
+            }
+            Bluetooth::DeleteBond(peer) => {
+                trace!("DeleteBond");
+                HostProtocolMessage::Bluetooth(match self.link.delete_bond(peer).await {
+                    true => Bluetooth::AckDeleteBond,
+                    false => Bluetooth::NackDeleteBond,
+                })
+            }
+            Bluetooth::ClearBonds => {
+                trace!("ClearBonds");
+                self.link.clear_bonds().await;
+                HostProtocolMessage::Bluetooth(Bluetooth::AckClearBonds)
+            }
+            Bluetooth::SetPrivacy(privacy) => {
+                trace!("SetPrivacy");
+                if privacy.is_valid() {
+                    self.link.set_privacy(privacy).await;
+                    HostProtocolMessage::Bluetooth(Bluetooth::AckSetPrivacy)
+                } else {
+                    HostProtocolMessage::Bluetooth(Bluetooth::NackSetPrivacy)
+                }
+            }
+            Bluetooth::SetIdentityAddress(addr) => {
+                trace!("SetIdentityAddress");
+                if addr.as_ref().is_none_or(is_random_static) {
+                    self.link.set_identity_address(addr).await;
+                    HostProtocolMessage::Bluetooth(Bluetooth::AckSetIdentityAddress)
+                } else {
+                    HostProtocolMessage::Bluetooth(Bluetooth::NackSetIdentityAddress)
+                }
+            }
+            Bluetooth::GetAddressOnAir => {
+                trace!("GetAddressOnAir");
+                HostProtocolMessage::Bluetooth(Bluetooth::AddressOnAir(self.link.address_on_air().await))
+            }
+            Bluetooth::SetPreferredPhy(phys) => {
+                trace!("SetPreferredPhy");
+                HostProtocolMessage::Bluetooth(match Phy::all().contains(phys) && self.link.set_preferred_phy(phys).await {
+                    true => Bluetooth::AckSetPreferredPhy,
+                    false => Bluetooth::NackSetPreferredPhy,
+                })
+            }
+            Bluetooth::GetPhy => {
+                trace!("GetPhy");
+                HostProtocolMessage::Bluetooth(Bluetooth::PhyStatus(self.link.phy().await))
+            }
+            Bluetooth::SetSarRx(enabled) => {
+                trace!("SetSarRx");
+                self.link.set_sar_rx(enabled);
+                HostProtocolMessage::Bluetooth(Bluetooth::AckSetSarRx)
+            }
+            _ => {
+                trace!("Other");
+                HostProtocolMessage::InappropriateMessage(self.state())
+            }
+        }
+    }
+
+    /// Takes up to `MAX_EVENTS` events and releases the IRQ line once the queue is empty
+    async fn drain_events(&self) -> EventBatch {
+        let mut batch = EventBatch {
+            events: Default::default(),
+            overflow: self.link.take_events_overflow(),
+        };
+        while !batch.events.is_full() {
+            let Some(event) = self.link.next_event() else {
+                self.irq.set_high().await;
+                break;
+            };
+            let _ = batch.events.push(event);
+        }
+        batch
+    }
+
+    /// Answers `GetReceivedDataBatch` with as many packets as fit in a response of `max_len` bytes
+    async fn respond_batch(&mut self, request_id: Option<RequestId>, max_len: u16, framed: bool, resp: &mut [u8]) -> Option<Outcome> {
+        let budget = batch_response_len(max_len).min(resp.len()).checked_sub(PacketBatch::OVERHEAD)?;
+        let len = self.receive_batch(&mut resp[frame::DATA_RESPONSE_OFFSET..][..budget]).await;
+        let batch = PacketBatch {
+            packets: &[],
+            remaining: self.remaining(),
+        };
+        let message = HostProtocolMessage::Bluetooth(Bluetooth::ReceivedDataBatch(batch));
+        finish_data_response(resp, request_id, message, len, framed)
+    }
+
+    /// Answers `GetReceivedSarData` with the reassembled message, releases the IRQ line if none is
+    /// complete
+    async fn respond_sar(&mut self, request_id: Option<RequestId>, framed: bool, resp: &mut [u8]) -> Option<Outcome> {
+        let data_end = resp.len().checked_sub(frame::CRC_SIZE)?;
+        let Some(len) = self.link.receive_sar(resp.get_mut(frame::DATA_RESPONSE_OFFSET..data_end)?) else {
+            self.irq.set_high().await;
+            let message = HostProtocolMessage::Bluetooth(Bluetooth::NoReceivedData);
+            return encode_response(resp, request_id, message, framed);
+        };
+        let message = HostProtocolMessage::Bluetooth(Bluetooth::ReceivedSarData(&[]));
+        finish_data_response(resp, request_id, message, len, framed)
+    }
+
+    /// Takes as many packets as fit in `buf` and releases the IRQ line once none are left.
+    /// Returns the length of the encoded packets.
+    async fn receive_batch(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        while let Some(packet) = self.pending.take().or_else(|| self.link.receive()) {
+            match PacketBatch::push(buf, len, &packet) {
+                Some(new_len) => len = new_len,
+                None => {
+                    self.pending = Some(packet);
+                    break;
+                }
+            }
+        }
+        if self.remaining() == 0 {
+            self.irq.set_high().await;
+        }
+        len
+    }
+
+    /// Number of received packets not taken by the host yet
+    fn remaining(&self) -> u16 {
+        let remaining = self.link.received_count() + usize::from(self.pending.is_some());
+        remaining.try_into().unwrap_or(u16::MAX)
+    }
+
+    fn state(&self) -> State {
+        match self.enabled {
+            true => State::Enabled,
+            false => State::Disabled,
+        }
+    }
+
+    /// Handles HMAC challenge-response authentication
+    fn hmac_challenge_response(&self, nonce: u64) -> HostProtocolMessage<'static> {
+        type HmacSha256 = Hmac<ShaChallenge>;
+        let Some(secret) = self.secret.sealed_secret() else {
+            return HostProtocolMessage::ChallengeResult { result: [0xFF; 32] };
+        };
+
+        // Calculate HMAC response
+        if let Ok(mut mac) = HmacSha256::new_from_slice(secret) {
+            mac.update(&nonce.to_be_bytes());
+            let result: [u8; 32] = mac.finalize().into_bytes().into();
+            debug!("{=[u8;32]:#X}", result);
+            HostProtocolMessage::ChallengeResult { result }
+        } else {
+            HostProtocolMessage::ChallengeResult { result: [0xFF; 32] }
+        }
+    }
+}
+
+/// Encodes `message` into `resp`, see [`Comms::process`]
+fn encode_response(resp: &mut [u8], request_id: Option<RequestId>, message: HostProtocolMessage, framed: bool) -> Option<Outcome> {
+    trace!("Sending response");
+    let payload_end = resp.len().checked_sub(frame::CRC_SIZE)?;
+    let payload_buf = resp.get_mut(frame::LEN_SIZE..payload_end)?;
+    let Ok(payload_len) = Envelope::new(request_id, message).encode(payload_buf).map(|payload| payload.len()) else {
+        error!("Failed to serialize response");
+        return None;
+    };
+    let Some(resp_len) = frame::finish_response(resp, payload_len, framed) else {
+        error!("Failed to frame response");
+        return None;
+    };
+    Some(Outcome::Respond(resp_len))
+}
+
+/// Completes a response whose data was written into `resp`, see [`frame::finish_data_response`]
+fn finish_data_response(
+    resp: &mut [u8],
+    request_id: Option<RequestId>,
+    message: HostProtocolMessage,
+    data_len: usize,
+    framed: bool,
+) -> Option<Outcome> {
+    trace!("Sending response");
+    let Some(resp_len) = frame::finish_data_response(resp, request_id, message, data_len, framed) else {
+        error!("Failed to frame response");
+        return None;
+    };
+    Some(Outcome::Respond(resp_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockComms, EVENT_CAPACITY, FACTORY_ADDRESS, RX_CAPACITY};
+    use embassy_futures::block_on;
+    use host_protocol::{
+        AcceptListEntry, AddressType, AdvFilter, BtAddress, ManufacturerData, MAX_BATCH_MSG_SIZE, MAX_BATCH_PACKETS_LEN, MAX_MSG_SIZE,
+    };
+
+    fn bluetooth<'a>(comms: &'a mut MockComms, req: Bluetooth<'a>) -> HostProtocolMessage<'a> {
+        block_on(comms.handle(HostProtocolMessage::Bluetooth(req)))
+    }
+
+    fn status(comms: &mut MockComms) -> BluetoothStatus {
+        match bluetooth(comms, Bluetooth::GetStatus) {
+            HostProtocolMessage::Bluetooth(Bluetooth::Status(status)) => status,
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn enable_disable_status() {
+        let mut comms = MockComms::mock(None);
+        assert_eq!(status(&mut comms).connection, ConnectionStatus::Disabled);
+        assert_eq!(
+            block_on(comms.handle(HostProtocolMessage::GetState)),
+            HostProtocolMessage::AckState(State::Disabled)
+        );
+
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::Enable),
+            HostProtocolMessage::Bluetooth(Bluetooth::AckEnable)
+        );
+        assert!(comms.link().enabled.get());
+        assert_eq!(status(&mut comms).connection, ConnectionStatus::WaitingForConnection);
+
+        comms.link().connect(-42);
+        assert_eq!(status(&mut comms).connection, ConnectionStatus::Connected { rssi: -42 });
+
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::Disable),
+            HostProtocolMessage::Bluetooth(Bluetooth::AckDisable)
+        );
+        assert!(!comms.link().enabled.get());
+        assert_eq!(comms.link().disconnects.get(), 1);
+        assert_eq!(status(&mut comms).connection, ConnectionStatus::Disabled);
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::AckEnable),
+            HostProtocolMessage::InappropriateMessage(State::Disabled)
+        );
+    }
+
+    #[test]
+    fn send_data() {
+        let mut comms = MockComms::mock(None);
+        let data = Message::from_slice(&[1, 2, 3]).unwrap();
+        let send = |comms: &mut MockComms| match bluetooth(comms, Bluetooth::SendData(data.clone())) {
+            HostProtocolMessage::Bluetooth(Bluetooth::SendDataResponse(resp)) => resp,
+            other => panic!("unexpected {other:?}"),
+        };
+        let sent = SendDataResponse::Sent;
+        let full = SendDataResponse::BufferFull;
+
+        bluetooth(&mut comms, Bluetooth::Enable);
+        assert_eq!(send(&mut comms), full);
+        comms.link().connect(-60);
+        assert_eq!(send(&mut comms), sent);
+        comms.link().tx_full.set(true);
+        assert_eq!(send(&mut comms), full);
+        assert_eq!(*comms.link().sent.borrow(), [data]);
+    }
+
+    #[test]
+    fn received_data_and_irq() {
+        let mut comms = MockComms::mock(None);
+        comms.link().push_received(&[1]);
+        comms.link().push_received(&[2]);
+        comms.irq().high.set(false);
+
+        for expected in [[1], [2]] {
+            let data = Message::from_slice(&expected).unwrap();
+            assert_eq!(
+                bluetooth(&mut comms, Bluetooth::GetReceivedData),
+                HostProtocolMessage::Bluetooth(Bluetooth::ReceivedData(data))
+            );
+            assert!(!comms.irq().high.get());
+        }
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::GetReceivedData),
+            HostProtocolMessage::Bluetooth(Bluetooth::NoReceivedData)
+        );
+        assert!(comms.irq().high.get());
+    }
+
+    fn batch(comms: &mut MockComms, max_len: u16) -> (Vec<Vec<u8>>, u16) {
+        let mut req = [0u8; MAX_MSG_SIZE];
+        let request = Envelope::new(None, HostProtocolMessage::Bluetooth(Bluetooth::GetReceivedDataBatch { max_len }));
+        let req = frame::encode_request(&request, &mut req).unwrap();
+        let mut resp = [0u8; MAX_BATCH_MSG_SIZE];
+        let Some(Outcome::Respond(len)) = block_on(comms.process(req, &mut resp)) else {
+            panic!("no response");
+        };
+        assert!(len <= batch_response_len(max_len));
+        match frame::decode_response(&resp[..len]).unwrap().message {
+            HostProtocolMessage::Bluetooth(Bluetooth::ReceivedDataBatch(batch)) => {
+                (batch.iter().map(<[u8]>::to_vec).collect(), batch.remaining)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn received_data_batch() {
+        let mut comms = MockComms::mock(None);
+        // Two packets fill the smallest batch, the third one stays pending
+        comms.link().push_received(&[1; 120]);
+        comms.link().push_received(&[2; 100]);
+        comms.link().push_received(&[3; 200]);
+        comms.link().push_received(&[4]);
+        comms.irq().high.set(false);
+
+        assert_eq!(batch(&mut comms, 0), (vec![vec![1; 120], vec![2; 100]], 2));
+        assert!(!comms.irq().high.get());
+
+        // The pending packet comes first, for single reads as well
+        let data = Message::from_slice(&[3; 200]).unwrap();
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::GetReceivedData),
+            HostProtocolMessage::Bluetooth(Bluetooth::ReceivedData(data))
+        );
+        assert_eq!(batch(&mut comms, u16::MAX), (vec![vec![4]], 0));
+        assert!(comms.irq().high.get());
+        assert_eq!(batch(&mut comms, u16::MAX), (vec![], 0));
+    }
+
+    #[test]
+    fn process_full_batch() {
+        let mut comms = MockComms::mock(None);
+        for i in 0..RX_CAPACITY {
+            comms.link().push_received(&[i as u8; APP_MTU]);
+        }
+        let mut req = [0u8; MAX_MSG_SIZE];
+        let request = Envelope::new(
+            Some(u16::MAX),
+            HostProtocolMessage::Bluetooth(Bluetooth::GetReceivedDataBatch { max_len: u16::MAX }),
+        );
+        let req = frame::encode_request(&request, &mut req).unwrap();
+        let mut resp = [0u8; MAX_BATCH_MSG_SIZE];
+        let Some(Outcome::Respond(len)) = block_on(comms.process(req, &mut resp)) else {
+            panic!("no response");
+        };
+        assert!(len <= MAX_BATCH_MSG_SIZE);
+        let resp = frame::decode_response(&resp[..len]).unwrap();
+        let HostProtocolMessage::Bluetooth(Bluetooth::ReceivedDataBatch(batch)) = resp.message else {
+            panic!("unexpected {:?}", resp.message);
+        };
+        let count = batch.iter().count();
+        assert_eq!(count, MAX_BATCH_PACKETS_LEN / (APP_MTU + 2));
+        assert_eq!(usize::from(batch.remaining), RX_CAPACITY - count);
+    }
+
+    #[test]
+    fn send_data_batch() {
+        let mut comms = MockComms::mock(None);
+        let mut buf = [0u8; MAX_BATCH_PACKETS_LEN];
+        let mut len = 0;
+        for packet in [&[1; 10][..], &[2; 20], &[3; APP_MTU + 1], &[4]] {
+            len = PacketBatch::push(&mut buf, len, packet).unwrap();
+        }
+        let send = |comms: &mut MockComms, packets| match bluetooth(comms, Bluetooth::SendDataBatch { packets }) {
+            HostProtocolMessage::Bluetooth(Bluetooth::SendDataBatchResponse { sent }) => sent,
+            other => panic!("unexpected {other:?}"),
+        };
+
+        assert_eq!(send(&mut comms, &buf[..len]), 0);
+        comms.link().connect(-60);
+        // Stops at the packet longer than APP_MTU
+        assert_eq!(send(&mut comms, &buf[..len]), 2);
+        assert_eq!(*comms.link().sent.borrow(), [&[1; 10][..], &[2; 20]]);
+        comms.link().tx_full.set(true);
+        assert_eq!(send(&mut comms, &buf[..len]), 0);
+    }
+
+    #[test]
+    fn sar_round_trip() {
+        let mut comms = MockComms::mock(None);
+        comms.link().connect(-60);
+        let message: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let send = |comms: &mut MockComms, offset: u16| {
+            let data = &message[offset.into()..];
+            match bluetooth(
+                comms,
+                Bluetooth::SendSarData {
+                    total_len: 600,
+                    offset,
+                    data,
+                },
+            ) {
+                HostProtocolMessage::Bluetooth(Bluetooth::SendSarDataResponse { sent }) => sent,
+                other => panic!("unexpected {other:?}"),
+            }
+        };
+
+        // Only full segments are sent
+        comms.link().tx_room.set(Some(2));
+        let sent = send(&mut comms, 0);
+        assert_eq!(usize::from(sent), 2 * APP_MTU - sar::START_HEADER_SIZE - 1);
+        comms.link().tx_room.set(None);
+        assert_eq!(send(&mut comms, sent), 600 - sent);
+
+        let mut reassembler = sar::Reassembler::<600>::new();
+        let complete: Vec<bool> = comms.link().sent.borrow().iter().map(|s| reassembler.push(s).unwrap()).collect();
+        assert_eq!(complete, [false, false, true]);
+        assert_eq!(reassembler.message(), message);
+
+        // and back, segments are plain packets until the host enables reassembly
+        let mut segments = Vec::new();
+        let mut segmenter = sar::Segmenter::new(&message);
+        let mut buf = [0u8; APP_MTU];
+        while let Some(segment) = segmenter.next(&mut buf) {
+            segments.push(segment.to_vec());
+        }
+        comms.link().push_received(&segments[0]);
+        assert_eq!(sar_data(&mut comms), None);
+        assert_eq!(batch(&mut comms, 0), (vec![segments[0].clone()], 0));
+
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetSarRx(true)),
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetSarRx)
+        );
+        // The end of a lost message is dropped
+        comms.link().push_received(&[sar::END, 1]);
+        for segment in &segments {
+            comms.link().push_received(segment);
+        }
+        // and so is the next message until this one was taken
+        comms.link().push_received(&[sar::SINGLE, 1]);
+        comms.irq().high.set(false);
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::GetReceivedData),
+            HostProtocolMessage::Bluetooth(Bluetooth::NoReceivedData)
+        );
+        comms.irq().high.set(false);
+        assert_eq!(sar_data(&mut comms), Some(message.clone()));
+        assert!(!comms.irq().high.get());
+        assert_eq!(sar_data(&mut comms), None);
+        assert!(comms.irq().high.get());
+
+        let stats = comms.link().stats(false);
+        assert_eq!((stats.rx_packets, stats.rx_dropped), (1 + segments.len() as u32, 2));
+        assert!(status(&mut comms).queue_overflow);
+        let events: Vec<Event> = comms.link().events.borrow().iter().cloned().collect();
+        assert_eq!(events.iter().filter(|event| **event == Event::DataAvailable).count(), 2);
+    }
+
+    fn sar_data(comms: &mut MockComms) -> Option<Vec<u8>> {
+        let mut req = [0u8; MAX_MSG_SIZE];
+        let request = Envelope::new(Some(3), HostProtocolMessage::Bluetooth(Bluetooth::GetReceivedSarData));
+        let req = frame::encode_request(&request, &mut req).unwrap();
+        let mut resp = [0u8; MAX_BATCH_MSG_SIZE];
+        let Some(Outcome::Respond(len)) = block_on(comms.process(req, &mut resp)) else {
+            panic!("no response");
+        };
+        let resp = frame::decode_response(&resp[..len]).unwrap();
+        assert_eq!(resp.request_id, Some(3));
+        match resp.message {
+            HostProtocolMessage::Bluetooth(Bluetooth::ReceivedSarData(data)) => Some(data.to_vec()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NoReceivedData) => None,
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn conn_params() {
+        let mut comms = MockComms::mock(None);
+        let params = ConnParams {
+            min_interval: 24,
+            max_interval: 40,
+            latency: 4,
+            supervision_timeout: 400,
+        };
+        let ack = HostProtocolMessage::Bluetooth(Bluetooth::AckSetConnParams);
+        let nack = HostProtocolMessage::Bluetooth(Bluetooth::NackSetConnParams);
+        assert_eq!(bluetooth(&mut comms, Bluetooth::SetConnParams(params)), nack);
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::GetConnParams),
+            HostProtocolMessage::Bluetooth(Bluetooth::ConnParams(None))
+        );
+
+        comms.link().connect(-60);
+        let invalid = ConnParams {
+            supervision_timeout: 10,
+            ..params
+        };
+        assert_eq!(bluetooth(&mut comms, Bluetooth::SetConnParams(invalid)), nack);
+        assert_eq!(bluetooth(&mut comms, Bluetooth::SetConnParams(params)), ack);
+        // The mock central grants the longest interval
+        let granted = ConnParams {
+            min_interval: 40,
+            ..params
+        };
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::GetConnParams),
+            HostProtocolMessage::Bluetooth(Bluetooth::ConnParams(Some(granted)))
+        );
+    }
+
+    #[test]
+    fn adv_params() {
+        let mut comms = MockComms::mock(None);
+        let params = AdvParams {
+            fast_interval: 48,
+            fast_duration: 30,
+            slow_interval: 1600,
+            timeout: 600,
+        };
+        assert_eq!(
+            bluetooth(
+                &mut comms,
+                Bluetooth::SetAdvParams(AdvParams {
+                    slow_interval: 20,
+                    ..params
+                })
+            ),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetAdvParams)
+        );
+        assert_eq!(comms.link().adv_params.get(), None);
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetAdvParams(params)),
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetAdvParams)
+        );
+        assert_eq!(comms.link().adv_params.get(), Some(params));
+    }
+
+    #[test]
+    fn adv_data() {
+        let mut comms = MockComms::mock(None);
+        let mut data = AdvData {
+            tx_power_level: true,
+            manufacturer_data: Some(ManufacturerData {
+                company_id: 0xFFFF,
+                data: [1, 2, 3][..].try_into().unwrap(),
+            }),
+            ..AdvData::new()
+        };
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetAdvData(data.clone())),
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetAdvData)
+        );
+        assert_eq!(*comms.link().adv_data.borrow(), data);
+
+        // One byte over
+        data.appearance = Some(0x0080);
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetAdvData(data)),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetAdvData)
+        );
+        assert_eq!(comms.link().adv_data.borrow().appearance, None);
+
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetAdvMode(AdvMode::NonConnectable)),
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetAdvMode)
+        );
+        assert_eq!(comms.link().adv_mode.get(), AdvMode::NonConnectable);
+    }
+
+    #[test]
+    fn accept_list() {
+        let mut comms = MockComms::mock(None);
+        let mut list = AcceptList {
+            filter: AdvFilter::Both,
+            ..AcceptList::new()
+        };
+        // Nobody could connect
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetAcceptList(list.clone())),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetAcceptList)
+        );
+        let peer = AcceptListEntry {
+            address: BtAddress {
+                addr_type: AddressType::Public,
+                addr: [1, 2, 3, 4, 5, 6],
+            },
+            irk: Some([0x42; 16]),
+        };
+        list.peers.push(peer).unwrap();
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetAcceptList(list.clone())),
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetAcceptList)
+        );
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::GetAcceptList),
+            HostProtocolMessage::Bluetooth(Bluetooth::AcceptList(list))
+        );
+    }
+
+    #[test]
+    fn pairing_and_bonds() {
+        let mut comms = MockComms::mock(None);
+        let peer = BtAddress {
+            addr_type: AddressType::RandomStatic,
+            addr: [0xC1, 2, 3, 4, 5, 6],
+        };
+        let confirm = |comms: &mut MockComms, confirm| match bluetooth(comms, Bluetooth::ConfirmPasskey(confirm)) {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckConfirmPasskey) => true,
+            HostProtocolMessage::Bluetooth(Bluetooth::NackConfirmPasskey) => false,
+            other => panic!("unexpected {other:?}"),
+        };
+        let bonds = |comms: &mut MockComms| match bluetooth(comms, Bluetooth::ListBonds) {
+            HostProtocolMessage::Bluetooth(Bluetooth::Bonds(bonds)) => bonds,
+            other => panic!("unexpected {other:?}"),
+        };
+        assert!(!confirm(&mut comms, true));
+
+        comms.link().connect(-60);
+        comms.link().pairing_request(peer, 123456);
+        assert!(confirm(&mut comms, false));
+        assert!(bonds(&mut comms).is_empty());
+
+        comms.link().pairing_request(peer, 654321);
+        assert!(confirm(&mut comms, true));
+        assert!(!confirm(&mut comms, true));
+        assert_eq!(bonds(&mut comms), [peer]);
+        assert_eq!(
+            events(&mut comms).events,
+            [
+                Event::Connected,
+                Event::PairingPasskey {
+                    passkey: 123456,
+                    confirm: true
+                },
+                Event::PairingPasskey {
+                    passkey: 654321,
+                    confirm: true
+                },
+                Event::Secured,
+                Event::Bonded,
+            ]
+        );
+
+        let other = BtAddress {
+            addr_type: AddressType::Public,
+            ..peer
+        };
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::DeleteBond(other)),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackDeleteBond)
+        );
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::DeleteBond(peer)),
+            HostProtocolMessage::Bluetooth(Bluetooth::AckDeleteBond)
+        );
+        assert!(bonds(&mut comms).is_empty());
+
+        comms.link().pairing_request(peer, 111111);
+        assert!(confirm(&mut comms, true));
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::ClearBonds),
+            HostProtocolMessage::Bluetooth(Bluetooth::AckClearBonds)
+        );
+        assert!(bonds(&mut comms).is_empty());
+
+        // The bond list matches the flash
+        events(&mut comms);
+        comms.link().bond_storage_failing.set(true);
+        comms.link().pairing_request(peer, 222222);
+        assert!(confirm(&mut comms, true));
+        assert!(bonds(&mut comms).is_empty());
+        assert_eq!(events(&mut comms).events[1..], [Event::Secured, Event::BondStorageFailed]);
+    }
+
+    #[test]
+    fn protected_bond_storage() {
+        let mut comms = MockComms::mock(None);
+        assert!(capabilities(&mut comms).contains(Capabilities::BONDING));
+
+        // Pairing still secures the link, without bonding
+        comms.link().bond_storage_protected.set(true);
+        assert!(!capabilities(&mut comms).contains(Capabilities::BONDING));
+        comms.link().connect(-60);
+        let peer = BtAddress {
+            addr_type: AddressType::Public,
+            addr: [1, 2, 3, 4, 5, 6],
+        };
+        comms.link().pairing_request(peer, 123456);
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::ConfirmPasskey(true)),
+            HostProtocolMessage::Bluetooth(Bluetooth::AckConfirmPasskey)
+        );
+        assert_eq!(events(&mut comms).events.last(), Some(&Event::Secured));
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::ListBonds),
+            HostProtocolMessage::Bluetooth(Bluetooth::Bonds(Bonds::new()))
+        );
+    }
+
+    #[test]
+    fn address_privacy() {
+        let mut comms = MockComms::mock(None);
+        let address_on_air = |comms: &mut MockComms| match bluetooth(comms, Bluetooth::GetAddressOnAir) {
+            HostProtocolMessage::Bluetooth(Bluetooth::AddressOnAir(address)) => address,
+            other => panic!("unexpected {other:?}"),
+        };
+        assert_eq!(address_on_air(&mut comms), None);
+        comms.link().set_enabled(true);
+        assert_eq!(
+            address_on_air(&mut comms),
+            Some(BtAddress {
+                addr_type: AddressType::RandomStatic,
+                addr: FACTORY_ADDRESS,
+            })
+        );
+
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetIdentityAddress(Some([0x40, 1, 2, 3, 4, 5]))),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetIdentityAddress)
+        );
+        let custom = [0xC1, 2, 3, 4, 5, 6];
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetIdentityAddress(Some(custom))),
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetIdentityAddress)
+        );
+        assert_eq!(address_on_air(&mut comms).map(|address| address.addr), Some(custom));
+
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetPrivacy(Privacy::ResolvablePrivate { rotation_secs: 0 })),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetPrivacy)
+        );
+        let rpa = Privacy::ResolvablePrivate { rotation_secs: 60 };
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetPrivacy(rpa)),
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetPrivacy)
+        );
+        assert_eq!(comms.link().privacy.get(), rpa);
+        let address = address_on_air(&mut comms).unwrap();
+        assert_eq!(address.addr_type, AddressType::RandomPrivateResolvable);
+        assert!(!address.is_identity());
+
+        // Back to the factory address
+        bluetooth(&mut comms, Bluetooth::SetPrivacy(Privacy::Off));
+        bluetooth(&mut comms, Bluetooth::SetIdentityAddress(None));
+        assert_eq!(address_on_air(&mut comms).map(|address| address.addr), Some(FACTORY_ADDRESS));
+        // Not advertising while connected
+        comms.link().connect(-60);
+        assert_eq!(address_on_air(&mut comms), None);
+    }
+
+    #[test]
+    fn device_information() {
+        let mut comms = MockComms::mock(None);
+        let info = DeviceInformation {
+            hardware_revision: "B".try_into().unwrap(),
+            serial_number: "1234-5678".try_into().unwrap(),
+            bootloader_version: "3.0.3".try_into().unwrap(),
+            firmware_version: "4.0.0".try_into().unwrap(),
+        };
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetDeviceInformation(info.clone())),
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetDeviceInformation)
+        );
+        assert_eq!(*comms.link().device_information.borrow(), info);
+    }
+
+    #[test]
+    fn battery_level() {
+        let mut comms = MockComms::mock(None);
+        let ack = HostProtocolMessage::Bluetooth(Bluetooth::AckSetBatteryLevel);
+        assert_eq!(bluetooth(&mut comms, Bluetooth::SetBatteryLevel(90)), ack);
+        comms.link().connect(-60);
+        assert_eq!(bluetooth(&mut comms, Bluetooth::SetBatteryLevel(90)), ack);
+        assert_eq!(bluetooth(&mut comms, Bluetooth::SetBatteryLevel(89)), ack);
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetBatteryLevel(101)),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetBatteryLevel)
+        );
+        assert_eq!(comms.link().battery_level.get(), 89);
+        // Only the change while connected was notified
+        assert_eq!(*comms.link().battery_notifications.borrow(), [89]);
+    }
+
+    fn capabilities(comms: &mut MockComms) -> Capabilities {
+        match block_on(comms.handle(HostProtocolMessage::GetProtocolInfo)) {
+            HostProtocolMessage::AckProtocolInfo(info) => info.capabilities,
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "hid")]
+    #[test]
+    fn type_text() {
+        let mut comms = MockComms::mock(None);
+        assert!(capabilities(&mut comms).contains(Capabilities::HID));
+        let type_text = |comms: &mut MockComms, text: &str| match bluetooth(comms, Bluetooth::TypeText(text.try_into().unwrap())) {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckTypeText) => true,
+            HostProtocolMessage::Bluetooth(Bluetooth::NackTypeText) => false,
+            other => panic!("unexpected {other:?}"),
+        };
+        // Not connected
+        assert!(!type_text(&mut comms, "abc"));
+        comms.link().connect(-60);
+        assert!(type_text(&mut comms, "Address: bc1q"));
+        assert!(!type_text(&mut comms, "naïve"));
+        assert_eq!(*comms.link().typed.borrow(), "Address: bc1q");
+        assert_eq!(events(&mut comms).events, [Event::Connected, Event::TextTyped]);
+    }
+
+    #[cfg(not(feature = "hid"))]
+    #[test]
+    fn type_text_without_hid() {
+        let mut comms = MockComms::mock(None);
+        assert!(!capabilities(&mut comms).contains(Capabilities::HID));
+        comms.link().connect(-60);
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::TypeText("abc".try_into().unwrap())),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackTypeText)
+        );
+        assert!(comms.link().typed.borrow().is_empty());
+    }
+
+    #[test]
+    fn custom_service() {
+        use host_protocol::{CharProperties, CharSecurity, CustomCharacteristic};
+
+        let mut comms = MockComms::mock(None);
+        let uuid = |alias: u8| {
+            [
+                0xF0, 0x0D, 0, alias, 0xB5, 0xA3, 0xF3, 0x93, 0xE0, 0xA9, 0xE5, 0x0E, 0x24, 0xDC, 0xCA, 0x9E,
+            ]
+        };
+        let characteristic = |alias: u8, properties: CharProperties| CustomCharacteristic {
+            uuid: uuid(alias),
+            properties,
+            security: CharSecurity::Authenticated,
+            max_len: 4,
+        };
+        let mut service = CustomService {
+            uuid: uuid(1),
+            characteristics: heapless::Vec::new(),
+        };
+        let ack = HostProtocolMessage::Bluetooth(Bluetooth::AckRegisterCustomService);
+        let nack = HostProtocolMessage::Bluetooth(Bluetooth::NackRegisterCustomService);
+        // No characteristics
+        assert_eq!(bluetooth(&mut comms, Bluetooth::RegisterCustomService(service.clone())), nack);
+        service.characteristics.push(characteristic(2, CharProperties::WRITE)).unwrap();
+        service
+            .characteristics
+            .push(characteristic(3, CharProperties::READ | CharProperties::NOTIFY))
+            .unwrap();
+        assert_eq!(bluetooth(&mut comms, Bluetooth::RegisterCustomService(service.clone())), ack);
+        // Only once
+        assert_eq!(bluetooth(&mut comms, Bluetooth::RegisterCustomService(service.clone())), nack);
+        let mut comms = MockComms::mock(None);
+        bluetooth(&mut comms, Bluetooth::Enable);
+        bluetooth(&mut comms, Bluetooth::Disable);
+        // The GATT table is fixed once started
+        assert_eq!(bluetooth(&mut comms, Bluetooth::RegisterCustomService(service.clone())), nack);
+
+        let mut comms = MockComms::mock(None);
+        assert_eq!(bluetooth(&mut comms, Bluetooth::RegisterCustomService(service)), ack);
+        bluetooth(&mut comms, Bluetooth::Enable);
+        comms.link().connect(-60);
+        let value = |bytes: &[u8]| CustomValue::from_slice(bytes).unwrap();
+
+        // Writes
+        comms.link().custom_write(0, &[1, 2]);
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::GetCustomValue(0)),
+            HostProtocolMessage::Bluetooth(Bluetooth::CustomValue(Some(value(&[1, 2]))))
+        );
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::GetCustomValue(2)),
+            HostProtocolMessage::Bluetooth(Bluetooth::CustomValue(None))
+        );
+
+        // Reads
+        let reply = |comms: &mut MockComms, bytes: &[u8]| match bluetooth(comms, Bluetooth::ReplyCustomRead(value(bytes))) {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckReplyCustomRead) => true,
+            HostProtocolMessage::Bluetooth(Bluetooth::NackReplyCustomRead) => false,
+            other => panic!("unexpected {other:?}"),
+        };
+        assert!(!reply(&mut comms, &[3]));
+        comms.link().custom_read(1);
+        // Longer than the characteristic
+        assert!(!reply(&mut comms, &[1, 2, 3, 4, 5]));
+        assert!(reply(&mut comms, &[3]));
+        assert!(!reply(&mut comms, &[3]));
+        assert_eq!(*comms.link().custom_replies.borrow(), [(1, value(&[3]))]);
+
+        // Notifications
+        let notify = |comms: &mut MockComms, index: u8| match bluetooth(comms, Bluetooth::NotifyCustom { index, value: value(&[4]) }) {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckNotifyCustom) => true,
+            HostProtocolMessage::Bluetooth(Bluetooth::NackNotifyCustom) => false,
+            other => panic!("unexpected {other:?}"),
+        };
+        assert!(notify(&mut comms, 1));
+        // Not notifiable
+        assert!(!notify(&mut comms, 0));
+        assert!(!notify(&mut comms, 2));
+        assert_eq!(*comms.link().custom_notifications.borrow(), [(1, value(&[4]))]);
+
+        assert_eq!(
+            events(&mut comms).events,
+            [Event::Connected, Event::CustomWritten { index: 0 }, Event::CustomRead { index: 1 }]
+        );
+    }
+
+    #[test]
+    fn preferred_phy() {
+        let mut comms = MockComms::mock(None);
+        let ack = HostProtocolMessage::Bluetooth(Bluetooth::AckSetPreferredPhy);
+        let phy = |comms: &mut MockComms| match bluetooth(comms, Bluetooth::GetPhy) {
+            HostProtocolMessage::Bluetooth(Bluetooth::PhyStatus(status)) => status,
+            other => panic!("{other:?}"),
+        };
+        assert_eq!(phy(&mut comms), None);
+        // Kept for the next connection
+        assert_eq!(bluetooth(&mut comms, Bluetooth::SetPreferredPhy(Phy::LE_1M)), ack);
+        assert_eq!(
+            bluetooth(&mut comms, Bluetooth::SetPreferredPhy(Phy::from_bits_retain(0x04))),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetPreferredPhy)
+        );
+        assert_eq!(comms.link().preferred_phy.get(), Phy::LE_1M);
+
+        comms.link().connect(-60);
+        let le_1m = PhyStatus {
+            tx: Phy::LE_1M,
+            rx: Phy::LE_1M,
+        };
+        assert_eq!(phy(&mut comms), Some(le_1m));
+        // The preference doesn't apply to updates started by the central
+        comms.link().central_phy_request(Phy::LE_2M);
+        let le_2m = PhyStatus {
+            tx: Phy::LE_2M,
+            rx: Phy::LE_2M,
+        };
+        assert_eq!(phy(&mut comms), Some(le_2m));
+        // Requested again on the current connection
+        assert_eq!(bluetooth(&mut comms, Bluetooth::SetPreferredPhy(Phy::LE_1M)), ack);
+        assert_eq!(phy(&mut comms), Some(le_1m));
+
+        assert_eq!(bluetooth(&mut comms, Bluetooth::SetPreferredPhy(Phy::empty())), ack);
+        assert_eq!(phy(&mut comms), Some(le_2m));
+        comms.link().central_phy_request(Phy::LE_1M);
+        assert_eq!(phy(&mut comms), Some(le_1m));
+    }
+
+    #[test]
+    fn overflow_is_reported_once() {
+        let mut comms = MockComms::mock(None);
+        for i in 0..=RX_CAPACITY {
+            comms.link().push_received(&[i as u8]);
+        }
+        assert!(status(&mut comms).queue_overflow);
+        assert!(!status(&mut comms).queue_overflow);
+        assert_eq!(comms.link().received.borrow().len(), RX_CAPACITY);
+    }
+
+    #[test]
+    fn stats() {
+        let mut comms = MockComms::mock(None);
+        let stats = |comms: &mut MockComms, reset| match bluetooth(comms, Bluetooth::GetStats { reset }) {
+            HostProtocolMessage::Bluetooth(Bluetooth::Stats(stats)) => stats,
+            other => panic!("unexpected {other:?}"),
+        };
+        assert_eq!(stats(&mut comms, false), Stats::default());
+
+        bluetooth(&mut comms, Bluetooth::Enable);
+        let data = Message::from_slice(&[1, 2, 3]).unwrap();
+        bluetooth(&mut comms, Bluetooth::SendData(data.clone()));
+        comms.link().connect(-50);
+        bluetooth(&mut comms, Bluetooth::SendData(data));
+        for i in 0..=RX_CAPACITY {
+            comms.link().push_received(&[i as u8, 0]);
+        }
+        let mut resp = [0u8; MAX_MSG_SIZE];
+        block_on(comms.process(&[0xFF], &mut resp));
+        // A framed request with a bad CRC
+        let mut req = [0u8; MAX_MSG_SIZE];
+        let len = frame::encode_request(&Envelope::new(None, HostProtocolMessage::GetState), &mut req)
+            .unwrap()
+            .len();
+        req[len - 1] ^= 0xFF;
+        block_on(comms.process(&req[..len], &mut resp));
+        comms.count_read_error();
+        bluetooth(&mut comms, Bluetooth::Disconnect);
+
+        let expected = Stats {
+            rx_packets: RX_CAPACITY as u32,
+            rx_bytes: 2 * RX_CAPACITY as u64,
+            rx_dropped: 1,
+            tx_packets: 1,
+            tx_bytes: 3,
+            tx_buffer_full: 1,
+            deser_errors: 2,
+            spi_errors: 1,
+            connections: 1,
+            disconnections: 1,
+            adv_restarts: 0,
+        };
+        assert_eq!(stats(&mut comms, false), expected);
+        assert_eq!(stats(&mut comms, true), expected);
+        assert_eq!(stats(&mut comms, false), Stats::default());
+    }
+
+    fn events(comms: &mut MockComms) -> EventBatch {
+        match bluetooth(comms, Bluetooth::GetEvents) {
+            HostProtocolMessage::Bluetooth(Bluetooth::Events(batch)) => batch,
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn events_in_order() {
+        let mut comms = MockComms::mock(None);
+        bluetooth(&mut comms, Bluetooth::Enable);
+        comms.link().connect(-50);
+        comms.link().push_received(&[1]);
+        comms.link().push_received(&[2]);
+        comms.irq().high.set(false);
+
+        let batch = events(&mut comms);
+        assert_eq!(batch.events, [Event::Connected, Event::DataAvailable]);
+        assert!(!batch.overflow);
+        assert!(comms.irq().high.get());
+
+        bluetooth(&mut comms, Bluetooth::Disconnect);
+        assert_eq!(
+            events(&mut comms).events,
+            [Event::Disconnected {
+                reason: host_protocol::DisconnectReason::Host
+            }]
+        );
+        assert!(events(&mut comms).events.is_empty());
+    }
+
+    #[test]
+    fn events_overflow() {
+        let mut comms = MockComms::mock(None);
+        for _ in 0..=EVENT_CAPACITY {
+            comms.link().push_event(Event::TxComplete);
+        }
+        comms.irq().high.set(false);
+
+        let batch = events(&mut comms);
+        assert_eq!(batch.events.len(), host_protocol::MAX_EVENTS);
+        assert!(batch.overflow);
+        // a full batch may leave events behind
+        assert!(!comms.irq().high.get());
+
+        let batch = events(&mut comms);
+        assert!(batch.events.is_empty());
+        assert!(!batch.overflow);
+        assert!(comms.irq().high.get());
+    }
+
+    #[test]
+    fn hmac_challenge() {
+        let challenge = |comms: &mut MockComms| match block_on(comms.handle(HostProtocolMessage::ChallengeRequest { nonce: 0x0102 })) {
+            HostProtocolMessage::ChallengeResult { result } => result,
+            other => panic!("unexpected {other:?}"),
+        };
+        assert_eq!(challenge(&mut MockComms::mock(None)), [0xFF; 32]);
+
+        let secret = [0x5A; 32];
+        let mut mac = Hmac::<ShaChallenge>::new_from_slice(&secret).unwrap();
+        mac.update(&[0, 0, 0, 0, 0, 0, 1, 2]);
+        let expected: [u8; 32] = mac.finalize().into_bytes().into();
+        let mut comms = MockComms::mock(Some(secret));
+        assert_eq!(challenge(&mut comms), expected);
+        assert_ne!(challenge(&mut MockComms::mock(Some([0xA5; 32]))), expected);
+    }
+
+    #[test]
+    fn process_framed_enveloped_request() {
+        let mut comms = MockComms::mock(None);
+        let mut req = [0u8; MAX_MSG_SIZE];
+        let request = Envelope::new(Some(0x1234), HostProtocolMessage::Bluetooth(Bluetooth::GetBtAddress));
+        let req = frame::encode_request(&request, &mut req).unwrap();
+        let mut resp = [0u8; MAX_MSG_SIZE];
+        let Some(Outcome::Respond(len)) = block_on(comms.process(req, &mut resp)) else {
+            panic!("no response");
+        };
+        let resp = frame::decode_response(&resp[..len]).unwrap();
+        assert_eq!(resp.request_id, Some(0x1234));
+        assert_eq!(
+            resp.message,
+            HostProtocolMessage::Bluetooth(Bluetooth::AckBtAddress {
+                bt_address: [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]
+            })
+        );
+    }
+
+    #[test]
+    fn process_plain_request() {
+        let mut comms = MockComms::mock(None);
+        let mut resp = [0u8; MAX_MSG_SIZE];
+        // GetState
+        assert_eq!(block_on(comms.process(&[3], &mut resp)), Some(Outcome::Respond(4)));
+        assert_eq!(&resp[..4], &[0, 2, 4, 1]);
+        // Undecodable request
+        assert_eq!(block_on(comms.process(&[0xFF], &mut resp)), Some(Outcome::Respond(4)));
+        assert_eq!(&resp[..4], &[0, 2, 7, 0]);
+        // Reset
+        assert_eq!(block_on(comms.process(&[2], &mut resp)), Some(Outcome::Reset));
+    }
+}
@@ -0,0 +1,501 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! In-memory implementations of the hardware traits, to run [`Comms`](crate::Comms) on the host.
+
+use crate::bonds::{Bond, BondList};
+use crate::sar_rx::SarReceiver;
+use crate::{BleLink, Comms, DeviceInfo, IrqLine, Secret};
+use host_protocol::{
+    AcceptList, AddressType, AdvChan, AdvData, AdvMode, AdvParams, Bonds, BtAddress, CharProperties, ConnParams, CustomService,
+    CustomValue, DeviceInformation, DeviceName, DisconnectReason, Event, Message, Phy, PhyStatus, Privacy, SendDataResponse, Stats,
+    TxPower,
+};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+/// Number of received packets buffered, as in the firmware
+pub const RX_CAPACITY: usize = 16;
+
+/// Number of events buffered, as in the firmware
+pub const EVENT_CAPACITY: usize = 16;
+
+/// Address reported by `GetBtAddress`, and the identity address until the host sets another one
+pub const FACTORY_ADDRESS: [u8; 6] = [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF];
+
+/// BLE link recording what the handler asked for
+#[derive(Default)]
+pub struct MockLink {
+    pub enabled: Cell<bool>,
+    /// RSSI of the connected central, `None` if not connected
+    pub rssi: Cell<Option<i8>>,
+    /// Notifications fail with `BufferFull` while set
+    pub tx_full: Cell<bool>,
+    /// Number of notifications accepted before `BufferFull`, unlimited if `None`
+    pub tx_room: Cell<Option<usize>>,
+    pub sent: RefCell<Vec<Message>>,
+    pub received: RefCell<VecDeque<Message>>,
+    pub overflow: Cell<bool>,
+    /// Received packets are reassembled by `sar` instead of queued
+    pub sar_rx: Cell<bool>,
+    pub sar: RefCell<SarReceiver>,
+    pub events: RefCell<VecDeque<Event>>,
+    pub events_overflow: Cell<bool>,
+    pub disconnects: Cell<usize>,
+    pub adv_channels: Cell<Option<AdvChan>>,
+    pub adv_params: Cell<Option<AdvParams>>,
+    pub adv_data: RefCell<AdvData>,
+    pub adv_mode: Cell<AdvMode>,
+    pub accept_list: RefCell<AcceptList>,
+    pub tx_power: Cell<Option<TxPower>>,
+    pub device_name: RefCell<DeviceName>,
+    pub device_information: RefCell<DeviceInformation>,
+    pub battery_level: Cell<u8>,
+    /// Battery levels notified to the connected central
+    pub battery_notifications: RefCell<Vec<u8>>,
+    /// Text typed on the HID keyboard
+    pub typed: RefCell<String>,
+    pub custom_service: RefCell<Option<CustomService>>,
+    /// Values written by the central to the custom characteristics
+    pub custom_values: RefCell<Vec<CustomValue>>,
+    /// Custom characteristic read by the central, waiting for the host to reply
+    pub custom_read: Cell<Option<u8>>,
+    /// Values of the custom characteristics read by the central
+    pub custom_replies: RefCell<Vec<(u8, CustomValue)>>,
+    /// Values of the custom characteristics notified to the connected central
+    pub custom_notifications: RefCell<Vec<(u8, CustomValue)>>,
+    /// Parameters granted by the central, `None` if not connected
+    pub conn_params: Cell<Option<ConnParams>>,
+    /// PHYs set by the host, empty to let the link layer choose
+    pub preferred_phy: Cell<Phy>,
+    /// PHYs in use, `None` if not connected
+    pub phy: Cell<Option<PhyStatus>>,
+    /// Peer waiting for the numeric comparison to be confirmed
+    pub pairing: Cell<Option<BtAddress>>,
+    pub bonds: RefCell<BondList>,
+    /// The bootloader write protects the bond storage, pairing doesn't bond
+    pub bond_storage_protected: Cell<bool>,
+    /// Writing a new bond to flash fails, the bond is forgotten
+    pub bond_storage_failing: Cell<bool>,
+    pub privacy: Cell<Privacy>,
+    /// Identity address set by the host, `None` for [`FACTORY_ADDRESS`]
+    pub identity_address: Cell<Option<[u8; 6]>>,
+    /// Counters of the link, the notifications and the errors are counted by the handler
+    pub stats: Cell<Stats>,
+}
+
+impl MockLink {
+    pub fn connect(&self, rssi: i8) {
+        self.rssi.set(Some(rssi));
+        self.conn_params.set(Some(ConnParams {
+            min_interval: ConnParams::DEFAULT.max_interval,
+            ..ConnParams::DEFAULT
+        }));
+        self.phy.set(Some(Self::negotiate_phy(Phy::all(), self.preferred_phy.get())));
+        self.update_stats(|stats| stats.connections += 1);
+        self.push_event(Event::Connected);
+    }
+
+    /// Simulates a PHY update started by the central. Like the SoftDevice, the peripheral answers
+    /// that any PHY will do whatever the preferred PHYs are.
+    pub fn central_phy_request(&self, phys: Phy) {
+        if self.rssi.get().is_some() {
+            self.phy.set(Some(Self::negotiate_phy(phys, Phy::empty())));
+        }
+    }
+
+    /// The fastest PHY allowed by both sides, empty sets allow any PHY
+    fn negotiate_phy(central: Phy, peripheral: Phy) -> PhyStatus {
+        let allowed = |phys: Phy| match phys.is_empty() {
+            true => Phy::all(),
+            false => phys,
+        };
+        let phy = match allowed(central) & allowed(peripheral) {
+            both if both.contains(Phy::LE_2M) => Phy::LE_2M,
+            _ => Phy::LE_1M,
+        };
+        PhyStatus { tx: phy, rx: phy }
+    }
+
+    /// Simulates LESC pairing started by `peer`, with a numeric comparison of `passkey`
+    pub fn pairing_request(&self, peer: BtAddress, passkey: u32) {
+        self.pairing.set(Some(peer));
+        self.push_event(Event::PairingPasskey { passkey, confirm: true });
+    }
+
+    /// Simulates the central writing the custom characteristic `index`
+    pub fn custom_write(&self, index: u8, value: &[u8]) {
+        self.custom_values.borrow_mut()[usize::from(index)] = CustomValue::from_slice(value).unwrap();
+        self.push_event(Event::CustomWritten { index });
+    }
+
+    /// Simulates the central reading the custom characteristic `index`
+    pub fn custom_read(&self, index: u8) {
+        self.custom_read.set(Some(index));
+        self.push_event(Event::CustomRead { index });
+    }
+
+    /// Maximum value length of the custom characteristic `index`, with all its `properties`
+    fn custom_max_len(&self, index: u8, properties: CharProperties) -> Option<usize> {
+        let service = self.custom_service.borrow();
+        let characteristic = service.as_ref()?.characteristics.get(usize::from(index))?;
+        characteristic
+            .properties
+            .contains(properties)
+            .then_some(usize::from(characteristic.max_len))
+    }
+
+    /// Simulates a packet written by the central, dropped if the queue is full or, in SAR mode, if
+    /// it isn't a valid segment or a message waits to be taken
+    pub fn push_received(&self, data: &[u8]) {
+        if self.sar_rx.get() {
+            let Some(complete) = self.sar.borrow_mut().push(data) else {
+                self.overflow.set(true);
+                self.update_stats(|stats| stats.rx_dropped += 1);
+                return;
+            };
+            self.update_stats(|stats| {
+                stats.rx_packets += 1;
+                stats.rx_bytes += data.len() as u64;
+            });
+            if complete {
+                self.push_event(Event::DataAvailable);
+            }
+            return;
+        }
+        let mut received = self.received.borrow_mut();
+        if received.len() == RX_CAPACITY {
+            self.overflow.set(true);
+            self.update_stats(|stats| stats.rx_dropped += 1);
+            return;
+        }
+        self.update_stats(|stats| {
+            stats.rx_packets += 1;
+            stats.rx_bytes += data.len() as u64;
+        });
+        if received.is_empty() {
+            self.push_event(Event::DataAvailable);
+        }
+        received.push_back(Message::from_slice(data).expect("packet longer than APP_MTU"));
+    }
+
+    fn update_stats(&self, update: impl FnOnce(&mut Stats)) {
+        let mut stats = self.stats.get();
+        update(&mut stats);
+        self.stats.set(stats);
+    }
+
+    /// Queues an event, dropped if the queue is full
+    pub fn push_event(&self, event: Event) {
+        let mut events = self.events.borrow_mut();
+        if events.len() == EVENT_CAPACITY {
+            self.events_overflow.set(true);
+        } else {
+            events.push_back(event);
+        }
+    }
+}
+
+impl BleLink for MockLink {
+    fn set_enabled(&self, enabled: bool) {
+        self.enabled.set(enabled);
+    }
+
+    async fn disconnect(&self) {
+        self.conn_params.set(None);
+        self.phy.set(None);
+        self.pairing.set(None);
+        if self.rssi.take().is_some() {
+            self.disconnects.set(self.disconnects.get() + 1);
+            self.update_stats(|stats| stats.disconnections += 1);
+            self.push_event(Event::Disconnected {
+                reason: DisconnectReason::Host,
+            });
+        }
+    }
+
+    async fn rssi(&self) -> Option<i8> {
+        self.rssi.get()
+    }
+
+    async fn send(&self, data: &[u8]) -> SendDataResponse {
+        if self.rssi.get().is_none() || self.tx_full.get() || self.tx_room.get() == Some(0) {
+            return SendDataResponse::BufferFull;
+        }
+        self.tx_room.set(self.tx_room.get().map(|room| room - 1));
+        self.sent.borrow_mut().push(Message::from_slice(data).unwrap());
+        SendDataResponse::Sent
+    }
+
+    fn receive(&self) -> Option<Message> {
+        self.received.borrow_mut().pop_front()
+    }
+
+    fn received_count(&self) -> usize {
+        self.received.borrow().len()
+    }
+
+    fn take_overflow(&self) -> bool {
+        self.overflow.take()
+    }
+
+    fn set_sar_rx(&self, enabled: bool) {
+        self.sar_rx.set(enabled);
+        self.sar.borrow_mut().reset();
+    }
+
+    fn receive_sar(&self, buf: &mut [u8]) -> Option<usize> {
+        self.sar.borrow_mut().take(buf)
+    }
+
+    fn next_event(&self) -> Option<Event> {
+        self.events.borrow_mut().pop_front()
+    }
+
+    fn take_events_overflow(&self) -> bool {
+        self.events_overflow.take()
+    }
+
+    fn set_adv_channels(&self, disabled: AdvChan) {
+        self.adv_channels.set(Some(disabled));
+    }
+
+    async fn set_adv_params(&self, params: AdvParams) {
+        self.adv_params.set(Some(params));
+    }
+
+    async fn set_adv_data(&self, data: AdvData) {
+        *self.adv_data.borrow_mut() = data;
+    }
+
+    async fn set_adv_mode(&self, mode: AdvMode) {
+        self.adv_mode.set(mode);
+    }
+
+    async fn set_accept_list(&self, list: AcceptList) {
+        *self.accept_list.borrow_mut() = list;
+    }
+
+    async fn accept_list(&self) -> AcceptList {
+        self.accept_list.borrow().clone()
+    }
+
+    fn set_tx_power(&self, power: TxPower) {
+        self.tx_power.set(Some(power));
+    }
+
+    async fn set_device_name(&self, name: DeviceName) {
+        *self.device_name.borrow_mut() = name;
+    }
+
+    fn set_device_information(&self, info: DeviceInformation) {
+        *self.device_information.borrow_mut() = info;
+    }
+
+    async fn set_battery_level(&self, level: u8) {
+        if self.battery_level.replace(level) != level && self.rssi.get().is_some() {
+            self.battery_notifications.borrow_mut().push(level);
+        }
+    }
+
+    #[cfg(feature = "hid")]
+    async fn type_text(&self, text: host_protocol::KeyboardText) -> bool {
+        if self.rssi.get().is_none() {
+            return false;
+        }
+        // Typed at once
+        self.typed.borrow_mut().push_str(&text);
+        self.push_event(Event::TextTyped);
+        true
+    }
+
+    async fn register_custom_service(&self, service: CustomService) -> bool {
+        if self.custom_service.borrow().is_some() {
+            return false;
+        }
+        *self.custom_values.borrow_mut() = vec![CustomValue::new(); service.characteristics.len()];
+        *self.custom_service.borrow_mut() = Some(service);
+        true
+    }
+
+    async fn custom_value(&self, index: u8) -> Option<CustomValue> {
+        self.custom_values.borrow().get(usize::from(index)).cloned()
+    }
+
+    async fn reply_custom_read(&self, value: CustomValue) -> bool {
+        let Some(index) = self.custom_read.get() else {
+            return false;
+        };
+        if self
+            .custom_max_len(index, CharProperties::READ)
+            .is_none_or(|max_len| value.len() > max_len)
+        {
+            return false;
+        }
+        self.custom_read.set(None);
+        self.custom_replies.borrow_mut().push((index, value));
+        true
+    }
+
+    async fn notify_custom(&self, index: u8, value: CustomValue) -> bool {
+        // The central subscribed to all notifications
+        if self.rssi.get().is_none()
+            || self.tx_full.get()
+            || self
+                .custom_max_len(index, CharProperties::NOTIFY)
+                .is_none_or(|max_len| value.len() > max_len)
+        {
+            return false;
+        }
+        self.custom_notifications.borrow_mut().push((index, value));
+        true
+    }
+
+    async fn set_conn_params(&self, params: ConnParams) -> bool {
+        if self.rssi.get().is_none() {
+            return false;
+        }
+        // The central grants the longest interval
+        self.conn_params.set(Some(ConnParams {
+            min_interval: params.max_interval,
+            ..params
+        }));
+        true
+    }
+
+    async fn conn_params(&self) -> Option<ConnParams> {
+        self.conn_params.get()
+    }
+
+    async fn set_preferred_phy(&self, phys: Phy) -> bool {
+        self.preferred_phy.set(phys);
+        if self.rssi.get().is_some() {
+            // The central supports both PHYs
+            self.phy.set(Some(Self::negotiate_phy(Phy::all(), phys)));
+        }
+        true
+    }
+
+    async fn phy(&self) -> Option<PhyStatus> {
+        self.phy.get()
+    }
+
+    async fn confirm_passkey(&self, confirm: bool) -> bool {
+        let Some(peer) = self.pairing.take() else {
+            return false;
+        };
+        if confirm && self.bond_storage_protected.get() {
+            self.push_event(Event::Secured);
+        } else if confirm && self.bond_storage_failing.get() {
+            self.push_event(Event::Secured);
+            self.push_event(Event::BondStorageFailed);
+        } else if confirm {
+            // The central confirmed as well and distributed its keys
+            self.bonds.borrow_mut().insert(Bond {
+                peer,
+                irk: [0x11; 16],
+                ltk: [0x22; 16],
+                ltk_flags: 0x47,
+                ediv: 0,
+                rand: [0; 8],
+            });
+            self.push_event(Event::Secured);
+            self.push_event(Event::Bonded);
+        }
+        true
+    }
+
+    fn bond_storage_available(&self) -> bool {
+        !self.bond_storage_protected.get()
+    }
+
+    async fn bonds(&self) -> Bonds {
+        self.bonds.borrow().peers()
+    }
+
+    async fn delete_bond(&self, peer: BtAddress) -> bool {
+        self.bonds.borrow_mut().remove(&peer)
+    }
+
+    async fn clear_bonds(&self) {
+        self.bonds.borrow_mut().clear();
+    }
+
+    async fn set_privacy(&self, privacy: Privacy) {
+        self.privacy.set(privacy);
+    }
+
+    async fn set_identity_address(&self, addr: Option<[u8; 6]>) {
+        self.identity_address.set(addr);
+    }
+
+    async fn address_on_air(&self) -> Option<BtAddress> {
+        // Advertising while enabled and not connected
+        if !self.enabled.get() || self.rssi.get().is_some() {
+            return None;
+        }
+        Some(match self.privacy.get() {
+            Privacy::Off => BtAddress {
+                addr_type: AddressType::RandomStatic,
+                addr: self.identity_address.get().unwrap_or(FACTORY_ADDRESS),
+            },
+            // The two most significant bits of a resolvable private address are 0b01
+            Privacy::ResolvablePrivate { .. } => BtAddress {
+                addr_type: AddressType::RandomPrivateResolvable,
+                addr: [0x4A, 0x11, 0x22, 0x33, 0x44, 0x55],
+            },
+        })
+    }
+
+    fn stats(&self, reset: bool) -> Stats {
+        match reset {
+            true => self.stats.take(),
+            false => self.stats.get(),
+        }
+    }
+}
+
+/// IRQ line level, starting high
+pub struct MockIrq {
+    pub high: Cell<bool>,
+}
+
+impl Default for MockIrq {
+    fn default() -> Self {
+        Self { high: Cell::new(true) }
+    }
+}
+
+impl IrqLine for MockIrq {
+    async fn set_high(&self) {
+        self.high.set(true);
+    }
+}
+
+/// UICR content, unsealed by default
+#[derive(Default)]
+pub struct MockSecret(pub Option<[u8; 32]>);
+
+impl Secret for MockSecret {
+    fn sealed_secret(&self) -> Option<&[u8]> {
+        self.0.as_ref().map(|secret| secret.as_slice())
+    }
+}
+
+/// Handler with all mocks, as seen by the host before any request
+pub type MockComms = Comms<MockLink, MockIrq, MockSecret>;
+
+impl MockComms {
+    pub fn mock(secret: Option<[u8; 32]>) -> Self {
+        Comms::new(
+            MockLink::default(),
+            MockIrq::default(),
+            MockSecret(secret),
+            DeviceInfo {
+                address: FACTORY_ADDRESS,
+                device_id: [1, 2, 3, 4, 5, 6, 7, 8],
+                version: "4.0.0",
+            },
+        )
+    }
+}
@@ -0,0 +1,101 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Reassembly of the [`sar`](host_protocol::sar) segments written by the central.
+//!
+//! Segments are reassembled as they are written, apart from the queue of plain packets, and the
+//! complete message is kept until the host takes it with `GetReceivedSarData`.
+
+use host_protocol::sar::Reassembler;
+use host_protocol::MAX_SAR_MSG_SIZE;
+
+/// Reassembles one message at a time
+#[derive(Default)]
+pub struct SarReceiver {
+    reassembler: Reassembler<MAX_SAR_MSG_SIZE>,
+    /// The reassembled message waits to be taken
+    complete: bool,
+}
+
+impl SarReceiver {
+    pub const fn new() -> Self {
+        Self {
+            reassembler: Reassembler::new(),
+            complete: false,
+        }
+    }
+
+    /// Adds a segment written by the central, returns `Some(true)` once a message is complete.
+    ///
+    /// `None` if the segment was dropped, because the last message wasn't taken yet or the segment
+    /// is invalid. The rest of a message is dropped along with its first invalid segment.
+    pub fn push(&mut self, segment: &[u8]) -> Option<bool> {
+        if self.complete {
+            return None;
+        }
+        self.complete = self.reassembler.push(segment).ok()?;
+        Some(self.complete)
+    }
+
+    /// Copies the reassembled message into `buf` and makes room for the next one.
+    /// Returns its length, `None` if no message is complete or it doesn't fit in `buf`.
+    pub fn take(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if !self.complete {
+            return None;
+        }
+        let message = self.reassembler.message();
+        let len = message.len();
+        buf.get_mut(..len)?.copy_from_slice(message);
+        self.reset();
+        Some(len)
+    }
+
+    /// Drops the complete or partial message
+    pub fn reset(&mut self) {
+        self.complete = false;
+        self.reassembler.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use host_protocol::sar;
+
+    #[test]
+    fn one_message_at_a_time() {
+        let mut receiver = SarReceiver::new();
+        let mut buf = [0u8; MAX_SAR_MSG_SIZE];
+        assert_eq!(receiver.take(&mut buf), None);
+
+        assert_eq!(receiver.push(&[sar::START, 3, 0, 1]), Some(false));
+        assert_eq!(receiver.push(&[sar::END, 2, 3]), Some(true));
+        // The next message waits until this one was taken
+        assert_eq!(receiver.push(&[sar::SINGLE, 4]), None);
+        assert_eq!(receiver.take(&mut buf[..2]), None);
+        assert_eq!(receiver.take(&mut buf), Some(3));
+        assert_eq!(buf[..3], [1, 2, 3]);
+        assert_eq!(receiver.take(&mut buf), None);
+
+        assert_eq!(receiver.push(&[sar::SINGLE]), Some(true));
+        assert_eq!(receiver.take(&mut buf), Some(0));
+    }
+
+    #[test]
+    fn invalid_segments() {
+        let mut receiver = SarReceiver::new();
+        let mut buf = [0u8; MAX_SAR_MSG_SIZE];
+        assert_eq!(receiver.push(&[sar::CONTINUE, 1]), None);
+        assert_eq!(receiver.push(&[sar::START, 3, 0, 1]), Some(false));
+        assert_eq!(receiver.push(&[0x42]), None);
+        assert_eq!(receiver.push(&[sar::END, 2, 3]), None);
+        assert_eq!(receiver.take(&mut buf), None);
+
+        assert_eq!(receiver.push(&[sar::START, 3, 0, 1]), Some(false));
+        receiver.reset();
+        assert_eq!(receiver.push(&[sar::END, 2, 3]), None);
+        assert_eq!(receiver.push(&[sar::SINGLE, 5]), Some(true));
+        receiver.reset();
+        assert_eq!(receiver.take(&mut buf), None);
+    }
+}
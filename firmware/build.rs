@@ -13,7 +13,7 @@
 
 #[cfg(not(feature = "debug"))]
 use consts::SIGNATURE_HEADER_SIZE;
-use consts::{BASE_APP_ADDR, BASE_BOOTLOADER_ADDR};
+use consts::{BASE_APP_ADDR, BOND_STORAGE_ADDR};
 use std::env;
 use std::fs::File;
 use std::io::Write;
@@ -30,23 +30,25 @@ fn main() {
     let signature_header_size = SIGNATURE_HEADER_SIZE;
     /* The SoftDevices S113 7.3.0 minimal RAM requirement is 4.4K (0x1198) */
     /* and use a maximum of 1.75K (0x700) for call stack. */
-    /* We choose to reserve 10648 bytes (0x2998) at the begining of RAM */
-    let soft_device_ram_reserved = 10648;
+    /* We choose to reserve 10648 bytes (0x2998) at the begining of RAM, */
+    /* plus 896 bytes (0x380) for the attribute table enlarged from 0x580 to 0x900 */
+    /* by the DIS, BAS, HID and custom services. */
+    let soft_device_ram_reserved = 10648 + 896;
 
     let memory_x_content = format!(
         r##"
-        BASE_BOOTLOADER_ADDR = {:#X};
+        BOND_STORAGE_ADDR = {:#X};
         BASE_APP_ADDR = {:#X};
         SIGNATURE_HEADER_SIZE = {};
 
         MEMORY
         {{
             /* NOTE 1 K = 1 KiBi = 1024 bytes */
-            FLASH (rx) : ORIGIN = 0x00000000 + BASE_APP_ADDR + SIGNATURE_HEADER_SIZE, LENGTH = BASE_BOOTLOADER_ADDR - BASE_APP_ADDR - SIGNATURE_HEADER_SIZE
+            FLASH (rx) : ORIGIN = 0x00000000 + BASE_APP_ADDR + SIGNATURE_HEADER_SIZE, LENGTH = BOND_STORAGE_ADDR - BASE_APP_ADDR - SIGNATURE_HEADER_SIZE
             RAM : ORIGIN = 0x20000000 + {}, LENGTH = 24K - {}
         }}
         "##,
-        BASE_BOOTLOADER_ADDR, BASE_APP_ADDR, signature_header_size, soft_device_ram_reserved, soft_device_ram_reserved
+        BOND_STORAGE_ADDR, BASE_APP_ADDR, signature_header_size, soft_device_ram_reserved, soft_device_ram_reserved
     );
     File::create(out.join("./memory.x"))
         .unwrap()
@@ -0,0 +1,32 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Battery Service ([BAS]) implementation, with the level measured by the MPU.
+//! [BAS]: https://www.bluetooth.com/specifications/specs/battery-service-1-0/
+
+use defmt::error;
+use nrf_softdevice::ble::Connection;
+use nrf_softdevice::gatt_service;
+
+#[gatt_service(uuid = "180F")]
+pub struct Bas {
+    /// Level in percent, 0 until the host sets it
+    #[characteristic(uuid = "2A19", read, notify)]
+    battery_level: u8,
+}
+
+impl Bas {
+    /// Stores `level`, and notifies the central of a change if it subscribed
+    pub(crate) fn set_level(&self, level: u8, connection: Option<&Connection>) {
+        if self.battery_level_get().is_ok_and(|current| current == level) {
+            return;
+        }
+        if self.battery_level_set(&level).is_err() {
+            error!("Setting the battery level failed");
+        }
+        if let Some(connection) = connection {
+            // Fails if the central didn't enable notifications
+            let _ = self.battery_level_notify(connection, &level);
+        }
+    }
+}
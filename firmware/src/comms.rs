@@ -1,230 +1,319 @@
 // SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use core::sync::atomic::AtomicBool;
-
+#[cfg(feature = "hid")]
+use crate::hid::{HID_TEXT, HID_TYPING};
 use crate::{
-    server::Server, BT_ADV_CHAN, BT_ADV_CHANGED, BT_DATA_RX, BT_DATA_RX_OVERFLOW, BT_ENABLE, CONNECTION, DEVICE_NAME, IRQ_OUT_PIN,
+    custom,
+    security::reply_passkey,
+    server::{adv_address, from_gap_conn_params, from_gap_phys, phy_update, to_gap_conn_params, Server},
+    BONDS, BONDS_CHANGED, BOND_STORAGE_PROTECTED, BT_ACCEPT_LIST, BT_ADV_CHAN, BT_ADV_CHANGED, BT_ADV_DATA, BT_ADV_MODE, BT_ADV_PARAMS,
+    BT_DATA_RX, BT_DATA_RX_OVERFLOW, BT_DISCONNECT_REQUESTED, BT_ENABLE, BT_EVENTS, BT_EVENTS_OVERFLOW, BT_IDENTITY_ADDRESS,
+    BT_PREFERRED_PHY, BT_PRIVACY, BT_SAR_RX, BT_SAR_RX_ENABLED, BT_STATS, BT_TX_BLOCKED, CONNECTION, DEVICE_NAME, IRQ_OUT_PIN,
     TX_PWR_VALUE,
 };
 use consts::{UICR_SEALED_SECRET, UICR_SEAL_INDEX, UICR_SECRET_SIZE, UICR_SECRET_START};
-use defmt::{debug, error, trace};
+use core::sync::atomic::Ordering;
+use defmt::{error, trace};
 use embassy_nrf::{peripherals::SPI0, spis::Spis};
-use hmac::{Hmac, Mac};
-use host_protocol::{AdvChan, Bluetooth, BluetoothStatus, HostProtocolMessage, PostcardError, SendDataResponse, State, MAX_MSG_SIZE};
-use postcard::{from_bytes, to_slice};
-use sha2::Sha256 as ShaChallenge;
-
-// This is redundant with BT_ENABLE, only used to report
-// the current state over the host-protocol.
-static BT_STATE_COPY: AtomicBool = AtomicBool::new(false);
-
-pub struct CommsContext<'a> {
-    pub address: [u8; 6],
-    pub device_id: [u8; 8],
+use firmware_core::{BleLink, Comms, DeviceInfo, IrqLine, Outcome, Secret};
+use host_protocol::{
+    AcceptList, AdvChan, AdvData, AdvMode, AdvParams, Bonds, BtAddress, ConnParams, CustomService, CustomValue, DeviceInformation,
+    DeviceName, Event, Message, Phy, PhyStatus, Privacy, SendDataResponse, Stats, TxPower, MAX_BATCH_MSG_SIZE,
+};
+
+/// [`BleLink`] backed by the SoftDevice tasks
+pub struct SoftdeviceLink<'a> {
     pub server: &'a Server,
 }
 
-/// Main communication task that handles incoming SPI messages from the MPU
-/// Decodes postcard-encoded messages and routes them to appropriate handlers
-pub async fn comms_task(mut spi: Spis<'static, SPI0>, context: CommsContext<'_>) -> ! {
-    // Buffer for raw incoming SPI data
-    let mut req_buf = [0u8; MAX_MSG_SIZE];
-    let mut resp_buf = [0u8; MAX_MSG_SIZE];
-
-    loop {
-        // Read data from SPI
-        let res = spi.read(&mut req_buf).await;
+impl BleLink for SoftdeviceLink<'_> {
+    fn set_enabled(&self, enabled: bool) {
+        BT_ENABLE.signal(enabled);
+        if enabled {
+            // Restarts advertising if it timed out
+            BT_ADV_CHANGED.signal(());
+        }
+    }
 
-        // Exit if no data received
-        let Ok(n) = res else {
-            error!("Failed to read from SPI");
-            continue;
-        };
+    async fn disconnect(&self) {
+        if let Some(connection) = CONNECTION.read().await.as_ref() {
+            BT_DISCONNECT_REQUESTED.store(true, Ordering::Relaxed);
+            let _ = connection.disconnect();
+        }
+    }
 
-        let resp = match from_bytes(&req_buf[..n]) {
-            Ok(req) => host_protocol_handler(req, &context).await,
-            Err(_) => HostProtocolMessage::PostcardError(PostcardError::Deser),
-        };
-        trace!("Sending response");
-        let Ok(resp) = to_slice(&resp, &mut resp_buf[2..]) else {
-            error!("Failed to serialize response");
-            continue;
-        };
-        let resp_len = resp.len();
-        resp_buf[..2].copy_from_slice(&u16::to_be_bytes(resp_len as u16));
-        // Async and blocking perform exactly the same, but an async write
-        // makes the subsequent read unreliable.
-        let _ = spi.blocking_write_from_ram(&resp_buf[..resp_len + 2]);
+    async fn rssi(&self) -> Option<i8> {
+        CONNECTION.read().await.as_ref().map(|conn| conn.rssi().unwrap_or(i8::MIN))
     }
-}
 
-/// Handles HostProtocol messages received from the MPU
-async fn host_protocol_handler<'a>(req: HostProtocolMessage<'a>, context: &CommsContext<'_>) -> HostProtocolMessage<'a> {
-    match req {
-        HostProtocolMessage::Bluetooth(bluetooth_msg) => {
-            trace!("Received HostProtocolMessage::Bluetooth");
-            match bluetooth_msg {
-                Bluetooth::DisableChannels(chan) => {
-                    trace!("DisableChannels");
-                    if chan == AdvChan::all() {
-                        HostProtocolMessage::Bluetooth(Bluetooth::NackDisableChannels)
-                    } else {
-                        BT_ADV_CHAN.store(chan.bits(), core::sync::atomic::Ordering::Relaxed);
-                        BT_ADV_CHANGED.signal(());
-                        HostProtocolMessage::Bluetooth(Bluetooth::AckDisableChannels)
-                    }
-                }
-                Bluetooth::Enable => {
-                    trace!("Enabled");
-                    BT_ENABLE.signal(true);
-                    BT_STATE_COPY.store(true, core::sync::atomic::Ordering::Relaxed);
-                    HostProtocolMessage::Bluetooth(Bluetooth::AckEnable)
-                }
-                Bluetooth::Disable => {
-                    trace!("Disabled");
-                    // clean disconnect if connected
-                    if let Some(connection) = CONNECTION.read().await.as_ref() {
-                        let _ = connection.disconnect();
-                    }
-                    BT_ENABLE.signal(false);
-                    BT_STATE_COPY.store(false, core::sync::atomic::Ordering::Relaxed);
-                    HostProtocolMessage::Bluetooth(Bluetooth::AckDisable)
-                }
-                Bluetooth::GetStatus => {
-                    trace!("GetStatus");
-                    let conn_lock = CONNECTION.read().await;
-                    let queue_overflow = BT_DATA_RX_OVERFLOW.swap(false, core::sync::atomic::Ordering::Relaxed);
-                    let result = if let Some(conn) = &*conn_lock {
-                        BluetoothStatus {
-                            connection: host_protocol::ConnectionStatus::Connected {
-                                rssi: conn.rssi().unwrap_or(i8::MIN),
-                            },
-                            queue_overflow,
-                        }
-                    } else if BT_STATE_COPY.load(core::sync::atomic::Ordering::Relaxed) {
-                        BluetoothStatus {
-                            connection: host_protocol::ConnectionStatus::WaitingForConnection,
-                            queue_overflow,
-                        }
-                    } else {
-                        BluetoothStatus {
-                            connection: host_protocol::ConnectionStatus::Disabled,
-                            queue_overflow,
-                        }
-                    };
-                    HostProtocolMessage::Bluetooth(Bluetooth::Status(result))
-                }
-                Bluetooth::GetFirmwareVersion => {
-                    trace!("GetFirmwareVersion");
-                    let version = env!("CARGO_PKG_VERSION");
-                    HostProtocolMessage::Bluetooth(Bluetooth::AckFirmwareVersion { version })
-                }
-                Bluetooth::GetReceivedData => HostProtocolMessage::Bluetooth(match BT_DATA_RX.try_receive() {
-                    Ok(data) => {
-                        trace!("GetReceivedData Some");
-                        Bluetooth::ReceivedData(data)
-                    }
-                    Err(_) => {
-                        trace!("GetReceivedData None");
-                        IRQ_OUT_PIN.lock().await.as_mut().map(|pin| pin.set_high());
-                        Bluetooth::NoReceivedData
-                    }
-                }),
-                Bluetooth::SendData(data) => HostProtocolMessage::Bluetooth({
-                    trace!("SendData Some");
-                    let conn_lock = CONNECTION.read().await;
-                    if let Some(connection) = &conn_lock.as_ref() {
-                        match context.server.send_notify(connection, &data) {
-                            Ok(_) => Bluetooth::SendDataResponse(SendDataResponse::Sent),
-                            Err(_) => Bluetooth::SendDataResponse(SendDataResponse::BufferFull),
-                        }
-                    } else {
-                        trace!("Not connected");
-                        Bluetooth::SendDataResponse(SendDataResponse::BufferFull)
-                    }
-                }),
-                Bluetooth::GetBtAddress => HostProtocolMessage::Bluetooth(Bluetooth::AckBtAddress {
-                    bt_address: context.address,
-                }),
-                Bluetooth::SetTxPower { power } => {
-                    trace!("SetTxPower");
-                    TX_PWR_VALUE.store(i8::from(power), core::sync::atomic::Ordering::Relaxed);
-                    BT_ADV_CHANGED.signal(());
-                    HostProtocolMessage::Bluetooth(Bluetooth::AckTxPower)
-                }
-                Bluetooth::GetDeviceId => HostProtocolMessage::Bluetooth(Bluetooth::AckDeviceId {
-                    device_id: context.device_id,
-                }),
-                Bluetooth::Disconnect => {
-                    trace!("Disconnect");
-                    // Get current connection and disconnect if connected
-                    if let Some(connection) = CONNECTION.read().await.as_ref() {
-                        let _ = connection.disconnect();
-                    }
-                    HostProtocolMessage::Bluetooth(Bluetooth::AckDisconnect)
-                }
-                Bluetooth::SetDeviceName { name } => {
-                    trace!("SetDeviceName");
-                    *DEVICE_NAME.lock().await = name;
-                    BT_ADV_CHANGED.signal(());
-                    HostProtocolMessage::Bluetooth(Bluetooth::AckSetDeviceName)
-                }
-                Bluetooth::Echo(msg) => HostProtocolMessage::Bluetooth(Bluetooth::EchoResponse(msg)),
-                _ => {
-                    trace!("Other");
-                    HostProtocolMessage::InappropriateMessage(get_state())
+    async fn send(&self, data: &[u8]) -> SendDataResponse {
+        let conn_lock = CONNECTION.read().await;
+        if let Some(connection) = &conn_lock.as_ref() {
+            match self.server.send_notify(connection, data) {
+                Ok(_) => SendDataResponse::Sent,
+                Err(_) => {
+                    BT_TX_BLOCKED.store(true, Ordering::Relaxed);
+                    SendDataResponse::BufferFull
                 }
             }
+        } else {
+            trace!("Not connected");
+            SendDataResponse::BufferFull
         }
-        HostProtocolMessage::Reset => {
-            trace!("Reset");
-            cortex_m::peripheral::SCB::sys_reset();
+    }
+
+    fn receive(&self) -> Option<Message> {
+        BT_DATA_RX.try_receive().ok()
+    }
+
+    fn received_count(&self) -> usize {
+        BT_DATA_RX.len()
+    }
+
+    fn take_overflow(&self) -> bool {
+        BT_DATA_RX_OVERFLOW.swap(false, Ordering::Relaxed)
+    }
+
+    fn set_sar_rx(&self, enabled: bool) {
+        BT_SAR_RX_ENABLED.store(enabled, Ordering::Relaxed);
+        BT_SAR_RX.lock(|sar| sar.borrow_mut().reset());
+    }
+
+    fn receive_sar(&self, buf: &mut [u8]) -> Option<usize> {
+        BT_SAR_RX.lock(|sar| sar.borrow_mut().take(buf))
+    }
+
+    fn next_event(&self) -> Option<Event> {
+        BT_EVENTS.try_receive().ok()
+    }
+
+    fn take_events_overflow(&self) -> bool {
+        BT_EVENTS_OVERFLOW.swap(false, Ordering::Relaxed)
+    }
+
+    fn set_adv_channels(&self, disabled: AdvChan) {
+        BT_ADV_CHAN.store(disabled.bits(), Ordering::Relaxed);
+        BT_ADV_CHANGED.signal(());
+    }
+
+    async fn set_adv_params(&self, params: AdvParams) {
+        *BT_ADV_PARAMS.lock().await = params;
+        BT_ADV_CHANGED.signal(());
+    }
+
+    async fn set_adv_data(&self, data: AdvData) {
+        *BT_ADV_DATA.lock().await = data;
+        BT_ADV_CHANGED.signal(());
+    }
+
+    async fn set_adv_mode(&self, mode: AdvMode) {
+        *BT_ADV_MODE.lock().await = mode;
+        BT_ADV_CHANGED.signal(());
+    }
+
+    async fn set_accept_list(&self, list: AcceptList) {
+        *BT_ACCEPT_LIST.lock().await = list;
+        BT_ADV_CHANGED.signal(());
+    }
+
+    async fn accept_list(&self) -> AcceptList {
+        BT_ACCEPT_LIST.lock().await.clone()
+    }
+
+    fn set_tx_power(&self, power: TxPower) {
+        TX_PWR_VALUE.store(i8::from(power), Ordering::Relaxed);
+        BT_ADV_CHANGED.signal(());
+    }
+
+    async fn set_device_name(&self, name: DeviceName) {
+        *DEVICE_NAME.lock().await = name;
+        BT_ADV_CHANGED.signal(());
+    }
+
+    fn set_device_information(&self, info: DeviceInformation) {
+        self.server.dis.set_device_information(&info);
+    }
+
+    async fn set_battery_level(&self, level: u8) {
+        self.server.bas.set_level(level, CONNECTION.read().await.as_ref());
+    }
+
+    async fn register_custom_service(&self, service: CustomService) -> bool {
+        custom::register(&service)
+    }
+
+    async fn custom_value(&self, index: u8) -> Option<CustomValue> {
+        custom::value(index)
+    }
+
+    async fn reply_custom_read(&self, value: CustomValue) -> bool {
+        custom::reply_read(&value)
+    }
+
+    async fn notify_custom(&self, index: u8, value: CustomValue) -> bool {
+        custom::notify(index, &value).await
+    }
+
+    async fn set_conn_params(&self, params: ConnParams) -> bool {
+        match CONNECTION.read().await.as_ref() {
+            Some(connection) => match connection.set_conn_params(to_gap_conn_params(&params)) {
+                Ok(()) => true,
+                Err(_) => {
+                    error!("set_conn_params error");
+                    false
+                }
+            },
+            None => false,
         }
-        HostProtocolMessage::ChallengeRequest { nonce } => {
-            trace!("ChallengeRequest");
-            hmac_challenge_response(nonce)
+    }
+
+    async fn conn_params(&self) -> Option<ConnParams> {
+        CONNECTION
+            .read()
+            .await
+            .as_ref()
+            .map(|connection| from_gap_conn_params(&connection.conn_params()))
+    }
+
+    async fn set_preferred_phy(&self, phys: Phy) -> bool {
+        BT_PREFERRED_PHY.store(phys.bits(), Ordering::Relaxed);
+        match CONNECTION.read().await.as_ref() {
+            Some(connection) => phy_update(connection, phys),
+            None => true,
         }
-        HostProtocolMessage::GetState => {
-            trace!("GetState");
-            HostProtocolMessage::AckState(get_state())
+    }
+
+    async fn phy(&self) -> Option<PhyStatus> {
+        CONNECTION.read().await.as_ref().map(|connection| from_gap_phys(&connection.phy()))
+    }
+
+    async fn confirm_passkey(&self, confirm: bool) -> bool {
+        reply_passkey(confirm)
+    }
+
+    fn bond_storage_available(&self) -> bool {
+        !BOND_STORAGE_PROTECTED.load(Ordering::Relaxed)
+    }
+
+    async fn bonds(&self) -> Bonds {
+        BONDS.lock(|bonds| bonds.borrow().peers())
+    }
+
+    async fn delete_bond(&self, peer: BtAddress) -> bool {
+        let deleted = BONDS.lock(|bonds| bonds.borrow_mut().remove(&peer));
+        if deleted {
+            BONDS_CHANGED.signal(());
         }
-        _ => {
-            trace!("Other");
-            HostProtocolMessage::InappropriateMessage(get_state())
+        deleted
+    }
+
+    async fn clear_bonds(&self) {
+        BONDS.lock(|bonds| bonds.borrow_mut().clear());
+        BONDS_CHANGED.signal(());
+    }
+
+    async fn set_privacy(&self, privacy: Privacy) {
+        *BT_PRIVACY.lock().await = privacy;
+        BT_ADV_CHANGED.signal(());
+    }
+
+    async fn set_identity_address(&self, addr: Option<[u8; 6]>) {
+        *BT_IDENTITY_ADDRESS.lock().await = addr;
+        BT_ADV_CHANGED.signal(());
+    }
+
+    async fn address_on_air(&self) -> Option<BtAddress> {
+        adv_address()
+    }
+
+    fn stats(&self, reset: bool) -> Stats {
+        BT_STATS.lock(|stats| match reset {
+            true => stats.take(),
+            false => *stats.borrow(),
+        })
+    }
+
+    #[cfg(feature = "hid")]
+    async fn type_text(&self, text: host_protocol::KeyboardText) -> bool {
+        if CONNECTION.read().await.is_none() || HID_TYPING.swap(true, Ordering::Relaxed) {
+            return false;
         }
+        HID_TEXT.signal(text);
+        true
+    }
+}
+
+/// nRF -> MPU IRQ output pin
+pub struct IrqOutPin;
+
+impl IrqLine for IrqOutPin {
+    async fn set_high(&self) {
+        IRQ_OUT_PIN.lock().await.as_mut().map(|pin| pin.set_high());
     }
 }
 
-fn get_state() -> State {
-    match BT_STATE_COPY.load(core::sync::atomic::Ordering::Relaxed) {
-        true => State::Enabled,
-        false => State::Disabled,
+/// Challenge secret provisioned in UICR
+pub struct UicrSecret;
+
+impl Secret for UicrSecret {
+    fn sealed_secret(&self) -> Option<&[u8]> {
+        let seal = unsafe { &*nrf52805_pac::UICR::ptr() }.customer[UICR_SEAL_INDEX]
+            .read()
+            .customer()
+            .bits();
+
+        if seal != UICR_SEALED_SECRET {
+            return None;
+        }
+
+        // Get device secret from UICR memory
+        Some(unsafe { core::slice::from_raw_parts(UICR_SECRET_START as *const u8, UICR_SECRET_SIZE as usize) })
     }
 }
 
-/// Handles HMAC challenge-response authentication
-fn hmac_challenge_response(nonce: u64) -> HostProtocolMessage<'static> {
-    type HmacSha256 = Hmac<ShaChallenge>;
-    let seal = unsafe { &*nrf52805_pac::UICR::ptr() }.customer[UICR_SEAL_INDEX]
-        .read()
-        .customer()
-        .bits();
-
-    if seal != UICR_SEALED_SECRET {
-        return HostProtocolMessage::ChallengeResult { result: [0xFF; 32] };
-    }
-
-    // Get device secret from UICR memory
-    let secret_as_slice = unsafe { core::slice::from_raw_parts(UICR_SECRET_START as *const u8, UICR_SECRET_SIZE as usize) };
-
-    // Calculate HMAC response
-    if let Ok(mut mac) = HmacSha256::new_from_slice(secret_as_slice) {
-        mac.update(&nonce.to_be_bytes());
-        let result: [u8; 32] = mac.finalize().into_bytes().into();
-        debug!("{=[u8;32]:#X}", result);
-        HostProtocolMessage::ChallengeResult { result }
-    } else {
-        HostProtocolMessage::ChallengeResult { result: [0xFF; 32] }
+pub type FirmwareComms<'a> = Comms<SoftdeviceLink<'a>, IrqOutPin, UicrSecret>;
+
+pub fn new_comms(address: [u8; 6], device_id: [u8; 8], server: &Server) -> FirmwareComms<'_> {
+    Comms::new(
+        SoftdeviceLink { server },
+        IrqOutPin,
+        UicrSecret,
+        DeviceInfo {
+            address,
+            device_id,
+            version: env!("CARGO_PKG_VERSION"),
+        },
+    )
+}
+
+/// Main communication task that handles incoming SPI messages from the MPU
+/// Decodes postcard-encoded messages and routes them to appropriate handlers
+pub async fn comms_task(mut spi: Spis<'static, SPI0>, mut comms: FirmwareComms<'_>) -> ! {
+    // Buffer for raw incoming SPI data, large enough for a full `SendDataBatch`
+    let mut req_buf = [0u8; MAX_BATCH_MSG_SIZE];
+    // Large enough for a full `ReceivedDataBatch`
+    let mut resp_buf = [0u8; MAX_BATCH_MSG_SIZE];
+
+    loop {
+        // Read data from SPI
+        let res = spi.read(&mut req_buf).await;
+
+        // Exit if no data received
+        let Ok(n) = res else {
+            error!("Failed to read from SPI");
+            comms.count_read_error();
+            continue;
+        };
+
+        match comms.process(&req_buf[..n], &mut resp_buf).await {
+            Some(Outcome::Respond(resp_len)) => {
+                // Async and blocking perform exactly the same, but an async write
+                // makes the subsequent read unreliable.
+                let _ = spi.blocking_write_from_ram(&resp_buf[..resp_len]);
+            }
+            Some(Outcome::Reset) => cortex_m::peripheral::SCB::sys_reset(),
+            None => {}
+        }
     }
 }
@@ -0,0 +1,243 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! GATT service defined by the host at runtime, to prototype services without a firmware update.
+//!
+//! The service is added with raw SoftDevice calls, as the service builder needs exclusive access
+//! to the SoftDevice which is already running by then. Writes are stored by the SoftDevice and
+//! fetched by the host, reads are deferred until the host replies.
+
+use crate::{push_event, BT_TX_BLOCKED, CONNECTION};
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use defmt::error;
+use embassy_sync::blocking_mutex::{self, raw::ThreadModeRawMutex};
+use heapless::Vec;
+use host_protocol::{CharProperties, CharSecurity, CustomService, CustomValue, Event, MAX_CUSTOM_CHARACTERISTICS, MAX_CUSTOM_VALUE_LEN};
+use nrf_softdevice::ble::gatt_server::{notify_value, DeferredReadReply, NotifyValueError};
+use nrf_softdevice::{raw, RawError};
+use raw::{ble_gap_conn_sec_mode_t, ble_gatts_attr_md_t, ble_uuid_t};
+
+/// Registered characteristic
+struct Characteristic {
+    value_handle: u16,
+    properties: CharProperties,
+    max_len: u8,
+}
+
+/// Read waiting for the host to reply
+struct PendingRead {
+    reply: DeferredReadReply,
+    index: u8,
+    offset: usize,
+}
+
+static CHARACTERISTICS: blocking_mutex::Mutex<ThreadModeRawMutex, RefCell<Vec<Characteristic, MAX_CUSTOM_CHARACTERISTICS>>> =
+    blocking_mutex::Mutex::new(RefCell::new(Vec::new()));
+static PENDING_READ: blocking_mutex::Mutex<ThreadModeRawMutex, RefCell<Option<PendingRead>>> =
+    blocking_mutex::Mutex::new(RefCell::new(None));
+/// Set by the first registration, services can't be removed so a failed one leaves part of its
+/// attributes in the GATT table
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Adds the 128-bit base of `uuid` to the SoftDevice, and returns the UUID with the registered base
+fn to_ble_uuid(uuid: &[u8; 16]) -> Option<ble_uuid_t> {
+    // The SoftDevice stores the least significant byte first
+    let mut uuid128 = raw::ble_uuid128_t { uuid128: *uuid };
+    uuid128.uuid128.reverse();
+    let mut uuid_type = 0;
+    let ret = unsafe { raw::sd_ble_uuid_vs_add(&uuid128, &mut uuid_type) };
+    if ret != raw::NRF_SUCCESS {
+        error!("sd_ble_uuid_vs_add error {}", ret);
+        return None;
+    }
+    Some(ble_uuid_t {
+        uuid: u16::from_be_bytes([uuid[2], uuid[3]]),
+        type_: uuid_type,
+    })
+}
+
+fn sec_mode(security: Option<CharSecurity>) -> ble_gap_conn_sec_mode_t {
+    // Security mode 1, level 1 is open, 2 needs encryption and 4 LESC MITM protection.
+    // Mode 0 level 0 is no access.
+    let (sm, lv) = match security {
+        None => (0, 0),
+        Some(CharSecurity::Open) => (1, 1),
+        Some(CharSecurity::Encrypted) => (1, 2),
+        Some(CharSecurity::Authenticated) => (1, 4),
+    };
+    ble_gap_conn_sec_mode_t {
+        _bitfield_1: ble_gap_conn_sec_mode_t::new_bitfield_1(sm, lv),
+    }
+}
+
+/// Adds `service` to the GATT table, only once even if it failed
+pub fn register(service: &CustomService) -> bool {
+    if REGISTERED.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    let Some(service_uuid) = to_ble_uuid(&service.uuid) else {
+        return false;
+    };
+    let mut service_handle = 0;
+    let ret = unsafe { raw::sd_ble_gatts_service_add(raw::BLE_GATTS_SRVC_TYPE_PRIMARY as u8, &service_uuid, &mut service_handle) };
+    if ret != raw::NRF_SUCCESS {
+        error!("sd_ble_gatts_service_add error {}", ret);
+        return false;
+    }
+
+    let mut registered = Vec::new();
+    for characteristic in &service.characteristics {
+        let Some(uuid) = to_ble_uuid(&characteristic.uuid) else {
+            return false;
+        };
+        let properties = characteristic.properties;
+        let security = Some(characteristic.security);
+        let readable = properties.contains(CharProperties::READ);
+        let writable = properties.intersects(CharProperties::WRITE | CharProperties::WRITE_WITHOUT_RESPONSE);
+        let notify = properties.contains(CharProperties::NOTIFY);
+
+        let cccd_md = ble_gatts_attr_md_t {
+            read_perm: sec_mode(Some(CharSecurity::Open)),
+            write_perm: sec_mode(security),
+            _bitfield_1: ble_gatts_attr_md_t::new_bitfield_1(0, raw::BLE_GATTS_VLOC_STACK as u8, 0, 0),
+        };
+        let mut char_md: raw::ble_gatts_char_md_t = unsafe { core::mem::zeroed() };
+        char_md.char_props = raw::ble_gatt_char_props_t {
+            _bitfield_1: raw::ble_gatt_char_props_t::new_bitfield_1(
+                0,
+                readable as u8,
+                properties.contains(CharProperties::WRITE_WITHOUT_RESPONSE) as u8,
+                properties.contains(CharProperties::WRITE) as u8,
+                notify as u8,
+                0,
+                0,
+            ),
+        };
+        if notify {
+            char_md.p_cccd_md = &cccd_md;
+        }
+        // Variable length value, with reads authorized by the host
+        let attr_md = ble_gatts_attr_md_t {
+            read_perm: sec_mode(security.filter(|_| readable)),
+            write_perm: sec_mode(security.filter(|_| writable)),
+            _bitfield_1: ble_gatts_attr_md_t::new_bitfield_1(1, raw::BLE_GATTS_VLOC_STACK as u8, readable as u8, 0),
+        };
+        let attr = raw::ble_gatts_attr_t {
+            p_uuid: &uuid,
+            p_attr_md: &attr_md,
+            init_len: 0,
+            init_offs: 0,
+            max_len: characteristic.max_len.into(),
+            p_value: core::ptr::null_mut(),
+        };
+        let mut handles: raw::ble_gatts_char_handles_t = unsafe { core::mem::zeroed() };
+        let ret = unsafe { raw::sd_ble_gatts_characteristic_add(service_handle, &char_md, &attr, &mut handles) };
+        if ret != raw::NRF_SUCCESS {
+            error!("sd_ble_gatts_characteristic_add error {}", ret);
+            return false;
+        }
+        let _ = registered.push(Characteristic {
+            value_handle: handles.value_handle,
+            properties,
+            max_len: characteristic.max_len,
+        });
+    }
+    CHARACTERISTICS.lock(|characteristics| characteristics.replace(registered));
+    true
+}
+
+/// Index of the characteristic with the value `handle`
+fn index_of(handle: u16) -> Option<u8> {
+    CHARACTERISTICS.lock(|characteristics| {
+        characteristics
+            .borrow()
+            .iter()
+            .position(|characteristic| characteristic.value_handle == handle)
+            .map(|index| index as u8)
+    })
+}
+
+/// Value handle and maximum length of the characteristic `index`, if it has `properties`
+fn find(index: u8, properties: CharProperties) -> Option<(u16, usize)> {
+    CHARACTERISTICS.lock(|characteristics| {
+        characteristics
+            .borrow()
+            .get(usize::from(index))
+            .filter(|characteristic| characteristic.properties.contains(properties))
+            .map(|characteristic| (characteristic.value_handle, usize::from(characteristic.max_len)))
+    })
+}
+
+/// Reports a write of the central to the host
+pub fn on_write(handle: u16) {
+    if let Some(index) = index_of(handle) {
+        push_event(Event::CustomWritten { index });
+    }
+}
+
+/// Reports a read of the central to the host, which replies with `ReplyCustomRead`
+pub fn on_read(handle: u16, offset: usize, reply: DeferredReadReply) {
+    let Some(index) = index_of(handle) else {
+        return;
+    };
+    // ATT requests are sequential, a previous read was dropped with its connection
+    PENDING_READ.lock(|pending| pending.replace(Some(PendingRead { reply, index, offset })));
+    push_event(Event::CustomRead { index });
+}
+
+/// Value of the characteristic `index` as last written by the central
+pub fn value(index: u8) -> Option<CustomValue> {
+    let (handle, _) = find(index, CharProperties::empty())?;
+    let mut buf = [0u8; MAX_CUSTOM_VALUE_LEN];
+    let mut value = raw::ble_gatts_value_t {
+        len: buf.len() as u16,
+        offset: 0,
+        p_value: buf.as_mut_ptr(),
+    };
+    let ret = unsafe { raw::sd_ble_gatts_value_get(raw::BLE_CONN_HANDLE_INVALID as u16, handle, &mut value) };
+    if ret != raw::NRF_SUCCESS {
+        error!("sd_ble_gatts_value_get error {}", ret);
+        return None;
+    }
+    CustomValue::from_slice(&buf[..usize::from(value.len).min(buf.len())]).ok()
+}
+
+/// Answers the pending read with `value`, left pending if the value is too long
+pub fn reply_read(value: &[u8]) -> bool {
+    let Some(read) = PENDING_READ.lock(|pending| {
+        let mut pending = pending.borrow_mut();
+        let fits = pending
+            .as_ref()
+            .and_then(|read| find(read.index, CharProperties::READ))
+            .is_some_and(|(_, max_len)| value.len() <= max_len);
+        pending.take_if(|_| fits)
+    }) else {
+        return false;
+    };
+    // Long reads continue at an offset, with the same value
+    read.reply.reply(Ok(value.get(read.offset..).unwrap_or_default())).is_ok()
+}
+
+/// Notifies the connected central of a new value of the characteristic `index`
+pub async fn notify(index: u8, value: &[u8]) -> bool {
+    let Some((handle, max_len)) = find(index, CharProperties::NOTIFY) else {
+        return false;
+    };
+    if value.len() > max_len {
+        return false;
+    }
+    let conn_lock = CONNECTION.read().await;
+    let Some(connection) = conn_lock.as_ref() else {
+        return false;
+    };
+    match notify_value(connection, handle, value) {
+        Ok(()) => true,
+        Err(NotifyValueError::Raw(RawError::Resources)) => {
+            // Reported with `TxComplete` once the queue drained
+            BT_TX_BLOCKED.store(true, Ordering::Relaxed);
+            false
+        }
+        Err(_) => false,
+    }
+}
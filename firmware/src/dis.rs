@@ -0,0 +1,65 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Device Information Service ([DIS]) implementation.
+//! [DIS]: https://www.bluetooth.com/specifications/specs/device-information-service-1-1/
+
+use consts::{DEFAULT_DEVICE_NAME, MANUFACTURER_NAME};
+use core::fmt::Write;
+use defmt::error;
+use host_protocol::{DeviceInformation, DisString, MAX_DIS_STRING_LEN};
+use nrf_softdevice::ble::gatt_server::SetValueError;
+use nrf_softdevice::gatt_service;
+
+/// Room for `<firmware version> bootloader <bootloader version>`
+const SOFTWARE_REVISION_LEN: usize = 2 * MAX_DIS_STRING_LEN + " bootloader ".len();
+
+#[gatt_service(uuid = "180A")]
+pub struct Dis {
+    #[characteristic(uuid = "2A29", read)]
+    manufacturer_name: DisString,
+
+    #[characteristic(uuid = "2A24", read)]
+    model_number: DisString,
+
+    #[characteristic(uuid = "2A25", read)]
+    serial_number: DisString,
+
+    #[characteristic(uuid = "2A27", read)]
+    hardware_revision: DisString,
+
+    /// Version of this BLE firmware
+    #[characteristic(uuid = "2A26", read)]
+    firmware_revision: DisString,
+
+    /// Version of the signed firmware image and of the bootloader that booted it
+    #[characteristic(uuid = "2A28", read)]
+    software_revision: heapless::String<SOFTWARE_REVISION_LEN>,
+}
+
+fn check(result: Result<(), SetValueError>) {
+    if result.is_err() {
+        error!("Setting a DIS value failed");
+    }
+}
+
+impl Dis {
+    /// Sets the strings known to the firmware, the others stay empty until the host sets them
+    pub(crate) fn init(&self) {
+        let string = |s: &str| DisString::try_from(s).unwrap_or_default();
+        check(self.manufacturer_name_set(&string(MANUFACTURER_NAME)));
+        check(self.model_number_set(&string(DEFAULT_DEVICE_NAME)));
+        check(self.firmware_revision_set(&string(env!("CARGO_PKG_VERSION"))));
+    }
+
+    pub(crate) fn set_device_information(&self, info: &DeviceInformation) {
+        check(self.serial_number_set(&info.serial_number));
+        check(self.hardware_revision_set(&info.hardware_revision));
+        let mut software_revision = heapless::String::new();
+        let _ = write!(software_revision, "{}", info.firmware_version);
+        if !info.bootloader_version.is_empty() {
+            let _ = write!(software_revision, " bootloader {}", info.bootloader_version);
+        }
+        check(self.software_revision_set(&software_revision));
+    }
+}
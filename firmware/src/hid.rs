@@ -0,0 +1,181 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! HID over GATT ([HOGP]) keyboard, typing the text sent by the host.
+//! [HOGP]: https://www.bluetooth.com/specifications/specs/hid-over-gatt-profile-1-0/
+
+use crate::{push_event, server::Server, CONNECTION};
+use core::sync::atomic::{AtomicBool, Ordering};
+use defmt::{error, info};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration};
+use firmware_core::hid::{key_report, KeyReport, RELEASE, REPORT_MAP};
+use host_protocol::{Event, KeyboardText};
+use nrf_softdevice::ble::gatt_server::builder::ServiceBuilder;
+use nrf_softdevice::ble::gatt_server::characteristic::{Attribute, Metadata, Properties};
+use nrf_softdevice::ble::gatt_server::{notify_value, NotifyValueError, RegisterError};
+use nrf_softdevice::ble::{SecurityMode, Uuid};
+use nrf_softdevice::{raw, RawError, Softdevice};
+
+const HID_SERVICE: Uuid = Uuid::new_16(0x1812);
+const HID_INFORMATION: Uuid = Uuid::new_16(0x2A4A);
+const REPORT_MAP_UUID: Uuid = Uuid::new_16(0x2A4B);
+const HID_CONTROL_POINT: Uuid = Uuid::new_16(0x2A4C);
+const REPORT: Uuid = Uuid::new_16(0x2A4D);
+const PROTOCOL_MODE: Uuid = Uuid::new_16(0x2A4E);
+const BOOT_KEYBOARD_INPUT: Uuid = Uuid::new_16(0x2A22);
+const BOOT_KEYBOARD_OUTPUT: Uuid = Uuid::new_16(0x2A32);
+const REPORT_REFERENCE: Uuid = Uuid::new_16(0x2908);
+
+/// HID version 1.11, no country code, normally connectable
+const HID_INFO: [u8; 4] = [0x11, 0x01, 0x00, 0x02];
+
+const BOOT_PROTOCOL: u8 = 0;
+const REPORT_PROTOCOL: u8 = 1;
+
+/// Report ID 0 (the report map defines none) and type
+const INPUT_REPORT_REFERENCE: [u8; 2] = [0, 1];
+const OUTPUT_REPORT_REFERENCE: [u8; 2] = [0, 2];
+
+/// Only paired centrals get to see the keys
+const SECURITY: SecurityMode = SecurityMode::LescMitm;
+
+/// Text to type, signaled by the comms task
+pub static HID_TEXT: Signal<ThreadModeRawMutex, KeyboardText> = Signal::new();
+/// Set from `TypeText` until the text was typed
+pub static HID_TYPING: AtomicBool = AtomicBool::new(false);
+/// Signal that queued notifications were transmitted, to send the next key reports
+pub static HID_TX_COMPLETE: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+pub struct Hid {
+    input_report: u16,
+    boot_input: u16,
+    protocol_mode: u16,
+    /// The central switched to the boot protocol
+    boot: AtomicBool,
+}
+
+impl Hid {
+    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        let mut service = ServiceBuilder::new(sd, HID_SERVICE)?;
+
+        let read = Metadata::new(Properties::new().read());
+        service
+            .add_characteristic(HID_INFORMATION, Attribute::new(HID_INFO).security(SECURITY), read)?
+            .build();
+        service
+            .add_characteristic(REPORT_MAP_UUID, Attribute::new(REPORT_MAP).security(SECURITY), read)?
+            .build();
+        service
+            .add_characteristic(
+                HID_CONTROL_POINT,
+                Attribute::new([0u8]).security(SECURITY),
+                Metadata::new(Properties::new().write_without_response()),
+            )?
+            .build();
+        let protocol_mode = service
+            .add_characteristic(
+                PROTOCOL_MODE,
+                Attribute::new([REPORT_PROTOCOL]).security(SECURITY),
+                Metadata::new(Properties::new().read().write_without_response()),
+            )?
+            .build();
+
+        let notify = Metadata::with_security(Properties::new().read().notify(), SECURITY);
+        let mut input_report = service.add_characteristic(REPORT, Attribute::new(RELEASE).security(SECURITY), notify)?;
+        input_report.add_descriptor(REPORT_REFERENCE, Attribute::new(INPUT_REPORT_REFERENCE).security(SECURITY))?;
+        let input_report = input_report.build();
+        // The LEDs are ignored, the output report only exists because the report map declares it
+        let leds = Metadata::new(Properties::new().read().write().write_without_response());
+        let mut output_report = service.add_characteristic(REPORT, Attribute::new([0u8]).security(SECURITY), leds)?;
+        output_report.add_descriptor(REPORT_REFERENCE, Attribute::new(OUTPUT_REPORT_REFERENCE).security(SECURITY))?;
+        output_report.build();
+
+        let boot_input = service
+            .add_characteristic(BOOT_KEYBOARD_INPUT, Attribute::new(RELEASE).security(SECURITY), notify)?
+            .build();
+        service
+            .add_characteristic(BOOT_KEYBOARD_OUTPUT, Attribute::new([0u8]).security(SECURITY), leds)?
+            .build();
+        service.build();
+
+        Ok(Self {
+            input_report: input_report.value_handle,
+            boot_input: boot_input.value_handle,
+            protocol_mode: protocol_mode.value_handle,
+            boot: AtomicBool::new(false),
+        })
+    }
+
+    /// HOGP returns to the report protocol on every connection, whatever the last central chose
+    pub(crate) fn on_connect(&self) {
+        self.boot.store(false, Ordering::Relaxed);
+        let mut mode = [REPORT_PROTOCOL];
+        let mut value = raw::ble_gatts_value_t {
+            len: mode.len() as u16,
+            offset: 0,
+            p_value: mode.as_mut_ptr(),
+        };
+        let ret = unsafe { raw::sd_ble_gatts_value_set(raw::BLE_CONN_HANDLE_INVALID as u16, self.protocol_mode, &mut value) };
+        if ret != raw::NRF_SUCCESS {
+            error!("sd_ble_gatts_value_set error {}", ret);
+        }
+    }
+
+    pub(crate) fn on_write(&self, handle: u16, data: &[u8]) {
+        if handle == self.protocol_mode {
+            let boot = data.first() == Some(&BOOT_PROTOCOL);
+            info!("HID boot protocol: {}", boot);
+            self.boot.store(boot, Ordering::Relaxed);
+        }
+    }
+
+    /// Sends `report` on the input report of the current protocol mode, waiting while the
+    /// notification queue is full. `false` if the connection was lost.
+    async fn send(&self, report: &KeyReport) -> bool {
+        let handle = match self.boot.load(Ordering::Relaxed) {
+            true => self.boot_input,
+            false => self.input_report,
+        };
+        loop {
+            HID_TX_COMPLETE.reset();
+            {
+                let conn_lock = CONNECTION.read().await;
+                let Some(connection) = conn_lock.as_ref() else {
+                    return false;
+                };
+                match notify_value(connection, handle, report) {
+                    Ok(()) => return true,
+                    Err(NotifyValueError::Raw(RawError::Resources)) => {}
+                    Err(_) => {
+                        // Disconnected, or the central didn't enable notifications
+                        error!("Sending a key report failed");
+                        return false;
+                    }
+                }
+            }
+            // The lock is released while waiting, the timeout notices a lost connection
+            let _ = with_timeout(Duration::from_millis(100), HID_TX_COMPLETE.wait()).await;
+        }
+    }
+}
+
+/// Types the texts of `TypeText`, as fast as the connection interval lets the reports through
+pub async fn typing_task(server: &Server) -> ! {
+    loop {
+        let text = HID_TEXT.wait().await;
+        // Every key is released so that repeated characters are typed again
+        let mut typed = true;
+        for report in text.chars().filter_map(key_report) {
+            if !server.hid.send(&report).await || !server.hid.send(&RELEASE).await {
+                typed = false;
+                break;
+            }
+        }
+        HID_TYPING.store(false, Ordering::Relaxed);
+        if typed {
+            push_event(Event::TextTyped);
+        }
+    }
+}
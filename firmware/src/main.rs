@@ -4,20 +4,27 @@
 #![no_std]
 #![no_main]
 
+mod bas;
 mod comms;
+mod custom;
+mod dis;
+#[cfg(feature = "hid")]
+mod hid;
 mod nus;
+mod security;
 mod server;
 
 use consts::DEFAULT_DEVICE_NAME;
+use core::cell::RefCell;
 use core::pin::pin;
-use core::sync::atomic::{AtomicBool, AtomicI8, AtomicU8};
+use core::sync::atomic::{AtomicBool, AtomicI8, AtomicU16, AtomicU8, Ordering};
 #[cfg(feature = "debug")]
 use defmt_rtt as _;
 use embassy_sync::signal::Signal;
 // global logger
 use embassy_nrf as _;
 use embassy_sync::rwlock::RwLock;
-use host_protocol::{DeviceName, Message};
+use host_protocol::{AcceptList, AdvData, AdvMode, AdvParams, DeviceName, Event, Message, Privacy, Stats};
 // time driver
 use panic_probe as _;
 
@@ -31,12 +38,15 @@ use embassy_nrf::{
     peripherals::SPI0,
     spis::{self, Spis},
 };
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::{self, raw::ThreadModeRawMutex};
 use embassy_sync::channel::Channel;
 use embassy_sync::mutex::Mutex;
+use firmware_core::bonds::BondList;
+use firmware_core::sar_rx::SarReceiver;
 use nrf52805_pac::FICR;
 use nrf_softdevice::ble::{get_address, Connection};
-use nrf_softdevice::Softdevice;
+use nrf_softdevice::{raw, Flash, Softdevice};
+use security::{bond_storage_protected, bond_storage_task, load_bonds};
 use server::{initialize_sd, run_bluetooth, Server};
 
 bind_interrupts!(struct Irqs {
@@ -63,21 +73,86 @@ mod dummy_logging {
 /// This limits memory usage while ensuring reliable data transfer.
 pub const BT_MAX_NUM_PKT: usize = 16;
 
+/// Maximum number of events queued for the MPU.
+pub const BT_MAX_NUM_EVENTS: usize = 16;
+
 // Signal for BT state
 static BT_ENABLE: Signal<ThreadModeRawMutex, bool> = Signal::new();
 static BT_ADV_CHAN: AtomicU8 = AtomicU8::new(0);
+// PHYs requested on new connections, 0 lets the SoftDevice choose
+static BT_PREFERRED_PHY: AtomicU8 = AtomicU8::new(0);
 static BT_DATA_RX: Channel<ThreadModeRawMutex, Message, BT_MAX_NUM_PKT> = Channel::new();
 static BT_DATA_RX_OVERFLOW: AtomicBool = AtomicBool::new(false);
+// Set by the host to reassemble the received packets as SAR segments instead of queueing them
+static BT_SAR_RX_ENABLED: AtomicBool = AtomicBool::new(false);
+static BT_SAR_RX: blocking_mutex::Mutex<ThreadModeRawMutex, RefCell<SarReceiver>> =
+    blocking_mutex::Mutex::new(RefCell::new(SarReceiver::new()));
+static BT_EVENTS: Channel<ThreadModeRawMutex, Event, BT_MAX_NUM_EVENTS> = Channel::new();
+static BT_EVENTS_OVERFLOW: AtomicBool = AtomicBool::new(false);
+// Set when the host closes the connection, to report the disconnect reason
+static BT_DISCONNECT_REQUESTED: AtomicBool = AtomicBool::new(false);
+// Set when a notification didn't fit in the SoftDevice queue, to report when it drained
+static BT_TX_BLOCKED: AtomicBool = AtomicBool::new(false);
 static TX_PWR_VALUE: AtomicI8 = AtomicI8::new(0i8);
 static DEVICE_NAME: Mutex<ThreadModeRawMutex, DeviceName> = Mutex::new(DeviceName::new());
+static BT_ADV_PARAMS: Mutex<ThreadModeRawMutex, AdvParams> = Mutex::new(AdvParams::DEFAULT);
+static BT_ADV_DATA: Mutex<ThreadModeRawMutex, AdvData> = Mutex::new(AdvData::new());
+static BT_ADV_MODE: Mutex<ThreadModeRawMutex, AdvMode> = Mutex::new(AdvMode::Connectable);
+static BT_ACCEPT_LIST: Mutex<ThreadModeRawMutex, AcceptList> = Mutex::new(AcceptList::new());
+static BT_PRIVACY: Mutex<ThreadModeRawMutex, Privacy> = Mutex::new(Privacy::Off);
+// Random static identity address set by the host, `None` for the factory address
+static BT_IDENTITY_ADDRESS: Mutex<ThreadModeRawMutex, Option<[u8; 6]>> = Mutex::new(None);
 // Signal to show that advertisement needs to be restarted
 static BT_ADV_CHANGED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+// Bonded peers, locked from the SoftDevice event handlers
+static BONDS: blocking_mutex::Mutex<ThreadModeRawMutex, RefCell<BondList>> = blocking_mutex::Mutex::new(RefCell::new(BondList::new()));
+// Signal to write the bonds to flash
+static BONDS_CHANGED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+// Set at boot if the bootloader write protects the bond storage, pairing then doesn't bond
+static BOND_STORAGE_PROTECTED: AtomicBool = AtomicBool::new(false);
+// Connection waiting for the host to confirm the numeric comparison
+static PASSKEY_CONN: AtomicU16 = AtomicU16::new(raw::BLE_CONN_HANDLE_INVALID as u16);
+// Counters of the BLE link, the comms task counts the notifications and the request errors
+static BT_STATS: blocking_mutex::Mutex<ThreadModeRawMutex, RefCell<Stats>> = blocking_mutex::Mutex::new(RefCell::new(Stats {
+    rx_packets: 0,
+    rx_bytes: 0,
+    rx_dropped: 0,
+    tx_packets: 0,
+    tx_bytes: 0,
+    tx_buffer_full: 0,
+    deser_errors: 0,
+    spi_errors: 0,
+    connections: 0,
+    disconnections: 0,
+    adv_restarts: 0,
+}));
 
 static CONNECTION: RwLock<ThreadModeRawMutex, Option<Connection>> = RwLock::new(None);
 
 /// nRF -> MPU IRQ output pin
 static IRQ_OUT_PIN: Mutex<ThreadModeRawMutex, Option<Output>> = Mutex::new(None);
 
+/// Pulls the IRQ line low to get the attention of the MPU
+fn assert_irq() {
+    if let Ok(mut lock) = IRQ_OUT_PIN.try_lock() {
+        lock.as_mut().map(|pin| pin.set_low());
+    }
+}
+
+/// Updates the counters reported by `GetStats`
+fn update_stats(update: impl FnOnce(&mut Stats)) {
+    BT_STATS.lock(|stats| update(&mut stats.borrow_mut()));
+}
+
+/// Queues an event for the MPU and pulls the IRQ line low
+fn push_event(event: Event) {
+    if BT_EVENTS.try_send(event).is_err() {
+        error!("Error BT_EVENTS");
+        BT_EVENTS_OVERFLOW.store(true, Ordering::Relaxed);
+    }
+    assert_irq();
+}
+
 #[embassy_executor::task]
 async fn softdevice_task(sd: &'static Softdevice) -> ! {
     info!("SD is running");
@@ -122,8 +197,18 @@ async fn main(spawner: Spawner) {
     let sd = initialize_sd().await;
 
     let server = unwrap!(Server::new(sd), "Creating the softdevice failed");
+    let flash = Flash::take(sd);
     unwrap!(spawner.spawn(softdevice_task(sd)), "Spawning the softdevice failed");
 
+    // Load the bonds stored before the last reset, unless an older bootloader protects their pages
+    if bond_storage_protected() {
+        warn!("Bond storage write protected, bonding disabled");
+        BOND_STORAGE_PROTECTED.store(true, Ordering::Relaxed);
+    } else {
+        let next = load_bonds();
+        unwrap!(spawner.spawn(bond_storage_task(flash, next)), "Spawning the bond storage failed");
+    }
+
     // Get Bt device address
     let mut address = get_address(sd).bytes();
     address.reverse();
@@ -138,15 +223,13 @@ async fn main(spawner: Spawner) {
         device_id.to_le_bytes()
     };
     // Comm task
-    let comms = comms_task(
-        spi,
-        comms::CommsContext {
-            address,
-            device_id,
-            server: &server,
-        },
-    );
+    let comms = comms_task(spi, comms::new_comms(address, device_id, &server));
     let ble = run_bluetooth(sd, &server);
+    #[cfg(feature = "hid")]
+    let ble = async {
+        let typing = hid::typing_task(&server);
+        futures::future::select(pin!(ble), pin!(typing)).await;
+    };
     info!("Init tasks");
 
     futures::future::select(pin!(comms), pin!(ble)).await;
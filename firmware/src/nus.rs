@@ -4,17 +4,18 @@
 //! Nordic Uart Service ([NUS]) implementation.
 //! [NUS]: https://developer.nordicsemi.com/nRF_Connect_SDK/doc/latest/nrf/libraries/bluetooth_services/services/nus.html
 
-use crate::{BT_DATA_RX, BT_DATA_RX_OVERFLOW, IRQ_OUT_PIN};
+use crate::{assert_irq, push_event, update_stats, BT_DATA_RX, BT_DATA_RX_OVERFLOW, BT_SAR_RX, BT_SAR_RX_ENABLED};
+use core::sync::atomic::Ordering;
 use defmt::{debug, error, info};
-use host_protocol::Message;
+use host_protocol::{Event, Message};
 use nrf_softdevice::gatt_service;
 
 #[gatt_service(uuid = "6E400001-B5A3-F393-E0A9-E50E24DCCA9E")]
 pub struct Nus {
-    #[characteristic(uuid = "6E400002-B5A3-F393-E0A9-E50E24DCCA9E", write_without_response)]
+    #[characteristic(uuid = "6E400002-B5A3-F393-E0A9-E50E24DCCA9E", write_without_response, security = "LescMitm")]
     rx: Message,
 
-    #[characteristic(uuid = "6E400003-B5A3-F393-E0A9-E50E24DCCA9E", notify)]
+    #[characteristic(uuid = "6E400003-B5A3-F393-E0A9-E50E24DCCA9E", notify, security = "LescMitm")]
     tx: Message,
 }
 
@@ -23,17 +24,40 @@ impl Nus {
         match event {
             NusEvent::TxCccdWrite { notifications } => {
                 info!("Enable NUS: {}", notifications);
+                push_event(match notifications {
+                    true => Event::NotificationsEnabled,
+                    false => Event::NotificationsDisabled,
+                });
             }
             NusEvent::RxWrite(data) => {
                 debug!("Received: {} bytes 0x{:x}", data.len(), data);
-                if BT_DATA_RX.try_send(data).is_err() {
-                    error!("Error BT_DATA_RX");
-                    BT_DATA_RX_OVERFLOW.store(true, core::sync::atomic::Ordering::Relaxed);
+                let len = data.len() as u64;
+                let available = match BT_SAR_RX_ENABLED.load(Ordering::Relaxed) {
+                    // Segments are reassembled here, so that they never mix with the plain packets
+                    true => BT_SAR_RX.lock(|sar| sar.borrow_mut().push(&data)),
+                    false => {
+                        let was_empty = BT_DATA_RX.is_empty();
+                        BT_DATA_RX.try_send(data).ok().map(|_| was_empty)
+                    }
+                };
+                match available {
+                    Some(available) => {
+                        update_stats(|stats| {
+                            stats.rx_packets += 1;
+                            stats.rx_bytes += len;
+                        });
+                        if available {
+                            push_event(Event::DataAvailable);
+                        }
+                    }
+                    None => {
+                        error!("Dropped received packet");
+                        BT_DATA_RX_OVERFLOW.store(true, Ordering::Relaxed);
+                        update_stats(|stats| stats.rx_dropped += 1);
+                    }
                 }
                 // Notify MCU that we got something
-                if let Ok(mut lock) = IRQ_OUT_PIN.try_lock() {
-                    lock.as_mut().map(|pin| pin.set_low());
-                }
+                assert_irq();
             }
         }
     }
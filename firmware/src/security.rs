@@ -0,0 +1,188 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! LE Secure Connections pairing confirmed by the user on the MPU, and bonds persisted in the
+//! bond storage pages.
+
+use crate::server::{from_gap_addr, to_gap_addr};
+use crate::{push_event, BONDS, BONDS_CHANGED, BOND_STORAGE_PROTECTED, PASSKEY_CONN};
+use consts::BOND_STORAGE_ADDR;
+use core::sync::atomic::{AtomicBool, Ordering};
+use defmt::{error, info};
+use embedded_storage_async::nor_flash::NorFlash;
+use firmware_core::bonds::{Bond, BondList, EncodeBuffer, NextWrite};
+use host_protocol::Event;
+use nrf52805_pac::{BPROT, FICR};
+use nrf_softdevice::ble::security::{IoCapabilities, SecurityHandler};
+use nrf_softdevice::ble::{Connection, EncryptionInfo, IdentityKey, MasterId, SecurityMode};
+use nrf_softdevice::{raw, Flash};
+use raw::{ble_gap_id_key_t, ble_gap_irk_t};
+
+/// Size of each of the two flash pages holding the bonds
+const BOND_PAGE_SIZE: usize = 4096;
+
+/// Set by a new bond, which is reported once written to flash
+static BOND_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Pairs and bonds with the centrals, the user confirms the passkey on the MPU
+pub struct Bonder;
+
+pub static BONDER: Bonder = Bonder;
+
+/// Identity resolving key of the target, taken from the identity root in FICR so that bonded
+/// peers still resolve its private addresses after a reset
+pub fn device_irk() -> ble_gap_irk_t {
+    let ficr = unsafe { &*FICR::ptr() };
+    let mut irk = [0u8; 16];
+    for (bytes, ir) in irk.chunks_exact_mut(4).zip(ficr.ir.iter()) {
+        bytes.copy_from_slice(&ir.read().bits().to_le_bytes());
+    }
+    ble_gap_irk_t { irk }
+}
+
+fn bond_page_addr(page: usize) -> u32 {
+    BOND_STORAGE_ADDR + (page * BOND_PAGE_SIZE) as u32
+}
+
+/// Bootloaders from before the bond storage was reserved write protect its pages with BPROT, which
+/// stays set until the next reset
+pub fn bond_storage_protected() -> bool {
+    let config1 = unsafe { &*BPROT::ptr() }.config1.read();
+    config1.region37().is_enabled() || config1.region38().is_enabled()
+}
+
+/// Loads the bonds stored before the last reset, returns where to write the next copy
+pub fn load_bonds() -> NextWrite {
+    let pages = [0, 1].map(|page| unsafe { core::slice::from_raw_parts(bond_page_addr(page) as *const u8, BOND_PAGE_SIZE) });
+    let (bonds, next) = BondList::load(pages);
+    BONDS.lock(|cell| cell.replace(bonds));
+    next
+}
+
+/// Passkey of 6 ASCII digits as a number
+fn passkey_value(passkey: &[u8; 6]) -> u32 {
+    passkey
+        .iter()
+        .fold(0, |value, digit| value * 10 + u32::from(digit.wrapping_sub(b'0')))
+}
+
+fn identity_key(bond: &Bond) -> IdentityKey {
+    IdentityKey::from_raw(ble_gap_id_key_t {
+        id_info: ble_gap_irk_t { irk: bond.irk },
+        id_addr_info: to_gap_addr(&bond.peer),
+    })
+}
+
+/// Answers the pending numeric comparison, `false` if there is none or it already timed out
+pub fn reply_passkey(confirm: bool) -> bool {
+    let conn_handle = PASSKEY_CONN.swap(raw::BLE_CONN_HANDLE_INVALID as u16, Ordering::Relaxed);
+    if conn_handle == raw::BLE_CONN_HANDLE_INVALID as u16 {
+        return false;
+    }
+    let key_type = match confirm {
+        true => raw::BLE_GAP_AUTH_KEY_TYPE_PASSKEY,
+        false => raw::BLE_GAP_AUTH_KEY_TYPE_NONE,
+    };
+    let ret = unsafe { raw::sd_ble_gap_auth_key_reply(conn_handle, key_type as u8, core::ptr::null()) };
+    if ret != raw::NRF_SUCCESS {
+        error!("sd_ble_gap_auth_key_reply error {}", ret);
+        return false;
+    }
+    true
+}
+
+impl SecurityHandler for Bonder {
+    fn io_capabilities(&self) -> IoCapabilities {
+        // Passkeys are shown on the MPU display and confirmed with its buttons
+        IoCapabilities::DisplayYesNo
+    }
+
+    fn lesc(&self) -> bool {
+        true
+    }
+
+    fn can_bond(&self, _conn: &Connection) -> bool {
+        // Pairs without bonding if the bonds couldn't be stored
+        !BOND_STORAGE_PROTECTED.load(Ordering::Relaxed)
+    }
+
+    fn display_passkey(&self, passkey: &[u8; 6]) {
+        push_event(Event::PairingPasskey {
+            passkey: passkey_value(passkey),
+            confirm: false,
+        });
+    }
+
+    fn confirm_passkey(&self, conn: &Connection, passkey: &[u8; 6]) {
+        let Some(conn_handle) = conn.handle() else {
+            return;
+        };
+        PASSKEY_CONN.store(conn_handle, Ordering::Relaxed);
+        push_event(Event::PairingPasskey {
+            passkey: passkey_value(passkey),
+            confirm: true,
+        });
+    }
+
+    fn on_security_update(&self, _conn: &Connection, security_mode: SecurityMode) {
+        info!("Security mode {}", security_mode);
+        if security_mode == SecurityMode::LescMitm {
+            push_event(Event::Secured);
+        }
+    }
+
+    fn on_bonded(&self, _conn: &Connection, master_id: MasterId, key: EncryptionInfo, peer_id: IdentityKey) {
+        let bond = Bond {
+            peer: from_gap_addr(peer_id.addr.as_raw()),
+            irk: peer_id.irk.as_raw().irk,
+            ltk: key.ltk,
+            ltk_flags: key.flags,
+            ediv: master_id.ediv,
+            rand: master_id.rand,
+        };
+        BONDS.lock(|bonds| bonds.borrow_mut().insert(bond));
+        BOND_PENDING.store(true, Ordering::Relaxed);
+        BONDS_CHANGED.signal(());
+    }
+
+    fn get_key(&self, conn: &Connection, master_id: MasterId) -> Option<EncryptionInfo> {
+        // LESC keys all have a zero master ID, the peer is found by its address
+        let peer = conn.peer_address();
+        BONDS.lock(|bonds| {
+            bonds
+                .borrow()
+                .iter()
+                .find(|bond| bond.ediv == master_id.ediv && bond.rand == master_id.rand && identity_key(bond).is_match(peer))
+                .map(|bond| EncryptionInfo {
+                    ltk: bond.ltk,
+                    flags: bond.ltk_flags,
+                })
+        })
+    }
+}
+
+/// Writes the bonds to flash whenever they change, alternating between the two pages so that the
+/// previous copy survives a reset while writing
+#[embassy_executor::task]
+pub async fn bond_storage_task(mut flash: Flash, mut next: NextWrite) -> ! {
+    let mut buf = EncodeBuffer::new();
+    loop {
+        BONDS_CHANGED.wait().await;
+        // Bonds made while writing are written and reported with the next change
+        let bonded = BOND_PENDING.swap(false, Ordering::Relaxed);
+        let len = BONDS.lock(|bonds| bonds.borrow().encode(next.sequence, &mut buf).len());
+        let addr = bond_page_addr(next.page);
+        if flash.erase(addr, addr + BOND_PAGE_SIZE as u32).await.is_err() || flash.write(addr, &buf[..len]).await.is_err() {
+            // Forgets the changes so that ListBonds reports the stored bonds, the page is written
+            // again with the next change
+            error!("Failed to store the bonds");
+            next = load_bonds();
+            push_event(Event::BondStorageFailed);
+            continue;
+        }
+        next.advance();
+        if bonded {
+            push_event(Event::Bonded);
+        }
+    }
+}
@@ -3,46 +3,123 @@
 
 use core::pin::pin;
 
-use crate::{nus::*, BT_ADV_CHAN, BT_ADV_CHANGED, BT_ENABLE, CONNECTION, DEVICE_NAME, TX_PWR_VALUE};
+#[cfg(feature = "hid")]
+use crate::hid::{Hid, HID_TX_COMPLETE};
+use crate::{
+    bas::Bas,
+    custom,
+    dis::Dis,
+    nus::*,
+    push_event,
+    security::{device_irk, BONDER},
+    update_stats, BT_ACCEPT_LIST, BT_ADV_CHAN, BT_ADV_CHANGED, BT_ADV_DATA, BT_ADV_MODE, BT_ADV_PARAMS, BT_DISCONNECT_REQUESTED, BT_ENABLE,
+    BT_IDENTITY_ADDRESS, BT_PREFERRED_PHY, BT_PRIVACY, BT_TX_BLOCKED, CONNECTION, DEVICE_NAME, PASSKEY_CONN, TX_PWR_VALUE,
+};
 use consts::{ATT_MTU, SERVICES_LIST, SHORT_NAME};
-use defmt::{debug, error, info, unwrap};
-use host_protocol::MAX_DEVICE_NAME_LEN;
+use core::sync::atomic::Ordering;
+use defmt::{error, info, unwrap};
+use embassy_time::{with_timeout, Duration};
+use futures::future::Either;
+use host_protocol::{
+    AcceptList, AddressType, AdvData, AdvFilter, AdvMode, BtAddress, ConnParams, DisconnectReason, Event, ManufacturerData, Phy, PhyStatus,
+    Privacy, ServiceData, MAX_ACCEPT_LIST_LEN, MAX_ADV_DATA_LEN, MAX_DEVICE_NAME_LEN,
+};
 use nrf_softdevice::ble::advertisement_builder::{
-    AdvertisementBuilder, Flag, LegacyAdvertisementBuilder, LegacyAdvertisementPayload, ServiceList,
+    AdvertisementBuilder, AdvertisementDataType, Flag, LegacyAdvertisementBuilder, LegacyAdvertisementPayload, ServiceList,
 };
-use nrf_softdevice::ble::gatt_server::{notify_value, NotifyValueError};
-use nrf_softdevice::ble::peripheral;
+use nrf_softdevice::ble::gatt_server::{notify_value, DeferredReadReply, NotifyValueError, RegisterError, Service, WriteOp};
+use nrf_softdevice::ble::peripheral::{self, FilterPolicy};
 use nrf_softdevice::ble::{gatt_server, Connection, TxPower};
-use nrf_softdevice::gatt_server;
 use nrf_softdevice::{raw, Softdevice};
-use raw::ble_gap_conn_params_t;
-
-// Get connection interval with macro
-// to get 15ms just call ci_ms!(15)
-macro_rules! ci_ms {
-    ($a:expr) => {{
-        let ms = ($a as f32 * 1000.0) / 1250.0;
-        debug!("ci units: {}", ms);
-        ms as u16
-    }};
-}
+use raw::{ble_gap_addr_t, ble_gap_conn_params_t, ble_gap_id_key_t, ble_gap_irk_t, ble_gap_phys_t};
 
 static DEVICE_NAME_SEC_MODE: raw::ble_gap_conn_sec_mode_t = raw::ble_gap_conn_sec_mode_t {
     // Security Mode 0 Level 0: No write access
     _bitfield_1: raw::__BindgenBitfieldUnit::new([0x00]),
 };
 
-#[gatt_server]
 pub struct Server {
     nus: Nus,
+    pub dis: Dis,
+    pub bas: Bas,
+    #[cfg(feature = "hid")]
+    pub hid: Hid,
+}
+
+pub enum ServerEvent {
+    Nus(NusEvent),
+    /// Queued notifications were transmitted
+    TxComplete,
 }
 
 impl Server {
+    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        let server = Self {
+            nus: Nus::new(sd)?,
+            dis: Dis::new(sd)?,
+            bas: Bas::new(sd)?,
+            #[cfg(feature = "hid")]
+            hid: Hid::new(sd)?,
+        };
+        server.dis.init();
+        Ok(server)
+    }
+
     pub fn send_notify<'a>(&self, connection: &'a Connection, buffer: &[u8]) -> Result<(), NotifyValueError> {
         notify_value(connection, self.nus.get_handle(), &buffer)
     }
 }
 
+pub fn to_gap_conn_params(params: &ConnParams) -> ble_gap_conn_params_t {
+    ble_gap_conn_params_t {
+        min_conn_interval: params.min_interval,
+        max_conn_interval: params.max_interval,
+        slave_latency: params.latency,
+        conn_sup_timeout: params.supervision_timeout,
+    }
+}
+
+/// Parameters as last updated by a SoftDevice connection parameter update event
+pub fn from_gap_conn_params(params: &ble_gap_conn_params_t) -> ConnParams {
+    ConnParams {
+        min_interval: params.min_conn_interval,
+        max_interval: params.max_conn_interval,
+        latency: params.slave_latency,
+        supervision_timeout: params.conn_sup_timeout,
+    }
+}
+
+/// Starts a PHY update procedure with `phys` in both directions, an empty set lets the
+/// SoftDevice choose. The result is reported by a PHY update event.
+pub fn phy_update(connection: &Connection, phys: Phy) -> bool {
+    let Some(conn_handle) = connection.handle() else {
+        return false;
+    };
+    let gap_phys = ble_gap_phys_t {
+        tx_phys: phys.bits(),
+        rx_phys: phys.bits(),
+    };
+    let ret = unsafe { raw::sd_ble_gap_phy_update(conn_handle, &gap_phys) };
+    if ret != raw::NRF_SUCCESS {
+        error!("sd_ble_gap_phy_update error {}", ret);
+        return false;
+    }
+    true
+}
+
+/// PHYs as last updated by a SoftDevice PHY update event
+pub fn from_gap_phys(phys: &ble_gap_phys_t) -> PhyStatus {
+    PhyStatus {
+        tx: Phy::from_bits_truncate(phys.tx_phys),
+        rx: Phy::from_bits_truncate(phys.rx_phys),
+    }
+}
+
+/// Attribute table for the worst case: GAP, GATT, NUS, DIS, BAS, HID and a custom service with
+/// the most and largest notifying characteristics. That's roughly 70 attributes and 1 KiB of
+/// values kept in the table. `build.rs` reserves the RAM above the default size.
+const ATTR_TAB_SIZE: u32 = 0x900;
+
 #[allow(static_mut_refs)]
 pub async fn initialize_sd() -> &'static mut Softdevice {
     static mut DEVICE_NAME_STORAGE: [u8; MAX_DEVICE_NAME_LEN] = [0; MAX_DEVICE_NAME_LEN];
@@ -59,7 +136,7 @@ pub async fn initialize_sd() -> &'static mut Softdevice {
         }),
         conn_gatt: Some(raw::ble_gatt_conn_cfg_t { att_mtu: ATT_MTU as u16 }),
         gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t {
-            attr_tab_size: raw::BLE_GATTS_ATTR_TAB_SIZE_DEFAULT,
+            attr_tab_size: ATTR_TAB_SIZE,
         }),
         gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
             adv_set_count: 1,
@@ -82,15 +159,149 @@ pub async fn initialize_sd() -> &'static mut Softdevice {
     Softdevice::enable(&config)
 }
 
-async fn run_bluetooth_inner(sd: &'static Softdevice, server: &Server) -> ! {
-    static ADV_DATA: LegacyAdvertisementPayload = LegacyAdvertisementBuilder::new()
+pub fn to_gap_addr(address: &BtAddress) -> ble_gap_addr_t {
+    let mut addr = address.addr;
+    // The SoftDevice stores the least significant byte first
+    addr.reverse();
+    ble_gap_addr_t {
+        _bitfield_1: ble_gap_addr_t::new_bitfield_1(0, address.addr_type as u8),
+        addr,
+    }
+}
+
+pub fn from_gap_addr(address: &ble_gap_addr_t) -> BtAddress {
+    let mut addr = address.addr;
+    // The SoftDevice stores the least significant byte first
+    addr.reverse();
+    let addr_type = match address.addr_type() {
+        0 => AddressType::Public,
+        1 => AddressType::RandomStatic,
+        2 => AddressType::RandomPrivateResolvable,
+        _ => AddressType::RandomPrivateNonResolvable,
+    };
+    BtAddress { addr_type, addr }
+}
+
+/// Address the SoftDevice advertises with, `None` while not advertising
+pub fn adv_address() -> Option<BtAddress> {
+    let mut addr: ble_gap_addr_t = unsafe { core::mem::zeroed() };
+    // The only advertising set of the SoftDevice gets handle 0
+    let ret = unsafe { raw::sd_ble_gap_adv_addr_get(0, &mut addr) };
+    (ret == raw::NRF_SUCCESS).then(|| from_gap_addr(&addr))
+}
+
+/// Sets the identity address and the address privacy, which is only possible while not advertising
+fn apply_address(identity: &ble_gap_addr_t, privacy: Privacy) -> bool {
+    let ret = unsafe { raw::sd_ble_gap_addr_set(identity) };
+    if ret != raw::NRF_SUCCESS {
+        error!("sd_ble_gap_addr_set error {}", ret);
+        return false;
+    }
+    let (privacy_mode, private_addr_cycle_s) = match privacy {
+        Privacy::Off => (raw::BLE_GAP_PRIVACY_MODE_OFF, Privacy::DEFAULT_ROTATION_SECS),
+        Privacy::ResolvablePrivate { rotation_secs } => (raw::BLE_GAP_PRIVACY_MODE_DEVICE_PRIVACY, rotation_secs),
+    };
+    // Also distributed to the peers when bonding
+    let mut irk = device_irk();
+    let params = raw::ble_gap_privacy_params_t {
+        privacy_mode: privacy_mode as u8,
+        private_addr_type: raw::BLE_GAP_ADDR_TYPE_RANDOM_PRIVATE_RESOLVABLE as u8,
+        private_addr_cycle_s,
+        p_device_irk: &mut irk,
+    };
+    let ret = unsafe { raw::sd_ble_gap_privacy_set(&params) };
+    if ret != raw::NRF_SUCCESS {
+        error!("sd_ble_gap_privacy_set error {}", ret);
+        return false;
+    }
+    true
+}
+
+/// Loads the accept list into the SoftDevice, which is only possible while not advertising
+fn apply_accept_list(list: &AcceptList) -> bool {
+    let addrs: heapless::Vec<ble_gap_addr_t, MAX_ACCEPT_LIST_LEN> = list.peers.iter().map(|peer| to_gap_addr(&peer.address)).collect();
+    let id_keys: heapless::Vec<ble_gap_id_key_t, MAX_ACCEPT_LIST_LEN> = list
+        .peers
+        .iter()
+        .filter_map(|peer| {
+            peer.irk.map(|irk| ble_gap_id_key_t {
+                id_info: ble_gap_irk_t { irk },
+                id_addr_info: to_gap_addr(&peer.address),
+            })
+        })
+        .collect();
+    let addr_ptrs: heapless::Vec<*const ble_gap_addr_t, MAX_ACCEPT_LIST_LEN> = addrs.iter().map(|addr| addr as *const _).collect();
+    let id_key_ptrs: heapless::Vec<*const ble_gap_id_key_t, MAX_ACCEPT_LIST_LEN> = id_keys.iter().map(|key| key as *const _).collect();
+
+    // Empty lists are cleared with a null pointer
+    let p_addrs = if addr_ptrs.is_empty() {
+        core::ptr::null()
+    } else {
+        addr_ptrs.as_ptr()
+    };
+    let ret = unsafe { raw::sd_ble_gap_whitelist_set(p_addrs, addr_ptrs.len() as u8) };
+    if ret != raw::NRF_SUCCESS {
+        error!("sd_ble_gap_whitelist_set error {}", ret);
+        return false;
+    }
+    let p_id_keys = if id_key_ptrs.is_empty() {
+        core::ptr::null()
+    } else {
+        id_key_ptrs.as_ptr()
+    };
+    let ret = unsafe { raw::sd_ble_gap_device_identities_set(p_id_keys, core::ptr::null(), id_key_ptrs.len() as u8) };
+    if ret != raw::NRF_SUCCESS {
+        error!("sd_ble_gap_device_identities_set error {}", ret);
+        return false;
+    }
+    true
+}
+
+/// Advertising data with the host-defined AD structures, and the short name if there is room left
+fn adv_payload(ad: &AdvData) -> LegacyAdvertisementPayload {
+    let mut builder = LegacyAdvertisementBuilder::new()
         .flags(&[Flag::GeneralDiscovery, Flag::LE_Only])
-        .services_128(ServiceList::Complete, &SERVICES_LIST)
-        .short_name(SHORT_NAME)
-        .build();
+        .services_128(ServiceList::Complete, &SERVICES_LIST);
+    if let Some(appearance) = ad.appearance {
+        builder = builder.raw(AdvertisementDataType::APPEARANCE, &appearance.to_le_bytes());
+    }
+    if ad.tx_power_level {
+        builder = builder.raw(AdvertisementDataType::TXPOWER_LEVEL, &[TX_PWR_VALUE.load(Ordering::Relaxed) as u8]);
+    }
+    // 16-bit identifier followed by the data
+    let mut buf = [0u8; MAX_ADV_DATA_LEN];
+    if let Some(ManufacturerData { company_id, data }) = &ad.manufacturer_data {
+        buf[..2].copy_from_slice(&company_id.to_le_bytes());
+        buf[2..2 + data.len()].copy_from_slice(data);
+        builder = builder.raw(AdvertisementDataType::MANUFACTURER_SPECIFIC_DATA, &buf[..2 + data.len()]);
+    }
+    if let Some(ServiceData { uuid, data }) = &ad.service_data {
+        buf[..2].copy_from_slice(&uuid.to_le_bytes());
+        buf[2..2 + data.len()].copy_from_slice(data);
+        builder = builder.raw(AdvertisementDataType::SERVICE_DATA_16, &buf[..2 + data.len()]);
+    }
+    if ad.encoded_len() + 2 + SHORT_NAME.len() <= MAX_ADV_DATA_LEN {
+        builder = builder.short_name(SHORT_NAME);
+    }
+    builder.build()
+}
 
+async fn run_bluetooth_inner(sd: &'static Softdevice, server: &Server, factory_address: &ble_gap_addr_t) -> ! {
     loop {
         BT_ADV_CHANGED.reset();
+        let identity = match *BT_IDENTITY_ADDRESS.lock().await {
+            Some(addr) => to_gap_addr(&BtAddress {
+                addr_type: AddressType::RandomStatic,
+                addr,
+            }),
+            None => *factory_address,
+        };
+        if !apply_address(&identity, *BT_PRIVACY.lock().await) {
+            // Don't advertise a trackable address when privacy couldn't be enabled
+            BT_ADV_CHANGED.wait().await;
+            continue;
+        }
+        let adv_data = adv_payload(&*BT_ADV_DATA.lock().await);
         const MAX_ADVERTISEMENT_LEN: usize = MAX_DEVICE_NAME_LEN + 2;
         let scan_data = {
             let device_name = DEVICE_NAME.lock().await;
@@ -100,52 +311,110 @@ async fn run_bluetooth_inner(sd: &'static Softdevice, server: &Server) -> ! {
                 .build()
         };
 
-        let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
-            adv_data: &ADV_DATA,
-            scan_data: &scan_data,
+        let adv_params = *BT_ADV_PARAMS.lock().await;
+        let adv_mode = *BT_ADV_MODE.lock().await;
+        let filter_policy = {
+            let accept_list = BT_ACCEPT_LIST.lock().await;
+            apply_accept_list(&accept_list).then_some(match accept_list.filter {
+                AdvFilter::Any => FilterPolicy::Any,
+                AdvFilter::ScanRequests => FilterPolicy::ScanRequests,
+                AdvFilter::ConnectRequests => FilterPolicy::ConnectRequests,
+                AdvFilter::Both => FilterPolicy::Both,
+            })
         };
-        // Set advertising timer in units of 625us (about 50ms with 75 units)
-        let config = peripheral::Config {
-            interval: 75,
-            channel_mask: [0, 0, 0, 0, BT_ADV_CHAN.load(core::sync::atomic::Ordering::Relaxed)],
-            tx_power: match TX_PWR_VALUE.load(core::sync::atomic::Ordering::Relaxed) {
-                -40 => TxPower::Minus40dBm,
-                -20 => TxPower::Minus20dBm,
-                -16 => TxPower::Minus16dBm,
-                -12 => TxPower::Minus12dBm,
-                -8 => TxPower::Minus8dBm,
-                -4 => TxPower::Minus4dBm,
-                3 => TxPower::Plus3dBm,
-                4 => TxPower::Plus4dBm,
-                _ => TxPower::ZerodBm,
-            },
-            ..Default::default()
+        let Some(filter_policy) = filter_policy else {
+            // Don't let everyone in when the list couldn't be loaded
+            BT_ADV_CHANGED.wait().await;
+            continue;
         };
+        // Start advertising, with the fast interval first
+        let advertised = 'advertise: {
+            for (interval, duration) in adv_params.phases() {
+                // Advertising interval in units of 625us
+                let config = peripheral::Config {
+                    interval: interval.into(),
+                    channel_mask: [0, 0, 0, 0, BT_ADV_CHAN.load(core::sync::atomic::Ordering::Relaxed)],
+                    tx_power: match TX_PWR_VALUE.load(core::sync::atomic::Ordering::Relaxed) {
+                        -40 => TxPower::Minus40dBm,
+                        -20 => TxPower::Minus20dBm,
+                        -16 => TxPower::Minus16dBm,
+                        -12 => TxPower::Minus12dBm,
+                        -8 => TxPower::Minus8dBm,
+                        -4 => TxPower::Minus4dBm,
+                        3 => TxPower::Plus3dBm,
+                        4 => TxPower::Plus4dBm,
+                        _ => TxPower::ZerodBm,
+                    },
+                    filter_policy,
+                    ..Default::default()
+                };
 
-        let advertise_fut = peripheral::advertise_connectable(sd, adv, &config);
-        let adv_changed_fut = BT_ADV_CHANGED.wait();
-        // Start advertising
-        let mut conn = match futures::future::select(pin!(advertise_fut), adv_changed_fut).await {
-            futures::future::Either::Left((conn, _)) => unwrap!(conn, "Advertise failed"),
-            futures::future::Either::Right(((), _)) => {
-                info!("Advertisement data changed, restarting");
-                continue;
+                // Beacons advertise until the phase ends
+                let advertise_fut = async {
+                    match adv_mode {
+                        AdvMode::Connectable => {
+                            let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
+                                adv_data: &adv_data,
+                                scan_data: &scan_data,
+                            };
+                            Some(unwrap!(
+                                peripheral::advertise_pairable(sd, adv, &config, &BONDER).await,
+                                "Advertise failed"
+                            ))
+                        }
+                        AdvMode::NonConnectable => {
+                            let adv = peripheral::NonconnectableAdvertisement::NonscannableUndirected { adv_data: &adv_data };
+                            unwrap!(peripheral::advertise(sd, adv, &config).await, "Advertise failed");
+                            None
+                        }
+                        AdvMode::ScannableNonConnectable => {
+                            let adv = peripheral::NonconnectableAdvertisement::ScannableUndirected {
+                                adv_data: &adv_data,
+                                scan_data: &scan_data,
+                            };
+                            unwrap!(peripheral::advertise(sd, adv, &config).await, "Advertise failed");
+                            None
+                        }
+                    }
+                };
+                let adv_changed_fut = BT_ADV_CHANGED.wait();
+                let phase_fut = futures::future::select(pin!(advertise_fut), adv_changed_fut);
+                let phase = match duration {
+                    Some(secs) => with_timeout(Duration::from_secs(secs.into()), phase_fut).await.ok(),
+                    None => Some(phase_fut.await),
+                };
+                match phase {
+                    Some(Either::Left((Some(conn), _))) => break 'advertise Some(conn),
+                    Some(Either::Right(((), _))) => {
+                        info!("Advertisement data changed, restarting");
+                        break 'advertise None;
+                    }
+                    Some(Either::Left((None, _))) | None => info!("Advertising phase timed out"),
+                }
             }
+            // Stay quiet until the host enables or changes the advertising again
+            push_event(Event::AdvertisingTimeout);
+            BT_ADV_CHANGED.wait().await;
+            None
+        };
+        let Some(mut conn) = advertised else {
+            update_stats(|stats| stats.adv_restarts += 1);
+            continue;
         };
 
         info!("advertising done!");
 
-        let gap_conn_param = ble_gap_conn_params_t {
-            conn_sup_timeout: 500,         // 5s
-            max_conn_interval: ci_ms!(50), // 50ms
-            min_conn_interval: ci_ms!(5),  // 5ms
-            slave_latency: 0,
-        };
-        // Request connection param update
-        if conn.set_conn_params(gap_conn_param).is_err() {
+        #[cfg(feature = "hid")]
+        server.hid.on_connect();
+
+        // Request connection param update, the host can change them later on
+        if conn.set_conn_params(to_gap_conn_params(&ConnParams::DEFAULT)).is_err() {
             error!("set_conn_params error")
         }
 
+        // Every connection starts on 1M, switch to the PHY preferred by the host
+        phy_update(&conn, Phy::from_bits_truncate(BT_PREFERRED_PHY.load(Ordering::Relaxed)));
+
         // Enable to biggest LL payload size to optimize BLE throughput
         if conn
             .data_length_update(Some(&raw::ble_gap_data_length_params_t {
@@ -162,7 +431,15 @@ async fn run_bluetooth_inner(sd: &'static Softdevice, server: &Server) -> ! {
         // Start rssi capture
         conn.start_rssi();
 
+        // The NUS needs an authenticated link, ask the central to encrypt it or to pair
+        if conn.request_security().is_err() {
+            error!("request_security error");
+        }
+
+        BT_DISCONNECT_REQUESTED.store(false, Ordering::Relaxed);
         *CONNECTION.write().await = Some(conn);
+        update_stats(|stats| stats.connections += 1);
+        push_event(Event::Connected);
         {
             let conn_lock = CONNECTION.read().await;
             let Some(conn) = conn_lock.as_ref() else {
@@ -173,19 +450,40 @@ async fn run_bluetooth_inner(sd: &'static Softdevice, server: &Server) -> ! {
             info!("gatt_server run exited");
         }
         *CONNECTION.write().await = None;
+        PASSKEY_CONN.store(raw::BLE_CONN_HANDLE_INVALID as u16, Ordering::Relaxed);
+        let reason = match BT_DISCONNECT_REQUESTED.swap(false, Ordering::Relaxed) {
+            true => DisconnectReason::Host,
+            false => DisconnectReason::Remote,
+        };
+        update_stats(|stats| stats.disconnections += 1);
+        push_event(Event::Disconnected { reason });
     }
 }
 
 pub async fn run_bluetooth(sd: &'static Softdevice, server: &Server) -> ! {
+    // Kept to restore it after the host set another identity address
+    let mut factory_address: ble_gap_addr_t = unsafe { core::mem::zeroed() };
+    unsafe { raw::sd_ble_gap_addr_get(&mut factory_address) };
     loop {
         // Wait for start signal
         while !BT_ENABLE.wait().await {}
-        let run_bluetooth_fut = run_bluetooth_inner(sd, &server);
+        let run_bluetooth_fut = run_bluetooth_inner(sd, &server, &factory_address);
         let check_stopped_fut = async { while BT_ENABLE.wait().await {} };
 
         info!("Starting BLE advertisement");
         // source of this idea https://github.com/embassy-rs/nrf-softdevice/blob/master/examples/src/bin/ble_peripheral_onoff.rs
-        futures::future::select(pin!(run_bluetooth_fut), pin!(check_stopped_fut)).await;
+        if let Either::Right(_) = futures::future::select(pin!(run_bluetooth_fut), pin!(check_stopped_fut)).await {
+            // Stopped by the host, either while connected or while advertising
+            push_event(match CONNECTION.write().await.take() {
+                Some(_) => {
+                    update_stats(|stats| stats.disconnections += 1);
+                    Event::Disconnected {
+                        reason: DisconnectReason::Host,
+                    }
+                }
+                None => Event::AdvertisingStopped,
+            });
+        }
     }
 }
 
@@ -193,6 +491,34 @@ impl Server {
     fn handle_event(&self, event: ServerEvent) {
         match event {
             ServerEvent::Nus(e) => self.nus.handle(e),
+            ServerEvent::TxComplete => {
+                #[cfg(feature = "hid")]
+                HID_TX_COMPLETE.signal(());
+                if BT_TX_BLOCKED.swap(false, Ordering::Relaxed) {
+                    push_event(Event::TxComplete);
+                }
+            }
         }
     }
 }
+
+// Implemented by hand instead of with `#[gatt_server]` to get notified of transmitted notifications
+impl gatt_server::Server for Server {
+    type Event = ServerEvent;
+
+    fn on_write(&self, _conn: &Connection, handle: u16, _op: WriteOp, _offset: usize, data: &[u8]) -> Option<Self::Event> {
+        #[cfg(feature = "hid")]
+        self.hid.on_write(handle, data);
+        custom::on_write(handle);
+        self.nus.on_write(handle, data).map(ServerEvent::Nus)
+    }
+
+    fn on_deferred_read(&self, handle: u16, offset: usize, reply: DeferredReadReply) -> Option<Self::Event> {
+        custom::on_read(handle, offset, reply);
+        None
+    }
+
+    fn on_notify_tx_complete(&self, _conn: &Connection, _count: u8) -> Option<Self::Event> {
+        Some(ServerEvent::TxComplete)
+    }
+}
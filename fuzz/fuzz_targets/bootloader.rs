@@ -0,0 +1,82 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Feeds arbitrary SPI requests to the bootloader update state machine.
+//!
+//! The first byte selects the firmware in flash (bit 0: has a cosign2 header, bit 1: fully
+//! trusted) and whether erasing fails (bit 2), the rest is a sequence of length-prefixed requests.
+//! The flash covers the whole nRF52805 so writes outside the application area are caught.
+
+#![no_main]
+
+use bootloader_core::{
+    mock::{MockUicr, MockVerifier, RamFlash, APP_AREA},
+    BootCore, Outcome,
+};
+use consts::{BASE_APP_ADDR, BASE_BOOTLOADER_ADDR, BOND_STORAGE_ADDR};
+use host_protocol::{envelope::Envelope, frame, HostProtocolMessage, MAX_MSG_SIZE};
+use libfuzzer_sys::fuzz_target;
+
+/// Flash size of the nRF52805
+const FLASH_SIZE: usize = 0x30000;
+/// Content of the flash before the update, so that any change is visible
+const FILL: u8 = 0x5D;
+
+/// Bootloader messages that are not requests get no response
+fn is_ignored(req: &[u8]) -> bool {
+    let Ok(req) = frame::open_request(req) else {
+        return false;
+    };
+    matches!(postcard::from_bytes(Envelope::split(req).1), Ok(HostProtocolMessage::Bootloader(_)))
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&setup, mut data)) = data.split_first() else {
+        return;
+    };
+    let verifier = MockVerifier {
+        version: (setup & 1 != 0).then_some("4.0.0"),
+        trusted: setup & 2 != 0,
+        hash: [0x11; 32],
+    };
+    let mut boot = BootCore::new(
+        RamFlash::with_capacity(FILL, FLASH_SIZE),
+        MockUicr::default(),
+        verifier,
+        APP_AREA,
+        "3.0.3",
+    );
+    boot.flash().fail_erase = setup & 4 != 0;
+
+    let mut resp = [0u8; MAX_MSG_SIZE];
+    while let Some((&len, rest)) = data.split_first() {
+        let (req, rest) = rest.split_at(rest.len().min(len.into()));
+        data = rest;
+
+        let outcome = boot.process(req, &mut resp);
+
+        let mem = &boot.flash().mem;
+        assert!(mem[..BASE_APP_ADDR as usize].iter().all(|b| *b == FILL), "SoftDevice modified");
+        assert!(
+            mem[BOND_STORAGE_ADDR as usize..BASE_BOOTLOADER_ADDR as usize]
+                .iter()
+                .all(|b| *b == FILL),
+            "bond storage modified"
+        );
+        assert!(
+            mem[BASE_BOOTLOADER_ADDR as usize..].iter().all(|b| *b == FILL),
+            "bootloader modified"
+        );
+
+        match outcome {
+            Some(Outcome::Respond(len)) => assert!(len <= MAX_MSG_SIZE),
+            // The application takes over
+            Some(Outcome::Boot(len)) => {
+                assert!(len <= MAX_MSG_SIZE);
+                return;
+            }
+            Some(Outcome::Reset) => {}
+            None => assert!(is_ignored(req), "response does not fit in MAX_MSG_SIZE"),
+        }
+    }
+});
@@ -0,0 +1,52 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Feeds arbitrary SPI requests to the firmware request handler.
+//!
+//! The first byte selects the device state (bit 0: secret sealed, bit 1: connected),
+//! the rest is a sequence of length-prefixed requests. Every request is also received
+//! over BLE, so that the data transfer paths see arbitrary payloads too.
+
+#![no_main]
+
+use consts::APP_MTU;
+use embassy_futures::block_on;
+use firmware_core::{mock::MockComms, Outcome};
+use host_protocol::envelope::Envelope;
+use host_protocol::{batch_response_len, frame, Bluetooth, HostProtocolMessage, MAX_BATCH_MSG_SIZE, MAX_MSG_SIZE};
+use libfuzzer_sys::fuzz_target;
+
+/// Largest response allowed for `req`
+fn response_limit(req: &[u8]) -> usize {
+    let Ok(req) = frame::open_request(req) else {
+        return MAX_MSG_SIZE;
+    };
+    match postcard::from_bytes(Envelope::split(req).1) {
+        Ok(HostProtocolMessage::Bluetooth(Bluetooth::GetReceivedDataBatch { max_len })) => batch_response_len(max_len),
+        Ok(HostProtocolMessage::Bluetooth(Bluetooth::GetReceivedSarData)) => MAX_BATCH_MSG_SIZE,
+        _ => MAX_MSG_SIZE,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&setup, mut data)) = data.split_first() else {
+        return;
+    };
+    let mut comms = MockComms::mock((setup & 1 != 0).then_some([0x42; 32]));
+    if setup & 2 != 0 {
+        comms.link().connect(-40);
+    }
+
+    let mut resp = [0u8; MAX_BATCH_MSG_SIZE];
+    while let Some((&len, rest)) = data.split_first() {
+        let (req, rest) = rest.split_at(rest.len().min(len.into()));
+        data = rest;
+
+        comms.link().push_received(&req[..req.len().min(APP_MTU)]);
+        match block_on(comms.process(req, &mut resp)) {
+            Some(Outcome::Respond(len)) => assert!(len <= response_limit(req)),
+            Some(Outcome::Reset) => {}
+            None => panic!("response does not fit in MAX_BATCH_MSG_SIZE"),
+        }
+    }
+});
@@ -3,10 +3,10 @@
 
 use clap::{Parser, ValueEnum};
 use crc::{Crc, CRC_32_ISCSI};
+use host_protocol::client::Client;
+use host_protocol::transport::CobsTransport;
 use host_protocol::{Bluetooth, Bootloader, HostProtocolMessage};
-use std::{env, error::Error};
-use tokio::io::AsyncWriteExt;
-use tokio_serial::SerialPortBuilderExt;
+use std::{env, error::Error, time::Duration};
 
 const CHUNK_SIZE: usize = 256;
 
@@ -18,6 +18,7 @@ enum Command {
     Rssi,
     Address,
     FwVersion,
+    ProtocolInfo,
     SendData,
     EraseApp,
     UpdateApp,
@@ -32,6 +33,7 @@ impl From<Command> for HostProtocolMessage<'_> {
             Command::Rssi => HostProtocolMessage::Bluetooth(Bluetooth::GetStatus),
             Command::Address => HostProtocolMessage::Bluetooth(Bluetooth::GetBtAddress),
             Command::FwVersion => HostProtocolMessage::Bluetooth(Bluetooth::GetFirmwareVersion),
+            Command::ProtocolInfo => HostProtocolMessage::GetProtocolInfo,
             Command::SendData => HostProtocolMessage::Bluetooth(Bluetooth::SendData(heapless::Vec::from_iter([0x30; 200].into_iter()))),
             Command::EraseApp => HostProtocolMessage::Bootloader(Bootloader::EraseFirmware),
             Command::UpdateApp => HostProtocolMessage::Bootloader(Bootloader::EraseFirmware),
@@ -51,8 +53,7 @@ struct Args {
     cmd: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> Result<(), Box<dyn Error>> {
     pretty_env_logger::init();
 
     let args = Args::parse();
@@ -67,97 +68,78 @@ async fn main() -> Result<(), Box<dyn Error>> {
         return Ok(());
     }
 
-    if let Some(cmd) = args.cmd {
-        let mut serial = tokio_serial::new(&args.port, args.baudrate).open_native_async()?;
-        let mut buf = [0u8; 512]; // Buffer large enough for all messages
-        let msg = postcard::to_slice_cobs::<HostProtocolMessage>(&cmd.clone().into(), &mut buf).expect("Failed to serialize message");
-        println!(">>{:02x?}", msg);
-        serial.write_all(msg).await?;
-        serial.flush().await?;
+    let Some(cmd) = args.cmd else {
+        println!("Choose a command to be send.");
+        return Ok(());
+    };
+
+    let serial = tokio_serial::new(&args.port, args.baudrate)
+        .timeout(Duration::from_secs(5))
+        .open()?;
+    let mut client = Client::new(CobsTransport::new(serial));
 
-        // Wait for response
-        if let Err(_) = tokio::time::timeout(
-            std::time::Duration::from_secs(5),
-            tokio::io::AsyncReadExt::read(&mut serial, &mut buf),
-        )
-        .await
-        {
-            println!("No response from device");
+    // Send the command and wait for the response
+    let ans = match client.request(cmd.clone().into()) {
+        Ok(ans) => ans,
+        Err(e) => {
+            println!("No valid response from device: {e}");
             return Ok(());
         }
-        // Parse response
-        let cobs_buf = buf.as_mut_slice();
-        let ans: HostProtocolMessage = postcard::from_bytes_cobs(cobs_buf).unwrap();
-        match ans {
-            HostProtocolMessage::Bluetooth(Bluetooth::AckFirmwareVersion { version }) => {
-                println!("Firmware version: {version}");
-            }
-            HostProtocolMessage::Bluetooth(Bluetooth::Status(status)) => {
-                println!("Status: {:?}", status);
-            }
-            HostProtocolMessage::Bluetooth(Bluetooth::AckBtAddress { bt_address }) => {
-                println!("BT address: {:02x?}", bt_address);
-            }
-            HostProtocolMessage::Bootloader(Bootloader::AckEraseFirmware) => {
-                println!("Erased Application firmware!");
-                if cmd == Command::UpdateApp {
-                    println!("Using file in BtPackage folder to update - BT_application_signed.bin ");
-                    let update_file = include_bytes!("../../BtPackage/BT_application_signed.bin");
-                    for app_chunk in update_file.chunks(CHUNK_SIZE).enumerate() {
-                        // Prepare cobs packet to send to bootloader
-                        let block = Bootloader::WriteFirmwareBlock {
-                            block_idx: app_chunk.0,
-                            block_data: app_chunk.1,
-                        };
-                        println!("Preparing chunk idx {}", app_chunk.0);
-                        let crc = Crc::<u32>::new(&CRC_32_ISCSI);
-                        let crc_pkt = crc.checksum(app_chunk.1);
-
-                        let block_msg = postcard::to_slice_cobs(&HostProtocolMessage::Bootloader(block.clone()), &mut buf).unwrap();
-                        serial.write_all(block_msg).await?;
-                        serial.flush().await?;
-                        match tokio::time::timeout(
-                            std::time::Duration::from_millis(100),
-                            tokio::io::AsyncReadExt::read(&mut serial, &mut buf),
-                        )
-                        .await
-                        {
-                            Ok(Ok(_)) => {
-                                println!("Chunk {} sent!", app_chunk.0);
-                                let cobs_buf = buf.as_mut_slice();
-                                let msg: HostProtocolMessage = postcard::from_bytes_cobs(cobs_buf).unwrap();
+    };
+    match ans {
+        HostProtocolMessage::Bluetooth(Bluetooth::AckFirmwareVersion { version }) => {
+            println!("Firmware version: {version}");
+        }
+        HostProtocolMessage::Bluetooth(Bluetooth::Status(status)) => {
+            println!("Status: {:?}", status);
+        }
+        HostProtocolMessage::Bluetooth(Bluetooth::AckBtAddress { bt_address }) => {
+            println!("BT address: {:02x?}", bt_address);
+        }
+        HostProtocolMessage::AckProtocolInfo(info) => {
+            println!("Protocol info: {:?}", info);
+        }
+        HostProtocolMessage::Bootloader(Bootloader::AckEraseFirmware) => {
+            println!("Erased Application firmware!");
+            if cmd == Command::UpdateApp {
+                println!("Using file in BtPackage folder to update - BT_application_signed.bin ");
+                client.transport().port().set_timeout(Duration::from_millis(100))?;
+                let update_file = include_bytes!("../../BtPackage/BT_application_signed.bin");
+                for app_chunk in update_file.chunks(CHUNK_SIZE).enumerate() {
+                    let block = Bootloader::WriteFirmwareBlock {
+                        block_idx: app_chunk.0,
+                        block_data: app_chunk.1,
+                    };
+                    println!("Preparing chunk idx {}", app_chunk.0);
+                    let crc = Crc::<u32>::new(&CRC_32_ISCSI);
+                    let crc_pkt = crc.checksum(app_chunk.1);
 
-                                match msg {
-                                    HostProtocolMessage::Bootloader(Bootloader::AckWithIdxCrc { block_idx, crc }) => {
-                                        if (block_idx == app_chunk.0) && (crc == crc_pkt) {
-                                            println!("ACK packet {} with CRC {}", block_idx, crc);
-                                        } else {
-                                            println!("CRC mismatch");
-                                            break;
-                                        }
-                                    }
-                                    HostProtocolMessage::Bootloader(Bootloader::NackWithIdx { block_idx }) => {
-                                        println!("Chunk {} not acknowledged!", block_idx);
-                                        break;
-                                    }
-                                    _ => (),
-                                }
-                            }
-                            Err(_) => {
-                                println!("No response from device");
+                    match client.request(HostProtocolMessage::Bootloader(block)) {
+                        Ok(HostProtocolMessage::Bootloader(Bootloader::AckWithIdxCrc { block_idx, crc })) => {
+                            println!("Chunk {} sent!", app_chunk.0);
+                            if (block_idx == app_chunk.0) && (crc == crc_pkt) {
+                                println!("ACK packet {} with CRC {}", block_idx, crc);
+                            } else {
+                                println!("CRC mismatch");
                                 break;
                             }
-                            _ => (),
+                        }
+                        Ok(HostProtocolMessage::Bootloader(Bootloader::NackWithIdx { block_idx })) => {
+                            println!("Chunk {} not acknowledged!", block_idx);
+                            break;
+                        }
+                        Ok(_) => (),
+                        Err(e) => {
+                            println!("No response from device: {e}");
+                            break;
                         }
                     }
                 }
             }
-            _ => {
-                println!("<{ans:?}");
-            }
         }
-    } else {
-        println!("Choose a command to be send.");
+        _ => {
+            println!("<{ans:?}");
+        }
     }
 
     Ok(())
@@ -92,10 +92,41 @@ SECRET_SAVE_RESPONSE = {0: "NotAllowed", 1: "Sealed", 2: "Error"}
 
 TRUST_LEVEL = {0: "Full", 1: "Developer"}
 
-POSTCARD_ERROR = {0: "Deser", 1: "OverFull"}
+POSTCARD_ERROR = {0: "Deser", 1: "OverFull", 2: "Crc"}
 
 ADV_CHAN_BITS = {5: "C37", 6: "C38", 7: "C39"}
 
+PHY_BITS = {0: "LE_1M", 1: "LE_2M"}
+
+CHAR_PROPERTY_BITS = {1: "READ", 2: "WRITE_WITHOUT_RESPONSE", 3: "WRITE", 4: "NOTIFY"}
+
+CHAR_SECURITY = {0: "Open", 1: "Encrypted", 2: "Authenticated"}
+
+CAPABILITY_BITS = {
+    0: "BLUETOOTH", 1: "BOOTLOADER", 2: "CHALLENGE", 3: "REQUEST_ID", 4: "FRAME_CRC",
+    5: "EVENTS", 6: "RECEIVE_BATCH", 7: "SEND_BATCH",
+    8: "SAR", 9: "CONN_PARAMS", 10: "PHY", 11: "ADV_PARAMS",
+    12: "ADV_DATA", 13: "ADV_MODE", 14: "ACCEPT_LIST", 15: "BONDING",
+    16: "PRIVACY", 17: "DEVICE_INFORMATION", 18: "BATTERY", 19: "HID",
+    20: "CUSTOM_GATT", 21: "STATS",
+}
+
+EVENT = {
+    0: "Connected", 1: "Disconnected", 2: "NotificationsEnabled", 3: "NotificationsDisabled",
+    4: "DataAvailable", 5: "TxComplete", 6: "AdvertisingStopped", 7: "AdvertisingTimeout",
+    8: "PairingPasskey", 9: "Secured", 10: "Bonded", 11: "TextTyped",
+    12: "CustomWritten", 13: "CustomRead", 14: "BondStorageFailed",
+}
+
+DISCONNECT_REASON = {0: "Host", 1: "Remote"}
+
+ADV_MODE = {0: "Connectable", 1: "NonConnectable", 2: "ScannableNonConnectable"}
+
+ADV_FILTER = {0: "Any", 1: "ScanRequests", 2: "ConnectRequests", 3: "Both"}
+
+ADDRESS_TYPE = {0: "Public", 1: "RandomStatic", 2: "RandomPrivateResolvable",
+                3: "RandomPrivateNonResolvable"}
+
 # First MISO byte during a request transaction identifies the active firmware.
 MISO_TARGET = {0x69: "Bootloader", 0x51: "Application"}
 
@@ -111,7 +142,20 @@ _BT_SIMPLE = {
     7: "GetStatus", 11: "GetReceivedData", 13: "NoReceivedData",
     14: "GetFirmwareVersion", 16: "GetBtAddress", 19: "AckTxPower",
     20: "GetDeviceId", 22: "Disconnect", 23: "AckDisconnect",
-    25: "AckSetDeviceName",
+    25: "AckSetDeviceName", 28: "GetEvents", 36: "GetReceivedSarData",
+    39: "AckSetConnParams", 40: "NackSetConnParams", 41: "GetConnParams",
+    44: "AckSetPreferredPhy", 45: "NackSetPreferredPhy", 46: "GetPhy",
+    49: "AckSetAdvParams", 50: "NackSetAdvParams",
+    52: "AckSetAdvData", 53: "NackSetAdvData", 55: "AckSetAdvMode",
+    57: "AckSetAcceptList", 58: "NackSetAcceptList", 59: "GetAcceptList",
+    62: "AckConfirmPasskey", 63: "NackConfirmPasskey", 64: "ListBonds",
+    67: "AckDeleteBond", 68: "NackDeleteBond", 69: "ClearBonds", 70: "AckClearBonds",
+    72: "AckSetPrivacy", 73: "NackSetPrivacy", 75: "AckSetIdentityAddress",
+    76: "NackSetIdentityAddress", 77: "GetAddressOnAir", 80: "AckSetDeviceInformation",
+    82: "AckSetBatteryLevel", 83: "NackSetBatteryLevel", 85: "AckTypeText", 86: "NackTypeText",
+    88: "AckRegisterCustomService", 89: "NackRegisterCustomService", 93: "AckReplyCustomRead",
+    94: "NackReplyCustomRead", 96: "AckNotifyCustom", 97: "NackNotifyCustom",
+    101: "AckSetSarRx",
 }
 
 # Bootloader variants with no payload — discriminant -> name
@@ -128,6 +172,93 @@ def _fmt_adv_chan(byte):
     return " | ".join(parts) if parts else f"0x{byte:02X}"
 
 
+def _fmt_phy(byte):
+    parts = [name for bit, name in PHY_BITS.items() if byte & (1 << bit)]
+    return " | ".join(parts) if parts else "Auto"
+
+
+def _fmt_char_properties(byte):
+    parts = [name for bit, name in CHAR_PROPERTY_BITS.items() if byte & (1 << bit)]
+    return " | ".join(parts) if parts else f"0x{byte:02X}"
+
+
+def _fmt_uuid128(uuid):
+    h = uuid.hex().upper()
+    return f"{h[:8]}-{h[8:12]}-{h[12:16]}-{h[16:20]}-{h[20:]}"
+
+
+def _fmt_capabilities(bits):
+    parts = [name for bit, name in CAPABILITY_BITS.items() if bits & (1 << bit)]
+    return " | ".join(parts) if parts else f"0x{bits:X}"
+
+
+def _read_packet_sizes(data, pos):
+    """Read packets encoded back to back in a byte slice, returns their sizes."""
+    length, pos = read_vec_len(data, pos)
+    packets, pos = read_bytes(data, pos, length)
+    sizes = []
+    i = 0
+    while i < len(packets):
+        size, i = read_varint(packets, i)
+        sizes.append(f"{size}B")
+        i += size
+    return ", ".join(sizes), pos
+
+
+def _read_conn_params(data, pos):
+    """Read a ConnParams struct, returns it formatted in ms."""
+    min_interval, pos = read_varint(data, pos)
+    max_interval, pos = read_varint(data, pos)
+    latency, pos = read_varint(data, pos)
+    timeout, pos = read_varint(data, pos)
+    text = (f"{min_interval * 1.25}-{max_interval * 1.25}ms, latency={latency}, "
+            f"timeout={timeout * 10}ms")
+    return text, pos
+
+
+def _read_bt_address(data, pos):
+    """Read a BtAddress struct, returns it as type and colon separated hex."""
+    addr_type, pos = read_varint(data, pos)
+    addr, pos = read_bytes(data, pos, 6)
+    name = ADDRESS_TYPE.get(addr_type, f"?{addr_type}")
+    return f"{name} {addr.hex(':')}", pos
+
+
+def _read_accept_list(data, pos):
+    """Read an AcceptList struct, returns the filter policy and peers."""
+    policy, pos = read_varint(data, pos)
+    count, pos = read_vec_len(data, pos)
+    peers = []
+    for _ in range(count):
+        address, pos = _read_bt_address(data, pos)
+        has_irk, pos = read_bool(data, pos)
+        if has_irk:
+            _, pos = read_bytes(data, pos, 16)
+            address += " +IRK"
+        peers.append(address)
+    return f"{ADV_FILTER.get(policy, f'?{policy}')}, [{', '.join(peers)}]", pos
+
+
+def _read_adv_data(data, pos):
+    """Read an AdvData struct, returns the AD structures that are set."""
+    parts = []
+    some, pos = read_bool(data, pos)
+    if some:
+        appearance, pos = read_varint(data, pos)
+        parts.append(f"appearance=0x{appearance:04X}")
+    tx_power_level, pos = read_bool(data, pos)
+    if tx_power_level:
+        parts.append("tx_power_level")
+    for name in ("manufacturer", "service"):
+        some, pos = read_bool(data, pos)
+        if some:
+            ident, pos = read_varint(data, pos)
+            length, pos = read_vec_len(data, pos)
+            _, pos = read_bytes(data, pos, length)
+            parts.append(f"{name}=0x{ident:04X}:{length}B")
+    return ", ".join(parts), pos
+
+
 def decode_bluetooth(data, pos):
     """Decode a Bluetooth sub-message starting at *pos* (after top-level discriminant 0)."""
     sub, pos = read_varint(data, pos)
@@ -198,6 +329,208 @@ def decode_bluetooth(data, pos):
         length, pos = read_vec_len(data, pos)
         return f"BT::EchoResponse({length}B)"
 
+    if sub == 29:  # Events(EventBatch)
+        count, pos = read_vec_len(data, pos)
+        events = []
+        for _ in range(count):
+            event, pos = read_varint(data, pos)
+            name = EVENT.get(event, f"?{event}")
+            if event == 1:  # Disconnected { reason }
+                reason, pos = read_varint(data, pos)
+                name += f"({DISCONNECT_REASON.get(reason, f'?{reason}')})"
+            elif event == 8:  # PairingPasskey { passkey, confirm }
+                passkey, pos = read_varint(data, pos)
+                confirm, pos = read_bool(data, pos)
+                name += f"({passkey:06}{', confirm' if confirm else ''})"
+            elif event in (12, 13):  # CustomWritten / CustomRead { index }
+                index, pos = read_u8(data, pos)
+                name += f"({index})"
+            events.append(name)
+        overflow, pos = read_bool(data, pos)
+        extra = ", overflow" if overflow else ""
+        return f"BT::Events([{', '.join(events)}]{extra})"
+
+    if sub == 30:  # GetReceivedDataBatch { max_len }
+        max_len, pos = read_varint(data, pos)
+        return f"BT::GetReceivedDataBatch(max_len={max_len})"
+
+    if sub == 31:  # ReceivedDataBatch(PacketBatch)
+        sizes, pos = _read_packet_sizes(data, pos)
+        remaining, pos = read_varint(data, pos)
+        return f"BT::ReceivedDataBatch([{sizes}], remaining={remaining})"
+
+    if sub == 32:  # SendDataBatch { packets }
+        sizes, pos = _read_packet_sizes(data, pos)
+        return f"BT::SendDataBatch([{sizes}])"
+
+    if sub == 33:  # SendDataBatchResponse { sent }
+        sent, pos = read_varint(data, pos)
+        return f"BT::SendDataBatchResponse(sent={sent})"
+
+    if sub == 34:  # SendSarData { total_len, offset, data }
+        total_len, pos = read_varint(data, pos)
+        offset, pos = read_varint(data, pos)
+        length, pos = read_vec_len(data, pos)
+        return f"BT::SendSarData({offset}..{offset + length} of {total_len}B)"
+
+    if sub == 35:  # SendSarDataResponse { sent }
+        sent, pos = read_varint(data, pos)
+        return f"BT::SendSarDataResponse(sent={sent})"
+
+    if sub == 37:  # ReceivedSarData
+        length, pos = read_vec_len(data, pos)
+        return f"BT::ReceivedSarData({length}B)"
+
+    if sub == 38:  # SetConnParams(ConnParams)
+        params, pos = _read_conn_params(data, pos)
+        return f"BT::SetConnParams({params})"
+
+    if sub == 42:  # ConnParams(Option<ConnParams>)
+        some, pos = read_bool(data, pos)
+        if not some:
+            return "BT::ConnParams(None)"
+        params, pos = _read_conn_params(data, pos)
+        return f"BT::ConnParams({params})"
+
+    if sub == 43:  # SetPreferredPhy(Phy)
+        phys, pos = read_u8(data, pos)
+        return f"BT::SetPreferredPhy({_fmt_phy(phys)})"
+
+    if sub == 47:  # PhyStatus(Option<PhyStatus>)
+        some, pos = read_bool(data, pos)
+        if not some:
+            return "BT::PhyStatus(None)"
+        tx, pos = read_u8(data, pos)
+        rx, pos = read_u8(data, pos)
+        return f"BT::PhyStatus(tx={_fmt_phy(tx)}, rx={_fmt_phy(rx)})"
+
+    if sub == 48:  # SetAdvParams(AdvParams)
+        fast_interval, pos = read_varint(data, pos)
+        fast_duration, pos = read_varint(data, pos)
+        slow_interval, pos = read_varint(data, pos)
+        timeout, pos = read_varint(data, pos)
+        return (f"BT::SetAdvParams({fast_interval * 0.625}ms for {fast_duration}s, "
+                f"then {slow_interval * 0.625}ms, timeout={timeout}s)")
+
+    if sub == 51:  # SetAdvData(AdvData)
+        ad, pos = _read_adv_data(data, pos)
+        return f"BT::SetAdvData({ad})"
+
+    if sub == 54:  # SetAdvMode(AdvMode)
+        mode, pos = read_varint(data, pos)
+        return f"BT::SetAdvMode({ADV_MODE.get(mode, f'?{mode}')})"
+
+    if sub == 56:  # SetAcceptList(AcceptList)
+        accept_list, pos = _read_accept_list(data, pos)
+        return f"BT::SetAcceptList({accept_list})"
+
+    if sub == 60:  # AcceptList(AcceptList)
+        accept_list, pos = _read_accept_list(data, pos)
+        return f"BT::AcceptList({accept_list})"
+
+    if sub == 61:  # ConfirmPasskey(bool)
+        confirm, pos = read_bool(data, pos)
+        return f"BT::ConfirmPasskey({confirm})"
+
+    if sub == 65:  # Bonds(Vec<BtAddress>)
+        count, pos = read_vec_len(data, pos)
+        peers = []
+        for _ in range(count):
+            address, pos = _read_bt_address(data, pos)
+            peers.append(address)
+        return f"BT::Bonds([{', '.join(peers)}])"
+
+    if sub == 66:  # DeleteBond(BtAddress)
+        address, pos = _read_bt_address(data, pos)
+        return f"BT::DeleteBond({address})"
+
+    if sub == 71:  # SetPrivacy(Privacy)
+        privacy, pos = read_varint(data, pos)
+        if privacy == 0:
+            return "BT::SetPrivacy(Off)"
+        rotation_secs, pos = read_varint(data, pos)
+        return f"BT::SetPrivacy(ResolvablePrivate, rotation={rotation_secs}s)"
+
+    if sub == 74:  # SetIdentityAddress(Option<[u8; 6]>)
+        some, pos = read_bool(data, pos)
+        if not some:
+            return "BT::SetIdentityAddress(factory)"
+        addr, pos = read_bytes(data, pos, 6)
+        return f"BT::SetIdentityAddress({addr.hex(':')})"
+
+    if sub == 78:  # AddressOnAir(Option<BtAddress>)
+        some, pos = read_bool(data, pos)
+        if not some:
+            return "BT::AddressOnAir(None)"
+        address, pos = _read_bt_address(data, pos)
+        return f"BT::AddressOnAir({address})"
+
+    if sub == 79:  # SetDeviceInformation(DeviceInformation)
+        hardware_revision, pos = read_string(data, pos)
+        serial_number, pos = read_string(data, pos)
+        bootloader_version, pos = read_string(data, pos)
+        firmware_version, pos = read_string(data, pos)
+        return (f"BT::SetDeviceInformation(hw={hardware_revision!r}, serial={serial_number!r}, "
+                f"bootloader={bootloader_version!r}, firmware={firmware_version!r})")
+
+    if sub == 81:  # SetBatteryLevel(u8)
+        level, pos = read_u8(data, pos)
+        return f"BT::SetBatteryLevel({level}%)"
+
+    if sub == 84:  # TypeText(String)
+        text, pos = read_string(data, pos)
+        return f"BT::TypeText({text!r})"
+
+    if sub == 87:  # RegisterCustomService(CustomService)
+        uuid, pos = read_bytes(data, pos, 16)
+        count, pos = read_vec_len(data, pos)
+        characteristics = []
+        for _ in range(count):
+            char_uuid, pos = read_bytes(data, pos, 16)
+            properties, pos = read_u8(data, pos)
+            security, pos = read_varint(data, pos)
+            max_len, pos = read_u8(data, pos)
+            characteristics.append(f"{_fmt_uuid128(char_uuid)} {_fmt_char_properties(properties)} "
+                                   f"{CHAR_SECURITY.get(security, f'?{security}')} max_len={max_len}")
+        return f"BT::RegisterCustomService({_fmt_uuid128(uuid)}, [{'; '.join(characteristics)}])"
+
+    if sub == 90:  # GetCustomValue(u8)
+        index, pos = read_u8(data, pos)
+        return f"BT::GetCustomValue({index})"
+
+    if sub == 91:  # CustomValue(Option<Vec<u8>>)
+        some, pos = read_bool(data, pos)
+        if not some:
+            return "BT::CustomValue(None)"
+        length, pos = read_vec_len(data, pos)
+        return f"BT::CustomValue({length}B)"
+
+    if sub == 92:  # ReplyCustomRead(Vec<u8>)
+        length, pos = read_vec_len(data, pos)
+        return f"BT::ReplyCustomRead({length}B)"
+
+    if sub == 95:  # NotifyCustom { index, value }
+        index, pos = read_u8(data, pos)
+        length, pos = read_vec_len(data, pos)
+        return f"BT::NotifyCustom({index}, {length}B)"
+
+    if sub == 98:  # GetStats { reset }
+        reset, pos = read_bool(data, pos)
+        return f"BT::GetStats(reset={reset})"
+
+    if sub == 99:  # Stats(Stats)
+        fields = ("rx_packets", "rx_bytes", "rx_dropped", "tx_packets", "tx_bytes", "tx_buffer_full",
+                  "deser_errors", "spi_errors", "connections", "disconnections", "adv_restarts")
+        values = []
+        for name in fields:
+            value, pos = read_varint(data, pos)
+            values.append(f"{name}={value}")
+        return f"BT::Stats({', '.join(values)})"
+
+    if sub == 100:  # SetSarRx(bool)
+        enabled, pos = read_bool(data, pos)
+        return f"BT::SetSarRx({enabled})"
+
     return f"BT::?{sub}"
 
 
@@ -265,12 +598,37 @@ def decode_bootloader(data, pos):
 # Top-level message decoder
 # ---------------------------------------------------------------------------
 
+# First byte of a message wrapped in a request ID envelope, followed by a BE u16 ID.
+ENVELOPE_TAG = 0xE1
+
+# First byte of a CRC-protected request frame: tag + BE u16 length + payload + BE u32 CRC.
+FRAME_TAG = 0xF2
+
+
+def unframe_request(data):
+    """Strip the CRC frame of a request, if any. Returns (payload, framed)."""
+    if len(data) >= 7 and data[0] == FRAME_TAG:
+        length = (data[1] << 8) | data[2]
+        return data[3:3 + length], True
+    return data, False
+
+
 def decode_message(data):
-    """Decode a postcard-encoded HostProtocolMessage.
+    """Decode a postcard-encoded HostProtocolMessage, optionally in a request ID envelope.
 
     Returns a human-readable string, or ``None`` if the data does not look
     like a valid message.
     """
+    if len(data) > 3 and data[0] == ENVELOPE_TAG:
+        msg = decode_plain_message(data[3:])
+        if msg:
+            return f"#{(data[1] << 8) | data[2]} {msg}"
+        return None
+    return decode_plain_message(data)
+
+
+def decode_plain_message(data):
+    """Decode a postcard-encoded HostProtocolMessage without envelope."""
     if not data:
         return None
     try:
@@ -299,6 +657,16 @@ def decode_message(data):
         if disc == 8:  # InappropriateMessage(State)
             state, pos = read_varint(data, pos)
             return f"InappropriateMessage({STATE.get(state, f'?{state}')})"
+        if disc == 9:
+            return "GetProtocolInfo"
+        if disc == 10:  # AckProtocolInfo(ProtocolInfo)
+            major, pos = read_u8(data, pos)
+            minor, pos = read_u8(data, pos)
+            max_msg_size, pos = read_varint(data, pos)
+            app_mtu, pos = read_varint(data, pos)
+            caps, pos = read_varint(data, pos)
+            return (f"AckProtocolInfo(v{major}.{minor}, max_msg={max_msg_size}, "
+                    f"mtu={app_mtu}, {_fmt_capabilities(caps)})")
         return None
     except (ValueError, IndexError):
         return None
@@ -354,6 +722,13 @@ class HostProtocolAnalyzer(HighLevelAnalyzer):
 
     # ---- transaction processing -------------------------------------------
 
+    def _decode_request(self):
+        payload, framed = unframe_request(bytes(self.mosi))
+        msg = decode_message(payload)
+        if msg and framed:
+            return f"[crc] {msg}"
+        return msg
+
     def _process_transaction(self):
         if not self.mosi and not self.miso:
             return None
@@ -367,13 +742,13 @@ class HostProtocolAnalyzer(HighLevelAnalyzer):
         if not miso_idle and self.miso and self.miso[0] in MISO_TARGET:
             # First byte is a known identifier — this is a request transaction.
             # (These values as a BE length prefix would imply >20 KB, far above
-            # the 270-byte MAX_MSG_SIZE, so there is no ambiguity with responses.)
+            # the 1024-byte MAX_BATCH_MSG_SIZE, so there is no ambiguity with responses.)
             target = MISO_TARGET[self.miso[0]]
             miso_idle = True
 
         if miso_idle:
             # Request transaction: MOSI carries the postcard message, MISO is idle.
-            msg = decode_message(bytes(self.mosi))
+            msg = self._decode_request()
             if msg:
                 return AnalyzerFrame("request", self.start_time, self.end_time, {
                     "message": msg,
@@ -392,7 +767,7 @@ class HostProtocolAnalyzer(HighLevelAnalyzer):
                         })
 
             # MISO was non-zero but didn't decode as a response — fall back to MOSI.
-            msg = decode_message(bytes(self.mosi))
+            msg = self._decode_request()
             if msg:
                 return AnalyzerFrame("request", self.start_time, self.end_time, {
                     "message": msg,
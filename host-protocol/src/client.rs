@@ -0,0 +1,1007 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Typed host-side client for the BLE target.
+//!
+//! [`Client`] encodes requests, exchanges them over a [`Transport`] and turns the responses into
+//! typed results. Responses that don't match the request come back as [`Error`] instead of panics.
+
+use crate::envelope::{Envelope, RequestId};
+use crate::{
+    batch_response_len, AcceptList, AdvChan, AdvData, AdvMode, AdvParams, Bluetooth, BluetoothStatus, Bonds, BtAddress, Capabilities,
+    ConnParams, CustomService, CustomValue, DeviceInformation, DeviceName, EventBatch, HostProtocolMessage, KeyboardText, Message,
+    PacketBatch, Phy, PhyStatus, PostcardError, Privacy, ProtocolInfo, SendDataResponse, State, Stats, TxPower, MAX_BATCH_MSG_SIZE,
+    MAX_BATCH_PACKETS_LEN, MAX_MSG_SIZE,
+};
+use consts::APP_MTU;
+use std::fmt;
+
+pub use crate::transport::Transport;
+
+/// Errors returned by [`Client`]
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The transport failed
+    Transport(E),
+    /// The request could not be encoded
+    Encode(postcard::Error),
+    /// The response could not be decoded
+    Decode(postcard::Error),
+    /// The target could not decode the request
+    Postcard(PostcardError),
+    /// The target can't handle the request in its current state
+    InappropriateMessage(State),
+    /// The target answered with a response that doesn't match the request
+    UnexpectedResponse(String),
+    /// The response carries a different request ID than the request
+    RequestIdMismatch { expected: RequestId, received: Option<RequestId> },
+    /// The data doesn't fit in the message
+    DataTooLong(usize),
+    /// The target refused the request
+    Rejected,
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "transport error: {e}"),
+            Self::Encode(e) => write!(f, "failed to encode request: {e}"),
+            Self::Decode(e) => write!(f, "failed to decode response: {e}"),
+            Self::Postcard(e) => write!(f, "target failed to decode request: {e:?}"),
+            Self::InappropriateMessage(state) => write!(f, "inappropriate message in state {state:?}"),
+            Self::UnexpectedResponse(msg) => write!(f, "unexpected response: {msg}"),
+            Self::RequestIdMismatch { expected, received } => {
+                write!(f, "response for request {received:?} while waiting for request {expected}")
+            }
+            Self::DataTooLong(len) => write!(f, "{len} bytes don't fit in the message"),
+            Self::Rejected => write!(f, "request rejected by target"),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for Error<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Transport(e) => Some(e),
+            Self::Encode(e) | Self::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// `SendSarData` data per request, leaving room for the lengths and the offset
+const SAR_DATA_CHUNK: usize = MAX_BATCH_PACKETS_LEN - 4;
+
+/// BLE packets returned by [`Client::received_data_batch`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedBatch {
+    pub packets: Vec<Vec<u8>>,
+    /// Packets still queued on the target
+    pub remaining: u16,
+}
+
+/// Typed client for the BLE target
+pub struct Client<T: Transport> {
+    transport: T,
+    request_ids: bool,
+    next_request_id: RequestId,
+    req_buf: [u8; MAX_BATCH_MSG_SIZE],
+    resp_buf: [u8; MAX_BATCH_MSG_SIZE],
+}
+
+impl<T: Transport> Client<T> {
+    /// Creates a client sending plain messages, as understood by every target version
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            request_ids: false,
+            next_request_id: 0,
+            req_buf: [0; MAX_BATCH_MSG_SIZE],
+            resp_buf: [0; MAX_BATCH_MSG_SIZE],
+        }
+    }
+
+    /// Wraps requests in an [`Envelope`] and checks the request ID of responses
+    pub fn with_request_ids(mut self, enabled: bool) -> Self {
+        self.request_ids = enabled;
+        self
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    /// Sends a request and returns the raw response.
+    ///
+    /// `PostcardError` and `InappropriateMessage` responses are turned into errors, once the
+    /// request ID matched. A target that couldn't read the request ID answers without one.
+    pub fn request(&mut self, message: HostProtocolMessage) -> Result<HostProtocolMessage<'_>, Error<T::Error>> {
+        self.request_with_len(message, MAX_MSG_SIZE)
+    }
+
+    /// Like [`request`](Self::request), for responses of up to `resp_len` bytes
+    fn request_with_len(&mut self, message: HostProtocolMessage, resp_len: usize) -> Result<HostProtocolMessage<'_>, Error<T::Error>> {
+        let request_id = self.request_ids.then(|| {
+            let id = self.next_request_id;
+            self.next_request_id = self.next_request_id.wrapping_add(1);
+            id
+        });
+        let req = Envelope::new(request_id, message)
+            .encode(&mut self.req_buf)
+            .map_err(Error::Encode)?;
+        let len = self
+            .transport
+            .exchange(req, &mut self.resp_buf[..resp_len])
+            .map_err(Error::Transport)?;
+        let resp = Envelope::decode(&self.resp_buf[..len]).map_err(Error::Decode)?;
+        // A stale error response must not be taken for the result of this request
+        if resp.request_id != request_id {
+            return Err(Error::RequestIdMismatch {
+                expected: request_id.unwrap_or_default(),
+                received: resp.request_id,
+            });
+        }
+        match resp.message {
+            HostProtocolMessage::PostcardError(e) => Err(Error::Postcard(e)),
+            HostProtocolMessage::InappropriateMessage(state) => Err(Error::InappropriateMessage(state)),
+            message => Ok(message),
+        }
+    }
+
+    /// Queries protocol version and capabilities and uses request IDs if the target supports them
+    pub fn negotiate(&mut self) -> Result<ProtocolInfo, Error<T::Error>> {
+        let info = self.protocol_info()?;
+        self.request_ids = info.capabilities.contains(Capabilities::REQUEST_ID);
+        Ok(info)
+    }
+
+    pub fn protocol_info(&mut self) -> Result<ProtocolInfo, Error<T::Error>> {
+        match self.request(HostProtocolMessage::GetProtocolInfo)? {
+            HostProtocolMessage::AckProtocolInfo(info) => Ok(info),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn state(&mut self) -> Result<State, Error<T::Error>> {
+        match self.request(HostProtocolMessage::GetState)? {
+            HostProtocolMessage::AckState(state) => Ok(state),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Runs the HMAC challenge and returns the target's answer
+    pub fn challenge(&mut self, nonce: u64) -> Result<[u8; 32], Error<T::Error>> {
+        match self.request(HostProtocolMessage::ChallengeRequest { nonce })? {
+            HostProtocolMessage::ChallengeResult { result } => Ok(result),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Turns on the BLE radio
+    pub fn enable(&mut self) -> Result<(), Error<T::Error>> {
+        self.bluetooth(Bluetooth::Enable, |resp| matches!(resp, Bluetooth::AckEnable).then_some(()))
+    }
+
+    /// Turns off the BLE radio
+    pub fn disable(&mut self) -> Result<(), Error<T::Error>> {
+        self.bluetooth(Bluetooth::Disable, |resp| matches!(resp, Bluetooth::AckDisable).then_some(()))
+    }
+
+    /// Force disconnects the current BLE connection
+    pub fn disconnect(&mut self) -> Result<(), Error<T::Error>> {
+        self.bluetooth(Bluetooth::Disconnect, |resp| matches!(resp, Bluetooth::AckDisconnect).then_some(()))
+    }
+
+    /// Disables some advertising channels, at least one has to stay enabled
+    pub fn disable_channels(&mut self, channels: AdvChan) -> Result<(), Error<T::Error>> {
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::DisableChannels(channels)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckDisableChannels) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackDisableChannels) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Sets the advertising intervals and timeout
+    pub fn set_adv_params(&mut self, params: AdvParams) -> Result<(), Error<T::Error>> {
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::SetAdvParams(params)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetAdvParams) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetAdvParams) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Sets the AD structures advertised in place of the short name
+    pub fn set_adv_data(&mut self, data: AdvData) -> Result<(), Error<T::Error>> {
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::SetAdvData(data)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetAdvData) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetAdvData) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Selects how the target advertises, from the next time advertising starts
+    pub fn set_adv_mode(&mut self, mode: AdvMode) -> Result<(), Error<T::Error>> {
+        self.bluetooth(Bluetooth::SetAdvMode(mode), |resp| {
+            matches!(resp, Bluetooth::AckSetAdvMode).then_some(())
+        })
+    }
+
+    /// Replaces the filter accept list and the advertising filter policy
+    pub fn set_accept_list(&mut self, list: AcceptList) -> Result<(), Error<T::Error>> {
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::SetAcceptList(list)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetAcceptList) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetAcceptList) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn accept_list(&mut self) -> Result<AcceptList, Error<T::Error>> {
+        self.bluetooth(Bluetooth::GetAcceptList, |resp| match resp {
+            Bluetooth::AcceptList(list) => Some(list),
+            _ => None,
+        })
+    }
+
+    /// Answers the numeric comparison of a `PairingPasskey` event
+    pub fn confirm_passkey(&mut self, confirm: bool) -> Result<(), Error<T::Error>> {
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::ConfirmPasskey(confirm)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckConfirmPasskey) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackConfirmPasskey) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Identity addresses of the bonded peers, oldest first
+    pub fn bonds(&mut self) -> Result<Bonds, Error<T::Error>> {
+        self.bluetooth(Bluetooth::ListBonds, |resp| match resp {
+            Bluetooth::Bonds(bonds) => Some(bonds),
+            _ => None,
+        })
+    }
+
+    /// Forgets the bond with `peer`
+    pub fn delete_bond(&mut self, peer: BtAddress) -> Result<(), Error<T::Error>> {
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::DeleteBond(peer)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckDeleteBond) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackDeleteBond) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Forgets all bonds
+    pub fn clear_bonds(&mut self) -> Result<(), Error<T::Error>> {
+        self.bluetooth(Bluetooth::ClearBonds, |resp| matches!(resp, Bluetooth::AckClearBonds).then_some(()))
+    }
+
+    /// Selects between the identity address and resolvable private addresses
+    pub fn set_privacy(&mut self, privacy: Privacy) -> Result<(), Error<T::Error>> {
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::SetPrivacy(privacy)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetPrivacy) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetPrivacy) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Replaces the identity address with a random static address, `None` restores the factory one
+    pub fn set_identity_address(&mut self, addr: Option<[u8; 6]>) -> Result<(), Error<T::Error>> {
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::SetIdentityAddress(addr)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetIdentityAddress) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetIdentityAddress) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Address in the advertising packets, `None` while the target doesn't advertise
+    pub fn address_on_air(&mut self) -> Result<Option<BtAddress>, Error<T::Error>> {
+        self.bluetooth(Bluetooth::GetAddressOnAir, |resp| match resp {
+            Bluetooth::AddressOnAir(address) => Some(address),
+            _ => None,
+        })
+    }
+
+    /// Requests new parameters for the current connection
+    pub fn set_conn_params(&mut self, params: ConnParams) -> Result<(), Error<T::Error>> {
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::SetConnParams(params)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetConnParams) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetConnParams) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Parameters granted by the central, `None` if not connected
+    pub fn conn_params(&mut self) -> Result<Option<ConnParams>, Error<T::Error>> {
+        self.bluetooth(Bluetooth::GetConnParams, |resp| match resp {
+            Bluetooth::ConnParams(params) => Some(params),
+            _ => None,
+        })
+    }
+
+    /// Sets the PHYs to use, an empty set lets the link layer choose
+    pub fn set_preferred_phy(&mut self, phys: Phy) -> Result<(), Error<T::Error>> {
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::SetPreferredPhy(phys)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetPreferredPhy) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetPreferredPhy) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// PHYs negotiated for the current connection, `None` if not connected
+    pub fn phy(&mut self) -> Result<Option<PhyStatus>, Error<T::Error>> {
+        self.bluetooth(Bluetooth::GetPhy, |resp| match resp {
+            Bluetooth::PhyStatus(status) => Some(status),
+            _ => None,
+        })
+    }
+
+    pub fn set_tx_power(&mut self, power: TxPower) -> Result<(), Error<T::Error>> {
+        self.bluetooth(Bluetooth::SetTxPower { power }, |resp| {
+            matches!(resp, Bluetooth::AckTxPower).then_some(())
+        })
+    }
+
+    pub fn set_device_name(&mut self, name: &str) -> Result<(), Error<T::Error>> {
+        let name = DeviceName::try_from(name).map_err(|_| Error::DataTooLong(name.len()))?;
+        self.bluetooth(Bluetooth::SetDeviceName { name }, |resp| {
+            matches!(resp, Bluetooth::AckSetDeviceName).then_some(())
+        })
+    }
+
+    /// Sets the Device Information Service strings only known to the host
+    pub fn set_device_information(&mut self, info: DeviceInformation) -> Result<(), Error<T::Error>> {
+        self.bluetooth(Bluetooth::SetDeviceInformation(info), |resp| {
+            matches!(resp, Bluetooth::AckSetDeviceInformation).then_some(())
+        })
+    }
+
+    /// Sets the Battery Service level in percent
+    pub fn set_battery_level(&mut self, level: u8) -> Result<(), Error<T::Error>> {
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::SetBatteryLevel(level)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckSetBatteryLevel) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackSetBatteryLevel) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Types `text` on the HID keyboard
+    pub fn type_text(&mut self, text: &str) -> Result<(), Error<T::Error>> {
+        let text = KeyboardText::try_from(text).map_err(|_| Error::DataTooLong(text.len()))?;
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::TypeText(text)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckTypeText) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackTypeText) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Adds the host-defined GATT service, before enabling Bluetooth for the first time
+    pub fn register_custom_service(&mut self, service: CustomService) -> Result<(), Error<T::Error>> {
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::RegisterCustomService(service)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckRegisterCustomService) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackRegisterCustomService) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Value last written by the central to the characteristic `index` of the custom service
+    pub fn custom_value(&mut self, index: u8) -> Result<Option<CustomValue>, Error<T::Error>> {
+        self.bluetooth(Bluetooth::GetCustomValue(index), |resp| match resp {
+            Bluetooth::CustomValue(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    /// Answers the pending `CustomRead` event
+    pub fn reply_custom_read(&mut self, value: &[u8]) -> Result<(), Error<T::Error>> {
+        let value = CustomValue::from_slice(value).map_err(|_| Error::DataTooLong(value.len()))?;
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::ReplyCustomRead(value)))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckReplyCustomRead) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackReplyCustomRead) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Notifies the central of a new value of the characteristic `index` of the custom service
+    pub fn notify_custom(&mut self, index: u8, value: &[u8]) -> Result<(), Error<T::Error>> {
+        let value = CustomValue::from_slice(value).map_err(|_| Error::DataTooLong(value.len()))?;
+        match self.request(HostProtocolMessage::Bluetooth(Bluetooth::NotifyCustom { index, value }))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::AckNotifyCustom) => Ok(()),
+            HostProtocolMessage::Bluetooth(Bluetooth::NackNotifyCustom) => Err(Error::Rejected),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Counters since the target reset or the last time they were read with `reset`
+    pub fn stats(&mut self, reset: bool) -> Result<Stats, Error<T::Error>> {
+        self.bluetooth(Bluetooth::GetStats { reset }, |resp| match resp {
+            Bluetooth::Stats(stats) => Some(stats),
+            _ => None,
+        })
+    }
+
+    pub fn status(&mut self) -> Result<BluetoothStatus, Error<T::Error>> {
+        self.bluetooth(Bluetooth::GetStatus, |resp| match resp {
+            Bluetooth::Status(status) => Some(status),
+            _ => None,
+        })
+    }
+
+    /// Sends one BLE packet of at most `APP_MTU` bytes
+    pub fn send_data(&mut self, data: &[u8]) -> Result<SendDataResponse, Error<T::Error>> {
+        let data = Message::from_slice(data).map_err(|_| Error::DataTooLong(data.len()))?;
+        self.bluetooth(Bluetooth::SendData(data), |resp| match resp {
+            Bluetooth::SendDataResponse(resp) => Some(resp),
+            _ => None,
+        })
+    }
+
+    /// Sends BLE packets of at most `APP_MTU` bytes as consecutive notifications.
+    ///
+    /// Returns the number of leading packets queued by the target.
+    pub fn send_data_batch(&mut self, packets: &[&[u8]]) -> Result<usize, Error<T::Error>> {
+        let mut buf = [0u8; MAX_BATCH_PACKETS_LEN];
+        let mut len = 0;
+        for packet in packets {
+            if packet.len() > APP_MTU {
+                return Err(Error::DataTooLong(packet.len()));
+            }
+            len = PacketBatch::push(&mut buf, len, packet).ok_or(Error::DataTooLong(packets.iter().map(|p| p.len()).sum()))?;
+        }
+        self.bluetooth(Bluetooth::SendDataBatch { packets: &buf[..len] }, |resp| match resp {
+            Bluetooth::SendDataBatchResponse { sent } => Some(sent.into()),
+            _ => None,
+        })
+    }
+
+    /// Sends the part of `message` starting at `offset` as [`sar`](crate::sar) segments.
+    ///
+    /// Returns the number of bytes queued by the target, call again from `offset` plus that
+    /// number until the whole message was sent.
+    pub fn send_sar_data(&mut self, message: &[u8], offset: usize) -> Result<usize, Error<T::Error>> {
+        let total_len = u16::try_from(message.len()).map_err(|_| Error::DataTooLong(message.len()))?;
+        let data = message.get(offset..).ok_or(Error::DataTooLong(offset))?;
+        let request = Bluetooth::SendSarData {
+            total_len,
+            // offset <= total_len
+            offset: offset as u16,
+            data: &data[..data.len().min(SAR_DATA_CHUNK)],
+        };
+        self.bluetooth(request, |resp| match resp {
+            Bluetooth::SendSarDataResponse { sent } => Some(sent.into()),
+            _ => None,
+        })
+    }
+
+    /// Selects whether the target reassembles the packets written by the central from
+    /// [`sar`](crate::sar) segments for [`Client::received_sar_data`], instead of queueing them
+    pub fn set_sar_rx(&mut self, enabled: bool) -> Result<(), Error<T::Error>> {
+        self.bluetooth(Bluetooth::SetSarRx(enabled), |resp| {
+            matches!(resp, Bluetooth::AckSetSarRx).then_some(())
+        })
+    }
+
+    /// Returns the message reassembled from [`sar`](crate::sar) segments, if any
+    pub fn received_sar_data(&mut self) -> Result<Option<Vec<u8>>, Error<T::Error>> {
+        let request = HostProtocolMessage::Bluetooth(Bluetooth::GetReceivedSarData);
+        match self.request_with_len(request, MAX_BATCH_MSG_SIZE)? {
+            HostProtocolMessage::Bluetooth(Bluetooth::ReceivedSarData(data)) => Ok(Some(data.to_vec())),
+            HostProtocolMessage::Bluetooth(Bluetooth::NoReceivedData) => Ok(None),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Returns the oldest received BLE packet, if any
+    pub fn received_data(&mut self) -> Result<Option<Message>, Error<T::Error>> {
+        self.bluetooth(Bluetooth::GetReceivedData, |resp| match resp {
+            Bluetooth::ReceivedData(data) => Some(Some(data)),
+            Bluetooth::NoReceivedData => Some(None),
+            _ => None,
+        })
+    }
+
+    /// Returns as many received BLE packets as fit in a response of `max_len` bytes
+    pub fn received_data_batch(&mut self, max_len: u16) -> Result<ReceivedBatch, Error<T::Error>> {
+        let request = HostProtocolMessage::Bluetooth(Bluetooth::GetReceivedDataBatch { max_len });
+        match self.request_with_len(request, batch_response_len(max_len))? {
+            HostProtocolMessage::Bluetooth(Bluetooth::ReceivedDataBatch(batch)) => Ok(ReceivedBatch {
+                packets: batch.iter().map(<[u8]>::to_vec).collect(),
+                remaining: batch.remaining,
+            }),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Drains up to `MAX_EVENTS` queued link events
+    pub fn events(&mut self) -> Result<EventBatch, Error<T::Error>> {
+        self.bluetooth(Bluetooth::GetEvents, |resp| match resp {
+            Bluetooth::Events(batch) => Some(batch),
+            _ => None,
+        })
+    }
+
+    pub fn firmware_version(&mut self) -> Result<String, Error<T::Error>> {
+        self.bluetooth(Bluetooth::GetFirmwareVersion, |resp| match resp {
+            Bluetooth::AckFirmwareVersion { version } => Some(version.to_string()),
+            _ => None,
+        })
+    }
+
+    pub fn bt_address(&mut self) -> Result<[u8; 6], Error<T::Error>> {
+        self.bluetooth(Bluetooth::GetBtAddress, |resp| match resp {
+            Bluetooth::AckBtAddress { bt_address } => Some(bt_address),
+            _ => None,
+        })
+    }
+
+    pub fn device_id(&mut self) -> Result<[u8; 8], Error<T::Error>> {
+        self.bluetooth(Bluetooth::GetDeviceId, |resp| match resp {
+            Bluetooth::AckDeviceId { device_id } => Some(device_id),
+            _ => None,
+        })
+    }
+
+    fn bluetooth<R>(&mut self, request: Bluetooth, f: impl FnOnce(Bluetooth) -> Option<R>) -> Result<R, Error<T::Error>> {
+        match self.request(HostProtocolMessage::Bluetooth(request))? {
+            HostProtocolMessage::Bluetooth(resp) => {
+                let debug = format!("{resp:?}");
+                f(resp).ok_or(Error::UnexpectedResponse(debug))
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+fn unexpected<E>(message: HostProtocolMessage) -> Error<E> {
+    Error::UnexpectedResponse(format!("{message:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Loopback;
+    use crate::{is_random_static, AddressType, AdvFilter, ConnectionStatus, Event, PacketBatch, ServiceData};
+
+    /// Answers requests with a fixed handler, like a target would
+    fn fake_target(mut f: impl FnMut(Envelope) -> Envelope<'static>) -> Loopback<impl FnMut(&[u8], &mut [u8]) -> usize> {
+        Loopback(move |request: &[u8], response: &mut [u8]| {
+            let req = Envelope::decode(request).unwrap();
+            f(req).encode(response).unwrap().len()
+        })
+    }
+
+    fn reply(req: Envelope, message: HostProtocolMessage<'static>) -> Envelope<'static> {
+        Envelope::new(req.request_id, message)
+    }
+
+    /// Answers Bluetooth requests with `f`, requests it returns `None` for are inappropriate
+    fn bluetooth_target(mut f: impl FnMut(Bluetooth) -> Option<Bluetooth<'static>>) -> Loopback<impl FnMut(&[u8], &mut [u8]) -> usize> {
+        fake_target(move |req: Envelope| {
+            let resp = match req.message {
+                HostProtocolMessage::Bluetooth(msg) => f(msg).map(HostProtocolMessage::Bluetooth),
+                _ => None,
+            };
+            Envelope::new(
+                req.request_id,
+                resp.unwrap_or(HostProtocolMessage::InappropriateMessage(State::Enabled)),
+            )
+        })
+    }
+
+    const PEER: BtAddress = BtAddress {
+        addr_type: AddressType::Public,
+        addr: [1, 2, 3, 4, 5, 6],
+    };
+
+    #[test]
+    fn enable_status_send_data() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::Enable => Some(Bluetooth::AckEnable),
+            Bluetooth::GetStatus => Some(Bluetooth::Status(BluetoothStatus {
+                connection: ConnectionStatus::Connected { rssi: -50 },
+                queue_overflow: false,
+            })),
+            Bluetooth::SendData(_) => Some(Bluetooth::SendDataResponse(SendDataResponse::Sent)),
+            Bluetooth::GetFirmwareVersion => Some(Bluetooth::AckFirmwareVersion { version: "4.0.0" }),
+            _ => None,
+        }));
+        client.enable().unwrap();
+        assert_eq!(client.status().unwrap().connection, ConnectionStatus::Connected { rssi: -50 });
+        assert_eq!(client.send_data(&[1, 2, 3]).unwrap(), SendDataResponse::Sent);
+        assert_eq!(client.firmware_version().unwrap(), "4.0.0");
+        assert!(matches!(client.disable(), Err(Error::InappropriateMessage(State::Enabled))));
+    }
+
+    #[test]
+    fn events() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::GetEvents => Some(Bluetooth::Events(EventBatch {
+                events: heapless::Vec::from_slice(&[Event::DataAvailable]).unwrap(),
+                overflow: false,
+            })),
+            _ => None,
+        }));
+        assert_eq!(client.events().unwrap().events, [Event::DataAvailable]);
+    }
+
+    #[test]
+    fn received_data_batch() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::GetReceivedDataBatch { max_len: 2048 } => Some(Bluetooth::ReceivedDataBatch(PacketBatch {
+                packets: &[2, 1, 2, 0, 1, 3],
+                remaining: 4,
+            })),
+            _ => None,
+        }));
+        let batch = client.received_data_batch(2048).unwrap();
+        assert_eq!(batch.packets, [vec![1, 2], vec![], vec![3]]);
+        assert_eq!(batch.remaining, 4);
+    }
+
+    #[test]
+    fn send_data_batch() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            // The last packet didn't fit
+            Bluetooth::SendDataBatch { packets } => Some(Bluetooth::SendDataBatchResponse {
+                sent: crate::iter_packets(packets).count() as u16 - 1,
+            }),
+            _ => None,
+        }));
+        assert_eq!(client.send_data_batch(&[&[1; 200], &[2; 200], &[3]]).unwrap(), 2);
+    }
+
+    #[test]
+    fn sar_data() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            // Room for the first 100 bytes only
+            Bluetooth::SendSarData { offset, data, .. } => Some(Bluetooth::SendSarDataResponse {
+                sent: data.len().min(100 - usize::from(offset)) as u16,
+            }),
+            Bluetooth::SetSarRx(_) => Some(Bluetooth::AckSetSarRx),
+            Bluetooth::GetReceivedSarData => Some(Bluetooth::ReceivedSarData(&[0x55; 1000])),
+            _ => None,
+        }));
+        let message = [0xAA; 2000];
+        assert_eq!(client.send_sar_data(&message, 0).unwrap(), 100);
+        assert_eq!(client.send_sar_data(&message, 60).unwrap(), 40);
+        client.set_sar_rx(true).unwrap();
+        assert_eq!(client.received_sar_data().unwrap(), Some(vec![0x55; 1000]));
+    }
+
+    #[test]
+    fn conn_params() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::SetConnParams(params) => Some(match params.is_valid() {
+                true => Bluetooth::AckSetConnParams,
+                false => Bluetooth::NackSetConnParams,
+            }),
+            Bluetooth::GetConnParams => Some(Bluetooth::ConnParams(Some(ConnParams::DEFAULT))),
+            _ => None,
+        }));
+        client.set_conn_params(ConnParams::DEFAULT).unwrap();
+        let invalid = ConnParams {
+            latency: 1000,
+            ..ConnParams::DEFAULT
+        };
+        assert!(matches!(client.set_conn_params(invalid), Err(Error::Rejected)));
+        assert_eq!(client.conn_params().unwrap(), Some(ConnParams::DEFAULT));
+    }
+
+    #[test]
+    fn adv_params() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::SetAdvParams(params) => Some(match params.is_valid() {
+                true => Bluetooth::AckSetAdvParams,
+                false => Bluetooth::NackSetAdvParams,
+            }),
+            _ => None,
+        }));
+        client.set_adv_params(AdvParams::DEFAULT).unwrap();
+        let invalid = AdvParams {
+            slow_interval: 0,
+            ..AdvParams::DEFAULT
+        };
+        assert!(matches!(client.set_adv_params(invalid), Err(Error::Rejected)));
+    }
+
+    #[test]
+    fn adv_data() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::SetAdvData(data) => Some(match data.fits() {
+                true => Bluetooth::AckSetAdvData,
+                false => Bluetooth::NackSetAdvData,
+            }),
+            _ => None,
+        }));
+        let mut data = AdvData {
+            appearance: Some(0x0080),
+            ..AdvData::new()
+        };
+        client.set_adv_data(data.clone()).unwrap();
+        data.service_data = Some(ServiceData {
+            uuid: 0x180F,
+            data: heapless::Vec::from_slice(&[100, 0, 0]).unwrap(),
+        });
+        assert!(matches!(client.set_adv_data(data), Err(Error::Rejected)));
+    }
+
+    #[test]
+    fn adv_mode() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::SetAdvMode(_) => Some(Bluetooth::AckSetAdvMode),
+            _ => None,
+        }));
+        client.set_adv_mode(AdvMode::NonConnectable).unwrap();
+    }
+
+    #[test]
+    fn accept_list() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::SetAcceptList(list) => Some(match list.is_valid() {
+                true => Bluetooth::AckSetAcceptList,
+                false => Bluetooth::NackSetAcceptList,
+            }),
+            Bluetooth::GetAcceptList => Some(Bluetooth::AcceptList(AcceptList::new())),
+            _ => None,
+        }));
+        let filter_only = AcceptList {
+            filter: AdvFilter::Both,
+            ..AcceptList::new()
+        };
+        assert!(matches!(client.set_accept_list(filter_only), Err(Error::Rejected)));
+        client.set_accept_list(AcceptList::new()).unwrap();
+        assert_eq!(client.accept_list().unwrap(), AcceptList::new());
+    }
+
+    #[test]
+    fn pairing_and_bonds() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::ConfirmPasskey(_) => Some(Bluetooth::NackConfirmPasskey),
+            Bluetooth::ListBonds => Some(Bluetooth::Bonds(heapless::Vec::from_slice(&[PEER]).unwrap())),
+            Bluetooth::DeleteBond(address) => Some(match address == PEER {
+                true => Bluetooth::AckDeleteBond,
+                false => Bluetooth::NackDeleteBond,
+            }),
+            Bluetooth::ClearBonds => Some(Bluetooth::AckClearBonds),
+            _ => None,
+        }));
+        assert!(matches!(client.confirm_passkey(true), Err(Error::Rejected)));
+        assert_eq!(client.bonds().unwrap(), [PEER]);
+        client.delete_bond(PEER).unwrap();
+        let other = BtAddress {
+            addr_type: AddressType::RandomStatic,
+            ..PEER
+        };
+        assert!(matches!(client.delete_bond(other), Err(Error::Rejected)));
+        client.clear_bonds().unwrap();
+    }
+
+    #[test]
+    fn address_privacy() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::SetPrivacy(privacy) => Some(match privacy.is_valid() {
+                true => Bluetooth::AckSetPrivacy,
+                false => Bluetooth::NackSetPrivacy,
+            }),
+            Bluetooth::SetIdentityAddress(addr) => Some(match addr.as_ref().is_none_or(is_random_static) {
+                true => Bluetooth::AckSetIdentityAddress,
+                false => Bluetooth::NackSetIdentityAddress,
+            }),
+            Bluetooth::GetAddressOnAir => Some(Bluetooth::AddressOnAir(Some(PEER))),
+            _ => None,
+        }));
+        client
+            .set_privacy(Privacy::ResolvablePrivate {
+                rotation_secs: Privacy::DEFAULT_ROTATION_SECS,
+            })
+            .unwrap();
+        assert!(matches!(
+            client.set_privacy(Privacy::ResolvablePrivate { rotation_secs: 0 }),
+            Err(Error::Rejected)
+        ));
+        client.set_identity_address(Some([0xC1, 2, 3, 4, 5, 6])).unwrap();
+        assert!(matches!(client.set_identity_address(Some([1; 6])), Err(Error::Rejected)));
+        client.set_identity_address(None).unwrap();
+        assert_eq!(client.address_on_air().unwrap(), Some(PEER));
+    }
+
+    #[test]
+    fn device_information() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::SetDeviceInformation(_) => Some(Bluetooth::AckSetDeviceInformation),
+            _ => None,
+        }));
+        client.set_device_information(DeviceInformation::default()).unwrap();
+    }
+
+    #[test]
+    fn battery_level() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::SetBatteryLevel(level) => Some(match level <= 100 {
+                true => Bluetooth::AckSetBatteryLevel,
+                false => Bluetooth::NackSetBatteryLevel,
+            }),
+            _ => None,
+        }));
+        client.set_battery_level(100).unwrap();
+        assert!(matches!(client.set_battery_level(101), Err(Error::Rejected)));
+    }
+
+    #[test]
+    fn type_text() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::TypeText(text) => Some(match text.is_ascii() {
+                true => Bluetooth::AckTypeText,
+                false => Bluetooth::NackTypeText,
+            }),
+            _ => None,
+        }));
+        client.type_text("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq").unwrap();
+        assert!(matches!(client.type_text("€"), Err(Error::Rejected)));
+        assert!(matches!(client.type_text(&"a".repeat(201)), Err(Error::DataTooLong(201))));
+    }
+
+    #[test]
+    fn custom_service() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::RegisterCustomService(service) => Some(match service.is_valid() {
+                true => Bluetooth::AckRegisterCustomService,
+                false => Bluetooth::NackRegisterCustomService,
+            }),
+            Bluetooth::GetCustomValue(index) => Some(Bluetooth::CustomValue(
+                (index == 0).then(|| CustomValue::from_slice(&[1, 2, 3]).unwrap()),
+            )),
+            Bluetooth::ReplyCustomRead(_) => Some(Bluetooth::NackReplyCustomRead),
+            Bluetooth::NotifyCustom { index, .. } => Some(match index == 0 {
+                true => Bluetooth::AckNotifyCustom,
+                false => Bluetooth::NackNotifyCustom,
+            }),
+            _ => None,
+        }));
+        let service = CustomService {
+            uuid: [0x10; 16],
+            characteristics: heapless::Vec::new(),
+        };
+        assert!(matches!(client.register_custom_service(service), Err(Error::Rejected)));
+        assert_eq!(client.custom_value(0).unwrap().as_deref(), Some(&[1, 2, 3][..]));
+        assert_eq!(client.custom_value(1).unwrap(), None);
+        assert!(matches!(client.reply_custom_read(&[1]), Err(Error::Rejected)));
+        assert!(matches!(client.reply_custom_read(&[0; 65]), Err(Error::DataTooLong(65))));
+        client.notify_custom(0, &[4, 5]).unwrap();
+        assert!(matches!(client.notify_custom(1, &[4, 5]), Err(Error::Rejected)));
+    }
+
+    #[test]
+    fn stats() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::GetStats { reset } => Some(Bluetooth::Stats(Stats {
+                connections: u32::from(!reset),
+                ..Stats::default()
+            })),
+            _ => None,
+        }));
+        assert_eq!(client.stats(false).unwrap().connections, 1);
+        assert_eq!(client.stats(true).unwrap().connections, 0);
+    }
+
+    #[test]
+    fn preferred_phy() {
+        let mut client = Client::new(bluetooth_target(|req: Bluetooth| match req {
+            Bluetooth::SetPreferredPhy(phys) => Some(match Phy::all().contains(phys) {
+                true => Bluetooth::AckSetPreferredPhy,
+                false => Bluetooth::NackSetPreferredPhy,
+            }),
+            Bluetooth::GetPhy => Some(Bluetooth::PhyStatus(Some(PhyStatus {
+                tx: Phy::LE_2M,
+                rx: Phy::LE_2M,
+            }))),
+            _ => None,
+        }));
+        client.set_preferred_phy(Phy::LE_2M).unwrap();
+        assert!(matches!(client.set_preferred_phy(Phy::from_bits_retain(4)), Err(Error::Rejected)));
+        assert_eq!(client.phy().unwrap().map(|status| status.tx), Some(Phy::LE_2M));
+    }
+
+    #[test]
+    fn errors_instead_of_panics() {
+        let mut client = Client::new(fake_target(|req: Envelope| match req.message {
+            HostProtocolMessage::ChallengeRequest { .. } => reply(req, HostProtocolMessage::PostcardError(PostcardError::Deser)),
+            _ => reply(req, HostProtocolMessage::Bluetooth(Bluetooth::AckDisable)),
+        }));
+        assert!(matches!(client.challenge(1), Err(Error::Postcard(PostcardError::Deser))));
+        assert!(matches!(client.enable(), Err(Error::UnexpectedResponse(_))));
+        assert!(matches!(client.state(), Err(Error::UnexpectedResponse(_))));
+        assert!(matches!(client.send_data(&[0; 300]), Err(Error::DataTooLong(300))));
+        assert!(matches!(client.send_data_batch(&[&[0; 300]]), Err(Error::DataTooLong(300))));
+        assert!(matches!(client.send_data_batch(&[&[0; 200][..]; 6]), Err(Error::DataTooLong(1200))));
+        assert!(matches!(client.send_sar_data(&[0; 70000], 0), Err(Error::DataTooLong(70000))));
+        assert!(matches!(client.send_sar_data(&[0; 10], 11), Err(Error::DataTooLong(11))));
+        assert!(matches!(client.received_sar_data(), Err(Error::UnexpectedResponse(_))));
+        assert!(matches!(client.disable_channels(AdvChan::all()), Err(Error::UnexpectedResponse(_))));
+        assert!(matches!(client.phy(), Err(Error::UnexpectedResponse(_))));
+    }
+
+    #[test]
+    fn largest_sar_request_fits() {
+        let data = [0xFF; SAR_DATA_CHUNK];
+        let message = HostProtocolMessage::Bluetooth(Bluetooth::SendSarData {
+            total_len: u16::MAX,
+            offset: u16::MAX,
+            data: &data,
+        });
+        let mut buf = [0u8; MAX_BATCH_MSG_SIZE];
+        crate::frame::encode_request(&Envelope::new(Some(u16::MAX), message), &mut buf).unwrap();
+    }
+
+    #[test]
+    fn negotiate_enables_request_ids() {
+        let mut seen = Vec::new();
+        let mut client = Client::new(fake_target(move |req: Envelope| {
+            seen.push(req.request_id);
+            let resp = match req.message {
+                HostProtocolMessage::GetProtocolInfo => HostProtocolMessage::AckProtocolInfo(ProtocolInfo::new(Capabilities::REQUEST_ID)),
+                _ => HostProtocolMessage::ChallengeResult {
+                    result: [seen.len() as u8; 32],
+                },
+            };
+            reply(req, resp)
+        }));
+        assert_eq!(client.negotiate().unwrap().capabilities, Capabilities::REQUEST_ID);
+        assert_eq!(client.challenge(0).unwrap(), [2; 32]);
+        assert_eq!(client.challenge(0).unwrap(), [3; 32]);
+    }
+
+    #[test]
+    fn stale_response_is_rejected() {
+        let mut client = Client::new(fake_target(|req: Envelope| {
+            let stale = req.request_id.map(|id| id.wrapping_sub(1));
+            Envelope::new(stale, HostProtocolMessage::Bluetooth(Bluetooth::AckEnable))
+        }))
+        .with_request_ids(true);
+        assert!(matches!(
+            client.enable(),
+            Err(Error::RequestIdMismatch {
+                expected: 0,
+                received: Some(u16::MAX)
+            })
+        ));
+    }
+
+    #[test]
+    fn stale_error_is_rejected() {
+        let mut client = Client::new(fake_target(|req: Envelope| {
+            let stale = req.request_id.map(|id| id.wrapping_sub(1));
+            Envelope::new(stale, HostProtocolMessage::InappropriateMessage(State::Disabled))
+        }))
+        .with_request_ids(true);
+        assert!(matches!(
+            client.enable(),
+            Err(Error::RequestIdMismatch {
+                expected: 0,
+                received: Some(u16::MAX)
+            })
+        ));
+        // Frame errors come without request ID
+        let mut client = Client::new(fake_target(|_| {
+            Envelope::new(None, HostProtocolMessage::PostcardError(PostcardError::Crc))
+        }))
+        .with_request_ids(true);
+        assert!(matches!(
+            client.state(),
+            Err(Error::RequestIdMismatch {
+                expected: 0,
+                received: None
+            })
+        ));
+    }
+
+    #[test]
+    fn plain_requests_by_default() {
+        let mut client = Client::new(fake_target(|req: Envelope| {
+            assert_eq!(req.request_id, None);
+            reply(req, HostProtocolMessage::AckState(State::Disabled))
+        }));
+        assert_eq!(client.state().unwrap(), State::Disabled);
+    }
+}
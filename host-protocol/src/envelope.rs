@@ -0,0 +1,113 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Optional request ID envelope around a [`HostProtocolMessage`].
+//!
+//! An enveloped message is prefixed with [`ENVELOPE_TAG`] followed by a big-endian `u16` request ID.
+//! The target echoes the request ID in the envelope of the matching response, so the host can detect
+//! stale or duplicated responses. Messages without envelope are answered without envelope.
+//!
+//! Postcard encodes the `HostProtocolMessage` discriminant as a varint, so the first byte of a plain
+//! message is always below `0x80` and can't be mistaken for [`ENVELOPE_TAG`].
+
+use crate::HostProtocolMessage;
+use postcard::{from_bytes, to_slice};
+
+/// First byte of an enveloped message
+pub const ENVELOPE_TAG: u8 = 0xE1;
+
+/// Size of the envelope header: tag and request ID
+pub const ENVELOPE_HEADER_SIZE: usize = 3;
+
+/// Identifier chosen by the host to correlate a response with its request
+pub type RequestId = u16;
+
+/// A [`HostProtocolMessage`] with an optional request ID
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Envelope<'a> {
+    /// Request ID, `None` for plain messages
+    pub request_id: Option<RequestId>,
+    /// The wrapped message
+    pub message: HostProtocolMessage<'a>,
+}
+
+impl<'a> Envelope<'a> {
+    pub fn new(request_id: Option<RequestId>, message: HostProtocolMessage<'a>) -> Self {
+        Self { request_id, message }
+    }
+
+    /// Splits the envelope header from `buf`.
+    ///
+    /// Returns the request ID, if any, and the postcard-encoded message bytes.
+    pub fn split(buf: &[u8]) -> (Option<RequestId>, &[u8]) {
+        match buf {
+            [ENVELOPE_TAG, hi, lo, msg @ ..] => (Some(u16::from_be_bytes([*hi, *lo])), msg),
+            _ => (None, buf),
+        }
+    }
+
+    /// Decodes an enveloped or plain message
+    pub fn decode(buf: &'a [u8]) -> postcard::Result<Self> {
+        let (request_id, msg) = Self::split(buf);
+        Ok(Self {
+            request_id,
+            message: from_bytes(msg)?,
+        })
+    }
+
+    /// Encodes the message into `buf`, with envelope header only if a request ID is set
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> postcard::Result<&'b mut [u8]> {
+        let Some(request_id) = self.request_id else {
+            return to_slice(&self.message, buf);
+        };
+        if buf.len() < ENVELOPE_HEADER_SIZE {
+            return Err(postcard::Error::SerializeBufferFull);
+        }
+        let (header, body) = buf.split_at_mut(ENVELOPE_HEADER_SIZE);
+        header[0] = ENVELOPE_TAG;
+        header[1..].copy_from_slice(&request_id.to_be_bytes());
+        let len = to_slice(&self.message, body)?.len();
+        Ok(&mut buf[..ENVELOPE_HEADER_SIZE + len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Bluetooth, SendDataResponse};
+
+    #[test]
+    fn plain_message_is_unchanged() {
+        let mut buf = [0u8; 16];
+        let msg = HostProtocolMessage::Bluetooth(Bluetooth::GetStatus);
+        let encoded = Envelope::new(None, msg.clone()).encode(&mut buf).unwrap();
+        assert_eq!(encoded, &[0, 7]);
+        assert_eq!(Envelope::decode(&[0, 7]).unwrap(), Envelope::new(None, msg));
+    }
+
+    #[test]
+    fn request_id_round_trip() {
+        let mut buf = [0u8; 16];
+        let msg = HostProtocolMessage::Bluetooth(Bluetooth::SendDataResponse(SendDataResponse::Sent));
+        let encoded = Envelope::new(Some(0x1234), msg.clone()).encode(&mut buf).unwrap();
+        assert_eq!(encoded, &[ENVELOPE_TAG, 0x12, 0x34, 0, 10, 0]);
+        assert_eq!(Envelope::decode(encoded).unwrap(), Envelope::new(Some(0x1234), msg));
+    }
+
+    #[test]
+    fn split_keeps_undecodable_payload() {
+        assert_eq!(Envelope::split(&[ENVELOPE_TAG, 0, 1, 0xFF]), (Some(1), &[0xFF][..]));
+        assert_eq!(Envelope::split(&[ENVELOPE_TAG, 0]), (None, &[ENVELOPE_TAG, 0][..]));
+        assert!(Envelope::decode(&[ENVELOPE_TAG, 0]).is_err());
+    }
+
+    #[test]
+    fn encode_buffer_too_small() {
+        let mut buf = [0u8; 4];
+        let msg = HostProtocolMessage::Bluetooth(Bluetooth::SendDataResponse(SendDataResponse::Sent));
+        assert_eq!(
+            Envelope::new(Some(1), msg).encode(&mut buf),
+            Err(postcard::Error::SerializeBufferFull)
+        );
+    }
+}
@@ -0,0 +1,298 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! CRC-protected framing of SPI transactions.
+//!
+//! Frames add a big-endian length and a big-endian CRC32 (iSCSI polynomial) to the
+//! postcard-encoded, possibly [enveloped](crate::envelope), message:
+//!
+//! - request: `FRAME_TAG | len: u16 | payload | crc32: u32`
+//! - response: `len: u16 | payload | crc32: u32`
+//!
+//! The CRC covers the length and the payload. Responses keep the legacy 2-byte length prefix,
+//! so the target only appends the CRC when the request itself was framed.
+//! Plain requests start with a postcard varint below `0x80` and are never mistaken for a frame.
+
+use crate::envelope::{Envelope, RequestId, ENVELOPE_HEADER_SIZE};
+use crate::{Bluetooth, HostProtocolMessage, PostcardError};
+use crc::{Crc, CRC_32_ISCSI};
+use postcard::to_slice;
+
+/// First byte of a framed request
+pub const FRAME_TAG: u8 = 0xF2;
+
+/// Size of the big-endian length field
+pub const LEN_SIZE: usize = 2;
+
+/// Size of the big-endian CRC32 trailer
+pub const CRC_SIZE: usize = 4;
+
+/// Bytes added by a response frame around the payload
+pub const FRAME_OVERHEAD: usize = LEN_SIZE + CRC_SIZE;
+
+/// Bytes added by a request frame around the payload
+pub const REQUEST_FRAME_OVERHEAD: usize = 1 + FRAME_OVERHEAD;
+
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+/// Returns true if `buf` starts like a framed request
+pub fn is_framed_request(buf: &[u8]) -> bool {
+    buf.first() == Some(&FRAME_TAG)
+}
+
+/// Returns the payload of a request, checking length and CRC if it is framed.
+/// Plain requests are returned unchanged.
+pub fn open_request(buf: &[u8]) -> Result<&[u8], PostcardError> {
+    match buf {
+        [FRAME_TAG, frame @ ..] => open(frame),
+        _ => Ok(buf),
+    }
+}
+
+/// Returns the payload of a `len | payload | crc32` frame after checking length and CRC.
+/// Trailing bytes after the frame, such as SPI padding, are ignored.
+pub fn open(frame: &[u8]) -> Result<&[u8], PostcardError> {
+    let Some((len, rest)) = frame.split_first_chunk::<LEN_SIZE>() else {
+        return Err(PostcardError::Deser);
+    };
+    let payload_len = u16::from_be_bytes(*len) as usize;
+    if rest.len() < payload_len + CRC_SIZE {
+        return Err(PostcardError::Deser);
+    }
+    let (payload, rest) = rest.split_at(payload_len);
+    let crc = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+    if crc != checksum(len, payload) {
+        return Err(PostcardError::Crc);
+    }
+    Ok(payload)
+}
+
+/// Completes a response whose `payload_len` bytes were serialized at `buf[LEN_SIZE..]`.
+///
+/// Writes the length prefix and, if `with_crc` is set, the CRC trailer.
+/// Returns the number of bytes to send or `None` if `buf` is too small.
+pub fn finish_response(buf: &mut [u8], payload_len: usize, with_crc: bool) -> Option<usize> {
+    let len = u16::try_from(payload_len).ok()?.to_be_bytes();
+    let frame_len = LEN_SIZE + payload_len;
+    if buf.len() < frame_len || (with_crc && buf.len() < frame_len + CRC_SIZE) {
+        return None;
+    }
+    buf[..LEN_SIZE].copy_from_slice(&len);
+    if !with_crc {
+        return Some(frame_len);
+    }
+    let crc = checksum(&len, &buf[LEN_SIZE..frame_len]);
+    buf[frame_len..frame_len + CRC_SIZE].copy_from_slice(&crc.to_be_bytes());
+    Some(frame_len + CRC_SIZE)
+}
+
+/// Offset at which [`finish_data_response`] expects the data: room for the length prefix, the
+/// envelope header, both message discriminants and the data length
+pub const DATA_RESPONSE_OFFSET: usize = LEN_SIZE + ENVELOPE_HEADER_SIZE + 2 + 2;
+
+/// Completes a `ReceivedDataBatch` or `ReceivedSarData` response whose `data_len` bytes of data were
+/// written at `buf[DATA_RESPONSE_OFFSET..]`, so that the data isn't copied through another buffer.
+///
+/// `message` is the response with empty data, it supplies the other fields.
+/// Returns the number of bytes to send or `None` if `buf` is too small or `message` carries no data.
+pub fn finish_data_response(
+    buf: &mut [u8],
+    request_id: Option<RequestId>,
+    message: HostProtocolMessage,
+    data_len: usize,
+    with_crc: bool,
+) -> Option<usize> {
+    let mut suffix = [0u8; 3];
+    let suffix = match &message {
+        HostProtocolMessage::Bluetooth(Bluetooth::ReceivedDataBatch(batch)) if batch.packets.is_empty() => {
+            to_slice(&batch.remaining, &mut suffix).ok()?
+        }
+        HostProtocolMessage::Bluetooth(Bluetooth::ReceivedSarData(&[])) => &mut suffix[..0],
+        _ => return None,
+    };
+    // The empty data is encoded as a single zero length byte between header and suffix
+    let mut empty = [0u8; DATA_RESPONSE_OFFSET + 3];
+    let empty = Envelope::new(request_id, message).encode(&mut empty).ok()?;
+    let header = &empty[..empty.len() - 1 - suffix.len()];
+    let mut len = [0u8; 3];
+    let len = to_slice(&u32::try_from(data_len).ok()?, &mut len).ok()?;
+
+    let data_start = LEN_SIZE + header.len() + len.len();
+    let data_end = data_start + data_len;
+    let payload_len = data_end + suffix.len() - LEN_SIZE;
+    if data_start > DATA_RESPONSE_OFFSET || buf.len() < DATA_RESPONSE_OFFSET + data_len || buf.len() < LEN_SIZE + payload_len {
+        return None;
+    }
+    buf.copy_within(DATA_RESPONSE_OFFSET..DATA_RESPONSE_OFFSET + data_len, data_start);
+    buf[LEN_SIZE..LEN_SIZE + header.len()].copy_from_slice(header);
+    buf[LEN_SIZE + header.len()..data_start].copy_from_slice(len);
+    buf[data_end..data_end + suffix.len()].copy_from_slice(suffix);
+    finish_response(buf, payload_len, with_crc)
+}
+
+/// Encodes a framed request into `buf` (host side)
+pub fn encode_request<'b>(envelope: &Envelope, buf: &'b mut [u8]) -> postcard::Result<&'b mut [u8]> {
+    if buf.len() < REQUEST_FRAME_OVERHEAD {
+        return Err(postcard::Error::SerializeBufferFull);
+    }
+    buf[0] = FRAME_TAG;
+    let payload_end = buf.len() - CRC_SIZE;
+    let payload_len = envelope.encode(&mut buf[1 + LEN_SIZE..payload_end])?.len();
+    let len = finish_response(&mut buf[1..], payload_len, true).ok_or(postcard::Error::SerializeBufferFull)?;
+    Ok(&mut buf[..1 + len])
+}
+
+/// Wraps an already encoded message in a request frame (host side).
+/// Returns the frame length or `None` if `buf` is too small.
+pub fn frame_request(payload: &[u8], buf: &mut [u8]) -> Option<usize> {
+    let payload_end = 1 + LEN_SIZE + payload.len();
+    if buf.len() < payload_end {
+        return None;
+    }
+    buf[0] = FRAME_TAG;
+    buf[1 + LEN_SIZE..payload_end].copy_from_slice(payload);
+    Some(1 + finish_response(&mut buf[1..], payload.len(), true)?)
+}
+
+/// Decodes a framed response (host side)
+pub fn decode_response(buf: &[u8]) -> Result<Envelope<'_>, PostcardError> {
+    Envelope::decode(open(buf)?).map_err(|_| PostcardError::Deser)
+}
+
+fn checksum(len: &[u8; LEN_SIZE], payload: &[u8]) -> u32 {
+    let mut digest = CRC.digest();
+    digest.update(len);
+    digest.update(payload);
+    digest.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, PacketBatch};
+
+    fn send_data() -> Envelope<'static> {
+        let data = Message::from_slice(&[0x30; 32]).unwrap();
+        Envelope::new(Some(7), HostProtocolMessage::Bluetooth(Bluetooth::SendData(data)))
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let mut buf = [0u8; 64];
+        let encoded = encode_request(&send_data(), &mut buf).unwrap();
+        assert_eq!(encoded[0], FRAME_TAG);
+        assert_eq!(encoded.len(), REQUEST_FRAME_OVERHEAD + 3 + 2 + 1 + 32);
+        assert!(is_framed_request(encoded));
+        let payload = open_request(encoded).unwrap();
+        assert_eq!(Envelope::decode(payload).unwrap(), send_data());
+    }
+
+    #[test]
+    fn frame_encoded_payload() {
+        let mut payload = [0u8; 64];
+        let payload = send_data().encode(&mut payload).unwrap();
+        let mut buf = [0u8; 64];
+        let len = frame_request(payload, &mut buf).unwrap();
+        assert_eq!(&buf[..len], encode_request(&send_data(), &mut [0; 64]).unwrap());
+        assert_eq!(frame_request(payload, &mut buf[..len - 1]), None);
+    }
+
+    #[test]
+    fn plain_request_passes_through() {
+        assert!(!is_framed_request(&[0, 7]));
+        assert_eq!(open_request(&[0, 7]), Ok(&[0, 7][..]));
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let mut buf = [0u8; 64];
+        let payload_len = send_data().encode(&mut buf[LEN_SIZE..]).unwrap().len();
+        let len = finish_response(&mut buf, payload_len, true).unwrap();
+        assert_eq!(len, payload_len + FRAME_OVERHEAD);
+        // padding after the frame is ignored
+        assert_eq!(decode_response(&buf[..len + 3]), Ok(send_data()));
+    }
+
+    #[test]
+    fn legacy_response_has_no_crc() {
+        let mut buf = [0u8; 8];
+        buf[2..4].copy_from_slice(&[0, 7]);
+        assert_eq!(finish_response(&mut buf, 2, false), Some(4));
+        assert_eq!(&buf[..4], &[0, 2, 0, 7]);
+    }
+
+    #[test]
+    fn flipped_bit_is_detected() {
+        let mut buf = [0u8; 64];
+        let len = encode_request(&send_data(), &mut buf).unwrap().len();
+        for i in 1..len {
+            for bit in 0..8 {
+                let mut corrupted = buf;
+                corrupted[i] ^= 1 << bit;
+                assert!(open_request(&corrupted[..len]).is_err(), "byte {i} bit {bit}");
+            }
+        }
+        let mut corrupted = buf;
+        corrupted[10] ^= 0x01;
+        assert_eq!(open_request(&corrupted[..len]), Err(PostcardError::Crc));
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut buf = [0u8; 64];
+        let len = encode_request(&send_data(), &mut buf).unwrap().len();
+        assert_eq!(open_request(&buf[..len - 1]), Err(PostcardError::Deser));
+        assert_eq!(open_request(&buf[..2]), Err(PostcardError::Deser));
+    }
+
+    #[test]
+    fn data_response_in_place() {
+        let data: [u8; 1000] = core::array::from_fn(|i| i as u8);
+        for request_id in [None, Some(0x1234)] {
+            for data_len in [0, 1, 127, 128, 1000] {
+                for with_crc in [false, true] {
+                    let data = &data[..data_len];
+                    for (message, empty) in [
+                        (
+                            Bluetooth::ReceivedDataBatch(PacketBatch {
+                                packets: data,
+                                remaining: 300,
+                            }),
+                            Bluetooth::ReceivedDataBatch(PacketBatch {
+                                packets: &[],
+                                remaining: 300,
+                            }),
+                        ),
+                        (Bluetooth::ReceivedSarData(data), Bluetooth::ReceivedSarData(&[])),
+                    ] {
+                        let envelope = Envelope::new(request_id, HostProtocolMessage::Bluetooth(message));
+                        let mut expected = [0u8; 1100];
+                        let payload_len = envelope.encode(&mut expected[LEN_SIZE..]).unwrap().len();
+                        let expected_len = finish_response(&mut expected, payload_len, with_crc).unwrap();
+
+                        let mut buf = [0xAA; 1100];
+                        buf[DATA_RESPONSE_OFFSET..][..data_len].copy_from_slice(data);
+                        let empty = HostProtocolMessage::Bluetooth(empty);
+                        let len = finish_data_response(&mut buf, request_id, empty, data_len, with_crc);
+                        assert_eq!(len, Some(expected_len));
+                        assert_eq!(&buf[..expected_len], &expected[..expected_len]);
+                    }
+                }
+            }
+        }
+        let empty = || HostProtocolMessage::Bluetooth(Bluetooth::ReceivedSarData(&[]));
+        assert_eq!(finish_data_response(&mut [0; 16], None, empty(), 8, true), None);
+        assert_eq!(
+            finish_data_response(&mut [0; 64], None, HostProtocolMessage::GetState, 8, true),
+            None
+        );
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let mut buf = [0u8; 16];
+        assert_eq!(encode_request(&send_data(), &mut buf), Err(postcard::Error::SerializeBufferFull));
+        assert_eq!(finish_response(&mut buf, 12, true), None);
+        assert_eq!(finish_response(&mut buf, 12, false), Some(14));
+    }
+}
@@ -5,20 +5,72 @@
 //! The MPU running keyOS is the host and nRF52x BLE is the target MCU.
 //! Defines message types and structures for communication between the two processors.
 
-#![no_std]
+#![cfg_attr(not(feature = "client"), no_std)]
 
 use bitflags::bitflags;
 use consts::APP_MTU;
 use heapless::{String, Vec};
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "client")]
+pub mod client;
+pub mod envelope;
+pub mod frame;
+pub mod sar;
+#[cfg(feature = "client")]
+pub mod transport;
+
 /// Maximum supported message size to be serialized or deserialized by `postcard`.
 /// Messages larger than this will be rejected.
 pub const MAX_MSG_SIZE: usize = 270;
 
+/// Maximum size of a `ReceivedDataBatch` response or `SendDataBatch` request, including framing.
+/// Only targets with [`Capabilities::RECEIVE_BATCH`] and [`Capabilities::SEND_BATCH`] exchange
+/// messages larger than [`MAX_MSG_SIZE`].
+pub const MAX_BATCH_MSG_SIZE: usize = 1024;
+
+/// Maximum size of the encoded packets of a [`PacketBatch`] or `SendDataBatch`
+pub const MAX_BATCH_PACKETS_LEN: usize = MAX_BATCH_MSG_SIZE - PacketBatch::OVERHEAD;
+
+/// Maximum size of a message reassembled by the target, so that it fits in one `ReceivedSarData`
+pub const MAX_SAR_MSG_SIZE: usize = MAX_BATCH_PACKETS_LEN;
+
 /// The maximum lenght of the full device name
 pub const MAX_DEVICE_NAME_LEN: usize = 31;
 
+/// Maximum number of events returned by a single `GetEvents` request
+pub const MAX_EVENTS: usize = 16;
+
+/// Room for the [`AdvData`] AD structures in the 31 bytes of legacy advertising data, after the
+/// flags (3 bytes) and the 128-bit NUS UUID (18 bytes)
+pub const MAX_ADV_DATA_LEN: usize = 10;
+
+/// Maximum number of peers in the filter accept list, as supported by the SoftDevice
+pub const MAX_ACCEPT_LIST_LEN: usize = 8;
+
+/// Maximum number of bonded peers stored by the target, the oldest bond is replaced when full
+pub const MAX_BONDS: usize = 4;
+
+/// Maximum length of the Device Information Service strings supplied by the host
+pub const MAX_DIS_STRING_LEN: usize = 20;
+
+/// Maximum length of a `TypeText` text
+pub const MAX_TYPE_TEXT_LEN: usize = 200;
+
+/// Maximum number of characteristics of the [`CustomService`]
+pub const MAX_CUSTOM_CHARACTERISTICS: usize = 4;
+
+/// Maximum value length of a [`CustomCharacteristic`]
+pub const MAX_CUSTOM_VALUE_LEN: usize = 64;
+
+/// Major version of the host protocol.
+/// Bumped on changes that break compatibility with existing hosts or targets.
+pub const PROTOCOL_VERSION_MAJOR: u8 = 1;
+
+/// Minor version of the host protocol.
+/// Bumped when new messages or capabilities are appended in a backward compatible way.
+pub const PROTOCOL_VERSION_MINOR: u8 = 21;
+
 bitflags! {
     #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
     pub struct AdvChan: u8 {
@@ -28,8 +80,82 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Optional protocol features understood by the target.
+    ///
+    /// Make sure to only append new flags, to keep backward compatibility
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+    pub struct Capabilities: u32 {
+        /// `Bluetooth` messages for controlling the BLE radio and data transfer
+        const BLUETOOTH = 1 << 0;
+        /// `Bootloader` messages for firmware updates
+        const BOOTLOADER = 1 << 1;
+        /// `ChallengeRequest` HMAC authentication
+        const CHALLENGE = 1 << 2;
+        /// Request IDs in an [`envelope::Envelope`] are echoed back in the response
+        const REQUEST_ID = 1 << 3;
+        /// Requests in a CRC-protected [`frame`] are answered with a CRC-protected response
+        const FRAME_CRC = 1 << 4;
+        /// Link events are queued for `GetEvents` and signalled on the IRQ line
+        const EVENTS = 1 << 5;
+        /// `GetReceivedDataBatch` returns several packets in responses of up to [`MAX_BATCH_MSG_SIZE`]
+        const RECEIVE_BATCH = 1 << 6;
+        /// `SendDataBatch` queues several notifications in requests of up to [`MAX_BATCH_MSG_SIZE`]
+        const SEND_BATCH = 1 << 7;
+        /// `SendSarData` and `GetReceivedSarData` transfer messages larger than a packet, see [`sar`].
+        /// Targets reporting a minor version of 20 or later only reassemble after `SetSarRx(true)`.
+        const SAR = 1 << 8;
+        /// `SetConnParams` and `GetConnParams` control the parameters of the current connection
+        const CONN_PARAMS = 1 << 9;
+        /// `SetPreferredPhy` and `GetPhy` select and report the PHY of the connection
+        const PHY = 1 << 10;
+        /// `SetAdvParams` sets the advertising intervals and timeout
+        const ADV_PARAMS = 1 << 11;
+        /// `SetAdvData` adds host-defined AD structures to the advertising data
+        const ADV_DATA = 1 << 12;
+        /// `SetAdvMode` selects non-connectable beacon advertising
+        const ADV_MODE = 1 << 13;
+        /// `SetAcceptList` restricts scan and connect requests to known peers
+        const ACCEPT_LIST = 1 << 14;
+        /// LESC pairing confirmed with `ConfirmPasskey` and bonds managed with `ListBonds`,
+        /// `DeleteBond` and `ClearBonds`. The NUS is only accessible over an authenticated link.
+        /// Not reported when the target can't store bonds, it then pairs without bonding.
+        const BONDING = 1 << 15;
+        /// `SetPrivacy` advertises with resolvable private addresses, `SetIdentityAddress` replaces
+        /// the factory address and `GetAddressOnAir` reports the advertised address
+        const PRIVACY = 1 << 16;
+        /// Device Information Service, with strings supplied by `SetDeviceInformation`
+        const DEVICE_INFORMATION = 1 << 17;
+        /// Battery Service, with the level set by `SetBatteryLevel`
+        const BATTERY = 1 << 18;
+        /// HID keyboard typing the text of `TypeText`, only in firmware built with HID support
+        const HID = 1 << 19;
+        /// `RegisterCustomService` adds a host-defined GATT service, whose reads and writes are
+        /// reported as events
+        const CUSTOM_GATT = 1 << 20;
+        /// `GetStats` reports counters of the data transfers and the link
+        const STATS = 1 << 21;
+    }
+}
+
+bitflags! {
+    /// Properties of a [`CustomCharacteristic`], with the bits of the ATT characteristic declaration
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+    pub struct CharProperties: u8 {
+        /// Reads are answered by the host, see `CustomRead`
+        const READ = 1 << 1;
+        const WRITE_WITHOUT_RESPONSE = 1 << 2;
+        const WRITE = 1 << 3;
+        /// Sent with `NotifyCustom`
+        const NOTIFY = 1 << 4;
+    }
+}
+
 pub type Message = Vec<u8, APP_MTU>;
 pub type DeviceName = String<MAX_DEVICE_NAME_LEN>;
+pub type DisString = String<MAX_DIS_STRING_LEN>;
+pub type KeyboardText = String<MAX_TYPE_TEXT_LEN>;
+pub type CustomValue = Vec<u8, MAX_CUSTOM_VALUE_LEN>;
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
 pub enum TxPower {
@@ -100,22 +226,30 @@ pub enum Bluetooth<'a> {
     /// Request BLE firmware version
     GetFirmwareVersion,
     /// Response with firmware version string
-    AckFirmwareVersion { version: &'a str },
+    AckFirmwareVersion {
+        version: &'a str,
+    },
 
     /// Get bt address
     GetBtAddress,
-    /// Send bt address
-    AckBtAddress { bt_address: [u8; 6] },
+    /// Send bt address, the factory address even when advertising with another one
+    AckBtAddress {
+        bt_address: [u8; 6],
+    },
 
     /// Set Tx Output Power
-    SetTxPower { power: TxPower },
+    SetTxPower {
+        power: TxPower,
+    },
     /// Tx Output Power set
     AckTxPower,
 
     /// Get device id
     GetDeviceId,
     /// Send device id
-    AckDeviceId { device_id: [u8; 8] },
+    AckDeviceId {
+        device_id: [u8; 8],
+    },
 
     /// Force disconnect BLE connection
     Disconnect,
@@ -123,7 +257,9 @@ pub enum Bluetooth<'a> {
     AckDisconnect,
 
     /// Set GAP device name.
-    SetDeviceName { name: DeviceName },
+    SetDeviceName {
+        name: DeviceName,
+    },
     /// Acknowledge the set device name
     AckSetDeviceName,
 
@@ -132,6 +268,186 @@ pub enum Bluetooth<'a> {
 
     /// Echo back a message over SPI (from BLE)
     EchoResponse(Message),
+
+    /// Request the queued events, at most `MAX_EVENTS` at a time
+    GetEvents,
+    /// Queued events, oldest first
+    Events(EventBatch),
+
+    /// Request as many received packets as fit in a response of `max_len` bytes, including length
+    /// prefix and CRC. `max_len` is clamped to `MAX_MSG_SIZE..=MAX_BATCH_MSG_SIZE`.
+    GetReceivedDataBatch {
+        max_len: u16,
+    },
+    /// Received packets, oldest first
+    ReceivedDataBatch(#[serde(borrow)] PacketBatch<'a>),
+
+    /// Send packets of at most `APP_MTU` bytes as consecutive notifications. They are encoded
+    /// like the packets of a [`PacketBatch`], use [`PacketBatch::push`] to build them.
+    SendDataBatch {
+        packets: &'a [u8],
+    },
+    /// Number of leading packets queued, the rest was not sent because the TX queue is full
+    SendDataBatchResponse {
+        sent: u16,
+    },
+
+    /// Send the bytes of a `total_len` bytes message starting at `offset` as [`sar`] segments.
+    /// `data` may hold only part of the rest of the message.
+    SendSarData {
+        total_len: u16,
+        offset: u16,
+        data: &'a [u8],
+    },
+    /// Number of bytes of `data` queued, resume at `offset + sent` once the TX queue has room
+    SendSarDataResponse {
+        sent: u16,
+    },
+    /// Request the message reassembled from [`sar`] segments, of up to [`MAX_SAR_MSG_SIZE`] bytes.
+    /// Answered with `NoReceivedData` if no complete message was received. Segments written while
+    /// a message waits for this request are dropped.
+    GetReceivedSarData,
+    /// Reassembled message
+    ReceivedSarData(&'a [u8]),
+
+    /// Request new parameters for the current connection, the central decides what it grants
+    SetConnParams(ConnParams),
+    AckSetConnParams,
+    /// Not connected, invalid parameters or the request couldn't be sent
+    NackSetConnParams,
+    GetConnParams,
+    /// Parameters granted by the central, `None` if not connected.
+    /// Both intervals are the actual connection interval.
+    ConnParams(Option<ConnParams>),
+
+    /// Set the PHYs used for the current and future connections, requested from the central
+    /// right away and after connecting. Only applies to the PHY updates the peripheral starts:
+    /// the central can still switch to any PHY with its own PHY update request.
+    SetPreferredPhy(Phy),
+    AckSetPreferredPhy,
+    /// Unsupported PHY or the update request couldn't be sent
+    NackSetPreferredPhy,
+    GetPhy,
+    /// PHYs negotiated for the current connection, `None` if not connected
+    PhyStatus(Option<PhyStatus>),
+
+    /// Set the advertising timing, advertising restarts with the fast interval
+    SetAdvParams(AdvParams),
+    AckSetAdvParams,
+    /// Interval out of range
+    NackSetAdvParams,
+
+    /// Set the AD structures advertised in place of the short name, advertising restarts with them
+    SetAdvData(AdvData),
+    AckSetAdvData,
+    /// The AD structures are longer than [`MAX_ADV_DATA_LEN`]
+    NackSetAdvData,
+
+    /// Select how the target advertises, from the next time advertising starts.
+    /// A current connection is kept.
+    SetAdvMode(AdvMode),
+    AckSetAdvMode,
+
+    /// Replace the filter accept list and the advertising filter policy, from the next time
+    /// advertising starts
+    SetAcceptList(AcceptList),
+    AckSetAcceptList,
+    /// The list is invalid, see [`AcceptList::is_valid`]
+    NackSetAcceptList,
+    GetAcceptList,
+    AcceptList(AcceptList),
+
+    /// Answer the numeric comparison of a `PairingPasskey` event, `true` if the user confirmed
+    /// that both devices show the same passkey
+    ConfirmPasskey(bool),
+    AckConfirmPasskey,
+    /// No numeric comparison is pending
+    NackConfirmPasskey,
+    ListBonds,
+    /// Identity addresses of the bonded peers, oldest first
+    Bonds(Bonds),
+    /// Forget the bond with a peer, it has to pair again on its next connection
+    DeleteBond(BtAddress),
+    AckDeleteBond,
+    /// No bond with this peer
+    NackDeleteBond,
+    /// Forget all bonds
+    ClearBonds,
+    AckClearBonds,
+
+    /// Select between the identity address and resolvable private addresses, from the next time
+    /// advertising starts
+    SetPrivacy(Privacy),
+    AckSetPrivacy,
+    /// Rotation period out of range, see [`Privacy::is_valid`]
+    NackSetPrivacy,
+    /// Replace the factory identity address with a random static address from the next time
+    /// advertising starts, `None` restores the factory address. Bonded peers know the target by
+    /// its identity address, they have to pair again after it changed.
+    SetIdentityAddress(Option<[u8; 6]>),
+    AckSetIdentityAddress,
+    /// Not a random static address, see [`is_random_static`]
+    NackSetIdentityAddress,
+    GetAddressOnAir,
+    /// Address in the advertising packets, `None` while the target doesn't advertise
+    AddressOnAir(Option<BtAddress>),
+
+    /// Set the Device Information Service strings only known to the host
+    SetDeviceInformation(DeviceInformation),
+    AckSetDeviceInformation,
+
+    /// Set the Battery Service level in percent, subscribed centrals are notified of changes
+    SetBatteryLevel(u8),
+    AckSetBatteryLevel,
+    /// Level above 100%
+    NackSetBatteryLevel,
+
+    /// Type the text on the HID keyboard, `TextTyped` is reported once all keys were sent
+    TypeText(KeyboardText),
+    AckTypeText,
+    /// No HID support or connection, a text is still being typed, or a character has no key on
+    /// the US keyboard layout
+    NackTypeText,
+
+    /// Add a host-defined service to the GATT table, before the first `Enable` since the target
+    /// reset. Only one attempt is allowed, as a failed one can leave part of the service behind.
+    RegisterCustomService(CustomService),
+    AckRegisterCustomService,
+    /// Already attempted or enabled, invalid service (see [`CustomService::is_valid`]) or no
+    /// room left in the attribute table
+    NackRegisterCustomService,
+    /// Get the value last written by the central to a characteristic, by its index in the
+    /// [`CustomService`]
+    GetCustomValue(u8),
+    /// `None` for an unknown characteristic
+    CustomValue(Option<CustomValue>),
+    /// Answer the pending `CustomRead` with the value of the characteristic
+    ReplyCustomRead(CustomValue),
+    AckReplyCustomRead,
+    /// No read is pending, or the value is longer than the characteristic
+    NackReplyCustomRead,
+    /// Notify the subscribed central of a new value of a characteristic
+    NotifyCustom {
+        index: u8,
+        value: CustomValue,
+    },
+    AckNotifyCustom,
+    /// Unknown or not notifiable characteristic, value too long, not connected or not subscribed,
+    /// or the notification queue is full and `TxComplete` reports when it can be retried
+    NackNotifyCustom,
+
+    /// Get the counters since the target reset, or since the last request with `reset`, which
+    /// clears them after reading
+    GetStats {
+        reset: bool,
+    },
+    Stats(Stats),
+
+    /// Reassemble the packets written by the central from [`sar`] segments for `GetReceivedSarData`,
+    /// or queue them for `GetReceivedData` and `GetReceivedDataBatch`, the default.
+    /// Drops the message being reassembled.
+    SetSarRx(bool),
+    AckSetSarRx,
 }
 
 impl Bluetooth<'_> {
@@ -165,6 +481,80 @@ impl Bluetooth<'_> {
             Self::AckSetDeviceName => false,
             Self::Echo(_) => true,
             Self::EchoResponse(_) => false,
+            Self::GetEvents => true,
+            Self::Events(_) => false,
+            Self::GetReceivedDataBatch { .. } => true,
+            Self::ReceivedDataBatch(_) => false,
+            Self::SendDataBatch { .. } => true,
+            Self::SendDataBatchResponse { .. } => false,
+            Self::SendSarData { .. } => true,
+            Self::SendSarDataResponse { .. } => false,
+            Self::GetReceivedSarData => true,
+            Self::ReceivedSarData(_) => false,
+            Self::SetConnParams(_) => true,
+            Self::AckSetConnParams => false,
+            Self::NackSetConnParams => false,
+            Self::GetConnParams => true,
+            Self::ConnParams(_) => false,
+            Self::SetPreferredPhy(_) => true,
+            Self::AckSetPreferredPhy => false,
+            Self::NackSetPreferredPhy => false,
+            Self::GetPhy => true,
+            Self::PhyStatus(_) => false,
+            Self::SetAdvParams(_) => true,
+            Self::AckSetAdvParams => false,
+            Self::NackSetAdvParams => false,
+            Self::SetAdvData(_) => true,
+            Self::AckSetAdvData => false,
+            Self::NackSetAdvData => false,
+            Self::SetAdvMode(_) => true,
+            Self::AckSetAdvMode => false,
+            Self::SetAcceptList(_) => true,
+            Self::AckSetAcceptList => false,
+            Self::NackSetAcceptList => false,
+            Self::GetAcceptList => true,
+            Self::AcceptList(_) => false,
+            Self::ConfirmPasskey(_) => true,
+            Self::AckConfirmPasskey => false,
+            Self::NackConfirmPasskey => false,
+            Self::ListBonds => true,
+            Self::Bonds(_) => false,
+            Self::DeleteBond(_) => true,
+            Self::AckDeleteBond => false,
+            Self::NackDeleteBond => false,
+            Self::ClearBonds => true,
+            Self::AckClearBonds => false,
+            Self::SetPrivacy(_) => true,
+            Self::AckSetPrivacy => false,
+            Self::NackSetPrivacy => false,
+            Self::SetIdentityAddress(_) => true,
+            Self::AckSetIdentityAddress => false,
+            Self::NackSetIdentityAddress => false,
+            Self::GetAddressOnAir => true,
+            Self::AddressOnAir(_) => false,
+            Self::SetDeviceInformation(_) => true,
+            Self::AckSetDeviceInformation => false,
+            Self::SetBatteryLevel(_) => true,
+            Self::AckSetBatteryLevel => false,
+            Self::NackSetBatteryLevel => false,
+            Self::TypeText(_) => true,
+            Self::AckTypeText => false,
+            Self::NackTypeText => false,
+            Self::RegisterCustomService(_) => true,
+            Self::AckRegisterCustomService => false,
+            Self::NackRegisterCustomService => false,
+            Self::GetCustomValue(_) => true,
+            Self::CustomValue(_) => false,
+            Self::ReplyCustomRead(_) => true,
+            Self::AckReplyCustomRead => false,
+            Self::NackReplyCustomRead => false,
+            Self::NotifyCustom { .. } => true,
+            Self::AckNotifyCustom => false,
+            Self::NackNotifyCustom => false,
+            Self::GetStats { .. } => true,
+            Self::Stats(_) => false,
+            Self::SetSarRx(_) => true,
+            Self::AckSetSarRx => false,
         }
     }
 }
@@ -280,6 +670,8 @@ pub enum PostcardError {
     Deser,
     /// Buffer overflow
     OverFull,
+    /// Frame CRC mismatch
+    Crc,
 }
 
 /// Response codes for sending data over BLE connection
@@ -299,6 +691,31 @@ pub struct BluetoothStatus {
     pub queue_overflow: bool,
 }
 
+/// Counters reported by `GetStats`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// Packets written by the central and queued for `GetReceivedData`, or taken as SAR segments
+    pub rx_packets: u32,
+    pub rx_bytes: u64,
+    /// Packets written by the central while the receive queue was full, and SAR segments dropped
+    /// because they were invalid or a message was waiting for `GetReceivedSarData`
+    pub rx_dropped: u32,
+    /// Notifications queued by `SendData`, `SendDataBatch` and `SendSarData`
+    pub tx_packets: u32,
+    pub tx_bytes: u64,
+    /// Notifications rejected with `BufferFull`
+    pub tx_buffer_full: u32,
+    /// Requests answered with a `PostcardError`: undecodable messages and frames or envelopes
+    /// that failed their CRC, tag or length check
+    pub deser_errors: u32,
+    /// Failed reads of a request from the SPI bus
+    pub spi_errors: u32,
+    pub connections: u32,
+    pub disconnections: u32,
+    /// Advertising restarted by a new setting or `Enable`
+    pub adv_restarts: u32,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
 pub enum ConnectionStatus {
     Disabled,
@@ -306,6 +723,473 @@ pub enum ConnectionStatus {
     Connected { rssi: i8 },
 }
 
+/// BLE connection parameters, in the units of the Bluetooth specification
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnParams {
+    /// Minimum connection interval in 1.25 ms units
+    pub min_interval: u16,
+    /// Maximum connection interval in 1.25 ms units
+    pub max_interval: u16,
+    /// Number of connection events the peripheral may skip
+    pub latency: u16,
+    /// Supervision timeout in 10 ms units
+    pub supervision_timeout: u16,
+}
+
+impl ConnParams {
+    /// Parameters requested by the target after connecting: 7.5-50 ms interval, no latency and
+    /// a 5 s supervision timeout
+    pub const DEFAULT: Self = Self {
+        min_interval: 6,
+        max_interval: 40,
+        latency: 0,
+        supervision_timeout: 500,
+    };
+
+    /// Checks the ranges of the Bluetooth specification, and that the supervision timeout is longer
+    /// than twice the effective connection interval
+    pub fn is_valid(&self) -> bool {
+        (6..=3200).contains(&self.min_interval)
+            && (self.min_interval..=3200).contains(&self.max_interval)
+            && self.latency <= 499
+            && (10..=3200).contains(&self.supervision_timeout)
+            && u32::from(self.supervision_timeout) * 4 > (1 + u32::from(self.latency)) * u32::from(self.max_interval)
+    }
+}
+
+/// Advertising modes, all of them carry the same advertising data
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AdvMode {
+    /// Centrals can connect to the NUS, the full name is in the scan response
+    #[default]
+    Connectable,
+    /// Beacon without scan response that doesn't accept connections
+    NonConnectable,
+    /// Beacon with the full name in the scan response that doesn't accept connections
+    ScannableNonConnectable,
+}
+
+/// Kind of Bluetooth device address, in the order of the SoftDevice `BLE_GAP_ADDR_TYPE_*` values
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressType {
+    Public,
+    RandomStatic,
+    RandomPrivateResolvable,
+    RandomPrivateNonResolvable,
+}
+
+/// Bluetooth device address, most significant byte first like in `AckBtAddress`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BtAddress {
+    pub addr_type: AddressType,
+    pub addr: [u8; 6],
+}
+
+impl BtAddress {
+    /// Public and random static addresses identify a device, private ones change over time
+    pub fn is_identity(&self) -> bool {
+        matches!(self.addr_type, AddressType::Public | AddressType::RandomStatic)
+    }
+}
+
+/// Checks that `addr`, most significant byte first, is a random static address: the two most
+/// significant bits set, and the other bits neither all 0 nor all 1
+pub fn is_random_static(addr: &[u8; 6]) -> bool {
+    let random = u64::from_be_bytes([0, 0, addr[0], addr[1], addr[2], addr[3], addr[4], addr[5]]) & !(0b11 << 46);
+    addr[0] >> 6 == 0b11 && random != 0 && random != (1 << 46) - 1
+}
+
+/// Address the target advertises and connects with
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Privacy {
+    /// The identity address, recognized by every central but trackable
+    #[default]
+    Off,
+    /// A resolvable private address renewed every `rotation_secs` seconds. Only bonded centrals,
+    /// which got the identity resolving key of the target, recognize it.
+    ResolvablePrivate { rotation_secs: u16 },
+}
+
+impl Privacy {
+    /// Rotation period recommended by the Bluetooth specification
+    pub const DEFAULT_ROTATION_SECS: u16 = 900;
+
+    /// Checks that the rotation period is between 1 s and the 11.5 h accepted by the SoftDevice
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Self::Off => true,
+            Self::ResolvablePrivate { rotation_secs } => (1..=41400).contains(rotation_secs),
+        }
+    }
+}
+
+/// Device Information Service strings supplied by the host. The target fills in the
+/// manufacturer name, the model number and its own version as firmware revision.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceInformation {
+    pub hardware_revision: DisString,
+    pub serial_number: DisString,
+    /// Version reported by the bootloader `BootloaderVersion`
+    pub bootloader_version: DisString,
+    /// Version in the cosign2 header of the booted firmware, reported by the bootloader
+    /// `FirmwareVersion`
+    pub firmware_version: DisString,
+}
+
+/// Who can access a [`CustomCharacteristic`]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CharSecurity {
+    /// Any central
+    Open,
+    /// Centrals on an encrypted link, pairing without authentication is enough
+    Encrypted,
+    /// Centrals on a link secured with authenticated LESC keys, like the NUS
+    #[default]
+    Authenticated,
+}
+
+/// Characteristic of the [`CustomService`]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CustomCharacteristic {
+    /// 128-bit UUID, most significant byte first as in its string form
+    pub uuid: [u8; 16],
+    pub properties: CharProperties,
+    pub security: CharSecurity,
+    /// Maximum value length, up to [`MAX_CUSTOM_VALUE_LEN`]
+    pub max_len: u8,
+}
+
+impl CustomCharacteristic {
+    /// Checks that the characteristic can be accessed and holds at most [`MAX_CUSTOM_VALUE_LEN`]
+    /// bytes
+    pub fn is_valid(&self) -> bool {
+        !self.properties.is_empty() && (1..=MAX_CUSTOM_VALUE_LEN).contains(&usize::from(self.max_len))
+    }
+}
+
+/// GATT service defined by the host, to prototype services without a firmware update.
+///
+/// Writes of the central are reported with `CustomWritten` and fetched with `GetCustomValue`,
+/// reads are reported with `CustomRead` and answered with `ReplyCustomRead`.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct CustomService {
+    /// 128-bit UUID, most significant byte first as in its string form
+    pub uuid: [u8; 16],
+    pub characteristics: Vec<CustomCharacteristic, MAX_CUSTOM_CHARACTERISTICS>,
+}
+
+impl CustomService {
+    /// Checks that the service has valid characteristics with distinct UUIDs, which only differ
+    /// from the service UUID in bytes 2 and 3 (`xxxx` in `0000xxxx-0000-...`) like the NUS UUIDs
+    pub fn is_valid(&self) -> bool {
+        let same_base = |uuid: &[u8; 16]| uuid[..2] == self.uuid[..2] && uuid[4..] == self.uuid[4..];
+        !self.characteristics.is_empty()
+            && self.characteristics.iter().enumerate().all(|(i, characteristic)| {
+                characteristic.is_valid()
+                    && same_base(&characteristic.uuid)
+                    && characteristic.uuid != self.uuid
+                    && self.characteristics[..i].iter().all(|other| other.uuid != characteristic.uuid)
+            })
+    }
+}
+
+/// Identity addresses of bonded peers
+pub type Bonds = Vec<BtAddress, MAX_BONDS>;
+
+/// Peer of the filter accept list
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AcceptListEntry {
+    /// Identity address of the peer
+    pub address: BtAddress,
+    /// Identity resolving key distributed when bonding, to also accept the resolvable private
+    /// addresses of the peer
+    pub irk: Option<[u8; 16]>,
+}
+
+/// Which requests the filter accept list applies to while advertising
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AdvFilter {
+    /// Everyone can scan and connect
+    #[default]
+    Any,
+    /// Only peers in the list get a scan response
+    ScanRequests,
+    /// Only peers in the list can connect
+    ConnectRequests,
+    /// Only peers in the list can scan and connect
+    Both,
+}
+
+/// Filter accept list with the advertising filter policy using it
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct AcceptList {
+    pub filter: AdvFilter,
+    pub peers: Vec<AcceptListEntry, MAX_ACCEPT_LIST_LEN>,
+}
+
+impl AcceptList {
+    pub const fn new() -> Self {
+        Self {
+            filter: AdvFilter::Any,
+            peers: Vec::new(),
+        }
+    }
+
+    /// Checks that a filter has peers to accept, and that all peers have distinct identity addresses
+    pub fn is_valid(&self) -> bool {
+        (self.filter == AdvFilter::Any || !self.peers.is_empty())
+            && self
+                .peers
+                .iter()
+                .enumerate()
+                .all(|(i, peer)| peer.address.is_identity() && self.peers[..i].iter().all(|other| other.address != peer.address))
+    }
+}
+
+/// Host-defined AD structures of the advertising data.
+///
+/// They take the place of the short name, which is only advertised when there is room left. The
+/// full name stays in the scan response.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct AdvData {
+    /// GAP appearance value
+    pub appearance: Option<u16>,
+    /// Advertise the TX power level set with `SetTxPower`
+    pub tx_power_level: bool,
+    pub manufacturer_data: Option<ManufacturerData>,
+    pub service_data: Option<ServiceData>,
+}
+
+/// Manufacturer specific data, after a Bluetooth SIG company identifier
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ManufacturerData {
+    pub company_id: u16,
+    pub data: Vec<u8, { MAX_ADV_DATA_LEN - 4 }>,
+}
+
+/// Data of the service with a 16-bit UUID
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ServiceData {
+    pub uuid: u16,
+    pub data: Vec<u8, { MAX_ADV_DATA_LEN - 4 }>,
+}
+
+impl AdvData {
+    /// No AD structures, only the short name is advertised
+    pub const fn new() -> Self {
+        Self {
+            appearance: None,
+            tx_power_level: false,
+            manufacturer_data: None,
+            service_data: None,
+        }
+    }
+
+    /// Size of the AD structures, each with a length and a type byte
+    pub fn encoded_len(&self) -> usize {
+        self.appearance.map_or(0, |_| 4)
+            + if self.tx_power_level { 3 } else { 0 }
+            + self.manufacturer_data.as_ref().map_or(0, |m| 4 + m.data.len())
+            + self.service_data.as_ref().map_or(0, |s| 4 + s.data.len())
+    }
+
+    /// Checks that the AD structures fit in the advertising data
+    pub fn fits(&self) -> bool {
+        self.encoded_len() <= MAX_ADV_DATA_LEN
+    }
+}
+
+/// Advertising timing, intervals in 0.625 ms units and durations in seconds.
+///
+/// Advertising starts with `fast_interval`, switches to `slow_interval` after `fast_duration` and
+/// stops after `timeout`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AdvParams {
+    pub fast_interval: u16,
+    /// 0 to keep the fast interval
+    pub fast_duration: u16,
+    pub slow_interval: u16,
+    /// 0 to advertise until a central connects
+    pub timeout: u16,
+}
+
+impl AdvParams {
+    /// About 47 ms until a central connects
+    pub const DEFAULT: Self = Self {
+        fast_interval: 75,
+        fast_duration: 0,
+        slow_interval: 75,
+        timeout: 0,
+    };
+
+    /// Checks that both intervals are in the 20 ms to 10.24 s range of the Bluetooth specification
+    pub fn is_valid(&self) -> bool {
+        const INTERVALS: core::ops::RangeInclusive<u16> = 32..=16384;
+        INTERVALS.contains(&self.fast_interval) && INTERVALS.contains(&self.slow_interval)
+    }
+
+    /// Intervals to advertise with in turn, and for how many seconds. `None` advertises until a
+    /// central connects.
+    pub fn phases(&self) -> impl Iterator<Item = (u16, Option<u16>)> {
+        let fast_duration = [self.fast_duration, self.timeout].into_iter().filter(|&secs| secs != 0).min();
+        let slow = match (self.fast_duration, self.timeout) {
+            (0, _) => None,
+            (_, 0) => Some((self.slow_interval, None)),
+            (fast, timeout) if timeout > fast => Some((self.slow_interval, Some(timeout - fast))),
+            _ => None,
+        };
+        core::iter::once((self.fast_interval, fast_duration)).chain(slow)
+    }
+}
+
+bitflags! {
+    /// LE PHYs, with the values of the SoftDevice `BLE_GAP_PHY_*` flags.
+    /// An empty set lets the link layer pick the fastest PHY both sides support.
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+    pub struct Phy: u8 {
+        /// 1 Mbps, supported by every central
+        const LE_1M = 1 << 0;
+        /// 2 Mbps, twice the throughput for a shorter range
+        const LE_2M = 1 << 1;
+    }
+}
+
+/// PHYs in use on the current connection
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PhyStatus {
+    pub tx: Phy,
+    pub rx: Phy,
+}
+
+/// Link events queued by the target.
+///
+/// Make sure to only append new events at the end of the enum, to keep backward compatibility
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// A central connected
+    Connected,
+    /// The connection was closed
+    Disconnected { reason: DisconnectReason },
+    /// The central subscribed to notifications
+    NotificationsEnabled,
+    /// The central unsubscribed from notifications
+    NotificationsDisabled,
+    /// Data was received while the receive queue was empty
+    DataAvailable,
+    /// Notifications were transmitted after a `SendData` failed with `BufferFull`, sending can be retried
+    TxComplete,
+    /// Advertising stopped without a connection
+    AdvertisingStopped,
+    /// Advertising stopped after the [`AdvParams::timeout`], it restarts with the next `Enable` or
+    /// advertising setting
+    AdvertisingTimeout,
+    /// The central started pairing, the 6-digit `passkey` has to be shown to the user. With
+    /// `confirm` it is a numeric comparison answered with `ConfirmPasskey`, otherwise the user
+    /// types it on the central.
+    PairingPasskey { passkey: u32, confirm: bool },
+    /// The link is encrypted with authenticated LE Secure Connections keys, the NUS is accessible
+    Secured,
+    /// A new bond was written to flash, see `ListBonds`
+    Bonded,
+    /// All keys of a `TypeText` were sent, not reported if the connection was lost before
+    TextTyped,
+    /// The central wrote a characteristic of the [`CustomService`], see `GetCustomValue`. Only
+    /// the last value is kept when several writes arrive before it is fetched.
+    CustomWritten { index: u8 },
+    /// The central reads a characteristic of the [`CustomService`], to be answered with
+    /// `ReplyCustomRead` before the 30 s ATT timeout
+    CustomRead { index: u8 },
+    /// Writing the bonds to flash failed, the bonds changed since the last write are forgotten and
+    /// `ListBonds` reports the stored ones. Reported instead of `Bonded` for a new bond.
+    BondStorageFailed,
+}
+
+/// Why a connection was closed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DisconnectReason {
+    /// The host asked for it with `Disconnect` or `Disable`
+    Host,
+    /// The central disconnected or the link was lost
+    Remote,
+}
+
+/// Response to `GetEvents`
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct EventBatch {
+    pub events: Vec<Event, MAX_EVENTS>,
+    /// Events were dropped because the queue was full
+    pub overflow: bool,
+}
+
+/// Response to `GetReceivedDataBatch`
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct PacketBatch<'a> {
+    /// Packets encoded back to back like a [`Message`], iterate over them with [`PacketBatch::iter`]
+    pub packets: &'a [u8],
+    /// Number of packets still queued on the target
+    pub remaining: u16,
+}
+
+impl<'a> PacketBatch<'a> {
+    /// Largest size of a `ReceivedDataBatch` response or `SendDataBatch` request besides the packets,
+    /// with envelope and CRC frame
+    pub const OVERHEAD: usize = frame::FRAME_OVERHEAD + envelope::ENVELOPE_HEADER_SIZE + 2 + 2 + 3;
+
+    /// Appends `packet` to the packets encoded in the first `len` bytes of `buf`.
+    /// Returns the new length or `None` if the packet doesn't fit.
+    pub fn push(buf: &mut [u8], len: usize, packet: &[u8]) -> Option<usize> {
+        let encoded = postcard::to_slice(packet, buf.get_mut(len..)?).ok()?;
+        Some(len + encoded.len())
+    }
+
+    /// Iterates over the packets, stopping at the first malformed one
+    pub fn iter(&self) -> impl Iterator<Item = &'a [u8]> {
+        iter_packets(self.packets)
+    }
+}
+
+/// Iterates over packets encoded with [`PacketBatch::push`], stopping at the first malformed one
+pub fn iter_packets(mut packets: &[u8]) -> impl Iterator<Item = &[u8]> {
+    core::iter::from_fn(move || {
+        let (packet, tail) = postcard::take_from_bytes::<&[u8]>(packets).ok()?;
+        packets = tail;
+        Some(packet)
+    })
+}
+
+/// Response length for a `GetReceivedDataBatch { max_len }` request
+pub fn batch_response_len(max_len: u16) -> usize {
+    usize::from(max_len).clamp(MAX_MSG_SIZE, MAX_BATCH_MSG_SIZE)
+}
+
+/// Protocol version and limits reported by the target
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ProtocolInfo {
+    /// Protocol major version, see [`PROTOCOL_VERSION_MAJOR`]
+    pub version_major: u8,
+    /// Protocol minor version, see [`PROTOCOL_VERSION_MINOR`]
+    pub version_minor: u8,
+    /// Largest postcard message the target accepts or sends
+    pub max_msg_size: u16,
+    /// Largest payload of a single BLE data packet
+    pub app_mtu: u16,
+    /// Optional features supported by the target
+    pub capabilities: Capabilities,
+}
+
+impl ProtocolInfo {
+    /// Protocol information for this version of the crate with the given capabilities
+    pub const fn new(capabilities: Capabilities) -> Self {
+        Self {
+            version_major: PROTOCOL_VERSION_MAJOR,
+            version_minor: PROTOCOL_VERSION_MINOR,
+            max_msg_size: MAX_MSG_SIZE as u16,
+            app_mtu: APP_MTU as u16,
+            capabilities,
+        }
+    }
+}
+
 /// Top-level message types for host-target communication
 ///
 /// Make sure to only append new messages at the end of the enum, to keep backward compatibility
@@ -330,6 +1214,12 @@ pub enum HostProtocolMessage<'a> {
 
     /// An inappropriate message was received for the current state
     InappropriateMessage(State),
+
+    /// Query protocol version, limits and capabilities.
+    /// Targets predating this message answer with `PostcardError(Deser)`.
+    GetProtocolInfo,
+    /// Response with protocol version, limits and capabilities
+    AckProtocolInfo(ProtocolInfo),
 }
 
 impl HostProtocolMessage<'_> {
@@ -344,6 +1234,8 @@ impl HostProtocolMessage<'_> {
             Self::ChallengeResult { .. } => false,
             Self::PostcardError(_) => false,
             Self::InappropriateMessage(_) => false,
+            Self::GetProtocolInfo => true,
+            Self::AckProtocolInfo(_) => false,
         }
     }
 }
@@ -396,14 +1288,239 @@ mod tests {
                 ),
                 (HostProtocolMessage::PostcardError(PostcardError::Deser), &[7, 0]),
                 (HostProtocolMessage::PostcardError(PostcardError::OverFull), &[7, 1]),
+                (HostProtocolMessage::PostcardError(PostcardError::Crc), &[7, 2]),
                 (HostProtocolMessage::InappropriateMessage(State::Disabled), &[8, 1]),
                 (HostProtocolMessage::InappropriateMessage(State::Enabled), &[8, 0]),
                 (HostProtocolMessage::InappropriateMessage(State::FirmwareUpgrade), &[8, 2]),
                 (HostProtocolMessage::InappropriateMessage(State::Unknown), &[8, 3]),
+                (HostProtocolMessage::GetProtocolInfo, &[9]),
+                (
+                    HostProtocolMessage::AckProtocolInfo(ProtocolInfo {
+                        version_major: 1,
+                        version_minor: 0,
+                        max_msg_size: 270,
+                        app_mtu: 244,
+                        capabilities: Capabilities::BLUETOOTH | Capabilities::CHALLENGE,
+                    }),
+                    &[10, 1, 0, 142, 2, 244, 1, 5],
+                ),
             ],
         );
     }
 
+    #[test]
+    fn packet_batch() {
+        let mut buf = [0u8; 16];
+        let len = PacketBatch::push(&mut buf, 0, &[1, 2, 3]).unwrap();
+        let len = PacketBatch::push(&mut buf, len, &[]).unwrap();
+        let len = PacketBatch::push(&mut buf, len, &[4; 10]).unwrap();
+        assert_eq!(PacketBatch::push(&mut buf, len, &[5]), None);
+
+        let batch = PacketBatch {
+            packets: &buf[..len],
+            remaining: 1,
+        };
+        let packets: Vec<&[u8]> = batch.iter().collect();
+        assert_eq!(packets, [&[1, 2, 3][..], &[], &[4; 10]]);
+
+        // worst case framing of the largest response
+        let mut frame = [0u8; MAX_BATCH_MSG_SIZE];
+        let packets = [0xFF; MAX_BATCH_PACKETS_LEN];
+        let message = HostProtocolMessage::Bluetooth(Bluetooth::ReceivedDataBatch(PacketBatch {
+            packets: &packets,
+            remaining: u16::MAX,
+        }));
+        let envelope = envelope::Envelope::new(Some(u16::MAX), message);
+        let payload_len = envelope.encode(&mut frame[frame::LEN_SIZE..]).unwrap().len();
+        assert_eq!(frame::finish_response(&mut frame, payload_len, true), Some(MAX_BATCH_MSG_SIZE));
+
+        // and of the largest request
+        let message = HostProtocolMessage::Bluetooth(Bluetooth::SendDataBatch { packets: &packets });
+        let request = frame::encode_request(&envelope::Envelope::new(Some(u16::MAX), message), &mut frame).unwrap();
+        assert!(request.len() <= MAX_BATCH_MSG_SIZE);
+    }
+
+    #[test]
+    fn conn_params_validation() {
+        assert!(ConnParams::DEFAULT.is_valid());
+        let valid = ConnParams {
+            min_interval: 24,
+            max_interval: 40,
+            latency: 4,
+            supervision_timeout: 51,
+        };
+        assert!(valid.is_valid());
+        // timeout too short for the latency
+        assert!(!ConnParams {
+            supervision_timeout: 50,
+            ..valid
+        }
+        .is_valid());
+        assert!(!ConnParams { min_interval: 5, ..valid }.is_valid());
+        assert!(!ConnParams { max_interval: 23, ..valid }.is_valid());
+        assert!(!ConnParams { latency: 500, ..valid }.is_valid());
+        assert!(!ConnParams {
+            supervision_timeout: 3201,
+            ..valid
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn adv_phases() {
+        let phases = |params: AdvParams| params.phases().collect::<std::vec::Vec<_>>();
+        assert_eq!(phases(AdvParams::DEFAULT), [(75, None)]);
+        let params = AdvParams {
+            fast_interval: 48,
+            fast_duration: 30,
+            slow_interval: 1600,
+            timeout: 0,
+        };
+        assert_eq!(phases(params), [(48, Some(30)), (1600, None)]);
+        assert_eq!(phases(AdvParams { timeout: 300, ..params }), [(48, Some(30)), (1600, Some(270))]);
+        assert_eq!(phases(AdvParams { timeout: 20, ..params }), [(48, Some(20))]);
+        assert_eq!(phases(AdvParams { timeout: 30, ..params }), [(48, Some(30))]);
+        assert_eq!(
+            phases(AdvParams {
+                fast_duration: 0,
+                timeout: 60,
+                ..params
+            }),
+            [(48, Some(60))]
+        );
+
+        assert!(params.is_valid());
+        assert!(!AdvParams {
+            fast_interval: 31,
+            ..params
+        }
+        .is_valid());
+        assert!(!AdvParams {
+            slow_interval: 16385,
+            ..params
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn adv_data_fits() {
+        assert_eq!(AdvData::new().encoded_len(), 0);
+        let data = AdvData {
+            appearance: Some(0x0080),
+            tx_power_level: true,
+            manufacturer_data: None,
+            service_data: None,
+        };
+        assert_eq!(data.encoded_len(), 7);
+        assert!(data.fits());
+        let manufacturer_data = Some(ManufacturerData {
+            company_id: 0xFFFF,
+            data: heapless::Vec::from_slice(&[1, 2]).unwrap(),
+        });
+        assert!(!AdvData {
+            manufacturer_data: manufacturer_data.clone(),
+            ..data.clone()
+        }
+        .fits());
+        assert!(AdvData {
+            manufacturer_data,
+            tx_power_level: false,
+            ..data
+        }
+        .fits());
+    }
+
+    #[test]
+    fn accept_list_validation() {
+        let peer = AcceptListEntry {
+            address: BtAddress {
+                addr_type: AddressType::RandomStatic,
+                addr: [0xC0, 1, 2, 3, 4, 5],
+            },
+            irk: Some([0x11; 16]),
+        };
+        let mut list = AcceptList::new();
+        assert!(list.is_valid());
+        list.filter = AdvFilter::Both;
+        assert!(!list.is_valid());
+        list.peers.push(peer).unwrap();
+        assert!(list.is_valid());
+        // duplicate
+        list.peers.push(AcceptListEntry { irk: None, ..peer }).unwrap();
+        assert!(!list.is_valid());
+        list.peers[1].address.addr_type = AddressType::Public;
+        assert!(list.is_valid());
+        list.peers[1].address.addr_type = AddressType::RandomPrivateResolvable;
+        assert!(!list.is_valid());
+    }
+
+    #[test]
+    fn address_validation() {
+        assert!(is_random_static(&[0xC0, 0, 0, 0, 0, 1]));
+        assert!(is_random_static(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE]));
+        assert!(!is_random_static(&[0xC0, 0, 0, 0, 0, 0]));
+        assert!(!is_random_static(&[0xFF; 6]));
+        // resolvable private
+        assert!(!is_random_static(&[0x40, 1, 2, 3, 4, 5]));
+
+        assert!(Privacy::Off.is_valid());
+        assert!(Privacy::ResolvablePrivate {
+            rotation_secs: Privacy::DEFAULT_ROTATION_SECS
+        }
+        .is_valid());
+        assert!(!Privacy::ResolvablePrivate { rotation_secs: 0 }.is_valid());
+        assert!(!Privacy::ResolvablePrivate { rotation_secs: 41401 }.is_valid());
+    }
+
+    #[test]
+    fn custom_service_validation() {
+        let uuid = |alias: u8| {
+            [
+                0xF0, 0x0D, 0, alias, 0xB5, 0xA3, 0xF3, 0x93, 0xE0, 0xA9, 0xE5, 0x0E, 0x24, 0xDC, 0xCA, 0x9E,
+            ]
+        };
+        let characteristic = CustomCharacteristic {
+            uuid: uuid(2),
+            properties: CharProperties::READ | CharProperties::NOTIFY,
+            security: CharSecurity::Authenticated,
+            max_len: MAX_CUSTOM_VALUE_LEN as u8,
+        };
+        let mut service = CustomService {
+            uuid: uuid(1),
+            characteristics: heapless::Vec::new(),
+        };
+        assert!(!service.is_valid());
+        service.characteristics.push(characteristic).unwrap();
+        assert!(service.is_valid());
+        // duplicate
+        service.characteristics.push(characteristic).unwrap();
+        assert!(!service.is_valid());
+        service.characteristics[1].uuid = uuid(3);
+        assert!(service.is_valid());
+        service.characteristics[1].uuid = uuid(1);
+        assert!(!service.is_valid());
+        // other base
+        service.characteristics[1].uuid = uuid(3);
+        service.characteristics[1].uuid[15] = 0;
+        assert!(!service.is_valid());
+
+        assert!(!CustomCharacteristic {
+            max_len: 0,
+            ..characteristic
+        }
+        .is_valid());
+        assert!(!CustomCharacteristic {
+            max_len: MAX_CUSTOM_VALUE_LEN as u8 + 1,
+            ..characteristic
+        }
+        .is_valid());
+        assert!(!CustomCharacteristic {
+            properties: CharProperties::empty(),
+            ..characteristic
+        }
+        .is_valid());
+    }
+
     #[test]
     fn check_bootloader_messages() {
         // Test each variant
@@ -630,6 +1747,287 @@ mod tests {
                     ],
                 ),
                 (HostProtocolMessage::Bluetooth(Bluetooth::AckSetDeviceName), &[0, 25]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::GetEvents), &[0, 28]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::Events(EventBatch {
+                        events: heapless::Vec::from_slice(&[
+                            Event::Connected,
+                            Event::Disconnected {
+                                reason: DisconnectReason::Remote,
+                            },
+                            Event::AdvertisingStopped,
+                        ])
+                        .unwrap(),
+                        overflow: true,
+                    })),
+                    &[0, 29, 3, 0, 1, 1, 6, 1],
+                ),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::GetReceivedDataBatch { max_len: 1024 }),
+                    &[0, 30, 128, 8],
+                ),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::ReceivedDataBatch(PacketBatch {
+                        packets: &[2, 0xAA, 0xBB, 1, 0xCC],
+                        remaining: 3,
+                    })),
+                    &[0, 31, 5, 2, 0xAA, 0xBB, 1, 0xCC, 3],
+                ),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::SendDataBatch { packets: &[1, 0xAA, 0] }),
+                    &[0, 32, 3, 1, 0xAA, 0],
+                ),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::SendDataBatchResponse { sent: 2 }),
+                    &[0, 33, 2],
+                ),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::SendSarData {
+                        total_len: 300,
+                        offset: 241,
+                        data: &[0xAA, 0xBB],
+                    }),
+                    &[0, 34, 172, 2, 241, 1, 2, 0xAA, 0xBB],
+                ),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::SendSarDataResponse { sent: 59 }),
+                    &[0, 35, 59],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::GetReceivedSarData), &[0, 36]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::ReceivedSarData(&[1, 2, 3])),
+                    &[0, 37, 3, 1, 2, 3],
+                ),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::SetConnParams(ConnParams::DEFAULT)),
+                    &[0, 38, 6, 40, 0, 244, 3],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckSetConnParams), &[0, 39]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackSetConnParams), &[0, 40]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::GetConnParams), &[0, 41]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::ConnParams(None)), &[0, 42, 0]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::ConnParams(Some(ConnParams {
+                        min_interval: 36,
+                        max_interval: 36,
+                        latency: 0,
+                        supervision_timeout: 500,
+                    }))),
+                    &[0, 42, 1, 36, 36, 0, 244, 3],
+                ),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::SetPreferredPhy(Phy::LE_1M | Phy::LE_2M)),
+                    &[0, 43, 3],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckSetPreferredPhy), &[0, 44]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackSetPreferredPhy), &[0, 45]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::GetPhy), &[0, 46]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::PhyStatus(Some(PhyStatus {
+                        tx: Phy::LE_2M,
+                        rx: Phy::LE_1M,
+                    }))),
+                    &[0, 47, 1, 2, 1],
+                ),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::SetAdvParams(AdvParams {
+                        fast_interval: 48,
+                        fast_duration: 30,
+                        slow_interval: 1600,
+                        timeout: 300,
+                    })),
+                    &[0, 48, 48, 30, 192, 12, 172, 2],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckSetAdvParams), &[0, 49]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackSetAdvParams), &[0, 50]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::SetAdvData(AdvData {
+                        appearance: Some(0x0080),
+                        tx_power_level: true,
+                        manufacturer_data: Some(ManufacturerData {
+                            company_id: 0x1234,
+                            data: heapless::Vec::from_slice(&[0xAB]).unwrap(),
+                        }),
+                        service_data: None,
+                    })),
+                    &[0, 51, 1, 128, 1, 1, 1, 180, 36, 1, 171, 0],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckSetAdvData), &[0, 52]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackSetAdvData), &[0, 53]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::SetAdvMode(AdvMode::ScannableNonConnectable)),
+                    &[0, 54, 2],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckSetAdvMode), &[0, 55]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::SetAcceptList(AcceptList {
+                        filter: AdvFilter::ConnectRequests,
+                        peers: heapless::Vec::from_slice(&[AcceptListEntry {
+                            address: BtAddress {
+                                addr_type: AddressType::Public,
+                                addr: [1, 2, 3, 4, 5, 6],
+                            },
+                            irk: None,
+                        }])
+                        .unwrap(),
+                    })),
+                    &[0, 56, 2, 1, 0, 1, 2, 3, 4, 5, 6, 0],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckSetAcceptList), &[0, 57]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackSetAcceptList), &[0, 58]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::GetAcceptList), &[0, 59]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::AcceptList(AcceptList::new())),
+                    &[0, 60, 0, 0],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::ConfirmPasskey(true)), &[0, 61, 1]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckConfirmPasskey), &[0, 62]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackConfirmPasskey), &[0, 63]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::ListBonds), &[0, 64]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::Bonds(
+                        heapless::Vec::from_slice(&[BtAddress {
+                            addr_type: AddressType::RandomStatic,
+                            addr: [0xC1, 2, 3, 4, 5, 6],
+                        }])
+                        .unwrap(),
+                    )),
+                    &[0, 65, 1, 1, 0xC1, 2, 3, 4, 5, 6],
+                ),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::DeleteBond(BtAddress {
+                        addr_type: AddressType::Public,
+                        addr: [1, 2, 3, 4, 5, 6],
+                    })),
+                    &[0, 66, 0, 1, 2, 3, 4, 5, 6],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckDeleteBond), &[0, 67]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackDeleteBond), &[0, 68]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::ClearBonds), &[0, 69]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckClearBonds), &[0, 70]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::SetPrivacy(Privacy::ResolvablePrivate { rotation_secs: 900 })),
+                    &[0, 71, 1, 0x84, 0x07],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckSetPrivacy), &[0, 72]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackSetPrivacy), &[0, 73]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::SetIdentityAddress(Some([0xC1, 2, 3, 4, 5, 6]))),
+                    &[0, 74, 1, 0xC1, 2, 3, 4, 5, 6],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckSetIdentityAddress), &[0, 75]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackSetIdentityAddress), &[0, 76]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::GetAddressOnAir), &[0, 77]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::SetDeviceInformation(DeviceInformation {
+                        hardware_revision: DisString::try_from("B").unwrap(),
+                        serial_number: DisString::try_from("P1").unwrap(),
+                        bootloader_version: DisString::try_from("3.0.3").unwrap(),
+                        firmware_version: DisString::new(),
+                    })),
+                    &[0, 79, 1, b'B', 2, b'P', b'1', 5, b'3', b'.', b'0', b'.', b'3', 0],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckSetDeviceInformation), &[0, 80]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::SetBatteryLevel(85)), &[0, 81, 85]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckSetBatteryLevel), &[0, 82]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackSetBatteryLevel), &[0, 83]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::TypeText(KeyboardText::try_from("bc1q").unwrap())),
+                    &[0, 84, 4, b'b', b'c', b'1', b'q'],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckTypeText), &[0, 85]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackTypeText), &[0, 86]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::RegisterCustomService(CustomService {
+                        uuid: [0x10; 16],
+                        characteristics: heapless::Vec::from_slice(&[CustomCharacteristic {
+                            uuid: [0x20; 16],
+                            properties: CharProperties::WRITE | CharProperties::NOTIFY,
+                            security: CharSecurity::Encrypted,
+                            max_len: 20,
+                        }])
+                        .unwrap(),
+                    })),
+                    &[
+                        0, 87, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 1, 0x20,
+                        0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x18, 1, 20,
+                    ],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckRegisterCustomService), &[0, 88]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackRegisterCustomService), &[0, 89]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::GetCustomValue(2)), &[0, 90, 2]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::CustomValue(Some(CustomValue::from_slice(&[1, 2]).unwrap()))),
+                    &[0, 91, 1, 2, 1, 2],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::CustomValue(None)), &[0, 91, 0]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::ReplyCustomRead(CustomValue::from_slice(&[0x42]).unwrap())),
+                    &[0, 92, 1, 0x42],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckReplyCustomRead), &[0, 93]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackReplyCustomRead), &[0, 94]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::NotifyCustom {
+                        index: 1,
+                        value: CustomValue::from_slice(&[7, 8, 9]).unwrap(),
+                    }),
+                    &[0, 95, 1, 3, 7, 8, 9],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckNotifyCustom), &[0, 96]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::NackNotifyCustom), &[0, 97]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::GetStats { reset: true }), &[0, 98, 1]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::Stats(Stats {
+                        rx_packets: 1,
+                        rx_bytes: 300,
+                        rx_dropped: 2,
+                        tx_packets: 3,
+                        tx_bytes: 4,
+                        tx_buffer_full: 5,
+                        deser_errors: 6,
+                        spi_errors: 7,
+                        connections: 8,
+                        disconnections: 9,
+                        adv_restarts: 10,
+                    })),
+                    &[0, 99, 1, 0xAC, 0x02, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+                ),
+                (HostProtocolMessage::Bluetooth(Bluetooth::SetSarRx(true)), &[0, 100, 1]),
+                (HostProtocolMessage::Bluetooth(Bluetooth::AckSetSarRx), &[0, 101]),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::AddressOnAir(Some(BtAddress {
+                        addr_type: AddressType::RandomPrivateResolvable,
+                        addr: [0x4A, 2, 3, 4, 5, 6],
+                    }))),
+                    &[0, 78, 1, 2, 0x4A, 2, 3, 4, 5, 6],
+                ),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::Events(EventBatch {
+                        events: heapless::Vec::from_slice(&[Event::AdvertisingTimeout]).unwrap(),
+                        overflow: false,
+                    })),
+                    &[0, 29, 1, 7, 0],
+                ),
+                (
+                    HostProtocolMessage::Bluetooth(Bluetooth::Events(EventBatch {
+                        events: heapless::Vec::from_slice(&[
+                            Event::PairingPasskey {
+                                passkey: 123456,
+                                confirm: true,
+                            },
+                            Event::Secured,
+                            Event::Bonded,
+                            Event::TextTyped,
+                            Event::CustomWritten { index: 2 },
+                            Event::CustomRead { index: 0 },
+                            Event::BondStorageFailed,
+                        ])
+                        .unwrap(),
+                        overflow: false,
+                    })),
+                    &[0, 29, 7, 8, 0xC0, 0xC4, 0x07, 1, 9, 10, 11, 12, 2, 13, 0, 14, 0],
+                ),
             ],
         );
     }
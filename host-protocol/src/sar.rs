@@ -0,0 +1,280 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Segmentation and reassembly of messages larger than a NUS packet.
+//!
+//! Every segment starts with a one byte header:
+//!
+//! - single: `SINGLE | payload`, a complete message
+//! - start: `START | total_len: u16 (LE) | payload`
+//! - continue: `CONTINUE | payload`
+//! - end: `END | payload`, completes the message of `total_len` bytes
+//!
+//! The target splits `SendSarData` payloads into notifications with [`write_segment`]. Once the
+//! host sent `SetSarRx(true)`, it reassembles RX writes with a [`Reassembler`] as they arrive and
+//! keeps the message for `GetReceivedSarData`. The phone side is expected to use the same format
+//! in both directions.
+
+/// Header of a complete message in one segment
+pub const SINGLE: u8 = 0x00;
+/// Header of the first segment, followed by the total message length
+pub const START: u8 = 0x01;
+/// Header of a segment in the middle of a message
+pub const CONTINUE: u8 = 0x02;
+/// Header of the last segment
+pub const END: u8 = 0x03;
+
+/// Size of the `START` header with the total length
+pub const START_HEADER_SIZE: usize = 3;
+
+/// Why a segment was rejected, the partial message is dropped
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SarError {
+    /// Empty segment, unknown header or a `START` or `CONTINUE` completing the message
+    Malformed,
+    /// `CONTINUE` or `END` without a `START`
+    NotStarted,
+    /// The message is longer than the reassembly buffer or its announced length
+    TooLong,
+    /// `END` before the announced length was received
+    Truncated,
+}
+
+/// Writes the segment of a `total_len` bytes message starting at `offset` into `buf`, whose
+/// length is the maximum segment size.
+///
+/// `data` holds the message bytes from `offset` on, possibly not all of them. Returns the segment
+/// length and the number of bytes of `data` it carries, or `None` if there is nothing to send.
+/// Segments other than the last one are always full, so a message can be resumed at any offset
+/// returned by a previous call.
+pub fn write_segment(total_len: usize, offset: usize, data: &[u8], buf: &mut [u8]) -> Option<(usize, usize)> {
+    let total_len_le = u16::try_from(total_len).ok()?.to_le_bytes();
+    let data = &data[..data.len().min(total_len.checked_sub(offset)?)];
+    if data.is_empty() || buf.len() <= START_HEADER_SIZE {
+        return None;
+    }
+    let (header_len, payload_len) = if offset == 0 && data.len() == total_len && total_len < buf.len() {
+        buf[0] = SINGLE;
+        (1, total_len)
+    } else if offset == 0 {
+        buf[0] = START;
+        buf[1..START_HEADER_SIZE].copy_from_slice(&total_len_le);
+        (START_HEADER_SIZE, data.len().min(buf.len() - START_HEADER_SIZE))
+    } else {
+        let payload_len = data.len().min(buf.len() - 1);
+        buf[0] = match offset + payload_len == total_len {
+            true => END,
+            false => CONTINUE,
+        };
+        (1, payload_len)
+    };
+    buf[header_len..header_len + payload_len].copy_from_slice(&data[..payload_len]);
+    Some((header_len + payload_len, payload_len))
+}
+
+/// Splits a message into segments
+pub struct Segmenter<'a> {
+    message: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Segmenter<'a> {
+    pub fn new(message: &'a [u8]) -> Self {
+        Self { message, offset: 0 }
+    }
+
+    /// Writes the next segment of at most `buf.len()` bytes and returns it, `None` once the
+    /// message was sent
+    pub fn next<'b>(&mut self, buf: &'b mut [u8]) -> Option<&'b [u8]> {
+        let (len, sent) = write_segment(self.message.len(), self.offset, &self.message[self.offset..], buf)?;
+        self.offset += sent;
+        Some(&buf[..len])
+    }
+}
+
+/// Reassembles segments into messages of up to `N` bytes
+pub struct Reassembler<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    /// Announced length of the message in progress
+    total_len: Option<usize>,
+}
+
+impl<const N: usize> Default for Reassembler<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Reassembler<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            total_len: None,
+        }
+    }
+
+    /// Adds a segment, returns `true` once a message is complete.
+    ///
+    /// The complete message stays available in [`message`](Self::message) until the next segment.
+    /// On errors the partial message is dropped and segments are ignored up to the next `START`.
+    pub fn push(&mut self, segment: &[u8]) -> Result<bool, SarError> {
+        let result = self.push_inner(segment);
+        if result.is_err() {
+            self.reset();
+        }
+        result
+    }
+
+    fn push_inner(&mut self, segment: &[u8]) -> Result<bool, SarError> {
+        let (&header, payload) = segment.split_first().ok_or(SarError::Malformed)?;
+        let payload = match header {
+            SINGLE => {
+                self.len = 0;
+                self.total_len = Some(payload.len());
+                payload
+            }
+            START => {
+                let (len, payload) = payload.split_first_chunk().ok_or(SarError::Malformed)?;
+                let total_len = u16::from_le_bytes(*len).into();
+                if total_len > N {
+                    return Err(SarError::TooLong);
+                }
+                self.len = 0;
+                self.total_len = Some(total_len);
+                payload
+            }
+            CONTINUE | END if !self.in_progress() => return Err(SarError::NotStarted),
+            CONTINUE | END => payload,
+            _ => return Err(SarError::Malformed),
+        };
+        let total_len = self.total_len.unwrap_or_default();
+        let end = self.len + payload.len();
+        if end > total_len || end > N {
+            return Err(SarError::TooLong);
+        }
+        self.buf[self.len..end].copy_from_slice(payload);
+        self.len = end;
+        match header {
+            SINGLE => Ok(true),
+            END if end == total_len => Ok(true),
+            END => Err(SarError::Truncated),
+            _ if end == total_len => Err(SarError::Malformed),
+            _ => Ok(false),
+        }
+    }
+
+    /// Drops the complete or partial message
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.total_len = None;
+    }
+
+    fn in_progress(&self) -> bool {
+        self.total_len.is_some_and(|total_len| self.len < total_len)
+    }
+
+    /// The last complete message, empty while a message is in progress
+    pub fn message(&self) -> &[u8] {
+        match self.total_len {
+            Some(total_len) if self.len == total_len => &self.buf[..self.len],
+            _ => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{vec, vec::Vec};
+
+    fn segments(message: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+        let mut segmenter = Segmenter::new(message);
+        let mut buf = vec![0; mtu];
+        core::iter::from_fn(|| segmenter.next(&mut buf).map(<[u8]>::to_vec)).collect()
+    }
+
+    #[test]
+    fn segment_layout() {
+        assert_eq!(segments(&[1, 2, 3], 8), [vec![SINGLE, 1, 2, 3]]);
+        assert_eq!(
+            segments(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10], 6),
+            [vec![START, 10, 0, 1, 2, 3], vec![CONTINUE, 4, 5, 6, 7, 8], vec![END, 9, 10]]
+        );
+        assert_eq!(segments(&[1, 2, 3, 4], 5), [vec![SINGLE, 1, 2, 3, 4]]);
+        assert_eq!(segments(&[1, 2, 3, 4, 5], 5), [vec![START, 5, 0, 1, 2], vec![END, 3, 4, 5]]);
+        assert!(segments(&[], 8).is_empty());
+        assert!(segments(&[1; 10], START_HEADER_SIZE).is_empty());
+    }
+
+    #[test]
+    fn resume_at_offset() {
+        let message: Vec<u8> = (0..100).collect();
+        let mut buf = [0u8; 20];
+        let (len, sent) = write_segment(message.len(), 0, &message[..30], &mut buf).unwrap();
+        assert_eq!((len, sent), (20, 17));
+        // only part of the message at hand
+        let (len, sent) = write_segment(message.len(), 17, &message[17..30], &mut buf).unwrap();
+        assert_eq!((len, sent), (14, 13));
+        assert_eq!(buf[0], CONTINUE);
+        let (len, sent) = write_segment(message.len(), 90, &message[90..], &mut buf).unwrap();
+        assert_eq!((len, sent), (11, 10));
+        assert_eq!(buf[0], END);
+        assert_eq!(write_segment(message.len(), 100, &[], &mut buf), None);
+        assert_eq!(write_segment(message.len(), 101, &[1], &mut buf), None);
+        assert_eq!(write_segment(usize::from(u16::MAX) + 1, 0, &message, &mut buf), None);
+    }
+
+    #[test]
+    fn reassemble() {
+        let message: Vec<u8> = (0..=255).collect();
+        let mut reassembler = Reassembler::<256>::new();
+        for mtu in [4, 20, 255, 257, 300] {
+            let segments = segments(&message, mtu);
+            let (last, first) = segments.split_last().unwrap();
+            for segment in first {
+                assert_eq!(reassembler.push(segment), Ok(false));
+                assert!(reassembler.message().is_empty());
+            }
+            assert_eq!(reassembler.push(last), Ok(true));
+            assert_eq!(reassembler.message(), message);
+        }
+        assert_eq!(reassembler.push(&[SINGLE]), Ok(true));
+        assert!(reassembler.message().is_empty());
+
+        assert_eq!(reassembler.push(&[START, 4, 0, 1, 2]), Ok(false));
+        reassembler.reset();
+        assert_eq!(reassembler.push(&[END, 3, 4]), Err(SarError::NotStarted));
+    }
+
+    #[test]
+    fn reassembly_errors() {
+        let mut reassembler = Reassembler::<8>::new();
+        assert_eq!(reassembler.push(&[]), Err(SarError::Malformed));
+        assert_eq!(reassembler.push(&[0x04, 1]), Err(SarError::Malformed));
+        assert_eq!(reassembler.push(&[START, 1]), Err(SarError::Malformed));
+        assert_eq!(reassembler.push(&[CONTINUE, 1]), Err(SarError::NotStarted));
+        assert_eq!(reassembler.push(&[SINGLE; 10]), Err(SarError::TooLong));
+
+        assert_eq!(reassembler.push(&[START, 4, 0, 1, 2]), Ok(false));
+        assert_eq!(reassembler.push(&[END, 3]), Err(SarError::Truncated));
+        // the rest of a dropped message is ignored
+        assert_eq!(reassembler.push(&[END, 4]), Err(SarError::NotStarted));
+
+        assert_eq!(reassembler.push(&[START, 4, 0, 1, 2]), Ok(false));
+        assert_eq!(reassembler.push(&[CONTINUE, 3, 4]), Err(SarError::Malformed));
+        assert_eq!(reassembler.push(&[START, 4, 0, 1, 2]), Ok(false));
+        assert_eq!(reassembler.push(&[END, 3, 4, 5]), Err(SarError::TooLong));
+        assert_eq!(reassembler.push(&[START, 9, 0, 1, 2]), Err(SarError::TooLong));
+        assert_eq!(reassembler.push(&[START, 0, 0]), Err(SarError::Malformed));
+
+        // a new message can start at any time
+        assert_eq!(reassembler.push(&[START, 4, 0, 1, 2]), Ok(false));
+        assert_eq!(reassembler.push(&[SINGLE, 7]), Ok(true));
+        assert_eq!(reassembler.message(), [7]);
+        assert_eq!(reassembler.push(&[END, 3]), Err(SarError::NotStarted));
+    }
+}
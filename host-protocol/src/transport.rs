@@ -0,0 +1,404 @@
+// SPDX-FileCopyrightText: 2024 Foundation Devices, Inc. <hello@foundation.xyz>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Transports moving encoded messages between the host and the target.
+//!
+//! - [`CobsTransport`]: COBS-encoded messages terminated by `0x00` over a serial port
+//! - [`SpiTransport`]: the SPIS flow of the firmware, one transaction with the request followed by
+//!   one transaction reading the 2-byte big-endian length prefixed response
+//! - [`Loopback`]: in-memory target for tests
+
+use crate::frame::{self, CRC_SIZE, LEN_SIZE, REQUEST_FRAME_OVERHEAD};
+use crate::{PostcardError, MAX_BATCH_MSG_SIZE, MAX_MSG_SIZE};
+use embedded_hal::spi::SpiDevice;
+use std::convert::Infallible;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Moves encoded messages between the host and the target.
+pub trait Transport {
+    type Error: std::error::Error + 'static;
+
+    /// Sends one encoded request and receives the encoded response into `response`.
+    ///
+    /// Returns the length of the response.
+    fn exchange(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+/// Errors returned by [`CobsTransport`] and [`SpiTransport`]
+#[derive(Debug)]
+pub enum TransportError<E> {
+    /// The underlying port or bus failed
+    Io(E),
+    /// The target didn't answer in time
+    NoResponse,
+    /// The response is corrupted
+    Frame(PostcardError),
+    /// The message doesn't fit in the buffer
+    TooLong(usize),
+}
+
+impl<E: fmt::Debug> fmt::Display for TransportError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {e:?}"),
+            Self::NoResponse => write!(f, "no response from target"),
+            Self::Frame(e) => write!(f, "corrupted response: {e:?}"),
+            Self::TooLong(len) => write!(f, "{len} bytes message doesn't fit in the buffer"),
+        }
+    }
+}
+
+impl<E: fmt::Debug> std::error::Error for TransportError<E> {}
+
+/// COBS framing over a serial port, as used by the UART builds
+pub struct CobsTransport<P> {
+    port: P,
+    rx: Vec<u8>,
+}
+
+impl<P: Read + Write> CobsTransport<P> {
+    pub fn new(port: P) -> Self {
+        Self { port, rx: Vec::new() }
+    }
+
+    pub fn port(&mut self) -> &mut P {
+        &mut self.port
+    }
+
+    pub fn into_port(self) -> P {
+        self.port
+    }
+
+    /// Reads until the `0x00` sentinel, skipping empty frames
+    fn read_frame(&mut self, max_len: usize) -> Result<(), TransportError<io::Error>> {
+        self.rx.clear();
+        let mut chunk = [0u8; 64];
+        loop {
+            let n = match self.port.read(&mut chunk) {
+                Ok(0) => return Err(TransportError::NoResponse),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => return Err(TransportError::NoResponse),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(TransportError::Io(e)),
+            };
+            for &byte in &chunk[..n] {
+                match byte {
+                    0 if self.rx.is_empty() => {}
+                    // Bytes after the sentinel can't belong to this exchange
+                    0 => return Ok(()),
+                    _ => self.rx.push(byte),
+                }
+            }
+            if self.rx.len() > cobs::max_encoding_length(max_len) {
+                return Err(TransportError::TooLong(self.rx.len()));
+            }
+        }
+    }
+}
+
+impl<P: Read + Write> Transport for CobsTransport<P> {
+    type Error = TransportError<io::Error>;
+
+    fn exchange(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut encoded = cobs::encode_vec(request);
+        encoded.push(0);
+        self.port.write_all(&encoded).map_err(TransportError::Io)?;
+        self.port.flush().map_err(TransportError::Io)?;
+
+        self.read_frame(response.len().max(MAX_MSG_SIZE))?;
+        if self.rx.len() > cobs::max_encoding_length(response.len()) {
+            return Err(TransportError::TooLong(self.rx.len()));
+        }
+        cobs::decode(&self.rx, response).map_err(|_| TransportError::Frame(PostcardError::Deser))
+    }
+}
+
+/// Length-prefixed messages over SPI, the host being the controller.
+///
+/// A request is written in one transaction. After a turnaround delay the response is read in a
+/// second transaction: a 2-byte big-endian length, the payload and, with CRC framing, the CRC32.
+/// A length of zero (default character) or longer than a message (`0x5151` overread character)
+/// means the target isn't ready yet and the read is retried.
+///
+/// The response is read in a transaction of `response.len()` bytes, clamped to
+/// `MAX_MSG_SIZE..=MAX_BATCH_MSG_SIZE`.
+pub struct SpiTransport<D> {
+    spi: D,
+    crc: bool,
+    turnaround: Duration,
+    retries: usize,
+    buf: [u8; MAX_BATCH_MSG_SIZE + REQUEST_FRAME_OVERHEAD],
+}
+
+impl<D: SpiDevice> SpiTransport<D> {
+    pub fn new(spi: D) -> Self {
+        Self {
+            spi,
+            crc: false,
+            turnaround: Duration::from_millis(2),
+            retries: 5,
+            buf: [0; MAX_BATCH_MSG_SIZE + REQUEST_FRAME_OVERHEAD],
+        }
+    }
+
+    /// Wraps requests in a CRC-protected [frame](crate::frame) and checks the CRC of responses
+    pub fn with_crc(mut self, enabled: bool) -> Self {
+        self.crc = enabled;
+        self
+    }
+
+    /// Delay between the request and each attempt to read the response
+    pub fn with_turnaround(mut self, turnaround: Duration) -> Self {
+        self.turnaround = turnaround;
+        self
+    }
+
+    /// Number of response reads before giving up
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn spi(&mut self) -> &mut D {
+        &mut self.spi
+    }
+
+    pub fn into_spi(self) -> D {
+        self.spi
+    }
+}
+
+impl<D: SpiDevice> Transport for SpiTransport<D>
+where
+    D::Error: 'static,
+{
+    type Error = TransportError<D::Error>;
+
+    fn exchange(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, Self::Error> {
+        let req_len = if self.crc {
+            frame::frame_request(request, &mut self.buf).ok_or(TransportError::TooLong(request.len()))?
+        } else {
+            let buf = self.buf.get_mut(..request.len()).ok_or(TransportError::TooLong(request.len()))?;
+            buf.copy_from_slice(request);
+            request.len()
+        };
+        self.spi.write(&self.buf[..req_len]).map_err(TransportError::Io)?;
+
+        let trailer = if self.crc { CRC_SIZE } else { 0 };
+        let read_len = response.len().clamp(MAX_MSG_SIZE, MAX_BATCH_MSG_SIZE);
+        let resp_buf = &mut self.buf[..read_len];
+        for _ in 0..self.retries {
+            sleep(self.turnaround);
+            self.spi.read(resp_buf).map_err(TransportError::Io)?;
+            let len = u16::from_be_bytes([resp_buf[0], resp_buf[1]]) as usize;
+            if len == 0 || LEN_SIZE + len + trailer > read_len {
+                continue;
+            }
+            let payload = if self.crc {
+                frame::open(resp_buf).map_err(TransportError::Frame)?
+            } else {
+                &resp_buf[LEN_SIZE..LEN_SIZE + len]
+            };
+            let out = response.get_mut(..payload.len()).ok_or(TransportError::TooLong(payload.len()))?;
+            out.copy_from_slice(payload);
+            return Ok(payload.len());
+        }
+        Err(TransportError::NoResponse)
+    }
+}
+
+/// In-memory transport calling `F` with each request and a response buffer.
+///
+/// `F` returns the length of the response it wrote.
+pub struct Loopback<F>(pub F);
+
+impl<F: FnMut(&[u8], &mut [u8]) -> usize> Transport for Loopback<F> {
+    type Error = Infallible;
+
+    fn exchange(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, Infallible> {
+        Ok((self.0)(request, response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::Envelope;
+    use crate::{Bluetooth, HostProtocolMessage};
+    use embedded_hal::spi::{ErrorType, Operation};
+    use std::collections::VecDeque;
+
+    const ORC: u8 = 0x51;
+
+    fn encode(message: Bluetooth) -> Vec<u8> {
+        let mut buf = [0u8; MAX_MSG_SIZE];
+        postcard::to_slice(&HostProtocolMessage::Bluetooth(message), &mut buf)
+            .unwrap()
+            .to_vec()
+    }
+
+    fn get_status() -> Vec<u8> {
+        encode(Bluetooth::GetStatus)
+    }
+
+    fn ack_enable() -> Vec<u8> {
+        encode(Bluetooth::AckEnable)
+    }
+
+    /// Serial port answering each written message with the queued responses
+    #[derive(Default)]
+    struct FakePort {
+        tx: Vec<u8>,
+        rx: VecDeque<u8>,
+    }
+
+    impl Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.rx.read(buf)
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.tx.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cobs_exchange() {
+        let mut port = FakePort::default();
+        // leading sentinel from a previous frame and a trailing frame are ignored
+        port.rx.push_back(0);
+        port.rx.extend(cobs::encode_vec(&ack_enable()));
+        port.rx.extend([0, 0x02, 0x01, 0]);
+        let mut transport = CobsTransport::new(port);
+        let mut resp = [0u8; MAX_MSG_SIZE];
+        let len = transport.exchange(&get_status(), &mut resp).unwrap();
+        assert_eq!(&resp[..len], ack_enable());
+
+        let port = transport.into_port();
+        let mut sent = cobs::encode_vec(&get_status());
+        sent.push(0);
+        assert_eq!(port.tx, sent);
+    }
+
+    #[test]
+    fn cobs_no_response() {
+        let mut transport = CobsTransport::new(FakePort::default());
+        transport.port().rx.extend(cobs::encode_vec(&ack_enable()));
+        let mut resp = [0u8; MAX_MSG_SIZE];
+        assert!(matches!(
+            transport.exchange(&get_status(), &mut resp),
+            Err(TransportError::NoResponse)
+        ));
+    }
+
+    /// SPI target answering requests after `busy` reads, padding with the overread character.
+    /// While busy it clocks out the default character.
+    struct FakeSpis {
+        crc: bool,
+        busy: usize,
+        pending: Option<Vec<u8>>,
+        requests: Vec<Vec<u8>>,
+    }
+
+    impl FakeSpis {
+        fn new(crc: bool, busy: usize) -> Self {
+            Self {
+                crc,
+                busy,
+                pending: None,
+                requests: Vec::new(),
+            }
+        }
+    }
+
+    impl ErrorType for FakeSpis {
+        type Error = Infallible;
+    }
+
+    impl SpiDevice for FakeSpis {
+        fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+            for op in operations {
+                match op {
+                    Operation::Write(req) => {
+                        self.requests.push(req.to_vec());
+                        let payload = frame::open_request(req).unwrap();
+                        let mut resp = vec![0; MAX_MSG_SIZE];
+                        let payload_len = Envelope::new(Envelope::split(payload).0, HostProtocolMessage::Bluetooth(Bluetooth::AckEnable))
+                            .encode(&mut resp[LEN_SIZE..])
+                            .unwrap()
+                            .len();
+                        let len = frame::finish_response(&mut resp, payload_len, self.crc).unwrap();
+                        resp.truncate(len);
+                        self.pending = Some(resp);
+                    }
+                    Operation::Read(buf) if self.busy > 0 => {
+                        self.busy -= 1;
+                        buf.fill(0);
+                    }
+                    Operation::Read(buf) => {
+                        let resp = self.pending.take().unwrap_or_default();
+                        buf.fill(ORC);
+                        buf[..resp.len()].copy_from_slice(&resp);
+                    }
+                    Operation::DelayNs(_) => {}
+                    Operation::Transfer(..) | Operation::TransferInPlace(_) => {
+                        unreachable!("SpiTransport only reads and writes")
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn spi_exchange() {
+        let mut transport = SpiTransport::new(FakeSpis::new(false, 1)).with_turnaround(Duration::ZERO);
+        let mut resp = [0u8; MAX_MSG_SIZE];
+        let len = transport.exchange(&get_status(), &mut resp).unwrap();
+        assert_eq!(&resp[..len], ack_enable());
+        assert_eq!(transport.spi().requests, [get_status()]);
+    }
+
+    #[test]
+    fn spi_exchange_with_crc() {
+        let mut transport = SpiTransport::new(FakeSpis::new(true, 0))
+            .with_turnaround(Duration::ZERO)
+            .with_crc(true);
+        let mut resp = [0u8; MAX_MSG_SIZE];
+        let len = transport.exchange(&get_status(), &mut resp).unwrap();
+        assert_eq!(&resp[..len], ack_enable());
+        assert_eq!(transport.spi().requests[0][0], frame::FRAME_TAG);
+    }
+
+    #[test]
+    fn spi_no_response() {
+        let mut transport = SpiTransport::new(FakeSpis::new(false, 3))
+            .with_turnaround(Duration::ZERO)
+            .with_retries(3);
+        let mut resp = [0u8; MAX_MSG_SIZE];
+        assert!(matches!(
+            transport.exchange(&get_status(), &mut resp),
+            Err(TransportError::NoResponse)
+        ));
+    }
+
+    #[test]
+    fn loopback_exchange() {
+        let mut transport = Loopback(|req: &[u8], resp: &mut [u8]| {
+            resp[..req.len()].copy_from_slice(req);
+            req.len()
+        });
+        let mut resp = [0u8; 8];
+        assert_eq!(transport.exchange(&[1, 2, 3], &mut resp), Ok(3));
+        assert_eq!(&resp[..3], &[1, 2, 3]);
+    }
+}
@@ -3,7 +3,7 @@
 
 use cargo_metadata::MetadataCommand;
 use clap::{Parser, Subcommand};
-use consts::{BASE_APP_ADDR, BASE_BOOTLOADER_ADDR, SIGNATURE_HEADER_SIZE};
+use consts::{BASE_APP_ADDR, BASE_BOOTLOADER_ADDR, BOND_STORAGE_ADDR, SIGNATURE_HEADER_SIZE};
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::{exit, Command, Stdio};
@@ -302,7 +302,7 @@ fn build_bt_firmware(verbose: bool) {
     }
 
     // Created a full populated flash image to avoid the signed fw is different from the slice to check.
-    // We will always get the full slice of flash where app is flashed ( BASE_APP_ADDR up to BASE_BOOTLOADER_ADDR )
+    // We will always get the full slice of flash where app is flashed ( BASE_APP_ADDR up to BOND_STORAGE_ADDR )
     tracing::info!("Creating BT application bin file");
     // Print actual binary size information
     print_binary_size(
@@ -311,14 +311,14 @@ fn build_bt_firmware(verbose: bool) {
     );
 
     let mut cargo_cmd = Command::new(cargo());
-    let base_bootloader_addr = BASE_BOOTLOADER_ADDR.to_string();
+    let bond_storage_addr = BOND_STORAGE_ADDR.to_string();
     let cmd = cargo_cmd
         .current_dir(project_root().join("firmware"))
         .args(["objcopy", "--release"]);
     let mut cmd = cmd.args([
         "--",
         "--pad-to",
-        base_bootloader_addr.as_str(), // no need to reserve space for trailer because we don't use cosign2's extended signatures
+        bond_storage_addr.as_str(), // no need to reserve space for trailer because we don't use cosign2's extended signatures
         "-O",
         "binary",
         "../BtPackage/BT_application.bin",
@@ -632,8 +632,8 @@ fn print_binary_size(binary_path: &Path, description: &str) {
         let size_kb = size_bytes as f64 / 1024.0;
 
         // Calculate flash usage percentage
-        // Available flash space for application: from BASE_APP_ADDR to BASE_BOOTLOADER_ADDR, minus signature header
-        let app_flash_size = (BASE_BOOTLOADER_ADDR - BASE_APP_ADDR - SIGNATURE_HEADER_SIZE) as u64;
+        // Available flash space for application: from BASE_APP_ADDR to BOND_STORAGE_ADDR, minus signature header
+        let app_flash_size = (BOND_STORAGE_ADDR - BASE_APP_ADDR - SIGNATURE_HEADER_SIZE) as u64;
         let usage_percentage = (size_bytes as f64 / app_flash_size as f64) * 100.0;
 
         println!("📊 {} Size:", description);